@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+
+use crate::{
+    image::Image,
+    imports::{resolve_import_call, resolve_import_stub},
+    symbolize::{ConstantTracker, ImageSymbols, SymbolUse},
+    trace::try_disassemble_trace,
+    util::json_string,
+    vm_handler::is_vm_entry_call,
+    vm_matchers::HandlerVmInstruction,
+};
+
+/// What the code at a node of the call graph is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A vm call, the routine it enters was decoded
+    Vm,
+    /// Native code, or a vm call whose routine failed to decode
+    Native,
+    /// An import protection stub or a call to one
+    Import,
+}
+
+impl NodeKind {
+    fn name(self) -> &'static str {
+        match self {
+            NodeKind::Vm => "vm",
+            NodeKind::Native => "native",
+            NodeKind::Import => "import",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CallGraphNode {
+    pub address: u64,
+    pub kind: NodeKind,
+    /// Section and symbol of the address, or the dll!function of an import
+    pub label: String,
+    /// Handlers the routine executed, for vm nodes
+    pub handler_count: usize,
+}
+
+/// How control gets from one node to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// The routine exits into the target
+    Exit,
+    /// The code the vm exited into returns into the target vm call
+    Return,
+    /// The routine pushes the address of the target
+    Reference,
+}
+
+impl EdgeKind {
+    fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Exit => "exit",
+            EdgeKind::Return => "return",
+            EdgeKind::Reference => "reference",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallGraphEdge {
+    pub from: u64,
+    pub to: u64,
+    pub kind: EdgeKind,
+}
+
+/// Virtualized routines, the native code and imports they reach and how they reach them
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    pub nodes: Vec<CallGraphNode>,
+    pub edges: Vec<CallGraphEdge>,
+}
+
+impl CallGraph {
+    fn node(&self,
+            address: u64)
+            -> Option<&CallGraphNode> {
+        self.nodes.iter().find(|node| node.address == address)
+    }
+
+    fn add_node(&mut self,
+                node: CallGraphNode) {
+        if self.node(node.address).is_none() {
+            self.nodes.push(node);
+        }
+    }
+
+    /// Adds the edge unless the nodes are already connected
+    fn add_edge(&mut self,
+                edge: CallGraphEdge) {
+        if !self.edges.iter().any(|known| known.from == edge.from && known.to == edge.to) {
+            self.edges.push(edge);
+        }
+    }
+
+    /// The graph as JSON, addresses as hex strings
+    pub fn to_json(&self) -> String {
+        let nodes = self.nodes
+                        .iter()
+                        .map(|node| {
+                            format!("    {{\"address\": \"{:#x}\", \"kind\": \"{}\", \
+                                     \"label\": {}, \"handlers\": {}}}",
+                                    node.address,
+                                    node.kind.name(),
+                                    json_string(&node.label),
+                                    node.handler_count)
+                        })
+                        .collect::<Vec<_>>();
+        let edges = self.edges
+                        .iter()
+                        .map(|edge| {
+                            format!("    {{\"from\": \"{:#x}\", \"to\": \"{:#x}\", \
+                                     \"kind\": \"{}\"}}",
+                                    edge.from,
+                                    edge.to,
+                                    edge.kind.name())
+                        })
+                        .collect::<Vec<_>>();
+        format!("{{\n  \"nodes\": [\n{}\n  ],\n  \"edges\": [\n{}\n  ]\n}}",
+                nodes.join(",\n"),
+                edges.join(",\n"))
+    }
+
+    /// The graph in Graphviz DOT, vm routines are boxes and imports notes
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph call_graph {\n");
+        for node in self.nodes.iter() {
+            let shape = match node.kind {
+                NodeKind::Vm => "box",
+                NodeKind::Native => "ellipse",
+                NodeKind::Import => "note",
+            };
+            let label = format!("{:#x}\n{}", node.address, node.label);
+            dot += &format!("    \"{:#x}\" [label={}, shape={}];\n",
+                            node.address,
+                            json_string(&label),
+                            shape);
+        }
+        for edge in self.edges.iter() {
+            dot += &format!("    \"{:#x}\" -> \"{:#x}\" [label=\"{}\"];\n",
+                            edge.from,
+                            edge.to,
+                            edge.kind.name());
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+/// What the address is, empty outside of the sections
+fn symbol_label(image: &dyn Image,
+                symbols: &ImageSymbols,
+                address: u64)
+                -> String {
+    symbols.resolve(image, address).map(|symbol| symbol.to_string()).unwrap_or_default()
+}
+
+/// The node for code at the address, None for native code when only vm calls and imports are
+/// of interest
+fn classify(image: &dyn Image,
+            symbols: &ImageSymbols,
+            address: u64,
+            native: bool)
+            -> Option<CallGraphNode> {
+    let (kind, label) = if is_vm_entry_call(image, address) {
+        (NodeKind::Vm, symbol_label(image, symbols, address))
+    } else if let Some(stub) = resolve_import_call(image, symbols, address)
+        .or_else(|| resolve_import_stub(image, symbols, address))
+    {
+        (NodeKind::Import, stub.import)
+    } else if native {
+        (NodeKind::Native, symbol_label(image, symbols, address))
+    } else {
+        return None;
+    };
+
+    Some(CallGraphNode { address,
+                         kind,
+                         label,
+                         handler_count: 0 })
+}
+
+/// Decodes the routines of the vm calls and of every vm call they reach through their exits,
+/// the return addresses they leave for native code and the constants they push
+pub fn build_call_graph(image: &dyn Image,
+                        symbols: &ImageSymbols,
+                        vm_call_addresses: &[u64])
+                        -> CallGraph {
+    let mut graph = CallGraph::default();
+    let mut pending = vm_call_addresses.iter().copied().collect::<VecDeque<_>>();
+    let mut decoded = Vec::new();
+
+    while let Some(vm_call_address) = pending.pop_front() {
+        if decoded.contains(&vm_call_address) {
+            continue;
+        }
+        decoded.push(vm_call_address);
+
+        let label = symbol_label(image, symbols, vm_call_address);
+        let (vm_context, steps) = match try_disassemble_trace(image, vm_call_address) {
+            Ok(trace) => trace,
+            Err(_) => {
+                // Kept in the graph as native code, nothing is known about where it goes
+                match graph.nodes.iter_mut().find(|node| node.address == vm_call_address) {
+                    Some(node) => node.kind = NodeKind::Native,
+                    None => graph.nodes.push(CallGraphNode { address: vm_call_address,
+                                                             kind: NodeKind::Native,
+                                                             label,
+                                                             handler_count: 0 }),
+                }
+                continue;
+            },
+        };
+        let mut tracker = ConstantTracker::new(&vm_context, image);
+        let immediates = steps.iter()
+                              .filter_map(|step| match tracker.step(step) {
+                                  Some((SymbolUse::Immediate, value)) => Some(value),
+                                  _ => None,
+                              })
+                              .collect::<Vec<_>>();
+
+        match graph.nodes.iter_mut().find(|node| node.address == vm_call_address) {
+            Some(node) => node.handler_count = steps.len(),
+            None => {
+                graph.nodes.push(CallGraphNode { address: vm_call_address,
+                                                 kind: NodeKind::Vm,
+                                                 label,
+                                                 handler_count: steps.len() })
+            },
+        }
+
+        let mut targets = Vec::new();
+        let last = steps.last().unwrap();
+        if last.instruction == HandlerVmInstruction::VmExit {
+            let pop_count = last.handler.get_pop_order_vm_exit().len();
+            if let Some(continuation) = tracker.vm_exit_continuation(pop_count) {
+                targets.push((vm_call_address, continuation, EdgeKind::Exit));
+                if let Some(return_address) = tracker.vm_exit_return_address(pop_count) {
+                    targets.push((continuation, return_address, EdgeKind::Return));
+                }
+            }
+        }
+        for value in immediates {
+            targets.push((vm_call_address, value, EdgeKind::Reference));
+        }
+
+        for (from, to, kind) in targets {
+            let node = match classify(image, symbols, to, kind == EdgeKind::Exit) {
+                // Only vm calls are returned into
+                Some(node) if kind != EdgeKind::Return || node.kind == NodeKind::Vm => node,
+                _ => continue,
+            };
+            if node.kind == NodeKind::Vm {
+                pending.push_back(to);
+            }
+            graph.add_node(node);
+            graph.add_edge(CallGraphEdge { from, to, kind });
+        }
+    }
+
+    graph
+}
@@ -0,0 +1,294 @@
+use iced_x86::{Code, Instruction, Mnemonic, OpKind, Register};
+
+use crate::{
+    deobfuscate::{compute_liveness, handler_live_out, handler_live_out_flags, Liveness},
+    vm_handler::{VmHandler, VmRegisterAllocation},
+};
+
+fn gpr32(reg: Register) -> Register {
+    Register::try_from(Register::EAX as usize + reg.full_register().number()).unwrap()
+}
+
+fn is_immediate(op_kind: OpKind) -> bool {
+    matches!(op_kind,
+             OpKind::Immediate8 |
+             OpKind::Immediate16 |
+             OpKind::Immediate32 |
+             OpKind::Immediate8to16 |
+             OpKind::Immediate8to32 |
+             OpKind::Immediate8to64 |
+             OpKind::Immediate32to64)
+}
+
+pub fn alu_immediate_code(mnemonic: Mnemonic,
+                          size: usize)
+                          -> Option<Code> {
+    let code = match (mnemonic, size) {
+        (Mnemonic::Add, 8) => Code::Add_rm64_imm32,
+        (Mnemonic::Add, 4) => Code::Add_rm32_imm32,
+        (Mnemonic::Add, 2) => Code::Add_rm16_imm16,
+        (Mnemonic::Add, 1) => Code::Add_rm8_imm8,
+        (Mnemonic::Sub, 8) => Code::Sub_rm64_imm32,
+        (Mnemonic::Sub, 4) => Code::Sub_rm32_imm32,
+        (Mnemonic::Sub, 2) => Code::Sub_rm16_imm16,
+        (Mnemonic::Sub, 1) => Code::Sub_rm8_imm8,
+        (Mnemonic::And, 8) => Code::And_rm64_imm32,
+        (Mnemonic::And, 4) => Code::And_rm32_imm32,
+        (Mnemonic::And, 2) => Code::And_rm16_imm16,
+        (Mnemonic::And, 1) => Code::And_rm8_imm8,
+        (Mnemonic::Or, 8) => Code::Or_rm64_imm32,
+        (Mnemonic::Or, 4) => Code::Or_rm32_imm32,
+        (Mnemonic::Or, 2) => Code::Or_rm16_imm16,
+        (Mnemonic::Or, 1) => Code::Or_rm8_imm8,
+        (Mnemonic::Xor, 8) => Code::Xor_rm64_imm32,
+        (Mnemonic::Xor, 4) => Code::Xor_rm32_imm32,
+        (Mnemonic::Xor, 2) => Code::Xor_rm16_imm16,
+        (Mnemonic::Xor, 1) => Code::Xor_rm8_imm8,
+        _ => return None,
+    };
+    Some(code)
+}
+
+pub fn alu_reg_rm_code(mnemonic: Mnemonic,
+                       size: usize)
+                       -> Option<Code> {
+    let code = match (mnemonic, size) {
+        (Mnemonic::Mov, 8) => Code::Mov_r64_rm64,
+        (Mnemonic::Mov, 4) => Code::Mov_r32_rm32,
+        (Mnemonic::Mov, 2) => Code::Mov_r16_rm16,
+        (Mnemonic::Mov, 1) => Code::Mov_r8_rm8,
+        (Mnemonic::Add, 8) => Code::Add_r64_rm64,
+        (Mnemonic::Add, 4) => Code::Add_r32_rm32,
+        (Mnemonic::Add, 2) => Code::Add_r16_rm16,
+        (Mnemonic::Add, 1) => Code::Add_r8_rm8,
+        (Mnemonic::Sub, 8) => Code::Sub_r64_rm64,
+        (Mnemonic::Sub, 4) => Code::Sub_r32_rm32,
+        (Mnemonic::Sub, 2) => Code::Sub_r16_rm16,
+        (Mnemonic::Sub, 1) => Code::Sub_r8_rm8,
+        (Mnemonic::And, 8) => Code::And_r64_rm64,
+        (Mnemonic::And, 4) => Code::And_r32_rm32,
+        (Mnemonic::And, 2) => Code::And_r16_rm16,
+        (Mnemonic::And, 1) => Code::And_r8_rm8,
+        (Mnemonic::Or, 8) => Code::Or_r64_rm64,
+        (Mnemonic::Or, 4) => Code::Or_r32_rm32,
+        (Mnemonic::Or, 2) => Code::Or_r16_rm16,
+        (Mnemonic::Or, 1) => Code::Or_r8_rm8,
+        (Mnemonic::Xor, 8) => Code::Xor_r64_rm64,
+        (Mnemonic::Xor, 4) => Code::Xor_r32_rm32,
+        (Mnemonic::Xor, 2) => Code::Xor_r16_rm16,
+        (Mnemonic::Xor, 1) => Code::Xor_r8_rm8,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Keeps the address of the original instruction on the rewritten one
+fn rewritten(original: &Instruction,
+             instruction: Result<Instruction, iced_x86::IcedError>)
+             -> Instruction {
+    match instruction {
+        Ok(mut instruction) => {
+            instruction.set_len(original.len());
+            instruction.set_ip(original.ip());
+            instruction
+        },
+        Err(_) => *original,
+    }
+}
+
+fn immediate_instruction(code: Code,
+                         reg: Register,
+                         value: u64)
+                         -> Result<Instruction, iced_x86::IcedError> {
+    match reg.size() {
+        8 => Instruction::with2(code, reg, value as i32),
+        _ => Instruction::with2(code, reg, value as u32),
+    }
+}
+
+/// Rewrites an immediate add, sub, and, or or xor into the rm, imm form with the sign
+/// of add and sub normalised so the immediate is positive
+fn canonicalize_alu_immediate(instruction: &Instruction) -> Option<Instruction> {
+    let reg = instruction.op0_register();
+    let size = reg.size();
+    let bits = size as u32 * 8;
+    let mut mnemonic = instruction.mnemonic();
+    let mut value = instruction.immediate(1);
+
+    // Immediate as a signed value of the operand size
+    let signed = ((value << (64 - bits)) as i64) >> (64 - bits);
+    let minimum = i64::MIN >> (64 - bits);
+    if matches!(mnemonic, Mnemonic::Add | Mnemonic::Sub) && signed < 0 && signed != minimum {
+        mnemonic = match mnemonic {
+            Mnemonic::Add => Mnemonic::Sub,
+            _ => Mnemonic::Add,
+        };
+        value = signed.unsigned_abs();
+    }
+
+    if size == 8 && value as i64 != value as i32 as i64 {
+        return None;
+    }
+
+    let code = alu_immediate_code(mnemonic, size)?;
+    if code == instruction.code() && value == instruction.immediate(1) {
+        return None;
+    }
+
+    Some(rewritten(instruction, immediate_instruction(code, reg, value)))
+}
+
+/// Rewrites a single instruction into the form the matchers expect
+pub fn canonicalize_instruction(instruction: &Instruction) -> Instruction {
+    let mnemonic = instruction.mnemonic();
+
+    match mnemonic {
+        // lea reg, [reg + disp] is add reg, disp
+        // lea reg, [other] is mov reg, other
+        Mnemonic::Lea if matches!(instruction.op0_register().size(), 4 | 8) &&
+                         instruction.memory_index() == Register::None &&
+                         instruction.memory_base().is_gpr() &&
+                         instruction.memory_base().size() == instruction.op0_register().size() =>
+        {
+            let reg = instruction.op0_register();
+            let size = reg.size();
+            let base = instruction.memory_base();
+            let displacement = instruction.memory_displacement64();
+
+            // A 32 bit address wraps, any displacement is an imm32
+            if base == reg &&
+               displacement != 0 &&
+               (size == 4 || displacement as i64 == displacement as i32 as i64)
+            {
+                let code = alu_immediate_code(Mnemonic::Add, size).unwrap();
+                let lea_as_add = immediate_instruction(code, reg, displacement);
+                return canonicalize_instruction(&rewritten(instruction, lea_as_add));
+            }
+
+            if base != reg && displacement == 0 {
+                let code = alu_reg_rm_code(Mnemonic::Mov, size).unwrap();
+                return rewritten(instruction, Instruction::with2(code, reg, base));
+            }
+
+            *instruction
+        },
+
+        // inc and dec are add and sub by one
+        Mnemonic::Inc | Mnemonic::Dec if instruction.op0_kind() == OpKind::Register => {
+            let reg = instruction.op0_register();
+            let alu_mnemonic = if mnemonic == Mnemonic::Inc {
+                Mnemonic::Add
+            } else {
+                Mnemonic::Sub
+            };
+
+            match alu_immediate_code(alu_mnemonic, reg.size()) {
+                Some(code) => rewritten(instruction, immediate_instruction(code, reg, 1)),
+                None => *instruction,
+            }
+        },
+
+        Mnemonic::Add | Mnemonic::Sub | Mnemonic::And | Mnemonic::Or | Mnemonic::Xor
+            if instruction.op0_kind() == OpKind::Register &&
+               is_immediate(instruction.op1_kind()) =>
+        {
+            canonicalize_alu_immediate(instruction).unwrap_or(*instruction)
+        },
+
+        // Register to register operations always use the reg, rm encoding
+        Mnemonic::Mov |
+        Mnemonic::Add |
+        Mnemonic::Sub |
+        Mnemonic::And |
+        Mnemonic::Or |
+        Mnemonic::Xor
+            if instruction.op0_kind() == OpKind::Register &&
+               instruction.op1_kind() == OpKind::Register =>
+        {
+            let reg = instruction.op0_register();
+            match alu_reg_rm_code(mnemonic, reg.size()) {
+                Some(code) if code != instruction.code() => {
+                    rewritten(instruction,
+                              Instruction::with2(code, reg, instruction.op1_register()))
+                },
+                _ => *instruction,
+            }
+        },
+
+        // A 32 bit destination already clears the upper half
+        Mnemonic::Movzx if instruction.op0_register().size() == 8 => {
+            let reg = gpr32(instruction.op0_register());
+            let mut movzx = *instruction;
+            movzx.set_code(match instruction.code() {
+                               Code::Movzx_r64_rm8 => Code::Movzx_r32_rm8,
+                               _ => Code::Movzx_r32_rm16,
+                           });
+            movzx.set_op0_register(reg);
+            movzx
+        },
+
+        _ => *instruction,
+    }
+}
+
+/// Rewrites every instruction into the form the matchers expect, unless the rewrite changes how
+/// it sets flags that are still live. lea becomes an add that writes flags and inc and dec
+/// become an add and sub that also write the carry
+pub fn canonicalize_instructions(instructions: &[Instruction]) -> Vec<Instruction> {
+    // Only the flags decide, the registers a rewrite touches stay the same
+    let live_out = Liveness { registers: 0,
+                              flags: handler_live_out_flags(instructions) };
+    let live_after = compute_liveness(instructions, live_out);
+
+    instructions.iter()
+                .zip(live_after)
+                .map(|(instruction, live_after)| {
+                    let canonical = canonicalize_instruction(instruction);
+                    let flags_written =
+                        canonical.rflags_modified() | instruction.rflags_modified();
+                    if canonical.mnemonic() != instruction.mnemonic() &&
+                       flags_written & live_after.flags != 0
+                    {
+                        *instruction
+                    } else {
+                        canonical
+                    }
+                })
+                .collect()
+}
+
+impl VmHandler {
+    /// Rewrites exchanges whose other half is dead into plain moves
+    pub fn canonicalize_moves(&mut self,
+                              reg_allocation: &VmRegisterAllocation) {
+        let live_out = handler_live_out(&self.instructions, reg_allocation);
+        let live_after = compute_liveness(&self.instructions, live_out);
+
+        for (instruction, live_after) in self.instructions.iter_mut().zip(live_after) {
+            if instruction.mnemonic() != Mnemonic::Xchg ||
+               instruction.op0_kind() != OpKind::Register ||
+               instruction.op1_kind() != OpKind::Register
+            {
+                continue;
+            }
+
+            let reg1 = instruction.op0_register();
+            let reg2 = instruction.op1_register();
+            let code = match alu_reg_rm_code(Mnemonic::Mov, reg1.size()) {
+                Some(code) => code,
+                None => continue,
+            };
+
+            // xchg reg1, reg2 with reg2 dead afterwards is mov reg1, reg2
+            let mov = if !live_after.is_register_live(reg2) {
+                Instruction::with2(code, reg1, reg2)
+            } else if !live_after.is_register_live(reg1) {
+                Instruction::with2(code, reg2, reg1)
+            } else {
+                continue;
+            };
+
+            *instruction = rewritten(instruction, mov);
+        }
+    }
+}
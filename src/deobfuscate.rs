@@ -0,0 +1,362 @@
+use iced_x86::{
+    Code, FlowControl, Instruction, InstructionInfoFactory, Mnemonic, OpAccess, OpKind, Register,
+};
+
+use crate::vm_handler::{VmHandler, VmRegisterAllocation};
+
+/// Bitmask over the 16 general purpose registers
+type RegisterSet = u32;
+
+const ALL_REGISTERS: RegisterSet = 0xffff;
+
+fn register_bit(reg: Register) -> RegisterSet {
+    1 << reg.full_register().number()
+}
+
+/// Registers and rflags bits live after an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Liveness {
+    pub registers: RegisterSet,
+    pub flags: u32,
+}
+
+impl Liveness {
+    pub fn is_register_live(&self,
+                            reg: Register)
+                            -> bool {
+        self.registers & register_bit(reg) != 0
+    }
+}
+
+/// Flags live when a handler dispatches, a vmexit hands them back
+pub fn handler_live_out_flags(instructions: &[Instruction]) -> u32 {
+    match instructions.last().map(|insn| insn.code()) {
+        Some(Code::Retnq | Code::Retnd) => u32::MAX,
+        _ => 0,
+    }
+}
+
+/// State live when a handler dispatches, a vmexit hands every register back
+pub fn handler_live_out(instructions: &[Instruction],
+                        reg_allocation: &VmRegisterAllocation)
+                        -> Liveness {
+    match instructions.last().map(|insn| insn.code()) {
+        Some(Code::Retnq | Code::Retnd) => {
+            Liveness { registers: ALL_REGISTERS,
+                       flags: handler_live_out_flags(instructions) }
+        },
+        _ => {
+            let vm_registers = [reg_allocation.vip,
+                                reg_allocation.vsp,
+                                reg_allocation.key,
+                                reg_allocation.handler_address];
+            let registers = vm_registers.iter()
+                                        .fold(register_bit(Register::RSP), |set, &reg| {
+                                            set | register_bit(reg.into())
+                                        });
+            Liveness { registers,
+                       flags: handler_live_out_flags(instructions) }
+        },
+    }
+}
+
+fn is_partial_write(reg: Register) -> bool {
+    reg.is_gpr8() || reg.is_gpr16()
+}
+
+fn writes_memory(instruction_info: &iced_x86::InstructionInfo) -> bool {
+    instruction_info.used_memory().iter().any(|memory| {
+                                              matches!(memory.access(),
+                                                       OpAccess::Write |
+                                                       OpAccess::CondWrite |
+                                                       OpAccess::ReadWrite |
+                                                       OpAccess::ReadCondWrite)
+                                          })
+}
+
+/// Backwards liveness over a straight line of instructions
+pub fn compute_liveness(instructions: &[Instruction],
+                        live_out: Liveness)
+                        -> Vec<Liveness> {
+    let mut info_factory = InstructionInfoFactory::new();
+    let mut live_after = vec![live_out; instructions.len()];
+    let mut live = live_out;
+
+    for (index, instruction) in instructions.iter().enumerate().rev() {
+        live_after[index] = live;
+
+        let info = info_factory.info(instruction);
+        let mut defs = 0;
+        let mut uses = 0;
+        for used_register in info.used_registers() {
+            let reg = used_register.register();
+            if !reg.is_gpr() {
+                continue;
+            }
+
+            match used_register.access() {
+                OpAccess::Write if !is_partial_write(reg) => defs |= register_bit(reg),
+                OpAccess::Write | OpAccess::CondWrite => uses |= register_bit(reg),
+                OpAccess::Read |
+                OpAccess::CondRead |
+                OpAccess::ReadWrite |
+                OpAccess::ReadCondWrite => uses |= register_bit(reg),
+                _ => {},
+            }
+        }
+
+        live.registers = (live.registers & !defs) | uses;
+        live.flags = (live.flags & !instruction.rflags_modified()) | instruction.rflags_read();
+    }
+
+    live_after
+}
+
+/// True if removing the instruction cannot change any live state
+fn is_dead(instruction: &Instruction,
+           info_factory: &mut InstructionInfoFactory,
+           live_after: Liveness)
+           -> bool {
+    if instruction.flow_control() != FlowControl::Next {
+        return false;
+    }
+
+    if matches!(instruction.mnemonic(),
+                Mnemonic::Push |
+                Mnemonic::Pop |
+                Mnemonic::Pushfq |
+                Mnemonic::Popfq |
+                Mnemonic::Pushfd |
+                Mnemonic::Popfd)
+    {
+        return false;
+    }
+
+    if instruction.rflags_modified() & live_after.flags != 0 {
+        return false;
+    }
+
+    let info = info_factory.info(instruction);
+    if writes_memory(info) {
+        return false;
+    }
+
+    info.used_registers().iter().all(|used_register| {
+                                     let reg = used_register.register();
+                                     match used_register.access() {
+                                         OpAccess::Write |
+                                         OpAccess::CondWrite |
+                                         OpAccess::ReadWrite |
+                                         OpAccess::ReadCondWrite => {
+                                             reg.is_gpr() && !live_after.is_register_live(reg)
+                                         },
+                                         _ => true,
+                                     }
+                                 })
+}
+
+/// A write to a 32 bit register of 64 bit code clears the upper half of the full register,
+/// so a pair that leaves its value unchanged still changes the register
+fn zero_extends(reg: Register,
+                bitness: u32)
+                -> bool {
+    bitness == 64 && reg.is_gpr32()
+}
+
+fn same_register_operand(first: &Instruction,
+                         second: &Instruction)
+                         -> bool {
+    first.op0_kind() == OpKind::Register &&
+    second.op0_kind() == OpKind::Register &&
+    first.op0_register() == second.op0_register()
+}
+
+fn is_immediate_operand(instruction: &Instruction) -> bool {
+    instruction.op_count() == 2 &&
+    matches!(instruction.op1_kind(),
+             OpKind::Immediate8 |
+             OpKind::Immediate16 |
+             OpKind::Immediate32 |
+             OpKind::Immediate8to16 |
+             OpKind::Immediate8to32 |
+             OpKind::Immediate8to64 |
+             OpKind::Immediate32to64)
+}
+
+/// Pairs of instructions whose combined effect on the operand is nothing
+fn is_self_cancelling(first: &Instruction,
+                      second: &Instruction,
+                      bitness: u32)
+                      -> bool {
+    if first.mnemonic() == Mnemonic::Push && second.mnemonic() == Mnemonic::Pop {
+        return same_register_operand(first, second);
+    }
+
+    if first.mnemonic() == Mnemonic::Xchg && second.mnemonic() == Mnemonic::Xchg {
+        return first.op_kind(0) == OpKind::Register &&
+               first.op_kind(1) == OpKind::Register &&
+               second.op_kind(1) == OpKind::Register &&
+               !zero_extends(first.op0_register(), bitness) &&
+               !zero_extends(first.op1_register(), bitness) &&
+               ((first.op0_register() == second.op0_register() &&
+                 first.op1_register() == second.op1_register()) ||
+                (first.op0_register() == second.op1_register() &&
+                 first.op1_register() == second.op0_register()));
+    }
+
+    if !same_register_operand(first, second) || zero_extends(first.op0_register(), bitness) {
+        return false;
+    }
+
+    match (first.mnemonic(), second.mnemonic()) {
+        (Mnemonic::Not, Mnemonic::Not) |
+        (Mnemonic::Neg, Mnemonic::Neg) |
+        (Mnemonic::Bswap, Mnemonic::Bswap) |
+        (Mnemonic::Inc, Mnemonic::Dec) |
+        (Mnemonic::Dec, Mnemonic::Inc) => true,
+        (Mnemonic::Add, Mnemonic::Sub) |
+        (Mnemonic::Sub, Mnemonic::Add) |
+        (Mnemonic::Xor, Mnemonic::Xor) |
+        (Mnemonic::Rol, Mnemonic::Ror) |
+        (Mnemonic::Ror, Mnemonic::Rol) => {
+            is_immediate_operand(first) &&
+            is_immediate_operand(second) &&
+            first.immediate(1) == second.immediate(1)
+        },
+        _ => false,
+    }
+}
+
+fn add_immediate_code(size: usize) -> Code {
+    match size {
+        1 => Code::Add_rm8_imm8,
+        2 => Code::Add_rm16_imm16,
+        4 => Code::Add_rm32_imm32,
+        _ => Code::Add_rm64_imm32,
+    }
+}
+
+fn xor_immediate_code(size: usize) -> Code {
+    match size {
+        1 => Code::Xor_rm8_imm8,
+        2 => Code::Xor_rm16_imm16,
+        4 => Code::Xor_rm32_imm32,
+        _ => Code::Xor_rm64_imm32,
+    }
+}
+
+/// Folds two immediate operations on the same register into one instruction, or into none when
+/// they cancel and the register is left unchanged
+fn fold_constants(first: &Instruction,
+                  second: &Instruction,
+                  bitness: u32)
+                  -> Option<Option<Instruction>> {
+    if !same_register_operand(first, second) ||
+       !is_immediate_operand(first) ||
+       !is_immediate_operand(second)
+    {
+        return None;
+    }
+
+    let reg = first.op0_register();
+    let size = reg.size();
+    let signed_immediate = |instruction: &Instruction| match instruction.mnemonic() {
+        Mnemonic::Sub => instruction.immediate(1).wrapping_neg(),
+        _ => instruction.immediate(1),
+    };
+
+    let (code, value) = match (first.mnemonic(), second.mnemonic()) {
+        (Mnemonic::Add | Mnemonic::Sub, Mnemonic::Add | Mnemonic::Sub) => {
+            (add_immediate_code(size),
+             signed_immediate(first).wrapping_add(signed_immediate(second)))
+        },
+        (Mnemonic::Xor, Mnemonic::Xor) => {
+            (xor_immediate_code(size), first.immediate(1) ^ second.immediate(1))
+        },
+        _ => return None,
+    };
+
+    let value = match size {
+        8 => value,
+        _ => value & ((1u64 << (size * 8)) - 1),
+    };
+
+    if value == 0 {
+        return match zero_extends(reg, bitness) {
+            true => None,
+            false => Some(None),
+        };
+    }
+
+    let folded = match size {
+        1 | 2 | 4 => Instruction::with2(code, reg, value as u32),
+        _ if value as i64 == value as i32 as i64 => Instruction::with2(code, reg, value as i32),
+        _ => return None,
+    };
+
+    let mut folded = folded.ok()?;
+    folded.set_ip(first.ip());
+    Some(Some(folded))
+}
+
+impl VmHandler {
+    /// Removes the junk inserted by the mutation engine from the handler
+    pub fn deobfuscate(&self,
+                       reg_allocation: &VmRegisterAllocation)
+                       -> Vec<Instruction> {
+        let mut instructions = self.instructions.clone();
+
+        let live_out = handler_live_out(&instructions, reg_allocation);
+
+        let mut info_factory = InstructionInfoFactory::new();
+        loop {
+            let length = instructions.len();
+
+            // Dead store elimination
+            let live_after = compute_liveness(&instructions, live_out);
+            let mut index = 0;
+            instructions.retain(|instruction| {
+                            let keep = !is_dead(instruction, &mut info_factory, live_after[index]);
+                            index += 1;
+                            keep
+                        });
+
+            // Self cancelling pairs and constant folding, only when the flags they leave are dead
+            let live_after = compute_liveness(&instructions, live_out);
+            let mut cleaned = Vec::with_capacity(instructions.len());
+            let mut index = 0;
+            while index < instructions.len() {
+                if index + 1 < instructions.len() {
+                    let first = &instructions[index];
+                    let second = &instructions[index + 1];
+                    let flags_dead = (first.rflags_modified() | second.rflags_modified()) &
+                                     live_after[index + 1].flags ==
+                                     0;
+
+                    if flags_dead && is_self_cancelling(first, second, self.bitness) {
+                        index += 2;
+                        continue;
+                    }
+
+                    if flags_dead {
+                        if let Some(folded) = fold_constants(first, second, self.bitness) {
+                            cleaned.extend(folded);
+                            index += 2;
+                            continue;
+                        }
+                    }
+                }
+
+                cleaned.push(instructions[index]);
+                index += 1;
+            }
+            instructions = cleaned;
+
+            if instructions.len() == length {
+                break;
+            }
+        }
+
+        instructions
+    }
+}
@@ -0,0 +1,274 @@
+/// ELF identification bytes every ELF file starts with
+pub const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHN_UNDEF: u16 = 0;
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+
+const R_X86_64_RELATIVE: u32 = 8;
+
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+const DYNAMIC_SIZE: usize = 16;
+
+/// Pages segments are mapped in, the lowest segment is mapped from the start of its page
+const PAGE_SIZE: u64 = 0x1000;
+
+fn field<const N: usize>(bytes: &[u8],
+                         offset: usize)
+                         -> Result<[u8; N], String> {
+    bytes.get(offset .. offset + N)
+         .map(|field| field.try_into().unwrap())
+         .ok_or_else(|| format!("ELF field at {:#x} is past the end of the file", offset))
+}
+
+fn u16_at(bytes: &[u8],
+          offset: usize)
+          -> Result<u16, String> {
+    field(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8],
+          offset: usize)
+          -> Result<u32, String> {
+    field(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8],
+          offset: usize)
+          -> Result<u64, String> {
+    field(bytes, offset).map(u64::from_le_bytes)
+}
+
+/// The nul terminated string at offset of a string table
+fn string_at(bytes: &[u8],
+             table_offset: usize,
+             offset: usize)
+             -> String {
+    let start = table_offset + offset;
+    let string = bytes.get(start ..).unwrap_or_default();
+    let end = string.iter().position(|&byte| byte == 0).unwrap_or(string.len());
+    String::from_utf8_lossy(&string[.. end]).into_owned()
+}
+
+/// A PT_LOAD program header, file_size bytes of the file mapped at vaddr followed by zeros
+/// up to mem_size
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub mem_size: u64,
+    pub file_offset: u64,
+    pub file_size: u64,
+}
+
+struct SectionHeader {
+    kind: u32,
+    flags: u64,
+    vaddr: u64,
+    offset: usize,
+    size: usize,
+    link: usize,
+    name: usize,
+}
+
+/// A section occupying memory at run time
+#[derive(Debug, Clone)]
+pub struct ElfSection {
+    pub name: String,
+    pub vaddr: u64,
+    pub size: u64,
+    pub executable: bool,
+}
+
+/// A defined function or object symbol
+#[derive(Debug, Clone)]
+pub struct ElfSymbol {
+    pub name: String,
+    pub vaddr: u64,
+}
+
+/// The parts of an ELF64 x86-64 file a loader and symbolisation need, at the linked addresses
+pub struct ElfFile {
+    pub segments: Vec<Segment>,
+    pub sections: Vec<ElfSection>,
+    /// Symbols of .symtab and .dynsym, sorted by address
+    pub symbols: Vec<ElfSymbol>,
+    /// Slot and addend of every R_X86_64_RELATIVE relocation of the dynamic section
+    pub relative_relocations: Vec<(u64, u64)>,
+}
+
+impl ElfFile {
+    pub fn is_elf(bytes: &[u8]) -> bool {
+        bytes.starts_with(ELF_MAGIC)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if !Self::is_elf(bytes) {
+            return Err("not an ELF file".to_string());
+        }
+        if field::<2>(bytes, 4)? != [ELFCLASS64, ELFDATA2LSB] {
+            return Err("only little endian ELF64 files are supported".to_string());
+        }
+        if u16_at(bytes, 18)? != EM_X86_64 {
+            return Err("only x86-64 ELF files are supported".to_string());
+        }
+
+        let program_headers = u64_at(bytes, 32)? as usize;
+        let program_header_count = u16_at(bytes, 56)? as usize;
+        let section_headers = u64_at(bytes, 40)? as usize;
+        let section_header_count = u16_at(bytes, 60)? as usize;
+        let section_names = u16_at(bytes, 62)? as usize;
+
+        let mut segments = Vec::new();
+        let mut dynamic = None;
+        for index in 0 .. program_header_count {
+            let header = program_headers + index * PROGRAM_HEADER_SIZE;
+            let segment = Segment { vaddr: u64_at(bytes, header + 16)?,
+                                    mem_size: u64_at(bytes, header + 40)?,
+                                    file_offset: u64_at(bytes, header + 8)?,
+                                    file_size: u64_at(bytes, header + 32)? };
+            match u32_at(bytes, header)? {
+                PT_LOAD => segments.push(segment),
+                PT_DYNAMIC => dynamic = Some(segment),
+                _ => {},
+            }
+        }
+        if segments.is_empty() {
+            return Err("the ELF file has no loadable segments".to_string());
+        }
+
+        let mut headers = Vec::new();
+        for index in 0 .. section_header_count {
+            let header = section_headers + index * SECTION_HEADER_SIZE;
+            headers.push(SectionHeader { kind: u32_at(bytes, header + 4)?,
+                                         flags: u64_at(bytes, header + 8)?,
+                                         vaddr: u64_at(bytes, header + 16)?,
+                                         offset: u64_at(bytes, header + 24)? as usize,
+                                         size: u64_at(bytes, header + 32)? as usize,
+                                         link: u32_at(bytes, header + 40)? as usize,
+                                         name: u32_at(bytes, header)? as usize });
+        }
+        let names_offset = headers.get(section_names)
+                                  .map(|header| header.offset)
+                                  .unwrap_or_default();
+
+        let sections = headers.iter()
+                              .filter(|header| header.flags & SHF_ALLOC != 0 && header.size != 0)
+                              .map(|header| {
+                                  let executable = header.flags & SHF_EXECINSTR != 0;
+                                  ElfSection { name: string_at(bytes, names_offset, header.name),
+                                               vaddr: header.vaddr,
+                                               size: header.size as u64,
+                                               executable }
+                              })
+                              .collect();
+
+        let mut symbols = Vec::new();
+        for header in headers.iter()
+                             .filter(|header| matches!(header.kind, SHT_SYMTAB | SHT_DYNSYM))
+        {
+            let strings_offset = match headers.get(header.link) {
+                Some(strings) => strings.offset,
+                None => continue,
+            };
+            for index in 0 .. header.size / SYMBOL_SIZE {
+                let symbol = header.offset + index * SYMBOL_SIZE;
+                let kind = field::<1>(bytes, symbol + 4)?[0] & 0xf;
+                let vaddr = u64_at(bytes, symbol + 8)?;
+                if !matches!(kind, STT_FUNC | STT_OBJECT) ||
+                   u16_at(bytes, symbol + 6)? == SHN_UNDEF ||
+                   vaddr == 0
+                {
+                    continue;
+                }
+                let name = string_at(bytes, strings_offset, u32_at(bytes, symbol)? as usize);
+                symbols.push(ElfSymbol { name, vaddr });
+            }
+        }
+        symbols.sort_by(|a, b| (a.vaddr, &a.name).cmp(&(b.vaddr, &b.name)));
+        symbols.dedup_by(|a, b| a.vaddr == b.vaddr && a.name == b.name);
+
+        let mut elf_file = Self { segments,
+                                  sections,
+                                  symbols,
+                                  relative_relocations: Vec::new() };
+        if let Some(dynamic) = dynamic {
+            elf_file.relative_relocations = elf_file.read_relative_relocations(bytes, &dynamic)?;
+        }
+        Ok(elf_file)
+    }
+
+    /// The R_X86_64_RELATIVE entries of the DT_RELA table the dynamic segment points at
+    fn read_relative_relocations(&self,
+                                 bytes: &[u8],
+                                 dynamic: &Segment)
+                                 -> Result<Vec<(u64, u64)>, String> {
+        let mut table = None;
+        let mut table_size = 0;
+        for index in 0 .. dynamic.file_size as usize / DYNAMIC_SIZE {
+            let entry = dynamic.file_offset as usize + index * DYNAMIC_SIZE;
+            let value = u64_at(bytes, entry + 8)?;
+            match u64_at(bytes, entry)? {
+                DT_NULL => break,
+                DT_RELA => table = Some(value),
+                DT_RELASZ => table_size = value as usize,
+                _ => {},
+            }
+        }
+
+        let table = match table.and_then(|vaddr| self.file_offset(vaddr)) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        let mut relocations = Vec::new();
+        for index in 0 .. table_size / RELA_SIZE {
+            let entry = table + index * RELA_SIZE;
+            if u64_at(bytes, entry + 8)? as u32 == R_X86_64_RELATIVE {
+                relocations.push((u64_at(bytes, entry)?, u64_at(bytes, entry + 16)?));
+            }
+        }
+        Ok(relocations)
+    }
+
+    /// The linked address of the lowest segment, rounded down to its page
+    pub fn preferred_base(&self) -> u64 {
+        let lowest = self.segments.iter().map(|segment| segment.vaddr).min().unwrap();
+        lowest & !(PAGE_SIZE - 1)
+    }
+
+    /// Offset in the file of the byte mapped at the linked address vaddr
+    pub fn file_offset(&self,
+                       vaddr: u64)
+                       -> Option<usize> {
+        let segment = self.segment(vaddr)?;
+        let offset = vaddr - segment.vaddr;
+        (offset < segment.file_size).then(|| (segment.file_offset + offset) as usize)
+    }
+
+    /// The segment mapping the linked address vaddr
+    pub fn segment(&self,
+                   vaddr: u64)
+                   -> Option<&Segment> {
+        self.segments
+            .iter()
+            .find(|segment| (segment.vaddr .. segment.vaddr + segment.mem_size).contains(&vaddr))
+    }
+}
@@ -0,0 +1,936 @@
+use std::collections::HashMap;
+
+use iced_x86::{
+    Code, ConditionCode, Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, Register,
+};
+
+use crate::{
+    interpreter::{NativeState, VmMemory, NATIVE_STACK_BASE},
+    image::Image,
+    util::{handle_vm_call, is_high_byte_register, is_vm_call},
+    vm_handler::{Registers, VmHandler, VmRegisterAllocation},
+    walker::WalkEnd,
+};
+
+const CF: u64 = 1 << 0;
+const PF: u64 = 1 << 2;
+const AF: u64 = 1 << 4;
+const ZF: u64 = 1 << 6;
+const SF: u64 = 1 << 7;
+const DF: u64 = 1 << 10;
+const OF: u64 = 1 << 11;
+
+fn size_mask(size: usize) -> u64 {
+    match size {
+        8 => u64::MAX,
+        _ => (1 << (size * 8)) - 1,
+    }
+}
+
+fn sign_bit(size: usize) -> u64 {
+    1 << (size * 8 - 1)
+}
+
+fn sign_extend(value: u64,
+               size: usize)
+               -> u64 {
+    match size {
+        8 => value,
+        _ => (((value << (64 - size * 8)) as i64) >> (64 - size * 8)) as u64,
+    }
+}
+
+fn is_setcc(mnemonic: Mnemonic) -> bool {
+    matches!(mnemonic,
+             Mnemonic::Seto |
+             Mnemonic::Setno |
+             Mnemonic::Setb |
+             Mnemonic::Setae |
+             Mnemonic::Sete |
+             Mnemonic::Setne |
+             Mnemonic::Setbe |
+             Mnemonic::Seta |
+             Mnemonic::Sets |
+             Mnemonic::Setns |
+             Mnemonic::Setp |
+             Mnemonic::Setnp |
+             Mnemonic::Setl |
+             Mnemonic::Setge |
+             Mnemonic::Setle |
+             Mnemonic::Setg)
+}
+
+fn is_cmovcc(mnemonic: Mnemonic) -> bool {
+    matches!(mnemonic,
+             Mnemonic::Cmovo |
+             Mnemonic::Cmovno |
+             Mnemonic::Cmovb |
+             Mnemonic::Cmovae |
+             Mnemonic::Cmove |
+             Mnemonic::Cmovne |
+             Mnemonic::Cmovbe |
+             Mnemonic::Cmova |
+             Mnemonic::Cmovs |
+             Mnemonic::Cmovns |
+             Mnemonic::Cmovp |
+             Mnemonic::Cmovnp |
+             Mnemonic::Cmovl |
+             Mnemonic::Cmovge |
+             Mnemonic::Cmovle |
+             Mnemonic::Cmovg)
+}
+
+/// Why native execution stopped
+#[derive(Debug, Clone)]
+pub enum EmulationEnd {
+    /// The vm returned to native code at the address
+    VmExit(u64),
+    StepLimit,
+    /// Instruction at the address could not be executed
+    Fault(u64, String),
+}
+
+/// Registers of the vm when a handler is entered
+#[derive(Debug, Clone, Copy)]
+pub struct HandlerSnapshot {
+    pub handler_address: u64,
+    pub vip: u64,
+    pub vsp: u64,
+    pub key: u64,
+}
+
+/// Executes native x86-64 code over the PE image and an emulated stack
+pub struct X86Emulator<'a> {
+    pub registers: [u64; 16],
+    pub rflags: u64,
+    pub rip: u64,
+    pub memory: VmMemory<'a>,
+    image: &'a dyn Image,
+}
+
+impl<'a> X86Emulator<'a> {
+    pub fn new(image: &'a dyn Image,
+               rip: u64,
+               initial: &NativeState)
+               -> Self {
+        let mut emulator = Self { registers: [0; 16],
+                                  rflags: initial.registers
+                                                 .get(&Registers::Flags)
+                                                 .copied()
+                                                 .unwrap_or(0x202),
+                                  rip,
+                                  memory: VmMemory::new(image),
+                                  image };
+
+        for (&reg, &value) in initial.registers.iter() {
+            if reg != Registers::Flags {
+                emulator.write_register(reg.into(), value);
+            }
+        }
+        if !initial.registers.contains_key(&Registers::Rsp) {
+            emulator.write_register(Register::RSP, NATIVE_STACK_BASE);
+        }
+
+        emulator
+    }
+
+    pub fn native_state(&self) -> NativeState {
+        let mut state = NativeState::default();
+        for (index, &value) in self.registers.iter().enumerate() {
+            let reg = Register::try_from(Register::RAX as usize + index).unwrap();
+            state.registers.insert(reg.into(), value);
+        }
+        state.registers.insert(Registers::Flags, self.rflags);
+        state
+    }
+
+    pub fn read_register(&self,
+                         reg: Register)
+                         -> u64 {
+        let full = self.registers[reg.full_register().number()];
+        match is_high_byte_register(reg) {
+            true => (full >> 8) & 0xff,
+            false => full & size_mask(reg.size()),
+        }
+    }
+
+    pub fn write_register(&mut self,
+                          reg: Register,
+                          value: u64) {
+        let index = reg.full_register().number();
+        let full = self.registers[index];
+
+        self.registers[index] = match reg.size() {
+            8 => value,
+            4 => value & 0xffff_ffff,
+            _ if is_high_byte_register(reg) => (full & !0xff00) | ((value & 0xff) << 8),
+            size => (full & !size_mask(size)) | (value & size_mask(size)),
+        };
+    }
+
+    fn flag(&self,
+            bit: u64)
+            -> bool {
+        self.rflags & bit != 0
+    }
+
+    fn set_flag(&mut self,
+                bit: u64,
+                value: bool) {
+        match value {
+            true => self.rflags |= bit,
+            false => self.rflags &= !bit,
+        }
+    }
+
+    /// Sets zf, sf and pf from a result
+    fn set_result_flags(&mut self,
+                        result: u64,
+                        size: usize) {
+        let result = result & size_mask(size);
+        self.set_flag(ZF, result == 0);
+        self.set_flag(SF, result & sign_bit(size) != 0);
+        self.set_flag(PF, (result as u8).count_ones() & 1 == 0);
+    }
+
+    fn condition(&self,
+                 condition_code: ConditionCode)
+                 -> bool {
+        let (cf, zf, sf, of, pf) =
+            (self.flag(CF), self.flag(ZF), self.flag(SF), self.flag(OF), self.flag(PF));
+
+        match condition_code {
+            ConditionCode::o => of,
+            ConditionCode::no => !of,
+            ConditionCode::b => cf,
+            ConditionCode::ae => !cf,
+            ConditionCode::e => zf,
+            ConditionCode::ne => !zf,
+            ConditionCode::be => cf || zf,
+            ConditionCode::a => !cf && !zf,
+            ConditionCode::s => sf,
+            ConditionCode::ns => !sf,
+            ConditionCode::p => pf,
+            ConditionCode::np => !pf,
+            ConditionCode::l => sf != of,
+            ConditionCode::ge => sf == of,
+            ConditionCode::le => zf || sf != of,
+            ConditionCode::g => !zf && sf == of,
+            ConditionCode::None => true,
+        }
+    }
+
+    fn effective_address(&self,
+                         instruction: &Instruction)
+                         -> u64 {
+        // iced already resolves rip relative displacements to the absolute address
+        if instruction.is_ip_rel_memory_operand() {
+            return instruction.ip_rel_memory_address();
+        }
+
+        let base = match instruction.memory_base() {
+            Register::None => 0,
+            base => self.read_register(base),
+        };
+        let index = match instruction.memory_index() {
+            Register::None => 0,
+            index => self.read_register(index)
+                         .wrapping_mul(instruction.memory_index_scale() as u64),
+        };
+
+        base.wrapping_add(index)
+            .wrapping_add(instruction.memory_displacement64())
+    }
+
+    fn operand_size(&self,
+                    instruction: &Instruction,
+                    operand: u32)
+                    -> usize {
+        match instruction.op_kind(operand) {
+            OpKind::Register => instruction.op_register(operand).size(),
+            OpKind::Memory => instruction.memory_size().size(),
+            _ => self.operand_size(instruction, 0),
+        }
+    }
+
+    fn read_operand(&self,
+                    instruction: &Instruction,
+                    operand: u32)
+                    -> u64 {
+        match instruction.op_kind(operand) {
+            OpKind::Register => self.read_register(instruction.op_register(operand)),
+            OpKind::Memory => {
+                self.memory
+                    .read(self.effective_address(instruction), instruction.memory_size().size())
+            },
+            OpKind::NearBranch64 => instruction.near_branch64(),
+            _ => instruction.immediate(operand),
+        }
+    }
+
+    fn write_operand(&mut self,
+                     instruction: &Instruction,
+                     operand: u32,
+                     value: u64) {
+        match instruction.op_kind(operand) {
+            OpKind::Register => self.write_register(instruction.op_register(operand), value),
+            _ => {
+                let address = self.effective_address(instruction);
+                let size = instruction.memory_size().size();
+                self.memory.write(address, size, value);
+            },
+        }
+    }
+
+    pub fn push(&mut self,
+                value: u64) {
+        let rsp = self.read_register(Register::RSP).wrapping_sub(8);
+        self.write_register(Register::RSP, rsp);
+        self.memory.write(rsp, 8, value);
+    }
+
+    pub fn pop(&mut self) -> u64 {
+        let rsp = self.read_register(Register::RSP);
+        self.write_register(Register::RSP, rsp.wrapping_add(8));
+        self.memory.read(rsp, 8)
+    }
+
+    fn decode(&self,
+              address: u64)
+              -> Instruction {
+        let mut bytes = [0u8; 16];
+        match self.image.read(address, 16) {
+            Some(image_bytes) => bytes.copy_from_slice(&image_bytes),
+            None => {
+                for (offset, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.memory.read(address.wrapping_add(offset as u64), 1) as u8;
+                }
+            },
+        }
+
+        Decoder::with_ip(64, &bytes, address, DecoderOptions::NONE).decode()
+    }
+
+    /// add, adc, sub, sbb, cmp, and, or, xor and test
+    fn alu(&mut self,
+           instruction: &Instruction) {
+        let size = self.operand_size(instruction, 0);
+        let mask = size_mask(size);
+        let sign = sign_bit(size);
+        let lhs = self.read_operand(instruction, 0) & mask;
+        let rhs = self.read_operand(instruction, 1) & mask;
+        let carry_in = self.flag(CF) as u64;
+
+        let mnemonic = instruction.mnemonic();
+        let result = match mnemonic {
+            Mnemonic::Add | Mnemonic::Adc => {
+                let carry_in = if mnemonic == Mnemonic::Adc { carry_in } else { 0 };
+                let wide = lhs as u128 + rhs as u128 + carry_in as u128;
+                let result = wide as u64 & mask;
+                self.set_flag(CF, wide >> (size * 8) != 0);
+                self.set_flag(OF, (lhs ^ result) & (rhs ^ result) & sign != 0);
+                self.set_flag(AF, (lhs ^ rhs ^ result) & 0x10 != 0);
+                result
+            },
+            Mnemonic::Sub | Mnemonic::Sbb | Mnemonic::Cmp => {
+                let carry_in = if mnemonic == Mnemonic::Sbb { carry_in } else { 0 };
+                let result = lhs.wrapping_sub(rhs).wrapping_sub(carry_in) & mask;
+                self.set_flag(CF, (lhs as u128) < rhs as u128 + carry_in as u128);
+                self.set_flag(OF, (lhs ^ rhs) & (lhs ^ result) & sign != 0);
+                self.set_flag(AF, (lhs ^ rhs ^ result) & 0x10 != 0);
+                result
+            },
+            _ => {
+                let result = match mnemonic {
+                    Mnemonic::And | Mnemonic::Test => lhs & rhs,
+                    Mnemonic::Or => lhs | rhs,
+                    _ => lhs ^ rhs,
+                };
+                self.set_flag(CF, false);
+                self.set_flag(OF, false);
+                result
+            },
+        };
+
+        self.set_result_flags(result, size);
+        if !matches!(mnemonic, Mnemonic::Cmp | Mnemonic::Test) {
+            self.write_operand(instruction, 0, result);
+        }
+    }
+
+    /// shl, shr, sar, rol, ror, rcl and rcr
+    fn shift(&mut self,
+             instruction: &Instruction) {
+        let size = self.operand_size(instruction, 0);
+        let bits = size as u32 * 8;
+        let mask = size_mask(size);
+        let sign = sign_bit(size);
+        let value = self.read_operand(instruction, 0) & mask;
+        let count_mask = if size == 8 { 0x3f } else { 0x1f };
+        let count = match instruction.op_count() {
+            1 => 1,
+            _ => self.read_operand(instruction, 1) as u32 & count_mask,
+        };
+
+        if count == 0 {
+            return;
+        }
+
+        let (result, carry, overflow) = match instruction.mnemonic() {
+            Mnemonic::Shl | Mnemonic::Sal => {
+                let result = value.checked_shl(count).unwrap_or(0) & mask;
+                let carry = count <= bits && (value >> (bits - count)) & 1 != 0;
+                (result, carry, (result & sign != 0) != carry)
+            },
+            Mnemonic::Shr => {
+                let result = value.checked_shr(count).unwrap_or(0);
+                let carry = (value >> (count - 1)) & 1 != 0;
+                (result, carry, value & sign != 0)
+            },
+            Mnemonic::Sar => {
+                let signed = sign_extend(value, size) as i64;
+                let result = (signed >> count.min(63)) as u64 & mask;
+                let carry = (signed >> (count - 1).min(63)) & 1 != 0;
+                (result, carry, false)
+            },
+            Mnemonic::Rol | Mnemonic::Ror => {
+                let count = count % bits;
+                let result = match instruction.mnemonic() {
+                    Mnemonic::Rol => {
+                        ((value << count) | value.checked_shr(bits - count).unwrap_or(0)) & mask
+                    },
+                    _ => ((value >> count) | value.checked_shl(bits - count).unwrap_or(0)) & mask,
+                };
+                match instruction.mnemonic() {
+                    Mnemonic::Rol => {
+                        let carry = result & 1 != 0;
+                        (result, carry, (result & sign != 0) != carry)
+                    },
+                    _ => {
+                        let carry = result & sign != 0;
+                        (result, carry, carry != (result & (sign >> 1) != 0))
+                    },
+                }
+            },
+            _ => {
+                // Rotate through carry one bit at a time
+                let mut result = value;
+                let mut carry = self.flag(CF);
+                for _ in 0 .. count % (bits + 1) {
+                    match instruction.mnemonic() {
+                        Mnemonic::Rcl => {
+                            let out = result & sign != 0;
+                            result = ((result << 1) | carry as u64) & mask;
+                            carry = out;
+                        },
+                        _ => {
+                            let out = result & 1 != 0;
+                            result = (result >> 1) | if carry { sign } else { 0 };
+                            carry = out;
+                        },
+                    }
+                }
+                (result, carry, (result & sign != 0) != carry)
+            },
+        };
+
+        self.set_flag(CF, carry);
+        self.set_flag(OF, overflow);
+        if !matches!(instruction.mnemonic(),
+                     Mnemonic::Rol | Mnemonic::Ror | Mnemonic::Rcl | Mnemonic::Rcr)
+        {
+            self.set_result_flags(result, size);
+        }
+        self.write_operand(instruction, 0, result);
+    }
+
+    /// shld and shrd
+    fn double_shift(&mut self,
+                    instruction: &Instruction) {
+        let size = self.operand_size(instruction, 0);
+        let bits = size as u32 * 8;
+        let mask = size_mask(size);
+        let count_mask = if size == 8 { 0x3f } else { 0x1f };
+        let count = self.read_operand(instruction, 2) as u32 & count_mask;
+        if count == 0 || count > bits {
+            return;
+        }
+
+        let destination = self.read_operand(instruction, 0) & mask;
+        let source = self.read_operand(instruction, 1) & mask;
+        let (result, carry) = match instruction.mnemonic() {
+            Mnemonic::Shld => {
+                ((destination.checked_shl(count).unwrap_or(0) |
+                  source.checked_shr(bits - count).unwrap_or(0)) &
+                 mask,
+                 (destination >> (bits - count)) & 1 != 0)
+            },
+            _ => {
+                ((destination.checked_shr(count).unwrap_or(0) |
+                  source.checked_shl(bits - count).unwrap_or(0)) &
+                 mask,
+                 (destination >> (count - 1)) & 1 != 0)
+            },
+        };
+
+        self.set_flag(CF, carry);
+        self.set_flag(OF, (result ^ destination) & sign_bit(size) != 0);
+        self.set_result_flags(result, size);
+        self.write_operand(instruction, 0, result);
+    }
+
+    /// One operand mul, imul, div and idiv on the rdx:rax pair
+    fn multiply_divide(&mut self,
+                       instruction: &Instruction)
+                       -> Result<(), String> {
+        let size = self.operand_size(instruction, 0);
+        let bits = size * 8;
+        let mask = size_mask(size);
+        let operand = self.read_operand(instruction, 0) & mask;
+
+        let (low_reg, high_reg) = match size {
+            1 => (Register::AL, Register::AH),
+            2 => (Register::AX, Register::DX),
+            4 => (Register::EAX, Register::EDX),
+            _ => (Register::RAX, Register::RDX),
+        };
+        let low = self.read_register(low_reg);
+
+        match instruction.mnemonic() {
+            Mnemonic::Mul | Mnemonic::Imul => {
+                let product = match instruction.mnemonic() {
+                    Mnemonic::Mul => low as u128 * operand as u128,
+                    _ => {
+                        (sign_extend(low, size) as i64 as i128 *
+                         sign_extend(operand, size) as i64 as i128) as u128
+                    },
+                };
+                let result_low = product as u64 & mask;
+                let result_high = (product >> bits) as u64 & mask;
+
+                let overflow = match instruction.mnemonic() {
+                    Mnemonic::Mul => result_high != 0,
+                    _ => sign_extend(result_low, size) as i64 as i128 != product as i128,
+                };
+                self.set_flag(CF, overflow);
+                self.set_flag(OF, overflow);
+
+                match size {
+                    1 => self.write_register(Register::AX, product as u64 & 0xffff),
+                    _ => {
+                        self.write_register(low_reg, result_low);
+                        self.write_register(high_reg, result_high);
+                    },
+                }
+            },
+            _ => {
+                if operand == 0 {
+                    return Err("division by zero".to_string());
+                }
+
+                let high = match size {
+                    1 => self.read_register(Register::AH),
+                    _ => self.read_register(high_reg),
+                };
+                let dividend = ((high as u128) << bits) | low as u128;
+
+                let (quotient, remainder) = match instruction.mnemonic() {
+                    Mnemonic::Div => (dividend / operand as u128, dividend % operand as u128),
+                    _ => {
+                        let shift = 128 - 2 * bits as u32;
+                        let dividend = ((dividend << shift) as i128) >> shift;
+                        let divisor = sign_extend(operand, size) as i64 as i128;
+                        ((dividend / divisor) as u128, (dividend % divisor) as u128)
+                    },
+                };
+
+                match size {
+                    1 => {
+                        self.write_register(Register::AL, quotient as u64);
+                        self.write_register(Register::AH, remainder as u64);
+                    },
+                    _ => {
+                        self.write_register(low_reg, quotient as u64);
+                        self.write_register(high_reg, remainder as u64);
+                    },
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Executes the instruction at rip and returns it
+    pub fn step(&mut self) -> Result<Instruction, String> {
+        let instruction = self.decode(self.rip);
+        if instruction.code() == Code::INVALID {
+            return Err("invalid instruction".to_string());
+        }
+        self.rip = instruction.next_ip();
+
+        let mnemonic = instruction.mnemonic();
+        match mnemonic {
+            Mnemonic::Mov | Mnemonic::Movzx => {
+                let value = self.read_operand(&instruction, 1);
+                self.write_operand(&instruction, 0, value);
+            },
+            Mnemonic::Movsx | Mnemonic::Movsxd => {
+                let size = self.operand_size(&instruction, 1);
+                let value = sign_extend(self.read_operand(&instruction, 1), size);
+                self.write_operand(&instruction, 0, value);
+            },
+            Mnemonic::Lea => {
+                let address = self.effective_address(&instruction);
+                self.write_operand(&instruction, 0, address);
+            },
+            Mnemonic::Add |
+            Mnemonic::Adc |
+            Mnemonic::Sub |
+            Mnemonic::Sbb |
+            Mnemonic::Cmp |
+            Mnemonic::And |
+            Mnemonic::Or |
+            Mnemonic::Xor |
+            Mnemonic::Test => self.alu(&instruction),
+            Mnemonic::Inc | Mnemonic::Dec => {
+                let size = self.operand_size(&instruction, 0);
+                let value = self.read_operand(&instruction, 0) & size_mask(size);
+                let result = match mnemonic {
+                    Mnemonic::Inc => value.wrapping_add(1),
+                    _ => value.wrapping_sub(1),
+                } & size_mask(size);
+                let overflow = match mnemonic {
+                    Mnemonic::Inc => result == sign_bit(size),
+                    _ => value == sign_bit(size),
+                };
+                self.set_flag(OF, overflow);
+                self.set_result_flags(result, size);
+                self.write_operand(&instruction, 0, result);
+            },
+            Mnemonic::Neg => {
+                let size = self.operand_size(&instruction, 0);
+                let value = self.read_operand(&instruction, 0) & size_mask(size);
+                let result = value.wrapping_neg() & size_mask(size);
+                self.set_flag(CF, value != 0);
+                self.set_flag(OF, value == sign_bit(size));
+                self.set_result_flags(result, size);
+                self.write_operand(&instruction, 0, result);
+            },
+            Mnemonic::Not => {
+                let value = self.read_operand(&instruction, 0);
+                self.write_operand(&instruction, 0, !value);
+            },
+            Mnemonic::Shl |
+            Mnemonic::Sal |
+            Mnemonic::Shr |
+            Mnemonic::Sar |
+            Mnemonic::Rol |
+            Mnemonic::Ror |
+            Mnemonic::Rcl |
+            Mnemonic::Rcr => self.shift(&instruction),
+            Mnemonic::Shld | Mnemonic::Shrd => self.double_shift(&instruction),
+            Mnemonic::Mul | Mnemonic::Div | Mnemonic::Idiv => {
+                self.multiply_divide(&instruction)?
+            },
+            Mnemonic::Imul if instruction.op_count() == 1 => self.multiply_divide(&instruction)?,
+            Mnemonic::Imul => {
+                let size = self.operand_size(&instruction, 0);
+                let (lhs, rhs) = match instruction.op_count() {
+                    2 => (self.read_operand(&instruction, 0), self.read_operand(&instruction, 1)),
+                    _ => (self.read_operand(&instruction, 1), self.read_operand(&instruction, 2)),
+                };
+                let product = sign_extend(lhs, size) as i64 as i128 *
+                              sign_extend(rhs & size_mask(size), size) as i64 as i128;
+                let result = product as u64 & size_mask(size);
+                let overflow = sign_extend(result, size) as i64 as i128 != product;
+                self.set_flag(CF, overflow);
+                self.set_flag(OF, overflow);
+                self.write_operand(&instruction, 0, result);
+            },
+            Mnemonic::Bswap => {
+                let value = self.read_operand(&instruction, 0);
+                let result = match self.operand_size(&instruction, 0) {
+                    8 => value.swap_bytes(),
+                    _ => (value as u32).swap_bytes() as u64,
+                };
+                self.write_operand(&instruction, 0, result);
+            },
+            Mnemonic::Xchg => {
+                let first = self.read_operand(&instruction, 0);
+                let second = self.read_operand(&instruction, 1);
+                self.write_operand(&instruction, 0, second);
+                self.write_operand(&instruction, 1, first);
+            },
+            Mnemonic::Xadd => {
+                let first = self.read_operand(&instruction, 0);
+                let second = self.read_operand(&instruction, 1);
+                let size = self.operand_size(&instruction, 0);
+                let result = first.wrapping_add(second) & size_mask(size);
+                self.set_flag(CF, result < first & size_mask(size));
+                self.set_result_flags(result, size);
+                self.write_operand(&instruction, 1, first);
+                self.write_operand(&instruction, 0, result);
+            },
+            Mnemonic::Bt | Mnemonic::Bts | Mnemonic::Btr | Mnemonic::Btc => {
+                let size = self.operand_size(&instruction, 0);
+                let bit = self.read_operand(&instruction, 1) & (size as u64 * 8 - 1);
+                let value = self.read_operand(&instruction, 0);
+                self.set_flag(CF, (value >> bit) & 1 != 0);
+                let result = match mnemonic {
+                    Mnemonic::Bts => value | (1 << bit),
+                    Mnemonic::Btr => value & !(1 << bit),
+                    Mnemonic::Btc => value ^ (1 << bit),
+                    _ => return Ok(instruction),
+                };
+                self.write_operand(&instruction, 0, result);
+            },
+            Mnemonic::Cbw => {
+                let value = sign_extend(self.read_register(Register::AL), 1);
+                self.write_register(Register::AX, value);
+            },
+            Mnemonic::Cwde => {
+                let value = sign_extend(self.read_register(Register::AX), 2);
+                self.write_register(Register::EAX, value);
+            },
+            Mnemonic::Cdqe => {
+                let value = sign_extend(self.read_register(Register::EAX), 4);
+                self.write_register(Register::RAX, value);
+            },
+            Mnemonic::Cwd | Mnemonic::Cdq | Mnemonic::Cqo => {
+                let (source, destination, size) = match mnemonic {
+                    Mnemonic::Cwd => (Register::AX, Register::DX, 2),
+                    Mnemonic::Cdq => (Register::EAX, Register::EDX, 4),
+                    _ => (Register::RAX, Register::RDX, 8),
+                };
+                let negative = self.read_register(source) & sign_bit(size) != 0;
+                self.write_register(destination, if negative { u64::MAX } else { 0 });
+            },
+            Mnemonic::Stc => self.set_flag(CF, true),
+            Mnemonic::Clc => self.set_flag(CF, false),
+            Mnemonic::Cmc => self.set_flag(CF, !self.flag(CF)),
+            Mnemonic::Std => self.set_flag(DF, true),
+            Mnemonic::Cld => self.set_flag(DF, false),
+            Mnemonic::Push => {
+                let value = match instruction.op0_kind() {
+                    OpKind::Register | OpKind::Memory => self.read_operand(&instruction, 0),
+                    _ => instruction.immediate(0),
+                };
+                self.push(value);
+            },
+            Mnemonic::Pop => {
+                let value = self.pop();
+                self.write_operand(&instruction, 0, value);
+            },
+            Mnemonic::Pushfq => self.push(self.rflags),
+            Mnemonic::Popfq => self.rflags = self.pop() | 0x2,
+            Mnemonic::Jmp => self.rip = self.read_operand(&instruction, 0),
+            Mnemonic::Call => {
+                let target = self.read_operand(&instruction, 0);
+                self.push(instruction.next_ip());
+                self.rip = target;
+            },
+            Mnemonic::Ret => {
+                self.rip = self.pop();
+                if instruction.op_count() == 1 {
+                    let rsp = self.read_register(Register::RSP);
+                    self.write_register(Register::RSP, rsp.wrapping_add(instruction.immediate(0)));
+                }
+            },
+            _ if instruction.is_jcc_short_or_near() => {
+                if self.condition(instruction.condition_code()) {
+                    self.rip = instruction.near_branch64();
+                }
+            },
+            _ if is_setcc(mnemonic) => {
+                let value = self.condition(instruction.condition_code()) as u64;
+                self.write_operand(&instruction, 0, value);
+            },
+            _ if is_cmovcc(mnemonic) => {
+                let value = self.read_operand(&instruction, 1);
+                match self.condition(instruction.condition_code()) {
+                    true => self.write_operand(&instruction, 0, value),
+                    // A 32 bit destination is zero extended even if the move does not happen
+                    false => {
+                        let value = self.read_operand(&instruction, 0);
+                        self.write_operand(&instruction, 0, value);
+                    },
+                }
+            },
+            Mnemonic::Rdtsc => {
+                self.write_register(Register::RAX, 0);
+                self.write_register(Register::RDX, 0);
+            },
+            Mnemonic::Cpuid => {
+                for reg in [Register::RAX, Register::RBX, Register::RCX, Register::RDX] {
+                    self.write_register(reg, 0);
+                }
+            },
+            Mnemonic::Nop |
+            Mnemonic::Pause |
+            Mnemonic::Lfence |
+            Mnemonic::Mfence |
+            Mnemonic::Sfence |
+            Mnemonic::Prefetchnta |
+            Mnemonic::Prefetcht0 |
+            Mnemonic::Prefetcht1 |
+            Mnemonic::Prefetcht2 => {},
+            _ => return Err(format!("unsupported instruction {:?}", mnemonic)),
+        }
+
+        Ok(instruction)
+    }
+
+    fn snapshot(&self,
+                handler_address: u64,
+                reg_allocation: &VmRegisterAllocation)
+                -> HandlerSnapshot {
+        HandlerSnapshot { handler_address,
+                          vip: self.read_register(reg_allocation.vip.into()),
+                          vsp: self.read_register(reg_allocation.vsp.into()),
+                          key: self.read_register(reg_allocation.key.into()) }
+    }
+}
+
+/// Address of the instruction a handler dispatches with, found by walking it statically
+pub fn dispatch_address(handler_address: u64,
+                        image: &dyn Image)
+                        -> Option<u64> {
+    handler_dispatch(handler_address, image).map(|dispatch| dispatch.address)
+}
+
+/// The instruction a handler leaves with
+#[derive(Debug, Clone, Copy)]
+struct HandlerDispatch {
+    address: u64,
+    /// The handler is vmexit, it returns out of the dispatch loop
+    leaves_vm: bool,
+}
+
+fn handler_dispatch(handler_address: u64,
+                    image: &dyn Image)
+                    -> Option<HandlerDispatch> {
+    let vm_handler = VmHandler::new(handler_address, image);
+    if vm_handler.walk_end != WalkEnd::Dispatch {
+        return None;
+    }
+    let dispatch = vm_handler.instructions.last()?;
+
+    // Vmexit moves rsp to the virtual stack and returns to what it pops from there, a push, ret
+    // dispatch returns to what it pushed itself
+    let is_stack_switch = |insn: &Instruction| {
+        insn.mnemonic() == Mnemonic::Mov &&
+        insn.op0_kind() == OpKind::Register &&
+        insn.op0_register().full_register() == Register::RSP &&
+        insn.op1_kind() == OpKind::Register
+    };
+    let leaves_vm = matches!(dispatch.code(), Code::Retnq | Code::Retnd) &&
+                    vm_handler.instructions
+                              .iter()
+                              .rev()
+                              .take_while(|insn| insn.mnemonic() != Mnemonic::Push)
+                              .any(is_stack_switch);
+
+    Some(HandlerDispatch { address: dispatch.ip(),
+                           leaves_vm })
+}
+
+/// Where execution went after a dispatch
+#[derive(Debug, Clone, Copy)]
+pub enum DispatchEvent {
+    /// The handler at the address was entered
+    Handler(u64),
+    /// The vm returned to native code at the address
+    VmExit(u64),
+}
+
+/// Recognises the dispatches of a vm in natively executed instructions, starting at vmentry
+pub struct DispatchTracker {
+    dispatch: Option<HandlerDispatch>,
+    dispatch_cache: HashMap<u64, Option<HandlerDispatch>>,
+}
+
+impl DispatchTracker {
+    pub fn new(vm_entry_address: u64,
+               image: &dyn Image)
+               -> Self {
+        Self { dispatch: handler_dispatch(vm_entry_address, image),
+               dispatch_cache: HashMap::new() }
+    }
+
+    /// Looks at an executed instruction, given rip after it. A handler that can not be walked
+    /// dispatches with its first jmp or ret, and is never seen as vmexit
+    pub fn observe(&mut self,
+                   instruction: &Instruction,
+                   rip: u64,
+                   image: &dyn Image)
+                   -> Option<DispatchEvent> {
+        let leaves_vm = match self.dispatch {
+            Some(dispatch) if instruction.ip() == dispatch.address => dispatch.leaves_vm,
+            None if matches!(instruction.code(), Code::Jmp_rm64 | Code::Retnq) => false,
+            _ => return None,
+        };
+        if leaves_vm {
+            return Some(DispatchEvent::VmExit(rip));
+        }
+
+        self.dispatch = *self.dispatch_cache
+                             .entry(rip)
+                             .or_insert_with(|| handler_dispatch(rip, image));
+        Some(DispatchEvent::Handler(rip))
+    }
+}
+
+/// Vmentry of a vm call and the vm registers it allocates, read from its native code without
+/// decoding any bytecode
+fn vm_entry_registers(image: &dyn Image,
+                      vm_call_address: u64)
+                      -> Result<(u64, VmRegisterAllocation), String> {
+    if !is_vm_call(image, vm_call_address) {
+        return Err(format!("no push, call pair at {:#x}", vm_call_address));
+    }
+    let (_, vm_entry_address) = handle_vm_call(image, vm_call_address);
+    let vm_entry = VmHandler::new(vm_entry_address, image);
+    if !vm_entry.is_vm_entry() {
+        return Err(format!("the call at {:#x} does not enter a vmentry", vm_call_address));
+    }
+    Ok((vm_entry_address, vm_entry.get_register_allocation_vm_entry()))
+}
+
+/// Natively executes the vm call, from the push before the call into vmentry through the
+/// dispatch loop, recording the vm registers on entry to every handler
+pub fn emulate_vm_call<'a>(image: &'a dyn Image,
+                           vm_call_address: u64,
+                           initial: &NativeState,
+                           max_steps: usize)
+                           -> (Vec<HandlerSnapshot>, X86Emulator<'a>, EmulationEnd) {
+    let mut emulator = X86Emulator::new(image, vm_call_address, initial);
+    let (vm_entry_address, reg_allocation) = match vm_entry_registers(image, vm_call_address) {
+        Ok(vm_entry) => vm_entry,
+        Err(reason) => {
+            return (Vec::new(), emulator, EmulationEnd::Fault(vm_call_address, reason))
+        },
+    };
+
+    let mut snapshots = Vec::new();
+    let mut tracker = DispatchTracker::new(vm_entry_address, image);
+
+    for _ in 0 .. max_steps {
+        let address = emulator.rip;
+        let instruction = match emulator.step() {
+            Ok(instruction) => instruction,
+            Err(reason) => return (snapshots, emulator, EmulationEnd::Fault(address, reason)),
+        };
+
+        match tracker.observe(&instruction, emulator.rip, image) {
+            Some(DispatchEvent::Handler(handler_address)) => {
+                snapshots.push(emulator.snapshot(handler_address, &reg_allocation));
+            },
+            Some(DispatchEvent::VmExit(return_address)) => {
+                return (snapshots, emulator, EmulationEnd::VmExit(return_address));
+            },
+            None => {},
+        }
+    }
+
+    (snapshots, emulator, EmulationEnd::StepLimit)
+}
@@ -0,0 +1,1797 @@
+use std::{ops::Range, path::Path};
+
+use iced_x86::{Code, Encoder, Instruction, MemoryOperand, Mnemonic, Register};
+use pelite::base_relocs::build as build_base_relocs;
+
+use crate::{
+    canonicalize::{alu_immediate_code, alu_reg_rm_code},
+    image::FileImage,
+    util::XorShift64,
+    vm_handler::{Registers, VmRegisterAllocation},
+    vm_matchers::HandlerVmInstruction,
+};
+
+pub const IMAGE_BASE: u64 = 0x140000000;
+/// Preferred image base of 32 bit fixtures
+pub const IMAGE_BASE_32: u64 = 0x400000;
+pub const SECTION_RVA: u64 = 0x1000;
+const HEADERS_SIZE: usize = 0x200;
+/// GUID and age of the PDB the debug directory of every PE fixture names
+pub const FIXTURE_PDB_IDENTITY: ([u8; 16], u32) = (*b"vmp3 fixture pdb", 3);
+const FILE_ALIGNMENT: usize = 0x200;
+const SECTION_ALIGNMENT: usize = 0x1000;
+/// Constant 64 bit vmentry adds to the decrypted vip, 32 bit vips are whole addresses
+const VIP_BASE: u64 = 0x100000000;
+/// Native stack vmentry reserves below the saved registers for the register file
+const REGISTER_FILE_RESERVE: i32 = 0x400;
+
+/// The general purpose registers vmentry saves
+pub const SAVED_REGISTERS: [Registers; 15] = [Registers::Rax,
+                                              Registers::Rbx,
+                                              Registers::Rcx,
+                                              Registers::Rdx,
+                                              Registers::Rsi,
+                                              Registers::Rdi,
+                                              Registers::Rbp,
+                                              Registers::R8,
+                                              Registers::R9,
+                                              Registers::R10,
+                                              Registers::R11,
+                                              Registers::R12,
+                                              Registers::R13,
+                                              Registers::R14,
+                                              Registers::R15];
+
+/// The general purpose registers 32 bit vmentry saves, named by their full registers
+pub const SAVED_REGISTERS_32: [Registers; 7] = [Registers::Rax,
+                                                Registers::Rbx,
+                                                Registers::Rcx,
+                                                Registers::Rdx,
+                                                Registers::Rsi,
+                                                Registers::Rdi,
+                                                Registers::Rbp];
+
+/// The general purpose registers vmentry saves in the given mode
+pub fn saved_registers(bitness: u32) -> &'static [Registers] {
+    match bitness {
+        32 => &SAVED_REGISTERS_32,
+        _ => &SAVED_REGISTERS,
+    }
+}
+
+/// True if the low byte of the register can be addressed in the given mode, 32 bit code has
+/// no sil, dil or bpl
+fn has_byte_register(reg: Registers,
+                     bitness: u32)
+                     -> bool {
+    bitness == 64 ||
+    matches!(reg, Registers::Rax | Registers::Rbx | Registers::Rcx | Registers::Rdx)
+}
+
+/// File format of the generated image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureFormat {
+    Pe,
+    /// An ELF64 shared object linked at the 64 bit image base
+    Elf,
+}
+
+/// Shape of the generated protection
+pub struct FixtureConfig {
+    pub seed: u64,
+    /// Vm registers, none of them may be rsp or rcx
+    pub reg_allocation: VmRegisterAllocation,
+    /// Order vmentry pushes the saved registers and the flags in
+    pub push_order: Vec<Registers>,
+    pub vip_direction_forwards: bool,
+    /// Distinct handlers generated for every instruction shape
+    pub handler_copies: usize,
+    /// Insert junk, split handlers into jump chained blocks and add opaque branches
+    pub mutate: bool,
+    /// 32 or 64, the mode of the generated image
+    pub bitness: u32,
+    pub format: FixtureFormat,
+}
+
+impl FixtureConfig {
+    /// Random register allocation, push order and direction
+    pub fn random(seed: u64) -> Self {
+        Self::random_with_bitness(seed, 64)
+    }
+
+    /// Random register allocation, push order and direction of a 32 bit image
+    pub fn random_32(seed: u64) -> Self {
+        Self::random_with_bitness(seed, 32)
+    }
+
+    fn random_with_bitness(seed: u64,
+                           bitness: u32)
+                           -> Self {
+        let mut rng = XorShift64::new(seed);
+
+        let mut candidates = saved_registers(bitness).iter()
+                                                     .copied()
+                                                     .filter(|&reg| reg != Registers::Rcx)
+                                                     .collect::<Vec<_>>();
+        shuffle(&mut candidates, &mut rng);
+        // The key and the operand register are used bytewise, in 32 bit they take two of the
+        // registers with byte forms and the other vm registers take the rest
+        if bitness == 32 {
+            candidates.sort_by_key(|&reg| has_byte_register(reg, bitness));
+            candidates.swap(2, 3);
+        }
+        let reg_allocation = VmRegisterAllocation { vip: candidates[0],
+                                                    vsp: candidates[1],
+                                                    key: candidates[2],
+                                                    handler_address: candidates[3] };
+
+        let mut push_order = saved_registers(bitness).to_vec();
+        push_order.push(Registers::Flags);
+        shuffle(&mut push_order, &mut rng);
+
+        Self { seed,
+               reg_allocation,
+               push_order,
+               vip_direction_forwards: rng.next_u64() & 1 == 0,
+               handler_copies: 1 + (rng.next_u64() % 3) as usize,
+               mutate: true,
+               bitness,
+               format: FixtureFormat::Pe }
+    }
+}
+
+/// A generated PE and what disassembling it must reproduce
+pub struct Fixture {
+    pub bytes: Vec<u8>,
+    /// Address of the push, call pair entering the vm
+    pub vm_call_address: u64,
+    /// Address the vm returns to after vmexit
+    pub return_address: u64,
+    /// Handler executing every instruction of the program
+    pub handler_addresses: Vec<u64>,
+    pub program: Vec<HandlerVmInstruction>,
+    /// Addresses of the encrypted bytecode, the vm reads all of it
+    pub bytecode: Range<u64>,
+    /// Import protection stubs, PE32+ fixtures have them
+    pub imports: Option<FixtureImports>,
+}
+
+impl Fixture {
+    pub fn write(&self,
+                 path: &Path)
+                 -> std::io::Result<()> {
+        std::fs::write(path, &self.bytes)
+    }
+
+    /// The generated file, read at its preferred image base
+    pub fn image(&self) -> FileImage {
+        FileImage::new(self.bytes.clone(), None).unwrap()
+    }
+}
+
+/// Program that pops the image base, every saved register and the flags into the register
+/// file and pushes them back in the order vmexit restores them
+pub fn round_trip_program(push_order: &[Registers]) -> Vec<HandlerVmInstruction> {
+    round_trip_program_sized(push_order, 8)
+}
+
+/// Round trip program of an image whose native words take word_size bytes
+pub fn round_trip_program_sized(push_order: &[Registers],
+                                word_size: usize)
+                                -> Vec<HandlerVmInstruction> {
+    let mut program = vec![HandlerVmInstruction::Pop(word_size, 0x80)];
+    for index in (0 .. push_order.len()).rev() {
+        program.push(HandlerVmInstruction::Pop(word_size, (index * word_size) as u8));
+    }
+    for index in 0 .. push_order.len() {
+        program.push(HandlerVmInstruction::Push(word_size, (index * word_size) as u8));
+    }
+    program.push(HandlerVmInstruction::VmExit);
+    program
+}
+
+fn shuffle<T>(items: &mut [T],
+              rng: &mut XorShift64) {
+    for index in (1 .. items.len()).rev() {
+        let other = (rng.next_u64() % (index as u64 + 1)) as usize;
+        items.swap(index, other);
+    }
+}
+
+fn stack_slot(size: usize) -> usize {
+    size.max(2)
+}
+
+/// The register of the given size sharing its full register with `reg`
+fn sized(reg: Register,
+         size: usize)
+         -> Register {
+    let number = reg.full_register().number();
+    let register = match size {
+        8 => Register::RAX as usize + number,
+        4 => Register::EAX as usize + number,
+        2 => Register::AX as usize + number,
+        // spl, bpl, sil and dil come after ah, ch, dh and bh
+        1 if number < 4 => Register::AL as usize + number,
+        1 => Register::AL as usize + number + 4,
+        _ => panic!("No register of size {}", size),
+    };
+    Register::try_from(register).unwrap()
+}
+
+fn load_code(size: usize) -> Code {
+    alu_reg_rm_code(Mnemonic::Mov, size).unwrap()
+}
+
+fn store_code(size: usize) -> Code {
+    match size {
+        8 => Code::Mov_rm64_r64,
+        4 => Code::Mov_rm32_r32,
+        2 => Code::Mov_rm16_r16,
+        _ => Code::Mov_rm8_r8,
+    }
+}
+
+fn at(base: Register,
+      displacement: i64)
+      -> MemoryOperand {
+    MemoryOperand::with_base_displ(base, displacement)
+}
+
+/// Instruction with its operands, panicking if the code does not take them
+macro_rules! op {
+    ($code:expr, $op0:expr) => {
+        Instruction::with1($code, $op0).unwrap()
+    };
+    ($code:expr, $op0:expr, $op1:expr) => {
+        Instruction::with2($code, $op0, $op1).unwrap()
+    };
+}
+
+const NOT_CODES: [Code; 4] = [Code::Not_rm8, Code::Not_rm16, Code::Not_rm32, Code::Not_rm64];
+const NEG_CODES: [Code; 4] = [Code::Neg_rm8, Code::Neg_rm16, Code::Neg_rm32, Code::Neg_rm64];
+const INC_CODES: [Code; 4] = [Code::Inc_rm8, Code::Inc_rm16, Code::Inc_rm32, Code::Inc_rm64];
+const DEC_CODES: [Code; 4] = [Code::Dec_rm8, Code::Dec_rm16, Code::Dec_rm32, Code::Dec_rm64];
+const ROL_CODES: [Code; 4] =
+    [Code::Rol_rm8_imm8, Code::Rol_rm16_imm8, Code::Rol_rm32_imm8, Code::Rol_rm64_imm8];
+const ROR_CODES: [Code; 4] =
+    [Code::Ror_rm8_imm8, Code::Ror_rm16_imm8, Code::Ror_rm32_imm8, Code::Ror_rm64_imm8];
+const SHR_CODES: [Code; 4] =
+    [Code::Shr_rm8_CL, Code::Shr_rm16_CL, Code::Shr_rm32_CL, Code::Shr_rm64_CL];
+
+/// Code of a byte, word, dword and qword table for the operand size
+fn sized_code(codes: &[Code; 4],
+              size: usize)
+              -> Code {
+    codes[size.trailing_zeros() as usize]
+}
+
+/// A single step of a decryption, applied in place to a register
+#[derive(Debug, Clone, Copy)]
+enum Transform {
+    Add(u64),
+    Sub(u64),
+    Xor(u64),
+    Not,
+    Neg,
+    Inc,
+    Dec,
+    Rol(u32),
+    Ror(u32),
+    Bswap,
+}
+
+impl Transform {
+    fn random(bits: u32,
+              rng: &mut XorShift64)
+              -> Self {
+        let mask = u64::MAX >> (64 - bits);
+        // 64 bit immediates are sign extended imm32s
+        let constant = match bits {
+            64 => rng.next_u64() as i32 as u64,
+            _ => rng.next_u64() & mask,
+        };
+        let count = 1 + (rng.next_u64() % (bits as u64 - 1)) as u32;
+        let choices = if bits >= 32 { 10 } else { 9 };
+
+        match rng.next_u64() % choices {
+            0 => Transform::Add(constant),
+            1 => Transform::Sub(constant),
+            2 => Transform::Xor(constant),
+            3 => Transform::Not,
+            4 => Transform::Neg,
+            5 => Transform::Inc,
+            6 => Transform::Dec,
+            7 => Transform::Rol(count),
+            8 => Transform::Ror(count),
+            _ => Transform::Bswap,
+        }
+    }
+
+    fn inverse(self) -> Self {
+        match self {
+            Transform::Add(constant) => Transform::Sub(constant),
+            Transform::Sub(constant) => Transform::Add(constant),
+            Transform::Inc => Transform::Dec,
+            Transform::Dec => Transform::Inc,
+            Transform::Rol(count) => Transform::Ror(count),
+            Transform::Ror(count) => Transform::Rol(count),
+            transform => transform,
+        }
+    }
+
+    fn apply(self,
+             value: u64,
+             bits: u32)
+             -> u64 {
+        let mask = u64::MAX >> (64 - bits);
+        let value = value & mask;
+        let result = match self {
+            Transform::Add(constant) => value.wrapping_add(constant),
+            Transform::Sub(constant) => value.wrapping_sub(constant),
+            Transform::Xor(constant) => value ^ constant,
+            Transform::Not => !value,
+            Transform::Neg => value.wrapping_neg(),
+            Transform::Inc => value.wrapping_add(1),
+            Transform::Dec => value.wrapping_sub(1),
+            Transform::Rol(count) => (value << count) | (value >> (bits - count)),
+            Transform::Ror(count) => (value >> count) | (value << (bits - count)),
+            Transform::Bswap => value.swap_bytes() >> (64 - bits),
+        };
+        result & mask
+    }
+
+    fn instruction(self,
+                   reg: Register)
+                   -> Instruction {
+        let size = reg.size();
+        let immediate = |mnemonic, constant: u64| match size {
+            8 => op!(alu_immediate_code(mnemonic, size).unwrap(), reg, constant as i32),
+            _ => op!(alu_immediate_code(mnemonic, size).unwrap(), reg, constant as u32),
+        };
+        let unary = |codes: &[Code; 4]| op!(sized_code(codes, size), reg);
+        let rotate = |codes: &[Code; 4], count: u32| op!(sized_code(codes, size), reg, count);
+
+        match self {
+            Transform::Add(constant) => immediate(Mnemonic::Add, constant),
+            Transform::Sub(constant) => immediate(Mnemonic::Sub, constant),
+            Transform::Xor(constant) => immediate(Mnemonic::Xor, constant),
+            Transform::Not => unary(&NOT_CODES),
+            Transform::Neg => unary(&NEG_CODES),
+            Transform::Inc => unary(&INC_CODES),
+            Transform::Dec => unary(&DEC_CODES),
+            Transform::Rol(count) => rotate(&ROL_CODES, count),
+            Transform::Ror(count) => rotate(&ROR_CODES, count),
+            Transform::Bswap => match size {
+                8 => op!(Code::Bswap_r64, reg),
+                _ => op!(Code::Bswap_r32, reg),
+            },
+        }
+    }
+}
+
+fn random_program(bits: u32,
+                  rng: &mut XorShift64)
+                  -> Vec<Transform> {
+    let length = 1 + rng.next_u64() % 4;
+    (0 .. length).map(|_| Transform::random(bits, rng)).collect()
+}
+
+/// Value that decrypts to `plain` with the rolling key, which is then updated like the
+/// handler does
+fn encrypt(program: &[Transform],
+           plain: u64,
+           size: usize,
+           rolling_key: &mut u64)
+           -> u64 {
+    let bits = size as u32 * 8;
+    let mask = u64::MAX >> (64 - bits);
+    let plain = plain & mask;
+
+    let mut value = plain;
+    for transform in program.iter().rev() {
+        value = transform.inverse().apply(value, bits);
+    }
+    value ^= *rolling_key & mask;
+
+    *rolling_key ^= plain;
+    value
+}
+
+/// Registers a handler works with besides the vm registers
+#[derive(Clone, Copy)]
+struct Scratch {
+    /// Operand and first stack value
+    first: Register,
+    /// Second stack value, rcx so it can be a shift count
+    second: Register,
+    /// Next handler offset
+    offset: Register,
+    /// Target of junk instructions, none when the vm and scratch registers take them all
+    junk: Option<Register>,
+}
+
+/// Code of a handler with whether junk or a jump may be placed after each item
+#[derive(Default)]
+struct Body {
+    items: Vec<(Item, bool)>,
+}
+
+impl Body {
+    fn emit(&mut self,
+            instruction: Instruction) {
+        self.items.push((Item::Instruction(instruction), true));
+    }
+
+    /// Emits an instruction whose flags or stack effect must reach the next one unchanged
+    fn emit_fixed(&mut self,
+                  instruction: Instruction) {
+        self.items.push((Item::Instruction(instruction), false));
+    }
+}
+
+/// Native word sized codes of the generated code
+struct WordCodes {
+    push: Code,
+    pop: Code,
+    pop_rm: Code,
+    pushf: Code,
+    popf: Code,
+    ret: Code,
+    jmp_rm: Code,
+    jmp_rel: Code,
+    jb_rel: Code,
+    call_rel: Code,
+    push_imm: Code,
+    bswap: Code,
+}
+
+const WORD_CODES_64: WordCodes = WordCodes { push: Code::Push_r64,
+                                             pop: Code::Pop_r64,
+                                             pop_rm: Code::Pop_rm64,
+                                             pushf: Code::Pushfq,
+                                             popf: Code::Popfq,
+                                             ret: Code::Retnq,
+                                             jmp_rm: Code::Jmp_rm64,
+                                             jmp_rel: Code::Jmp_rel32_64,
+                                             jb_rel: Code::Jb_rel32_64,
+                                             call_rel: Code::Call_rel32_64,
+                                             push_imm: Code::Pushq_imm32,
+                                             bswap: Code::Bswap_r64 };
+
+const WORD_CODES_32: WordCodes = WordCodes { push: Code::Push_r32,
+                                             pop: Code::Pop_r32,
+                                             pop_rm: Code::Pop_rm32,
+                                             pushf: Code::Pushfd,
+                                             popf: Code::Popfd,
+                                             ret: Code::Retnd,
+                                             jmp_rm: Code::Jmp_rm32,
+                                             jmp_rel: Code::Jmp_rel32_32,
+                                             jb_rel: Code::Jb_rel32_32,
+                                             call_rel: Code::Call_rel32_32,
+                                             push_imm: Code::Pushd_imm32,
+                                             bswap: Code::Bswap_r32 };
+
+fn word_codes(bitness: u32) -> &'static WordCodes {
+    match bitness {
+        32 => &WORD_CODES_32,
+        _ => &WORD_CODES_64,
+    }
+}
+
+fn image_base(bitness: u32) -> u64 {
+    match bitness {
+        32 => IMAGE_BASE_32,
+        _ => IMAGE_BASE,
+    }
+}
+
+struct Generator<'a> {
+    config: &'a FixtureConfig,
+    codes: &'static WordCodes,
+    /// Bytes of a native word
+    word_size: usize,
+    vip: Register,
+    vsp: Register,
+    key: Register,
+    handler: Register,
+    scratch: Scratch,
+    rng: XorShift64,
+}
+
+/// A placed piece of code
+enum Item {
+    Instruction(Instruction),
+    /// jmp to a block
+    Jump(usize),
+    /// stc; jb to a block followed by bytes that never execute
+    OpaqueJump(usize, Vec<u8>),
+    /// lea reg, [rip + x] pointing at a block, lea reg, [x] in 32 bit
+    LeaBlock(Register, usize),
+    /// call to a block
+    Call(usize),
+    /// Instruction ending in an absolute address the loader relocates, mov reg, imm
+    Relocated(Instruction),
+}
+
+impl Item {
+    fn encode(&self,
+              ip: u64,
+              addresses: &[u64],
+              bitness: u32)
+              -> Vec<u8> {
+        let codes = word_codes(bitness);
+        let instructions = match self {
+            Item::Instruction(instruction) | Item::Relocated(instruction) => vec![*instruction],
+            Item::Jump(block) => {
+                vec![Instruction::with_branch(codes.jmp_rel, addresses[*block]).unwrap()]
+            },
+            Item::OpaqueJump(block, _) => {
+                vec![Instruction::with(Code::Stc),
+                     Instruction::with_branch(codes.jb_rel, addresses[*block]).unwrap()]
+            },
+            Item::LeaBlock(reg, block) if bitness == 32 => {
+                let address = MemoryOperand::with_displ(addresses[*block], 4);
+                vec![op!(Code::Lea_r32_m, sized(*reg, 4), address)]
+            },
+            Item::LeaBlock(reg, block) => {
+                vec![op!(Code::Lea_r64_m, *reg, at(Register::RIP, addresses[*block] as i64))]
+            },
+            Item::Call(block) => {
+                vec![Instruction::with_branch(codes.call_rel, addresses[*block]).unwrap()]
+            },
+        };
+
+        let mut encoder = Encoder::new(bitness);
+        let mut bytes = Vec::new();
+        for instruction in instructions {
+            let length = encoder.encode(&instruction, ip + bytes.len() as u64).unwrap();
+            bytes.extend_from_slice(&encoder.take_buffer()[.. length]);
+        }
+        if let Item::OpaqueJump(_, garbage) = self {
+            bytes.extend_from_slice(garbage);
+        }
+        bytes
+    }
+
+    /// True if the item ends in an absolute address of the native word size
+    fn is_relocated(&self,
+                    bitness: u32)
+                    -> bool {
+        match self {
+            Item::Relocated(_) => true,
+            Item::LeaBlock(..) => bitness == 32,
+            _ => false,
+        }
+    }
+}
+
+/// Handler entry block with the decryptions its encoding needs
+struct GeneratedHandler {
+    block: usize,
+    operand_program: Vec<Transform>,
+    offset_program: Vec<Transform>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(config: &'a FixtureConfig) -> Self {
+        let reg_allocation = &config.reg_allocation;
+        let vm_registers = [reg_allocation.vip,
+                            reg_allocation.vsp,
+                            reg_allocation.key,
+                            reg_allocation.handler_address];
+        assert!(!vm_registers.contains(&Registers::Rsp) && !vm_registers.contains(&Registers::Rcx),
+                "Vm registers can not be rsp or rcx");
+        assert!(has_byte_register(reg_allocation.key, config.bitness),
+                "The key needs a byte register");
+
+        let mut rng = XorShift64::new(config.seed);
+        let mut free = saved_registers(config.bitness).iter()
+                                                      .copied()
+                                                      .filter(|reg| {
+                                                          *reg != Registers::Rcx &&
+                                                          !vm_registers.contains(reg)
+                                                      })
+                                                      .collect::<Vec<_>>();
+        shuffle(&mut free, &mut rng);
+        let first = free.iter()
+                        .position(|&reg| has_byte_register(reg, config.bitness))
+                        .expect("No byte register left for the operand");
+        free.swap(0, first);
+
+        Self { config,
+               codes: word_codes(config.bitness),
+               word_size: config.bitness as usize / 8,
+               vip: reg_allocation.vip.into(),
+               vsp: reg_allocation.vsp.into(),
+               key: reg_allocation.key.into(),
+               handler: reg_allocation.handler_address.into(),
+               scratch: Scratch { first: free[0].into(),
+                                  second: Register::RCX,
+                                  offset: free[1].into(),
+                                  junk: free.get(2).map(|&reg| reg.into()) },
+               rng }
+    }
+
+    /// The register at the native word size
+    fn word(&self,
+            reg: Register)
+            -> Register {
+        sized(reg, self.word_size)
+    }
+
+    /// [base + displacement] with the base at the native word size
+    fn word_at(&self,
+               base: Register,
+               displacement: i64)
+               -> MemoryOperand {
+        at(self.word(base), displacement)
+    }
+
+    /// add or sub of an immediate to a native word sized register
+    fn word_alu(&self,
+                mnemonic: Mnemonic,
+                reg: Register,
+                immediate: i32)
+                -> Instruction {
+        op!(alu_immediate_code(mnemonic, self.word_size).unwrap(), self.word(reg), immediate)
+    }
+
+    /// Reads `size` bytes at vip into `reg` and decrypts them with the rolling key
+    fn emit_decrypt(&self,
+                    body: &mut Body,
+                    reg: Register,
+                    size: usize,
+                    program: &[Transform]) {
+        let value = sized(reg, size);
+
+        if !self.config.vip_direction_forwards {
+            body.emit(self.word_alu(Mnemonic::Sub, self.vip, size as i32));
+        }
+        match size {
+            1 => body.emit(op!(Code::Movzx_r32_rm8, sized(reg, 4), self.word_at(self.vip, 0))),
+            _ => body.emit(op!(load_code(size), value, self.word_at(self.vip, 0))),
+        }
+        if self.config.vip_direction_forwards {
+            body.emit(self.word_alu(Mnemonic::Add, self.vip, size as i32));
+        }
+
+        let xor = alu_reg_rm_code(Mnemonic::Xor, size).unwrap();
+        body.emit(op!(xor, value, sized(self.key, size)));
+        for transform in program {
+            body.emit(transform.instruction(value));
+        }
+
+        match size {
+            4 if self.word_size == 8 => {
+                body.emit(op!(Code::Push_r64, self.key));
+                body.emit(op!(Code::Xor_rm32_r32, at(Register::RSP, 0), value));
+                body.emit(op!(Code::Pop_r64, self.key));
+            },
+            _ => body.emit(op!(xor, sized(self.key, size), value)),
+        }
+    }
+
+    /// Decrypts the next handler offset and jumps to the handler
+    fn emit_dispatch(&mut self,
+                     body: &mut Body,
+                     program: &[Transform]) {
+        let offset = self.scratch.offset;
+        self.emit_decrypt(body, offset, 4, program);
+        if self.word_size == 8 {
+            body.emit(op!(Code::Movsxd_r64_rm32, offset, sized(offset, 4)));
+        }
+        let add = alu_reg_rm_code(Mnemonic::Add, self.word_size).unwrap();
+        body.emit(op!(add, self.word(self.handler), self.word(offset)));
+
+        if self.rng.next_u64() & 1 == 0 {
+            body.emit_fixed(op!(self.codes.jmp_rm, self.word(self.handler)));
+        } else {
+            body.emit_fixed(op!(self.codes.push, self.word(self.handler)));
+            body.emit_fixed(Instruction::with(self.codes.ret));
+        }
+    }
+
+    /// Native code of an instruction between its operand decryption and its dispatch
+    fn emit_operation(&self,
+                      body: &mut Body,
+                      instruction: HandlerVmInstruction) {
+        let vsp = self.vsp;
+        let first = self.scratch.first;
+        let second = self.scratch.second;
+        let word_size = self.word_size;
+        let mov_word = load_code(word_size);
+
+        // Loads the value at [vsp + displacement], zero extending bytes and words if asked to
+        let load = |body: &mut Body, reg: Register, size: usize, displacement: i64, extend: bool| {
+            let source = self.word_at(vsp, displacement);
+            match (size, extend) {
+                (1, true) => body.emit(op!(Code::Movzx_r32_rm8, sized(reg, 4), source)),
+                (2, true) => body.emit(op!(Code::Movzx_r32_rm16, sized(reg, 4), source)),
+                _ => body.emit(op!(load_code(size), sized(reg, size), source)),
+            }
+        };
+        // Stores the result and flags of a binary operation over its two inputs
+        let store_result = |body: &mut Body, size: usize| {
+            let slot = stack_slot(size);
+            body.emit_fixed(op!(store_code(slot),
+                                self.word_at(vsp, word_size as i64),
+                                sized(first, slot)));
+            body.emit_fixed(Instruction::with(self.codes.pushf));
+            body.emit(op!(self.codes.pop_rm, self.word_at(vsp, 0)));
+        };
+        let register_file =
+            MemoryOperand::with_base_index(self.word(Register::RSP), self.word(first));
+
+        match instruction {
+            HandlerVmInstruction::Pop(size, _) => {
+                load(body, second, size, 0, false);
+                body.emit(self.word_alu(Mnemonic::Add, vsp, stack_slot(size) as i32));
+                body.emit(op!(store_code(size), register_file, sized(second, size)));
+            },
+            HandlerVmInstruction::Push(size, _) => {
+                body.emit(op!(load_code(size), sized(second, size), register_file));
+                body.emit(self.word_alu(Mnemonic::Sub, vsp, stack_slot(size) as i32));
+                body.emit(op!(store_code(size), self.word_at(vsp, 0), sized(second, size)));
+            },
+            HandlerVmInstruction::PushImm64(_) |
+            HandlerVmInstruction::PushImm32(_) |
+            HandlerVmInstruction::PushImm16(_) => {
+                let size = operand(instruction).unwrap().0;
+                body.emit(self.word_alu(Mnemonic::Sub, vsp, size as i32));
+                body.emit(op!(store_code(size), self.word_at(vsp, 0), sized(first, size)));
+            },
+            HandlerVmInstruction::PushVsp(size) => {
+                body.emit(op!(mov_word, self.word(first), self.word(vsp)));
+                body.emit(self.word_alu(Mnemonic::Sub, vsp, stack_slot(size) as i32));
+                body.emit(op!(store_code(size), self.word_at(vsp, 0), sized(first, size)));
+            },
+            HandlerVmInstruction::PopVsp(_) => {
+                body.emit(op!(mov_word, self.word(vsp), self.word_at(vsp, 0)));
+            },
+            HandlerVmInstruction::Add(size) |
+            HandlerVmInstruction::Nand(size) |
+            HandlerVmInstruction::Nor(size) => {
+                let slot = stack_slot(size);
+                load(body, first, size, 0, true);
+                load(body, second, size, slot as i64, false);
+                if slot < word_size {
+                    body.emit(self.word_alu(Mnemonic::Sub, vsp, (word_size - slot) as i32));
+                }
+
+                let (first, second) = (sized(first, size), sized(second, size));
+                let mnemonic = match instruction {
+                    HandlerVmInstruction::Add(_) => Mnemonic::Add,
+                    HandlerVmInstruction::Nand(_) => Mnemonic::Or,
+                    _ => Mnemonic::And,
+                };
+                if mnemonic != Mnemonic::Add {
+                    body.emit(op!(sized_code(&NOT_CODES, size), first));
+                    body.emit(op!(sized_code(&NOT_CODES, size), second));
+                }
+                body.emit_fixed(op!(alu_reg_rm_code(mnemonic, size).unwrap(), first, second));
+                store_result(body, size);
+            },
+            HandlerVmInstruction::Shr(size) => {
+                let slot = stack_slot(size);
+                load(body, first, size, 0, true);
+                load(body, second, 1, slot as i64, false);
+                // The byte count takes a word slot, the flags take a native word more
+                body.emit(self.word_alu(Mnemonic::Sub, vsp, word_size as i32 - 2));
+                let shr = sized_code(&SHR_CODES, size);
+                body.emit_fixed(op!(shr, sized(first, size), Register::CL));
+                store_result(body, size);
+            },
+            HandlerVmInstruction::Fetch(size) => {
+                let slot = stack_slot(size);
+                let address = self.word_at(first, 0);
+                body.emit(op!(mov_word, self.word(first), self.word_at(vsp, 0)));
+                match size {
+                    1 => body.emit(op!(Code::Movzx_r32_rm8, sized(first, 4), address)),
+                    _ => body.emit(op!(load_code(size), sized(first, size), address)),
+                }
+                if slot < word_size {
+                    body.emit(self.word_alu(Mnemonic::Add, vsp, (word_size - slot) as i32));
+                }
+                body.emit(op!(store_code(slot), self.word_at(vsp, 0), sized(first, slot)));
+            },
+            HandlerVmInstruction::Store(size) => {
+                body.emit(op!(mov_word, self.word(first), self.word_at(vsp, 0)));
+                body.emit(op!(mov_word,
+                              self.word(second),
+                              self.word_at(vsp, word_size as i64)));
+                body.emit(self.word_alu(Mnemonic::Add, vsp, 2 * word_size as i32));
+                body.emit(op!(store_code(size), self.word_at(first, 0), sized(second, size)));
+            },
+            instruction => panic!("Can not generate a handler for {:?}", instruction),
+        }
+    }
+
+    fn vm_exit_body(&self) -> Body {
+        let mut body = Body::default();
+        body.emit_fixed(op!(load_code(self.word_size),
+                            self.word(Register::RSP),
+                            self.word(self.vsp)));
+        for &reg in self.config.push_order.iter().rev() {
+            match reg {
+                Registers::Flags => body.emit_fixed(Instruction::with(self.codes.popf)),
+                reg => body.emit_fixed(op!(self.codes.pop, self.word(reg.into()))),
+            }
+        }
+        body.emit_fixed(Instruction::with(self.codes.ret));
+        body
+    }
+
+    /// Splits a body into chunks linked by jumps, with junk between instructions
+    fn mutate(&mut self,
+              body: Body)
+              -> Vec<Vec<Item>> {
+        let mut chunks = vec![Vec::new()];
+
+        for (item, splittable) in body.items {
+            chunks.last_mut().unwrap().push(item);
+            if !splittable || !self.config.mutate {
+                continue;
+            }
+
+            let insert_junk = self.rng.next_u64().is_multiple_of(4);
+            if let Some(junk) = self.scratch.junk.filter(|_| insert_junk) {
+                let junk = match self.rng.next_u64() % 3 {
+                    0 => op!(Code::Mov_r32_imm32, sized(junk, 4), self.rng.next_u64() as u32),
+                    1 => op!(self.codes.bswap, self.word(junk)),
+                    _ => op!(sized_code(&NOT_CODES, self.word_size), self.word(junk)),
+                };
+                chunks.last_mut().unwrap().push(Item::Instruction(junk));
+            }
+            if self.rng.next_u64().is_multiple_of(6) {
+                chunks.push(Vec::new());
+            }
+        }
+
+        chunks.retain(|chunk| !chunk.is_empty());
+        chunks
+    }
+
+    /// Adds the chunks of a body as blocks, returning the block entering it
+    fn add_blocks(&mut self,
+                  blocks: &mut Vec<Vec<Item>>,
+                  body: Body)
+                  -> usize {
+        let chunks = self.mutate(body);
+        let first_block = blocks.len();
+        let chunk_count = chunks.len();
+
+        for (index, mut items) in chunks.into_iter().enumerate() {
+            if index + 1 < chunk_count {
+                let next = first_block + index + 1;
+                match self.rng.next_u64() % 2 {
+                    0 => items.push(Item::Jump(next)),
+                    _ => {
+                        let length = 1 + self.rng.next_u64() % 6;
+                        let garbage = (0 .. length).map(|_| self.rng.next_u64() as u8).collect();
+                        items.push(Item::OpaqueJump(next, garbage));
+                    },
+                }
+            }
+            blocks.push(items);
+        }
+
+        first_block
+    }
+
+    fn vm_entry_body(&mut self,
+                     entry: usize,
+                     vip_program: &[Transform],
+                     offset_program: &[Transform])
+                     -> Body {
+        let mut body = Body::default();
+        let base = self.scratch.first;
+        let mov_word = load_code(self.word_size);
+        let add_word = alu_reg_rm_code(Mnemonic::Add, self.word_size).unwrap();
+
+        for &reg in self.config.push_order.iter() {
+            match reg {
+                Registers::Flags => body.emit_fixed(Instruction::with(self.codes.pushf)),
+                reg => body.emit_fixed(op!(self.codes.push, self.word(reg.into()))),
+            }
+        }
+        // The loader relocates the zero into the relocation delta
+        let delta = match self.word_size {
+            8 => op!(Code::Mov_r64_imm64, base, 0u64),
+            _ => op!(Code::Mov_r32_imm32, self.word(base), 0u32),
+        };
+        body.items.push((Item::Relocated(delta), true));
+        body.emit(op!(self.codes.push, self.word(base)));
+
+        // The vm call pushed the encrypted vip before the return address, the saved registers,
+        // the flags and the relocation delta
+        let pushed_value = (self.config.push_order.len() + 2) * self.word_size;
+        body.emit(op!(mov_word,
+                      self.word(self.vip),
+                      self.word_at(Register::RSP, pushed_value as i64)));
+        for transform in vip_program {
+            body.emit(transform.instruction(sized(self.vip, 4)));
+        }
+        if self.word_size == 8 {
+            body.emit(op!(Code::Mov_r64_imm64, base, VIP_BASE));
+            body.emit(op!(Code::Add_r64_rm64, self.vip, base));
+        }
+
+        // The rolling key starts from the vip at the preferred image base
+        body.emit(op!(mov_word, self.word(self.vsp), self.word(Register::RSP)));
+        body.emit(self.word_alu(Mnemonic::Sub, Register::RSP, REGISTER_FILE_RESERVE));
+        body.emit(op!(self.codes.push, self.word(self.vip)));
+        body.emit(op!(self.codes.pop, self.word(self.key)));
+        body.emit(op!(add_word, self.word(self.vip), self.word_at(self.vsp, 0)));
+        body.items.push((Item::LeaBlock(self.handler, entry), true));
+
+        self.emit_dispatch(&mut body, offset_program);
+        body
+    }
+}
+
+/// Instruction with an operand in the bytecode, its size and value
+fn operand(instruction: HandlerVmInstruction) -> Option<(usize, u64)> {
+    match instruction {
+        HandlerVmInstruction::Pop(_, offset) | HandlerVmInstruction::Push(_, offset) => {
+            Some((1, offset as u64))
+        },
+        HandlerVmInstruction::PushImm64(value) => Some((8, value)),
+        HandlerVmInstruction::PushImm32(value) => Some((4, value as u64)),
+        HandlerVmInstruction::PushImm16(value) => Some((2, value as u64)),
+        _ => None,
+    }
+}
+
+/// The instruction with its operand removed, instructions of the same shape share handlers
+fn shape(instruction: HandlerVmInstruction) -> HandlerVmInstruction {
+    match instruction {
+        HandlerVmInstruction::Pop(size, _) => HandlerVmInstruction::Pop(size, 0),
+        HandlerVmInstruction::Push(size, _) => HandlerVmInstruction::Push(size, 0),
+        HandlerVmInstruction::PushImm64(_) => HandlerVmInstruction::PushImm64(0),
+        HandlerVmInstruction::PushImm32(_) => HandlerVmInstruction::PushImm32(0),
+        HandlerVmInstruction::PushImm16(_) => HandlerVmInstruction::PushImm16(0),
+        instruction => instruction,
+    }
+}
+
+/// True if the generator has handlers for the instruction, values never take more than a
+/// native word
+fn is_supported(instruction: HandlerVmInstruction,
+                word_size: usize)
+                -> bool {
+    match instruction {
+        HandlerVmInstruction::Pop(size, _) |
+        HandlerVmInstruction::Push(size, _) |
+        HandlerVmInstruction::PushVsp(size) => matches!(size, 8 | 4 | 2) && size <= word_size,
+        HandlerVmInstruction::Add(size) |
+        HandlerVmInstruction::Nand(size) |
+        HandlerVmInstruction::Nor(size) |
+        HandlerVmInstruction::Fetch(size) => matches!(size, 8 | 4 | 2 | 1) && size <= word_size,
+        HandlerVmInstruction::Shr(size) => matches!(size, 8 | 2 | 1) && size <= word_size,
+        HandlerVmInstruction::PopVsp(size) | HandlerVmInstruction::Store(size) => {
+            size == word_size
+        },
+        HandlerVmInstruction::PushImm64(_) => word_size == 8,
+        HandlerVmInstruction::PushImm32(_) |
+        HandlerVmInstruction::PushImm16(_) |
+        HandlerVmInstruction::VmExit => true,
+        _ => false,
+    }
+}
+
+fn align(value: usize,
+         alignment: usize)
+         -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Functions a PE32+ fixture imports from IMPORT_DLL, each reached through a protection stub
+pub const FIXTURE_IMPORTS: [&str; 2] = ["Sleep", "GetTickCount"];
+const IMPORT_DLL: &str = "KERNEL32.dll";
+/// Constant the encrypted slot of the second stub is off from the import address table slot
+const STUB_KEY: u32 = 0x1b3c5d7f;
+
+/// Import protection stubs of a PE32+ fixture
+#[derive(Debug, Clone)]
+pub struct FixtureImports {
+    /// Stub of every import of FIXTURE_IMPORTS
+    pub stubs: Vec<u64>,
+    /// Import address table slot of every import
+    pub slots: Vec<u64>,
+    /// Call into the first stub, followed by the junk byte the stub skips
+    pub call_address: u64,
+}
+
+/// Appends an import directory for FIXTURE_IMPORTS and their protection stubs to the code.
+/// The first stub reads its slot and moves the return address past the junk byte after the
+/// call, the second jumps to code that decrypts the address of its slot from a relocated slot.
+/// Returns the stubs, the rva and size of the import directory and the rva of the relocation
+fn append_imports(text: &mut Vec<u8>,
+                  image_base: u64)
+                  -> (FixtureImports, u32, u32, u32) {
+    let rva = |text: &Vec<u8>| SECTION_RVA as u32 + text.len() as u32;
+    let rel32 = |target: u32, next_ip: u32| (target.wrapping_sub(next_ip) as i32).to_le_bytes();
+
+    text.resize(align(text.len(), 8), 0);
+    let mut hint_names = Vec::new();
+    let mut names = Vec::new();
+    for name in FIXTURE_IMPORTS.into_iter().chain([IMPORT_DLL]) {
+        names.push(hint_names.len() as u32);
+        if name != IMPORT_DLL {
+            hint_names.extend_from_slice(&0u16.to_le_bytes());
+        }
+        hint_names.extend_from_slice(name.as_bytes());
+        hint_names.push(0);
+        hint_names.resize(align(hint_names.len(), 2), 0);
+    }
+
+    // Lookup table, address table, descriptors and the names after them
+    let thunks_size = (FIXTURE_IMPORTS.len() + 1) * 8;
+    let lookup_table = rva(text);
+    let address_table = lookup_table + thunks_size as u32;
+    let directory = address_table + thunks_size as u32;
+    let hint_names_rva = directory + 2 * 20;
+    for _ in 0 .. 2 {
+        for &name in names[.. FIXTURE_IMPORTS.len()].iter() {
+            text.extend_from_slice(&((hint_names_rva + name) as u64).to_le_bytes());
+        }
+        text.extend_from_slice(&[0; 8]);
+    }
+    for field in [lookup_table, 0, 0, hint_names_rva + names[FIXTURE_IMPORTS.len()], address_table]
+    {
+        text.extend_from_slice(&field.to_le_bytes());
+    }
+    text.extend_from_slice(&[0; 20]);
+    text.extend_from_slice(&hint_names);
+    text.resize(align(text.len(), 8), 0);
+
+    let slots = (0 .. FIXTURE_IMPORTS.len()).map(|index| address_table + index as u32 * 8)
+                                           .collect::<Vec<_>>();
+    let encrypted_slot = rva(text);
+    let encrypted_value = image_base + slots[1] as u64 - STUB_KEY as u64;
+    text.extend_from_slice(&encrypted_value.to_le_bytes());
+
+    // push rax, mov rax, [slot], add qword ptr [rsp + 8], 1, xchg [rsp], rax, ret
+    let first_stub = rva(text);
+    text.extend_from_slice(&[0x50, 0x48, 0x8b, 0x05]);
+    text.extend_from_slice(&rel32(slots[0], first_stub + 8));
+    text.extend_from_slice(&[0x48, 0x83, 0x44, 0x24, 0x08, 0x01]);
+    text.extend_from_slice(&[0x48, 0x87, 0x04, 0x24, 0xc3]);
+
+    // jmp over a junk byte, push rax, mov rax, [encrypted slot], lea rax, [rax + key],
+    // mov rax, [rax], xchg [rsp], rax, ret
+    let second_stub = rva(text);
+    text.extend_from_slice(&[0xe9, 1, 0, 0, 0, 0xcc]);
+    text.extend_from_slice(&[0x50, 0x48, 0x8b, 0x05]);
+    text.extend_from_slice(&rel32(encrypted_slot, second_stub + 14));
+    text.extend_from_slice(&[0x48, 0x8d, 0x80]);
+    text.extend_from_slice(&STUB_KEY.to_le_bytes());
+    text.extend_from_slice(&[0x48, 0x8b, 0x00, 0x48, 0x87, 0x04, 0x24, 0xc3]);
+
+    // call first stub, junk byte, ret
+    let call_site = rva(text);
+    text.push(0xe8);
+    text.extend_from_slice(&rel32(first_stub, call_site + 5));
+    text.extend_from_slice(&[0xcc, 0xc3]);
+
+    let address = |rva: u32| image_base + rva as u64;
+    let imports = FixtureImports { stubs: vec![address(first_stub), address(second_stub)],
+                                   slots: slots.iter().map(|&slot| address(slot)).collect(),
+                                   call_address: address(call_site) };
+    (imports, directory, 2 * 20, encrypted_slot)
+}
+
+/// The code followed by an exception directory with a primary entry for the first five bytes
+/// of the function and a chained entry for the rest, and the rva of the directory
+fn append_exception_directory(code: &[u8],
+                              function: Range<u32>)
+                              -> (Vec<u8>, u32) {
+    let mut text = code.to_vec();
+    text.resize(align(text.len(), 4), 0);
+    let primary_unwind = SECTION_RVA as u32 + text.len() as u32;
+    let primary = [function.start, function.start + 5, primary_unwind];
+    // Version 1 without unwind codes, the chained info has UNW_FLAG_CHAININFO set and is
+    // followed by the entry it continues
+    text.extend_from_slice(&[1, 0, 0, 0]);
+    let chained_unwind = SECTION_RVA as u32 + text.len() as u32;
+    text.extend_from_slice(&[1 | 0x4 << 3, 0, 0, 0]);
+    for field in primary {
+        text.extend_from_slice(&field.to_le_bytes());
+    }
+
+    let directory = SECTION_RVA as u32 + text.len() as u32;
+    for field in primary.into_iter().chain([function.start + 5, function.end, chained_unwind]) {
+        text.extend_from_slice(&field.to_le_bytes());
+    }
+    (text, directory)
+}
+
+/// Appends a CodeView 7.0 record naming the fixture PDB and a debug directory pointing at it,
+/// returns the rva of the directory
+fn append_debug_directory(text: &mut Vec<u8>) -> u32 {
+    let (guid, age) = FIXTURE_PDB_IDENTITY;
+    text.resize(align(text.len(), 4), 0);
+    let record_rva = SECTION_RVA as u32 + text.len() as u32;
+    let mut record = b"RSDS".to_vec();
+    record.extend_from_slice(&guid);
+    record.extend_from_slice(&age.to_le_bytes());
+    record.extend_from_slice(b"fixture.pdb\0");
+    text.extend_from_slice(&record);
+    text.resize(align(text.len(), 4), 0);
+
+    // Characteristics, time stamp and version stay zero, type 2 is IMAGE_DEBUG_TYPE_CODEVIEW
+    let directory = SECTION_RVA as u32 + text.len() as u32;
+    let record_offset = HEADERS_SIZE as u32 + record_rva - SECTION_RVA as u32;
+    text.extend_from_slice(&[0; 12]);
+    for field in [2, record.len() as u32, record_rva, record_offset] {
+        text.extend_from_slice(&field.to_le_bytes());
+    }
+    directory
+}
+
+/// Image of a code section and a base relocation section with DIR64 entries at the rvas, or
+/// HIGHLOW entries in 32 bit. The code is followed by a debug directory naming the fixture
+/// PDB, a PE32+ image also has an exception directory for the function and imports reached
+/// through protection stubs
+fn build_pe(code: &[u8],
+            relocations: &[u32],
+            bitness: u32,
+            function: Range<u32>)
+            -> (Vec<u8>, Option<FixtureImports>) {
+    let kind = match bitness {
+        32 => 3,
+        _ => 10,
+    };
+    let mut relocations = relocations.to_vec();
+    let (mut code, exception_directory, imports) = match bitness {
+        32 => (code.to_vec(), None, None),
+        _ => {
+            let (mut text, directory) = append_exception_directory(code, function);
+            let (imports, import_directory, import_directory_size, relocation) =
+                append_imports(&mut text, image_base(bitness));
+            relocations.push(relocation);
+            (text, Some(directory), Some((imports, import_directory, import_directory_size)))
+        },
+    };
+    let debug_directory = append_debug_directory(&mut code);
+    let relocation_table = build_base_relocs(&relocations, &vec![kind; relocations.len()]);
+    let raw_size = align(code.len(), FILE_ALIGNMENT);
+    let relocation_rva = align(SECTION_RVA as usize + raw_size, SECTION_ALIGNMENT);
+    let relocation_raw_size = align(relocation_table.len(), FILE_ALIGNMENT);
+    let image_size = align(relocation_rva + relocation_raw_size, SECTION_ALIGNMENT);
+
+    let mut bytes = vec![0u8; 0x40];
+    bytes[.. 2].copy_from_slice(b"MZ");
+    bytes[0x3c .. 0x40].copy_from_slice(&0x40u32.to_le_bytes());
+
+    bytes.extend_from_slice(b"PE\0\0");
+    // File header, machine, optional header size and characteristics of each mode
+    let (machine, optional_header_size, characteristics) = match bitness {
+        32 => (0x14cu16, 0xe0u16, 0x102u16),
+        _ => (0x8664, 0xf0, 0x22),
+    };
+    bytes.extend_from_slice(&machine.to_le_bytes());
+    bytes.extend_from_slice(&2u16.to_le_bytes());
+    bytes.extend_from_slice(&[0; 12]);
+    bytes.extend_from_slice(&optional_header_size.to_le_bytes());
+    bytes.extend_from_slice(&characteristics.to_le_bytes());
+
+    // Optional header
+    let word_size = bitness as usize / 8;
+    let magic = match bitness {
+        32 => 0x10bu16,
+        _ => 0x20b,
+    };
+    bytes.extend_from_slice(&magic.to_le_bytes());
+    bytes.extend_from_slice(&[14, 0]);
+    bytes.extend_from_slice(&(raw_size as u32).to_le_bytes());
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&(SECTION_RVA as u32).to_le_bytes());
+    bytes.extend_from_slice(&(SECTION_RVA as u32).to_le_bytes());
+    if bitness == 32 {
+        // BaseOfData
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+    }
+    bytes.extend_from_slice(&image_base(bitness).to_le_bytes()[.. word_size]);
+    bytes.extend_from_slice(&(SECTION_ALIGNMENT as u32).to_le_bytes());
+    bytes.extend_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+    for version in [6u16, 0, 0, 0, 6, 0] {
+        bytes.extend_from_slice(&version.to_le_bytes());
+    }
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&(image_size as u32).to_le_bytes());
+    bytes.extend_from_slice(&(HEADERS_SIZE as u32).to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&3u16.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    for reserve in [0x100000u64, 0x1000, 0x100000, 0x1000] {
+        bytes.extend_from_slice(&reserve.to_le_bytes()[.. word_size]);
+    }
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    // Data directories, the import table is the second, the exception table the fourth, the
+    // base relocation table the sixth and the debug directory the seventh
+    bytes.extend_from_slice(&[0; 8]);
+    match &imports {
+        Some((_, directory, size)) => {
+            bytes.extend_from_slice(&directory.to_le_bytes());
+            bytes.extend_from_slice(&size.to_le_bytes());
+        },
+        None => bytes.extend_from_slice(&[0; 8]),
+    }
+    bytes.extend_from_slice(&[0; 8]);
+    match exception_directory {
+        Some(directory) => {
+            bytes.extend_from_slice(&directory.to_le_bytes());
+            bytes.extend_from_slice(&24u32.to_le_bytes());
+        },
+        None => bytes.extend_from_slice(&[0; 8]),
+    }
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&(relocation_rva as u32).to_le_bytes());
+    bytes.extend_from_slice(&(relocation_table.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&debug_directory.to_le_bytes());
+    bytes.extend_from_slice(&28u32.to_le_bytes());
+    bytes.extend_from_slice(&[0; 9 * 8]);
+
+    // Section header
+    bytes.extend_from_slice(b".text\0\0\0");
+    bytes.extend_from_slice(&(raw_size as u32).to_le_bytes());
+    bytes.extend_from_slice(&(SECTION_RVA as u32).to_le_bytes());
+    bytes.extend_from_slice(&(raw_size as u32).to_le_bytes());
+    bytes.extend_from_slice(&(HEADERS_SIZE as u32).to_le_bytes());
+    bytes.extend_from_slice(&[0; 12]);
+    bytes.extend_from_slice(&0xe0000020u32.to_le_bytes());
+
+    bytes.extend_from_slice(b".reloc\0\0");
+    bytes.extend_from_slice(&(relocation_table.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(relocation_rva as u32).to_le_bytes());
+    bytes.extend_from_slice(&(relocation_raw_size as u32).to_le_bytes());
+    bytes.extend_from_slice(&((HEADERS_SIZE + raw_size) as u32).to_le_bytes());
+    bytes.extend_from_slice(&[0; 12]);
+    bytes.extend_from_slice(&0x42000040u32.to_le_bytes());
+
+    bytes.resize(HEADERS_SIZE, 0);
+    bytes.extend_from_slice(&code);
+    bytes.resize(HEADERS_SIZE + raw_size, 0);
+    bytes.extend_from_slice(&relocation_table);
+    bytes.resize(HEADERS_SIZE + raw_size + relocation_raw_size, 0);
+    (bytes, imports.map(|(imports, ..)| imports))
+}
+
+/// Bytes of an ELF64 shared object mapping the whole file from the image base in one segment.
+/// The code is at SECTION_RVA with R_X86_64_RELATIVE relocations at the rvas and a vm_call
+/// symbol naming the vm call
+fn build_elf(code: &[u8],
+             relocations: &[u32],
+             vm_call_address: u64)
+             -> Vec<u8> {
+    let code_end = SECTION_RVA as usize + code.len();
+    let rela_offset = align(code_end, 8);
+    let dynamic_offset = rela_offset + relocations.len() * 24;
+    let symtab_offset = dynamic_offset + 4 * 16;
+    let strtab_offset = symtab_offset + 2 * 24;
+    let strtab = b"\0vm_call\0";
+    let shstrtab_offset = strtab_offset + strtab.len();
+    let shstrtab = b"\0.text\0.rela.dyn\0.dynamic\0.symtab\0.strtab\0.shstrtab\0";
+    let section_headers_offset = align(shstrtab_offset + shstrtab.len(), 8);
+    let file_size = section_headers_offset + 7 * 64;
+
+    let mut bytes = vec![0u8; SECTION_RVA as usize];
+    bytes[.. 4].copy_from_slice(b"\x7fELF");
+    // 64 bit, little endian, version 1
+    bytes[4 .. 7].copy_from_slice(&[2, 1, 1]);
+    let mut header = Vec::new();
+    // ET_DYN for x86-64
+    header.extend_from_slice(&3u16.to_le_bytes());
+    header.extend_from_slice(&62u16.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&vm_call_address.to_le_bytes());
+    header.extend_from_slice(&0x40u64.to_le_bytes());
+    header.extend_from_slice(&(section_headers_offset as u64).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    for size in [0x40u16, 56, 2, 64, 7, 6] {
+        header.extend_from_slice(&size.to_le_bytes());
+    }
+
+    // PT_LOAD of the whole file and PT_DYNAMIC
+    for (kind, offset, size) in [(1u32, 0, file_size), (2, dynamic_offset, 4 * 16)] {
+        header.extend_from_slice(&kind.to_le_bytes());
+        header.extend_from_slice(&7u32.to_le_bytes());
+        header.extend_from_slice(&(offset as u64).to_le_bytes());
+        header.extend_from_slice(&(IMAGE_BASE + offset as u64).to_le_bytes());
+        header.extend_from_slice(&(IMAGE_BASE + offset as u64).to_le_bytes());
+        header.extend_from_slice(&(size as u64).to_le_bytes());
+        header.extend_from_slice(&(size as u64).to_le_bytes());
+        header.extend_from_slice(&0x1000u64.to_le_bytes());
+    }
+    bytes[0x10 .. 0x10 + header.len()].copy_from_slice(&header);
+
+    bytes.extend_from_slice(code);
+    bytes.resize(rela_offset, 0);
+    for &rva in relocations {
+        bytes.extend_from_slice(&(IMAGE_BASE + rva as u64).to_le_bytes());
+        bytes.extend_from_slice(&8u64.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+    }
+    // DT_RELA, DT_RELASZ, DT_RELAENT, DT_NULL
+    for (tag, value) in [(7u64, IMAGE_BASE + rela_offset as u64),
+                         (8, relocations.len() as u64 * 24),
+                         (9, 24),
+                         (0, 0)]
+    {
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    // The null symbol and a global function in .text
+    bytes.extend_from_slice(&[0; 24]);
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&[0x12, 0]);
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&vm_call_address.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(strtab);
+    bytes.extend_from_slice(shstrtab);
+    bytes.resize(section_headers_offset, 0);
+
+    // name, type, flags, offset, size, link, info, entry size
+    let sections = [(0u32, 0u32, 0u64, 0, 0, 0u32, 0u32, 0u64),
+                    (1, 1, 0x6, SECTION_RVA as usize, code.len(), 0, 0, 0),
+                    (7, 4, 0x2, rela_offset, relocations.len() * 24, 4, 0, 24),
+                    (17, 6, 0x3, dynamic_offset, 4 * 16, 5, 0, 16),
+                    (26, 2, 0, symtab_offset, 2 * 24, 5, 1, 24),
+                    (34, 3, 0, strtab_offset, strtab.len(), 0, 0, 0),
+                    (42, 3, 0, shstrtab_offset, shstrtab.len(), 0, 0, 0)];
+    for (name, kind, flags, offset, size, link, info, entry_size) in sections {
+        let vaddr = match flags {
+            0 => 0,
+            _ => IMAGE_BASE + offset as u64,
+        };
+        bytes.extend_from_slice(&name.to_le_bytes());
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        bytes.extend_from_slice(&vaddr.to_le_bytes());
+        bytes.extend_from_slice(&(offset as u64).to_le_bytes());
+        bytes.extend_from_slice(&(size as u64).to_le_bytes());
+        bytes.extend_from_slice(&link.to_le_bytes());
+        bytes.extend_from_slice(&info.to_le_bytes());
+        bytes.extend_from_slice(&8u64.to_le_bytes());
+        bytes.extend_from_slice(&entry_size.to_le_bytes());
+    }
+    bytes
+}
+
+/// A module of a generated minidump and the offsets of its memory the dump captures
+pub struct DumpModule<'a> {
+    pub path: &'a str,
+    pub base: u64,
+    pub memory: &'a [u8],
+    pub captured: Vec<Range<usize>>,
+}
+
+/// Bytes of a minidump with a module list and the captured memory, in a memory64 list like
+/// full memory dumps or in a memory list
+pub fn build_minidump(modules: &[DumpModule],
+                      memory64: bool)
+                      -> Vec<u8> {
+    let ranges = modules.iter()
+                        .flat_map(|module| {
+                            module.captured.iter().map(move |range| {
+                                (module.base + range.start as u64, &module.memory[range.clone()])
+                            })
+                        })
+                        .collect::<Vec<_>>();
+
+    let module_list = 32 + 2 * 12;
+    let mut strings = Vec::new();
+    let mut string_offsets = Vec::new();
+    let strings_start = module_list + 4 + modules.len() * 108;
+    for module in modules {
+        string_offsets.push(strings_start + strings.len());
+        let path = module.path.encode_utf16().flat_map(u16::to_le_bytes).collect::<Vec<_>>();
+        strings.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        strings.extend_from_slice(&path);
+        strings.extend_from_slice(&[0, 0]);
+    }
+    let memory_list = align(strings_start + strings.len(), 8);
+    let memory_list_size = match memory64 {
+        true => 16 + ranges.len() * 16,
+        false => 4 + ranges.len() * 16,
+    };
+    let data_start = memory_list + memory_list_size;
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"MDMP");
+    bytes.extend_from_slice(&0xa793u32.to_le_bytes());
+    bytes.extend_from_slice(&2u32.to_le_bytes());
+    bytes.extend_from_slice(&32u32.to_le_bytes());
+    bytes.extend_from_slice(&[0; 16]);
+
+    // Stream directory
+    let memory_stream = if memory64 { 9u32 } else { 5 };
+    for (kind, size, rva) in [(4u32, strings_start - module_list, module_list),
+                              (memory_stream, memory_list_size, memory_list)]
+    {
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&(size as u32).to_le_bytes());
+        bytes.extend_from_slice(&(rva as u32).to_le_bytes());
+    }
+
+    bytes.extend_from_slice(&(modules.len() as u32).to_le_bytes());
+    for (module, &name) in modules.iter().zip(string_offsets.iter()) {
+        let mut entry = [0u8; 108];
+        entry[.. 8].copy_from_slice(&module.base.to_le_bytes());
+        entry[8 .. 12].copy_from_slice(&(module.memory.len() as u32).to_le_bytes());
+        entry[20 .. 24].copy_from_slice(&(name as u32).to_le_bytes());
+        bytes.extend_from_slice(&entry);
+    }
+    bytes.extend_from_slice(&strings);
+    bytes.resize(memory_list, 0);
+
+    if memory64 {
+        bytes.extend_from_slice(&(ranges.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&(data_start as u64).to_le_bytes());
+    } else {
+        bytes.extend_from_slice(&(ranges.len() as u32).to_le_bytes());
+    }
+    let mut data_offset = data_start;
+    for (address, data) in ranges.iter() {
+        bytes.extend_from_slice(&address.to_le_bytes());
+        if memory64 {
+            bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        } else {
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(data_offset as u32).to_le_bytes());
+        }
+        data_offset += data.len();
+    }
+    for (_, data) in ranges.iter() {
+        bytes.extend_from_slice(data);
+    }
+    bytes
+}
+
+/// A CodeView symbol record, padded to four bytes
+fn symbol_record(kind: u16,
+                 data: &[u8])
+                 -> Vec<u8> {
+    let length = align(4 + data.len(), 4) - 2;
+    let mut record = Vec::new();
+    record.extend_from_slice(&(length as u16).to_le_bytes());
+    record.extend_from_slice(&kind.to_le_bytes());
+    record.extend_from_slice(data);
+    record.resize(2 + length, 0);
+    record
+}
+
+/// Bytes of an MSF 7.0 PDB with the GUID and age whose DBI stream has one module with the
+/// procedures, a symbol record stream with the public symbols and a copy of the section
+/// headers. Sections are name, rva and size, symbols are name and rva
+pub fn build_pdb(identity: ([u8; 16], u32),
+                 sections: &[(&str, u32, u32)],
+                 procedures: &[(&str, u32)],
+                 publics: &[(&str, u32)])
+                 -> Vec<u8> {
+    const BLOCK_SIZE: usize = 0x200;
+    const MODULE_STREAM: u16 = 4;
+    const SYMBOL_RECORD_STREAM: u16 = 5;
+    const SECTION_HEADER_STREAM: u16 = 6;
+
+    let segment_offset = |rva: u32| {
+        let (index, &(_, start, _)) =
+            sections.iter()
+                    .enumerate()
+                    .find(|(_, &(_, start, size))| (start .. start + size).contains(&rva))
+                    .unwrap();
+        (index as u16 + 1, rva - start)
+    };
+    // Fields of a symbol record up to and including its address
+    let symbol_data = |fields: Vec<u8>, rva: u32| {
+        let (segment, offset) = segment_offset(rva);
+        let mut data = fields;
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&segment.to_le_bytes());
+        data
+    };
+
+    // S_GPROC32 has parent, end, next, code size, debug start and end and type before the
+    // address, and a flags byte after it
+    let mut module_symbols = 4u32.to_le_bytes().to_vec();
+    for &(name, rva) in procedures {
+        let mut data = symbol_data(vec![0; 28], rva);
+        data.push(0);
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        module_symbols.extend(symbol_record(0x1110, &data));
+    }
+    // S_PUB32 has flags before the address, 2 marks a function
+    let mut public_symbols = Vec::new();
+    for &(name, rva) in publics {
+        let mut data = symbol_data(2u32.to_le_bytes().to_vec(), rva);
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        public_symbols.extend(symbol_record(0x110e, &data));
+    }
+
+    let mut section_headers = Vec::new();
+    for &(name, rva, size) in sections {
+        let mut header = [0u8; 40];
+        header[.. name.len()].copy_from_slice(name.as_bytes());
+        header[8 .. 12].copy_from_slice(&size.to_le_bytes());
+        header[12 .. 16].copy_from_slice(&rva.to_le_bytes());
+        header[16 .. 20].copy_from_slice(&size.to_le_bytes());
+        header[36 .. 40].copy_from_slice(&0x60000020u32.to_le_bytes());
+        section_headers.extend_from_slice(&header);
+    }
+
+    let mut module_info = vec![0u8; 64];
+    module_info[34 .. 36].copy_from_slice(&MODULE_STREAM.to_le_bytes());
+    module_info[36 .. 40].copy_from_slice(&(module_symbols.len() as u32).to_le_bytes());
+    module_info.extend_from_slice(b"vm.obj\0vm.obj\0");
+    module_info.resize(align(module_info.len(), 4), 0);
+
+    let mut debug_header = vec![0xff; 11 * 2];
+    debug_header[10 .. 12].copy_from_slice(&SECTION_HEADER_STREAM.to_le_bytes());
+
+    let (guid, age) = identity;
+    let mut info = Vec::new();
+    for field in [20000404u32, 0, age] {
+        info.extend_from_slice(&field.to_le_bytes());
+    }
+    info.extend_from_slice(&guid);
+
+    // The MFC type server index sits between the substream sizes, it is set to catch a parser
+    // that takes it for one
+    let mut dbi = vec![0u8; 64];
+    dbi[0 .. 4].copy_from_slice(&(-1i32).to_le_bytes());
+    dbi[4 .. 8].copy_from_slice(&19990903u32.to_le_bytes());
+    dbi[8 .. 12].copy_from_slice(&age.to_le_bytes());
+    dbi[20 .. 22].copy_from_slice(&SYMBOL_RECORD_STREAM.to_le_bytes());
+    dbi[24 .. 28].copy_from_slice(&(module_info.len() as u32).to_le_bytes());
+    dbi[44 .. 48].copy_from_slice(&1u32.to_le_bytes());
+    dbi[48 .. 52].copy_from_slice(&(debug_header.len() as u32).to_le_bytes());
+    dbi[58 .. 60].copy_from_slice(&0x8664u16.to_le_bytes());
+    dbi.extend_from_slice(&module_info);
+    dbi.extend_from_slice(&debug_header);
+
+    let streams = [Vec::new(),
+                   info,
+                   Vec::new(),
+                   dbi,
+                   module_symbols,
+                   public_symbols,
+                   section_headers];
+
+    // The superblock and the two free block maps take the first blocks
+    let mut bytes = vec![0u8; 3 * BLOCK_SIZE];
+    let mut directory = (streams.len() as u32).to_le_bytes().to_vec();
+    let mut block_lists = Vec::new();
+    for stream in streams.iter() {
+        directory.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+        for block in stream.chunks(BLOCK_SIZE) {
+            block_lists.extend_from_slice(&((bytes.len() / BLOCK_SIZE) as u32).to_le_bytes());
+            bytes.extend_from_slice(block);
+            bytes.resize(align(bytes.len(), BLOCK_SIZE), 0);
+        }
+    }
+    directory.extend_from_slice(&block_lists);
+
+    let mut block_map = Vec::new();
+    for block in directory.chunks(BLOCK_SIZE) {
+        block_map.extend_from_slice(&((bytes.len() / BLOCK_SIZE) as u32).to_le_bytes());
+        bytes.extend_from_slice(block);
+        bytes.resize(align(bytes.len(), BLOCK_SIZE), 0);
+    }
+    let block_map_block = bytes.len() / BLOCK_SIZE;
+    bytes.extend_from_slice(&block_map);
+    bytes.resize(align(bytes.len(), BLOCK_SIZE), 0);
+    let block_count = bytes.len() / BLOCK_SIZE;
+
+    bytes[.. 32].copy_from_slice(b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0");
+    bytes[32 .. 36].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    bytes[36 .. 40].copy_from_slice(&1u32.to_le_bytes());
+    bytes[40 .. 44].copy_from_slice(&(block_count as u32).to_le_bytes());
+    bytes[44 .. 48].copy_from_slice(&(directory.len() as u32).to_le_bytes());
+    bytes[52 .. 56].copy_from_slice(&(block_map_block as u32).to_le_bytes());
+    bytes
+}
+
+/// Generates an image whose vm call runs the program. Every instruction shape gets its own
+/// handlers with independent decryptions, the bytecode is encrypted with the rolling key
+pub fn generate(config: &FixtureConfig,
+                program: &[HandlerVmInstruction])
+                -> Fixture {
+    assert!(program.last() == Some(&HandlerVmInstruction::VmExit),
+            "The program has to end with a vm exit");
+    assert!(config.format == FixtureFormat::Pe || config.bitness == 64,
+            "ELF fixtures are 64 bit");
+    let word_size = config.bitness as usize / 8;
+    if let Some(instruction) = program.iter().find(|&&insn| !is_supported(insn, word_size)) {
+        panic!("Can not generate a handler for {:?}", instruction);
+    }
+
+    let mut generator = Generator::new(config);
+    let mut blocks: Vec<Vec<Item>> = Vec::new();
+
+    // push encrypted vip; call vmentry; the vm returns to the int3s after it
+    let stub = blocks.len();
+    blocks.push(Vec::new());
+
+    let vip_program = random_program(32, &mut generator.rng);
+    let entry_offset_program = random_program(32, &mut generator.rng);
+    let entry_body = generator.vm_entry_body(blocks.len(), &vip_program, &entry_offset_program);
+    let entry = generator.add_blocks(&mut blocks, entry_body);
+
+    let mut shapes: Vec<(HandlerVmInstruction, Vec<GeneratedHandler>)> = Vec::new();
+    for &instruction in program {
+        let shape = shape(instruction);
+        if shapes.iter().any(|(known, _)| *known == shape) {
+            continue;
+        }
+
+        let copies = match shape {
+            HandlerVmInstruction::VmExit => 1,
+            _ => config.handler_copies.max(1),
+        };
+        let mut handlers = Vec::new();
+        for _ in 0 .. copies {
+            let operand_program = match operand(shape) {
+                Some((size, _)) => random_program(size as u32 * 8, &mut generator.rng),
+                None => Vec::new(),
+            };
+            let offset_program = random_program(32, &mut generator.rng);
+
+            let body = match shape {
+                HandlerVmInstruction::VmExit => generator.vm_exit_body(),
+                _ => {
+                    let mut body = Body::default();
+                    if let Some((size, _)) = operand(shape) {
+                        generator.emit_decrypt(&mut body,
+                                               generator.scratch.first,
+                                               size,
+                                               &operand_program);
+                    }
+                    generator.emit_operation(&mut body, shape);
+                    generator.emit_dispatch(&mut body, &offset_program);
+                    body
+                },
+            };
+            let block = generator.add_blocks(&mut blocks, body);
+            handlers.push(GeneratedHandler { block,
+                                             operand_program,
+                                             offset_program });
+        }
+        shapes.push((shape, handlers));
+    }
+
+    // Handler running every instruction
+    let chosen = program.iter()
+                        .map(|&instruction| {
+                            let handlers = &shapes.iter()
+                                                  .find(|(known, _)| *known == shape(instruction))
+                                                  .unwrap()
+                                                  .1;
+                            let index = (generator.rng.next_u64() % handlers.len() as u64) as usize;
+                            (handlers, index)
+                        })
+                        .collect::<Vec<_>>();
+
+    // Stub first, every other block in random order, then the bytecode
+    let mut order = (1 .. blocks.len()).collect::<Vec<_>>();
+    if config.mutate {
+        shuffle(&mut order, &mut generator.rng);
+    }
+    order.insert(0, stub);
+
+    let bitness = config.bitness;
+    let image_base = image_base(bitness);
+    let section_address = image_base + SECTION_RVA;
+    // The stub's size does not depend on the vip it pushes
+    blocks[stub] = vec![Item::Instruction(op!(generator.codes.push_imm, 0i32)),
+                        Item::Call(entry),
+                        Item::Instruction(Instruction::with(Code::Int3))];
+
+    let placeholder = vec![section_address; blocks.len()];
+    let sizes = blocks.iter()
+                      .map(|block| {
+                          block.iter()
+                               .map(|item| {
+                                   item.encode(section_address, &placeholder, bitness).len()
+                               })
+                               .sum::<usize>()
+                      })
+                      .collect::<Vec<_>>();
+
+    let mut addresses = vec![0; blocks.len()];
+    let mut code_size = 0;
+    for &block in order.iter() {
+        addresses[block] = section_address + code_size as u64;
+        code_size += sizes[block];
+    }
+    let bytecode_address = section_address + code_size as u64;
+
+    // Values in the order the vm reads them
+    let mut rolling_key = 0;
+    let mut stream: Vec<Vec<u8>> = Vec::new();
+    let mut handler_address = addresses[entry];
+    let mut offset_program = &entry_offset_program;
+    let mut handler_addresses = Vec::new();
+
+    let mut plain_values: Vec<(usize, u64, &Vec<Transform>)> = Vec::new();
+    for (index, &instruction) in program.iter().enumerate() {
+        let (handlers, choice) = chosen[index];
+        let handler = &handlers[choice];
+        let next_address = addresses[handler.block];
+
+        let offset = next_address.wrapping_sub(handler_address) as u32;
+        plain_values.push((4, offset as u64, offset_program));
+        if let Some((size, value)) = operand(instruction) {
+            plain_values.push((size, value, &handler.operand_program));
+        }
+
+        handler_addresses.push(next_address);
+        handler_address = next_address;
+        offset_program = &handler.offset_program;
+    }
+
+    let bytecode_size = plain_values.iter().map(|(size, ..)| size).sum::<usize>();
+    let initial_vip = match config.vip_direction_forwards {
+        true => bytecode_address,
+        false => bytecode_address + bytecode_size as u64,
+    };
+    rolling_key ^= initial_vip;
+    for &(size, value, program) in plain_values.iter() {
+        let encrypted = encrypt(program, value, size, &mut rolling_key);
+        stream.push(encrypted.to_le_bytes()[.. size].to_vec());
+    }
+    if !config.vip_direction_forwards {
+        stream.reverse();
+    }
+
+    let vip_base = match bitness {
+        32 => 0,
+        _ => VIP_BASE,
+    };
+    let mut encrypted_vip = initial_vip - vip_base;
+    for transform in vip_program.iter().rev() {
+        encrypted_vip = transform.inverse().apply(encrypted_vip, 32);
+    }
+    blocks[stub][0] =
+        Item::Instruction(op!(generator.codes.push_imm, encrypted_vip as u32 as i32));
+
+    let mut code = Vec::new();
+    let mut relocations = Vec::new();
+    for &block in order.iter() {
+        for item in blocks[block].iter() {
+            let ip = section_address + code.len() as u64;
+            code.extend(item.encode(ip, &addresses, bitness));
+            if item.is_relocated(bitness) {
+                let end = section_address + code.len() as u64;
+                relocations.push((end - word_size as u64 - image_base) as u32);
+            }
+        }
+        assert_eq!(section_address + code.len() as u64, addresses[block] + sizes[block] as u64);
+    }
+    for value in stream {
+        code.extend(value);
+    }
+    // Room for instruction reads past the end of the code
+    code.extend([0xcc; 0x40]);
+
+    let vm_call_address = addresses[stub];
+    let (bytes, imports) = match config.format {
+        FixtureFormat::Pe => {
+            let stub_rva = (vm_call_address - image_base) as u32;
+            build_pe(&code, &relocations, bitness, stub_rva .. stub_rva + sizes[stub] as u32)
+        },
+        FixtureFormat::Elf => (build_elf(&code, &relocations, vm_call_address), None),
+    };
+    Fixture { bytes,
+              vm_call_address,
+              return_address: vm_call_address + sizes[stub] as u64 - 1,
+              handler_addresses,
+              program: program.to_vec(),
+              bytecode: bytecode_address .. bytecode_address + bytecode_size as u64,
+              imports }
+}
@@ -0,0 +1,174 @@
+use pelite::{pe64, Wrap};
+
+use crate::{
+    image::{Headers, Image},
+    symbolize::ImageSymbols,
+};
+
+/// Flag of an UNWIND_INFO whose function continues a primary entry
+const UNW_FLAG_CHAININFO: u8 = 0x4;
+
+/// Entries followed back to find the primary entry of a chained function
+const MAX_CHAIN_LENGTH: usize = 32;
+
+/// A function of the exception directory, from the start of its primary entry to the end of
+/// the entry the address lies in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeFunction {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The RUNTIME_FUNCTION entries of the .pdata table, by address
+pub struct FunctionTable {
+    /// Begin and end address and unwind info rva of every entry, sorted by address
+    entries: Vec<(u64, u64, u32)>,
+    image_base: u64,
+}
+
+fn runtime_functions<'a, P: pe64::Pe<'a>>(pe: P) -> Vec<(u32, u32, u32)> {
+    match pe.exception() {
+        Ok(exception) => {
+            exception.functions()
+                     .map(|function| {
+                         let entry = function.image();
+                         (entry.BeginAddress, entry.EndAddress, entry.UnwindData)
+                     })
+                     .collect()
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+impl FunctionTable {
+    /// Only PE32+ images have an exception directory listing their functions
+    pub fn new(image: &dyn Image) -> Self {
+        let entries = match image.headers() {
+            Some(Headers::File(Wrap::T64(pe_file))) => runtime_functions(pe_file),
+            Some(Headers::Mapped(Wrap::T64(pe_view))) => runtime_functions(pe_view),
+            _ => Vec::new(),
+        };
+
+        let image_base = image.image_base();
+        let mut entries = entries.into_iter()
+                                 .map(|(begin, end, unwind)| {
+                                     (image_base + begin as u64, image_base + end as u64, unwind)
+                                 })
+                                 .collect::<Vec<_>>();
+        entries.sort();
+
+        Self { entries,
+               image_base }
+    }
+
+    /// The function the address lies in, following chained entries back to the primary one
+    pub fn containing(&self,
+                      image: &dyn Image,
+                      address: u64)
+                      -> Option<RuntimeFunction> {
+        let following = self.entries.partition_point(|&(begin, ..)| begin <= address);
+        let index = following.checked_sub(1)?;
+        let (begin, end, unwind) = self.entries[index];
+        if address >= end {
+            return None;
+        }
+
+        let mut start = begin;
+        let mut unwind = unwind;
+        for _ in 0 .. MAX_CHAIN_LENGTH {
+            match self.chained_entry(image, unwind) {
+                Some((primary_begin, primary_unwind)) => {
+                    start = primary_begin;
+                    unwind = primary_unwind;
+                },
+                None => break,
+            }
+        }
+
+        Some(RuntimeFunction { start, end })
+    }
+
+    /// Begin address and unwind info of the entry a chained UNWIND_INFO continues. The entry
+    /// follows the unwind codes, which are padded to an even count
+    fn chained_entry(&self,
+                     image: &dyn Image,
+                     unwind: u32)
+                     -> Option<(u64, u32)> {
+        let address = self.image_base + unwind as u64;
+        let header = image.read(address, 4)?;
+        if header[0] >> 3 & UNW_FLAG_CHAININFO == 0 {
+            return None;
+        }
+        let code_count = header[2] as u64;
+        let entry = image.read(address + 4 + code_count.next_multiple_of(2) * 2, 12)?;
+        let begin = u32::from_le_bytes(entry[0 .. 4].try_into().unwrap());
+        let unwind = u32::from_le_bytes(entry[8 .. 12].try_into().unwrap());
+        Some((self.image_base + begin as u64, unwind))
+    }
+}
+
+/// A function holding vm call sites, None for call sites outside of every function entry. Call
+/// sites in chained entries of a function share the group of its primary entry
+#[derive(Debug, Clone)]
+pub struct VirtualizedFunction {
+    pub function: Option<RuntimeFunction>,
+    /// Export, ELF or PDB symbol at the start of the function
+    pub name: Option<String>,
+    pub vm_call_addresses: Vec<u64>,
+}
+
+/// The vm call sites grouped by the function they belong to, in order of the functions
+pub fn virtualized_functions(image: &dyn Image,
+                             symbols: &ImageSymbols,
+                             table: &FunctionTable,
+                             vm_call_addresses: &[u64])
+                             -> Vec<VirtualizedFunction> {
+    let mut functions: Vec<VirtualizedFunction> = Vec::new();
+    for &vm_call_address in vm_call_addresses {
+        let function = table.containing(image, vm_call_address);
+        let start = function.map(|function| function.start);
+        let group = functions.iter_mut()
+                             .find(|virtualized| virtualized.function.map(|f| f.start) == start);
+        match group {
+            Some(virtualized) => virtualized.vm_call_addresses.push(vm_call_address),
+            None => {
+                let name = function.and_then(|function| symbols.name_at(function.start));
+                functions.push(VirtualizedFunction { function,
+                                                     name: name.map(str::to_string),
+                                                     vm_call_addresses: vec![vm_call_address] });
+            },
+        }
+    }
+    functions.sort_by_key(|virtualized| virtualized.function.map(|function| function.start));
+    functions
+}
+
+/// The function of a vm call site, for a line of output
+pub fn format_function(virtualized: &VirtualizedFunction) -> String {
+    describe_function(virtualized.function, virtualized.name.as_deref())
+}
+
+/// A function and the symbol at its start, for a line of output
+pub fn describe_function(function: Option<RuntimeFunction>,
+                         name: Option<&str>)
+                         -> String {
+    match function {
+        Some(function) => {
+            format!("{:#x}-{:#x} {}", function.start, function.end, name.unwrap_or("-"))
+        },
+        None => "no function entry".to_string(),
+    }
+}
+
+pub fn print_virtualized_functions(functions: &[VirtualizedFunction]) {
+    println!("Virtualized functions: {}",
+             functions.iter().filter(|virtualized| virtualized.function.is_some()).count());
+    for virtualized in functions {
+        let vm_calls = virtualized.vm_call_addresses
+                                  .iter()
+                                  .map(|address| format!("{:#x}", address))
+                                  .collect::<Vec<_>>()
+                                  .join(",");
+        println!("    {:<48} vm calls {}", format_function(virtualized), vm_calls);
+    }
+}
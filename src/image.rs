@@ -0,0 +1,337 @@
+use std::{borrow::Cow, ops::Range};
+
+use pelite::{pe32, pe64, PeFile, PeView, Wrap};
+
+use crate::{
+    elf::ElfFile,
+    minidump::Minidump,
+    rebase::{rebase_elf, rebase_image},
+};
+
+/// Headers of an image, PE headers parsed in the layout the image is in or an ELF file
+pub enum Headers<'a> {
+    File(PeFile<'a>),
+    Mapped(PeView<'a>),
+    Elf(&'a ElfFile),
+}
+
+/// Bytes of a loaded image by virtual address
+pub trait Image {
+    /// Address the image is analysed at
+    fn image_base(&self) -> u64;
+
+    /// 32 or 64, the mode its code is decoded in
+    fn bitness(&self) -> u32;
+
+    /// size bytes at va. Parts of the image without data, like a section past its raw data,
+    /// read as zero. None when va is outside the image
+    fn read(&self,
+            va: u64,
+            size: usize)
+            -> Option<Cow<'_, [u8]>>;
+
+    /// The headers, images without them have no sections, exports or imports
+    fn headers(&self) -> Option<Headers<'_>>;
+}
+
+/// ImageBase and SizeOfHeaders of the optional header and the bitness of the image
+fn optional_header_fields<'a, P32: pe32::Pe<'a>, P64: pe64::Pe<'a>>(pe: Wrap<P32, P64>)
+                                                                 -> (u64, usize, u32) {
+    match pe.optional_header() {
+        Wrap::T32(header) => (header.ImageBase as u64, header.SizeOfHeaders as usize, 32),
+        Wrap::T64(header) => (header.ImageBase, header.SizeOfHeaders as usize, 64),
+    }
+}
+
+/// size bytes at offset of data that ends early, zero filled past its end
+fn read_zero_filled(data: &[u8],
+                    offset: usize,
+                    size: usize)
+                    -> Cow<'_, [u8]> {
+    match data.get(offset .. offset + size) {
+        Some(bytes) => Cow::Borrowed(bytes),
+        None => {
+            let mut bytes = data.get(offset ..).unwrap_or_default().to_vec();
+            bytes.resize(size, 0);
+            Cow::Owned(bytes)
+        },
+    }
+}
+
+/// Bytes of an image laid out from its base, like a dump of its memory
+fn read_contiguous(bytes: &[u8],
+                   image_base: u64,
+                   va: u64,
+                   size: usize)
+                   -> Option<Cow<'_, [u8]>> {
+    let offset = usize::try_from(va.wrapping_sub(image_base)).ok()?;
+    if offset >= bytes.len() {
+        return None;
+    }
+    Some(read_zero_filled(bytes, offset, size))
+}
+
+struct FileSection {
+    rva: usize,
+    virtual_size: usize,
+    file_offset: usize,
+    raw_size: usize,
+}
+
+/// A PE file as it is on disk
+pub struct FileImage {
+    bytes: Vec<u8>,
+    image_base: u64,
+    bitness: u32,
+    headers_size: usize,
+    sections: Vec<FileSection>,
+}
+
+impl FileImage {
+    /// Relocates the file when it is analysed at a load address other than its image base
+    pub fn new(mut bytes: Vec<u8>,
+               load_address: Option<u64>)
+               -> Result<Self, pelite::Error> {
+        if let Some(load_address) = load_address {
+            rebase_image(&mut bytes, load_address)?;
+        }
+
+        let pe_file = PeFile::from_bytes(&bytes)?;
+        let (image_base, headers_size, bitness) = optional_header_fields(pe_file);
+        let sections = pe_file.section_headers()
+                              .iter()
+                              .map(|section| {
+                                  let raw_size = section.SizeOfRawData as usize;
+                                  let virtual_size = raw_size.max(section.VirtualSize as usize);
+                                  FileSection { rva: section.VirtualAddress as usize,
+                                                virtual_size,
+                                                file_offset: section.PointerToRawData as usize,
+                                                raw_size }
+                              })
+                              .collect();
+
+        Ok(Self { bytes,
+                  image_base,
+                  bitness,
+                  headers_size,
+                  sections })
+    }
+}
+
+impl Image for FileImage {
+    fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    fn bitness(&self) -> u32 {
+        self.bitness
+    }
+
+    fn read(&self,
+            va: u64,
+            size: usize)
+            -> Option<Cow<'_, [u8]>> {
+        let rva = usize::try_from(va.wrapping_sub(self.image_base)).ok()?;
+        if rva < self.headers_size {
+            return Some(read_zero_filled(&self.bytes[.. self.headers_size], rva, size));
+        }
+
+        let section = self.sections.iter().find(|section| {
+                                              (section.rva .. section.rva + section.virtual_size)
+                                                  .contains(&rva)
+                                          })?;
+        let raw_end = self.bytes.len().min(section.file_offset + section.raw_size);
+        let raw_data = self.bytes.get(section.file_offset .. raw_end).unwrap_or_default();
+        Some(read_zero_filled(raw_data, rva - section.rva, size))
+    }
+
+    fn headers(&self) -> Option<Headers<'_>> {
+        PeFile::from_bytes(&self.bytes).ok().map(Headers::File)
+    }
+}
+
+/// A PE image dumped from memory, its sections at their virtual addresses. The loader already
+/// relocated it, a load address only moves the base the image is analysed at
+pub struct MappedImage {
+    bytes: Vec<u8>,
+    image_base: u64,
+    bitness: u32,
+}
+
+impl MappedImage {
+    pub fn new(bytes: Vec<u8>,
+               load_address: Option<u64>)
+               -> Result<Self, pelite::Error> {
+        let (image_base, _, bitness) = optional_header_fields(PeView::from_bytes(&bytes)?);
+        Ok(Self { bytes,
+                  image_base: load_address.unwrap_or(image_base),
+                  bitness })
+    }
+}
+
+impl Image for MappedImage {
+    fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    fn bitness(&self) -> u32 {
+        self.bitness
+    }
+
+    fn read(&self,
+            va: u64,
+            size: usize)
+            -> Option<Cow<'_, [u8]>> {
+        read_contiguous(&self.bytes, self.image_base, va, size)
+    }
+
+    fn headers(&self) -> Option<Headers<'_>> {
+        PeView::from_bytes(&self.bytes).ok().map(Headers::Mapped)
+    }
+}
+
+/// Memory dumped from a running system at a base address, with headers that may be erased
+pub struct RawImage {
+    bytes: Vec<u8>,
+    base_address: u64,
+}
+
+impl RawImage {
+    pub fn new(bytes: Vec<u8>,
+               base_address: u64)
+               -> Self {
+        Self { bytes,
+               base_address }
+    }
+}
+
+impl Image for RawImage {
+    fn image_base(&self) -> u64 {
+        self.base_address
+    }
+
+    /// Taken from the headers, a dump with its headers erased is taken to be 64 bit
+    fn bitness(&self) -> u32 {
+        match PeView::from_bytes(&self.bytes) {
+            Ok(pe_view) => optional_header_fields(pe_view).2,
+            Err(_) => 64,
+        }
+    }
+
+    fn read(&self,
+            va: u64,
+            size: usize)
+            -> Option<Cow<'_, [u8]>> {
+        read_contiguous(&self.bytes, self.base_address, va, size)
+    }
+
+    fn headers(&self) -> Option<Headers<'_>> {
+        PeView::from_bytes(&self.bytes).ok().map(Headers::Mapped)
+    }
+}
+
+/// An ELF64 file as it is on disk, its loadable segments at the addresses their program headers
+/// give. The image base is the page of the lowest segment, a load address moves every segment
+pub struct ElfImage {
+    bytes: Vec<u8>,
+    image_base: u64,
+    elf_file: ElfFile,
+}
+
+impl ElfImage {
+    /// Applies the relative relocations like the loader does, for the load address if there
+    /// is one
+    pub fn new(mut bytes: Vec<u8>,
+               load_address: Option<u64>)
+               -> Result<Self, String> {
+        let elf_file = ElfFile::parse(&bytes)?;
+        let preferred_base = elf_file.preferred_base();
+        let image_base = load_address.unwrap_or(preferred_base);
+        rebase_elf(&mut bytes, &elf_file, image_base.wrapping_sub(preferred_base))?;
+
+        Ok(Self { bytes,
+                  image_base,
+                  elf_file })
+    }
+}
+
+impl Image for ElfImage {
+    fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    fn bitness(&self) -> u32 {
+        64
+    }
+
+    fn read(&self,
+            va: u64,
+            size: usize)
+            -> Option<Cow<'_, [u8]>> {
+        let vaddr = va.wrapping_sub(self.image_base).wrapping_add(self.elf_file.preferred_base());
+        let segment = self.elf_file.segment(vaddr)?;
+        let file_offset = segment.file_offset as usize;
+        let raw_end = self.bytes.len().min(file_offset + segment.file_size as usize);
+        let raw_data = self.bytes.get(file_offset .. raw_end).unwrap_or_default();
+        Some(read_zero_filled(raw_data, (vaddr - segment.vaddr) as usize, size))
+    }
+
+    fn headers(&self) -> Option<Headers<'_>> {
+        Some(Headers::Elf(&self.elf_file))
+    }
+}
+
+/// A module of a minidump, read from the memory the dump captured of it. Like a raw dump it
+/// is mapped and relocated, a load address only moves the base the image is analysed at
+pub struct MinidumpImage {
+    bytes: Vec<u8>,
+    image_base: u64,
+    /// Offsets from the image base the dump has memory for
+    captured: Vec<Range<usize>>,
+}
+
+impl MinidumpImage {
+    /// The module with the given file name or base address
+    pub fn new(dump_bytes: &[u8],
+               module: &str,
+               load_address: Option<u64>)
+               -> Result<Self, String> {
+        let minidump = Minidump::parse(dump_bytes)?;
+        let module = minidump.find_module(module)?;
+        let (bytes, captured) = minidump.module_memory(dump_bytes, module)?;
+
+        Ok(Self { bytes,
+                  image_base: load_address.unwrap_or(module.base),
+                  captured })
+    }
+}
+
+impl Image for MinidumpImage {
+    fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    /// Taken from the headers, a module whose headers were not captured is taken to be 64 bit
+    fn bitness(&self) -> u32 {
+        match PeView::from_bytes(&self.bytes) {
+            Ok(pe_view) => optional_header_fields(pe_view).2,
+            Err(_) => 64,
+        }
+    }
+
+    /// None when the dump has no memory at va
+    fn read(&self,
+            va: u64,
+            size: usize)
+            -> Option<Cow<'_, [u8]>> {
+        let offset = usize::try_from(va.wrapping_sub(self.image_base)).ok()?;
+        if !self.captured.iter().any(|range| range.contains(&offset)) {
+            return None;
+        }
+        read_contiguous(&self.bytes, self.image_base, va, size)
+    }
+
+    fn headers(&self) -> Option<Headers<'_>> {
+        PeView::from_bytes(&self.bytes).ok().map(Headers::Mapped)
+    }
+}
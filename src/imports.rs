@@ -0,0 +1,136 @@
+use std::{collections::HashMap, fmt::Display};
+
+use iced_x86::{Code, Register};
+
+use crate::{
+    emulator::X86Emulator,
+    image::Image,
+    interpreter::NativeState,
+    symbolize::ImageSymbols,
+    util::disassemble_instruction_at_va,
+};
+
+/// Native instructions a stub may take to reach its import
+const MAX_STUB_STEPS: usize = 64;
+
+/// Values the import address table slots hold while a stub runs, far from any image
+const IMPORT_SENTINEL_BASE: u64 = 0xfff0_0000_0000_0000;
+
+/// Return address of a stub that was not entered by a call
+const RETURN_SENTINEL: u64 = 0xffe0_0000_0000_0000;
+
+/// Bytes a stub may move the return address by to skip the junk after its call
+const MAX_RETURN_ADJUSTMENT: u64 = 0x10;
+
+/// An import protection stub and the import it jumps to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportStub {
+    pub stub_address: u64,
+    /// Import address table slot the stub reads the import from
+    pub slot: u64,
+    /// dll!function of the slot
+    pub import: String,
+    /// Where the import returns to when the stub is called, stubs move it past the junk
+    /// after the call
+    pub return_address: Option<u64>,
+}
+
+impl Display for ImportStub {
+    fn fmt(&self,
+           f: &mut std::fmt::Formatter<'_>)
+           -> std::fmt::Result {
+        write!(f, "{} via stub {:#x}", self.import, self.stub_address)?;
+        if let Some(return_address) = self.return_address {
+            write!(f, " returning to {:#x}", return_address)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the stub natively until it transfers control to the value of an import address table
+/// slot. The slots hold sentinels, so jumps, constant arithmetic and slots that hold the
+/// address of the slot in encrypted form are all followed. Only a tail jump is a stub: the
+/// stack is back where it was on entry and holds the return address, functions that call the
+/// import have their own frame below it
+fn emulate_stub(image: &dyn Image,
+                symbols: &ImageSymbols,
+                stub_address: u64,
+                return_address: u64)
+                -> Option<(u64, String, u64)> {
+    if image.bitness() != 64 {
+        return None;
+    }
+
+    let mut emulator = X86Emulator::new(image, stub_address, &NativeState::default());
+    emulator.push(return_address);
+    let entry_rsp = emulator.read_register(Register::RSP);
+
+    let mut sentinels = HashMap::new();
+    for (index, (&slot, import)) in symbols.import_slots().iter().enumerate() {
+        let sentinel = IMPORT_SENTINEL_BASE + index as u64 * 0x10;
+        emulator.memory.write(slot, 8, sentinel);
+        sentinels.insert(sentinel, (slot, import));
+    }
+
+    for _ in 0 .. MAX_STUB_STEPS {
+        emulator.step().ok()?;
+        if let Some(&(slot, import)) = sentinels.get(&emulator.rip) {
+            let rsp = emulator.read_register(Register::RSP);
+            let stub_return_address = emulator.memory.read(rsp, 8);
+            let tail_jump = rsp == entry_rsp &&
+                            stub_return_address.wrapping_sub(return_address) <=
+                            MAX_RETURN_ADJUSTMENT;
+            return tail_jump.then(|| (slot, import.clone(), stub_return_address));
+        }
+        if emulator.rip == return_address {
+            return None;
+        }
+    }
+
+    None
+}
+
+/// The import the code at the address reaches when it is a stub, like the immediates of a trace
+/// and the continuations of vm exits that return into a stub
+pub fn resolve_import_stub(image: &dyn Image,
+                           symbols: &ImageSymbols,
+                           stub_address: u64)
+                           -> Option<ImportStub> {
+    let (slot, import, _) = emulate_stub(image, symbols, stub_address, RETURN_SENTINEL)?;
+    Some(ImportStub { stub_address,
+                      slot,
+                      import,
+                      return_address: None })
+}
+
+/// The import a call at the address reaches through a stub
+pub fn resolve_import_call(image: &dyn Image,
+                           symbols: &ImageSymbols,
+                           call_address: u64)
+                           -> Option<ImportStub> {
+    image.read(call_address, 16)?;
+    let call = disassemble_instruction_at_va(image, call_address);
+    if call.code() != Code::Call_rel32_64 {
+        return None;
+    }
+    let stub_address = call.near_branch64();
+    let (slot, import, return_address) =
+        emulate_stub(image, symbols, stub_address, call.next_ip())?;
+    Some(ImportStub { stub_address,
+                      slot,
+                      import,
+                      return_address: Some(return_address) })
+}
+
+/// The continuation of a vm exit, with the import it reaches when it is a stub or a call to one
+pub fn describe_continuation(image: &dyn Image,
+                             symbols: &ImageSymbols,
+                             address: u64)
+                             -> String {
+    let stub = resolve_import_call(image, symbols, address)
+        .or_else(|| resolve_import_stub(image, symbols, address));
+    match stub {
+        Some(stub) => format!("{} -> {}", symbols.describe(image, address), stub),
+        None => symbols.describe(image, address),
+    }
+}
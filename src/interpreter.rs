@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+
+use crate::{
+    trace::TraceStep,
+    image::Image,
+    util::disassemble_instruction_at_va,
+    vm_handler::{Registers, VmContext, VmHandler},
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+/// Native rsp when the vm call executes, the virtual stack grows down from here
+pub const NATIVE_STACK_BASE: u64 = 0x7ff0_0000_0000;
+
+/// Bytes in the register file, enough for every u8 register offset
+pub const REGISTER_FILE_SIZE: usize = 0x108;
+
+/// Native registers and flags
+#[derive(Debug, Clone, Default)]
+pub struct NativeState {
+    pub registers: HashMap<Registers, u64>,
+}
+
+impl NativeState {
+    pub fn register(&self,
+                    reg: Registers)
+                    -> u64 {
+        self.registers.get(&reg).copied().unwrap_or(0)
+    }
+}
+
+/// A write made by a store instruction
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryWriteRecord {
+    pub address: u64,
+    pub size: usize,
+    pub value: u64,
+}
+
+/// The PE image with a writable overlay on top of it
+pub struct VmMemory<'a> {
+    image: &'a dyn Image,
+    overlay: HashMap<u64, u8>,
+}
+
+impl<'a> VmMemory<'a> {
+    pub fn new(image: &'a dyn Image)
+               -> Self {
+        Self { image,
+               overlay: HashMap::new() }
+    }
+
+    /// Little endian read, bytes that are neither written nor in the image read as zero
+    pub fn read(&self,
+                address: u64,
+                size: usize)
+                -> u64 {
+        (0 .. size as u64).rev().fold(0, |value, offset| {
+                                    let byte_address = address.wrapping_add(offset);
+                                    let byte = match self.overlay.get(&byte_address) {
+                                        Some(&byte) => byte,
+                                        None => self.image
+                                                    .read(byte_address, 1)
+                                                    .map(|bytes| bytes[0])
+                                                    .unwrap_or(0),
+                                    };
+                                    (value << 8) | byte as u64
+                                })
+    }
+
+    pub fn write(&mut self,
+                 address: u64,
+                 size: usize,
+                 value: u64) {
+        for offset in 0 .. size as u64 {
+            self.overlay
+                .insert(address.wrapping_add(offset), (value >> (offset * 8)) as u8);
+        }
+    }
+}
+
+/// How execution continues after an instruction
+#[derive(Debug, Clone)]
+pub enum InterpreterEvent {
+    Continue,
+    /// vip is loaded with a concrete value from the virtual stack
+    Branch(u64),
+    /// Native state when the vm hands control back
+    Exit(NativeState, u64),
+    /// The instruction has no known semantics
+    Unsupported(HandlerVmInstruction),
+}
+
+fn size_mask(size: usize) -> u64 {
+    match size {
+        8 => u64::MAX,
+        _ => (1 << (size * 8)) - 1,
+    }
+}
+
+/// Bytes an operand of the given size occupies on the virtual stack
+fn stack_slot(size: usize) -> usize {
+    size.max(2)
+}
+
+/// rflags produced by the native instruction implementing an arithmetic handler
+fn arithmetic_flags(result: u64,
+                    size: usize,
+                    carry: bool,
+                    overflow: bool)
+                    -> u64 {
+    let result = result & size_mask(size);
+    let sign = 1 << (size * 8 - 1);
+
+    let mut flags = 0x2;
+    if carry {
+        flags |= 1 << 0;
+    }
+    if (result as u8).count_ones() & 1 == 0 {
+        flags |= 1 << 2;
+    }
+    if result == 0 {
+        flags |= 1 << 6;
+    }
+    if result & sign != 0 {
+        flags |= 1 << 7;
+    }
+    if overflow {
+        flags |= 1 << 11;
+    }
+    flags
+}
+
+/// Executes decoded vm instructions on a concrete virtual stack and register file
+pub struct VmInterpreter<'a> {
+    pub memory: VmMemory<'a>,
+    pub register_file: [u8; REGISTER_FILE_SIZE],
+    pub vsp: u64,
+    /// Writes made by store instructions, in order
+    pub writes: Vec<MemoryWriteRecord>,
+}
+
+impl<'a> VmInterpreter<'a> {
+    /// Builds the virtual stack vmentry leaves behind: the pushed constant, the return
+    /// address and the native registers in push order
+    pub fn new(image: &'a dyn Image,
+               vm_context: &VmContext,
+               vm_call_address: u64,
+               initial: &NativeState)
+               -> Self {
+        let push_instruction = disassemble_instruction_at_va(image, vm_call_address);
+        let call_instruction =
+            disassemble_instruction_at_va(image, push_instruction.next_ip());
+
+        let mut interpreter = Self { memory: VmMemory::new(image),
+                                     register_file: [0; REGISTER_FILE_SIZE],
+                                     vsp: NATIVE_STACK_BASE,
+                                     writes: Vec::new() };
+
+        interpreter.push(8, vm_context.pushed_val);
+        interpreter.push(8, call_instruction.next_ip());
+        for &reg in vm_context.push_order.iter() {
+            let value = match reg {
+                Registers::Rsp => NATIVE_STACK_BASE,
+                reg => initial.register(reg),
+            };
+            interpreter.push(8, value);
+        }
+
+        // mov reg, imm64; push reg after the registers pushes the relocation delta
+        let vm_entry_handler = VmHandler::new(vm_context.vm_entry_address, image);
+        if let Some(constant) = vm_entry_handler.get_pushed_constant_vm_entry() {
+            interpreter.push(8, constant);
+        }
+
+        interpreter
+    }
+
+    pub fn push(&mut self,
+                size: usize,
+                value: u64) {
+        self.vsp = self.vsp.wrapping_sub(stack_slot(size) as u64);
+        self.memory.write(self.vsp, stack_slot(size), value & size_mask(size));
+    }
+
+    pub fn pop(&mut self,
+               size: usize)
+               -> u64 {
+        let value = self.memory.read(self.vsp, size);
+        self.vsp = self.vsp.wrapping_add(stack_slot(size) as u64);
+        value
+    }
+
+    fn read_register(&self,
+                     offset: u8,
+                     size: usize)
+                     -> u64 {
+        let offset = offset as usize;
+        self.register_file[offset .. offset + size].iter()
+                                                   .rev()
+                                                   .fold(0, |value, &byte| {
+                                                       (value << 8) | byte as u64
+                                                   })
+    }
+
+    fn write_register(&mut self,
+                      offset: u8,
+                      size: usize,
+                      value: u64) {
+        for index in 0 .. size {
+            self.register_file[offset as usize + index] = (value >> (index * 8)) as u8;
+        }
+    }
+
+    /// Pops two operands, pushes the result and the flags
+    fn binary(&mut self,
+              size: usize,
+              second_size: usize,
+              operation: fn(u64, u64, usize) -> (u64, bool, bool)) {
+        let first = self.pop(size);
+        let second = self.pop(second_size);
+        let (result, carry, overflow) = operation(first, second, size);
+
+        self.push(size, result);
+        self.push(8, arithmetic_flags(result, size, carry, overflow));
+    }
+
+    /// Executes one decoded handler
+    pub fn execute(&mut self,
+                   step: &TraceStep)
+                   -> InterpreterEvent {
+        if step.handler_class == HandlerClass::UnconditionalBranch {
+            return InterpreterEvent::Branch(self.pop(8));
+        }
+
+        match step.instruction {
+            HandlerVmInstruction::Pop(size, offset) => {
+                let value = self.pop(size);
+                self.write_register(offset, size, value);
+            },
+            HandlerVmInstruction::Push(size, offset) => {
+                let value = self.read_register(offset, size);
+                self.push(size, value);
+            },
+            HandlerVmInstruction::PushImm64(value) => self.push(8, value),
+            HandlerVmInstruction::PushImm32(value) => self.push(4, value as u64),
+            HandlerVmInstruction::PushImm16(value) => self.push(2, value as u64),
+            HandlerVmInstruction::PushVsp(size) => {
+                let vsp = self.vsp;
+                self.push(size, vsp);
+            },
+            HandlerVmInstruction::PopVsp(size) => self.vsp = self.pop(size),
+            HandlerVmInstruction::Add(size) => self.binary(size, size, add),
+            HandlerVmInstruction::Nand(size) => self.binary(size, size, nand),
+            HandlerVmInstruction::Nor(size) => self.binary(size, size, nor),
+            // The shift count is a byte
+            HandlerVmInstruction::Shr(size) => self.binary(size, 1, shr),
+            HandlerVmInstruction::Fetch(size) => {
+                let address = self.pop(8);
+                let value = self.memory.read(address, size);
+                self.push(size, value);
+            },
+            HandlerVmInstruction::Store(size) => {
+                let address = self.pop(8);
+                let value = self.pop(size);
+                self.memory.write(address, size, value);
+                self.writes.push(MemoryWriteRecord { address,
+                                                     size,
+                                                     value });
+            },
+            HandlerVmInstruction::VmExit => return self.vm_exit(&step.handler),
+            instruction => return InterpreterEvent::Unsupported(instruction),
+        }
+
+        InterpreterEvent::Continue
+    }
+
+    /// vmexit switches rsp to vsp and pops the native registers
+    fn vm_exit(&mut self,
+               vm_handler: &VmHandler)
+               -> InterpreterEvent {
+        let mut state = NativeState::default();
+
+        for reg in vm_handler.get_pop_order_vm_exit() {
+            let value = self.pop(8);
+            state.registers.insert(reg, value);
+        }
+
+        let return_address = self.pop(8);
+        state.registers.insert(Registers::Rsp, self.vsp);
+
+        InterpreterEvent::Exit(state, return_address)
+    }
+}
+
+pub fn add(first: u64,
+           second: u64,
+           size: usize)
+           -> (u64, bool, bool) {
+    let mask = size_mask(size);
+    let sign = 1 << (size * 8 - 1);
+    let result = first.wrapping_add(second) & mask;
+    let carry = result < (first & mask);
+    let overflow = (first ^ result) & (second ^ result) & sign != 0;
+    (result, carry, overflow)
+}
+
+/// not a; not b; or a, b
+pub fn nand(first: u64,
+            second: u64,
+            size: usize)
+            -> (u64, bool, bool) {
+    ((!first | !second) & size_mask(size), false, false)
+}
+
+/// not a; not b; and a, b
+pub fn nor(first: u64,
+           second: u64,
+           size: usize)
+           -> (u64, bool, bool) {
+    ((!first & !second) & size_mask(size), false, false)
+}
+
+pub fn shr(first: u64,
+           second: u64,
+           size: usize)
+           -> (u64, bool, bool) {
+    let count_mask = if size == 8 { 0x3f } else { 0x1f };
+    let count = second & count_mask;
+    let value = first & size_mask(size);
+    if count == 0 {
+        return (value, false, false);
+    }
+
+    let carry = count <= size as u64 * 8 && (value >> (count - 1)) & 1 != 0;
+    let overflow = count == 1 && value & (1 << (size * 8 - 1)) != 0;
+    (value.checked_shr(count as u32).unwrap_or(0), carry, overflow)
+}
+
+/// How an execution ended
+#[derive(Debug, Clone)]
+pub enum ExecutionEnd {
+    /// Native registers and the address returned to
+    Exit(NativeState, u64),
+    /// Handler at the address has no known semantics
+    Unsupported(u64, HandlerVmInstruction),
+    /// Handler at the address, or the branch it takes, could not be decoded for the reason given
+    DecodeFailed(u64, String),
+    StepLimit,
+}
+
+/// Runs the routine entered at a vm call, following branches through their concrete targets.
+/// Fails when vmentry can not be decoded, a handler that can not be decoded ends the execution
+pub fn execute_vm_call<'a>(image: &'a dyn Image,
+                           vm_call_address: u64,
+                           initial: &NativeState,
+                           max_steps: usize)
+                           -> Result<(VmContext, Vec<TraceStep>, VmInterpreter<'a>, ExecutionEnd),
+                                     String> {
+    let mut vm_context = VmContext::try_new(image, vm_call_address)?;
+    let mut interpreter =
+        VmInterpreter::new(image, &vm_context, vm_call_address, initial);
+    let mut steps = Vec::new();
+
+    for _ in 0 .. max_steps {
+        let handler_address = vm_context.handler_address;
+        let step = match vm_context.try_step(image) {
+            Ok(step) => step,
+            Err(reason) => {
+                let end = ExecutionEnd::DecodeFailed(handler_address, reason);
+                return Ok((vm_context, steps, interpreter, end));
+            },
+        };
+
+        let end = match interpreter.execute(&step) {
+            InterpreterEvent::Continue => None,
+            InterpreterEvent::Branch(target) => {
+                vm_context.follow_branch(&step.handler, target, image)
+                          .err()
+                          .map(|reason| ExecutionEnd::DecodeFailed(step.handler_address, reason))
+            },
+            InterpreterEvent::Exit(state, return_address) => {
+                Some(ExecutionEnd::Exit(state, return_address))
+            },
+            InterpreterEvent::Unsupported(instruction) => {
+                Some(ExecutionEnd::Unsupported(step.handler_address, instruction))
+            },
+        };
+
+        steps.push(step);
+        if let Some(end) = end {
+            return Ok((vm_context, steps, interpreter, end));
+        }
+    }
+
+    Ok((vm_context, steps, interpreter, ExecutionEnd::StepLimit))
+}
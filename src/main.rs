@@ -1,19 +1,62 @@
 use std::error::Error;
 
-use pelite::pe64::PeFile;
-use pelite::FileMap;
-
+mod callgraph;
+mod canonicalize;
+mod deobfuscate;
+mod elf;
+mod emulator;
+#[cfg(test)]
+mod fixture;
+mod functions;
+mod image;
+mod imports;
+mod interpreter;
 mod match_assembly;
+mod minidump;
+mod pdb;
+mod rebase;
+mod recording;
+mod register_map;
+mod report;
+mod slicer;
+mod symbolic;
+mod symbolize;
+#[cfg(test)]
+mod tests;
+mod trace;
 mod transforms;
 mod util;
+mod validate;
+mod virtual_stack;
 mod vm_handler;
+mod vm_map;
 mod vm_matchers;
+mod walker;
 
 use clap::Parser;
-use vm_handler::{VmContext, VmHandler};
+use vm_handler::{Registers, VmContext};
 
-use crate::util::handle_vm_call;
-use crate::vm_matchers::HandlerClass;
+use crate::callgraph::build_call_graph;
+use crate::elf::ElfFile;
+use crate::emulator::{emulate_vm_call, EmulationEnd};
+use crate::functions::{
+    format_function, print_virtualized_functions, virtualized_functions, FunctionTable,
+};
+use crate::image::{ElfImage, FileImage, Image, MappedImage, MinidumpImage, RawImage};
+use crate::imports::describe_continuation;
+use crate::interpreter::{execute_vm_call, ExecutionEnd, NativeState};
+use crate::minidump::Minidump;
+use crate::pdb::Pdb;
+use crate::recording::{align_recording, parse_recording, print_alignment, recorded_dispatches};
+use crate::register_map::{format_slot_comment, print_vm_exit_assignment, RegisterMap};
+use crate::report::HandlerInventory;
+use crate::symbolize::{format_symbol_comment, symbolize_trace, ConstantTracker, ImageSymbols};
+use crate::trace::disassemble_partial_trace;
+use crate::util::{format_instruction, handle_vm_call};
+use crate::validate::{print_validations, validate_trace};
+use crate::vm_map::{build_virtualization_map, scan_vm_calls};
+use crate::vm_matchers::{HandlerClass, HandlerVmInstruction, MatchEvaluation};
+use crate::walker::WalkEnd;
 
 fn parse_hex_vm_call(input_str: &str) -> Result<u64, std::num::ParseIntError> {
     let str_trimmed = input_str.trim_start_matches("0x");
@@ -21,103 +64,575 @@ fn parse_hex_vm_call(input_str: &str) -> Result<u64, std::num::ParseIntError> {
 }
 
 #[derive(Parser, Debug)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct CommandLineArgs {
+    #[clap(subcommand)]
+    pub command:     Option<Command>,
+    #[clap(flatten)]
+    pub disassemble: DisassembleArgs,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Inventory of the handlers seen across one or more traces
+    Report(ReportArgs),
+    /// Execute the routine on concrete values and print the state at vmexit
+    Run(RunArgs),
+    /// Execute vmentry and the handlers natively and print the vm registers at every handler
+    Emulate(EmulateArgs),
+    /// Check the decoded semantics of every handler against its native code on random inputs
+    Validate(ValidateArgs),
+    /// Align a recorded execution trace with the static decode and report divergences
+    Align(AlignArgs),
+    /// Call graph of the routines, native code and imports reachable from the vm calls
+    CallGraph(CallGraphArgs),
+    /// Map of every vm call in the image, the vmentry it enters and how far its routine decodes
+    Map(MapArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct DisassembleArgs {
     /// Input file
-    pub input_file:      String,
+    #[clap(required = true)]
+    pub input_file:        Option<String>,
     /// Vm call address
     /// Address of the push instruction in
     /// push <const>
     /// call vm_entry
+    #[clap(short, long, required = true, parse(try_from_str = parse_hex_vm_call))]
+    pub vm_call_address:   Option<u64>,
+    /// Print the deobfuscated native code of every handler
+    #[clap(long)]
+    pub show_handlers:     bool,
+    /// Run every matcher on every handler, report ambiguous and unmatched handlers
+    /// and print a confidence for each decoded instruction
+    #[clap(long)]
+    pub evaluate_matchers: bool,
+    #[clap(flatten)]
+    pub image:             ImageArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReportArgs {
+    /// Input file
+    pub input_file:      String,
+    /// Vm call addresses of the traces to include
+    #[clap(short, long, required = true, multiple_occurrences = true,
+           parse(try_from_str = parse_hex_vm_call))]
+    pub vm_call_address: Vec<u64>,
+    #[clap(flatten)]
+    pub image:           ImageArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// Input file
+    pub input_file:      String,
+    /// Vm call address
     #[clap(short, long, parse(try_from_str = parse_hex_vm_call))]
     pub vm_call_address: u64,
+    /// Initial value of a native register, e.g. rcx=0x1000
+    #[clap(short, long, multiple_occurrences = true, parse(try_from_str = parse_register_value))]
+    pub register:        Vec<(Registers, u64)>,
+    /// Maximum number of handlers to execute
+    #[clap(long, default_value = "100000")]
+    pub max_steps:       usize,
+    #[clap(flatten)]
+    pub image:           ImageArgs,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let command_line_args = CommandLineArgs::parse();
-    let input_file = &command_line_args.input_file;
+#[derive(clap::Args, Debug)]
+struct EmulateArgs {
+    /// Input file
+    pub input_file:      String,
+    /// Vm call address
+    #[clap(short, long, parse(try_from_str = parse_hex_vm_call))]
+    pub vm_call_address: u64,
+    /// Initial value of a native register, e.g. rcx=0x1000
+    #[clap(short, long, multiple_occurrences = true, parse(try_from_str = parse_register_value))]
+    pub register:        Vec<(Registers, u64)>,
+    /// Maximum number of native instructions to execute
+    #[clap(long, default_value = "10000000")]
+    pub max_steps:       usize,
+    #[clap(flatten)]
+    pub image:           ImageArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ValidateArgs {
+    /// Input file
+    pub input_file:      String,
+    /// Vm call address
+    #[clap(short, long, parse(try_from_str = parse_hex_vm_call))]
+    pub vm_call_address: u64,
+    /// Random inputs tried per handler
+    #[clap(long, default_value = "64")]
+    pub trials:          usize,
+    /// Seed of the random inputs
+    #[clap(long, default_value = "1", parse(try_from_str = parse_hex_vm_call))]
+    pub seed:            u64,
+    #[clap(flatten)]
+    pub image:           ImageArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct AlignArgs {
+    /// Input file
+    pub input_file:      String,
+    /// Vm call address
+    #[clap(short, long, parse(try_from_str = parse_hex_vm_call))]
+    pub vm_call_address: u64,
+    /// Recorded trace, CSV with a rip column or lines of rip=... rax=...
+    #[clap(short, long)]
+    pub recording:       String,
+    #[clap(flatten)]
+    pub image:           ImageArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct CallGraphArgs {
+    /// Input file
+    pub input_file:      String,
+    /// Vm call addresses the graph starts from
+    #[clap(short, long, required = true, multiple_occurrences = true,
+           parse(try_from_str = parse_hex_vm_call))]
+    pub vm_call_address: Vec<u64>,
+    /// How the graph is written
+    #[clap(long, arg_enum, default_value = "json")]
+    pub format:          GraphFormat,
+    #[clap(flatten)]
+    pub image:           ImageArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct MapArgs {
+    /// Input file
+    pub input_file:      String,
+    /// Vm call addresses to map, the executable sections are scanned for vm calls without them
+    #[clap(short, long, multiple_occurrences = true, parse(try_from_str = parse_hex_vm_call))]
+    pub vm_call_address: Vec<u64>,
+    /// How the map is written
+    #[clap(long, arg_enum, default_value = "table")]
+    pub format:          MapFormat,
+    #[clap(flatten)]
+    pub image:           ImageArgs,
+}
+
+/// Where the image of a command comes from and how it is read
+#[derive(clap::Args, Debug)]
+struct ImageArgs {
+    /// Address the image is loaded at, addresses are relocated to match it
+    #[clap(long, parse(try_from_str = parse_hex_vm_call))]
+    pub load_address: Option<u64>,
+    /// How the input file is laid out, a raw dump is read at the load address
+    #[clap(long, arg_enum, default_value = "file")]
+    pub layout:       ImageLayout,
+    /// Module of a user mode minidump (MDMP) to analyse, by file name or base address. Kernel
+    /// memory dumps (PAGEDU64) are not supported
+    #[clap(long)]
+    pub module:       Option<String>,
+    /// PDB of the image, its symbols name the addresses in the output
+    #[clap(long)]
+    pub pdb:          Option<String>,
+}
+
+/// Output format of the call graph
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum GraphFormat {
+    Json,
+    /// Graphviz
+    Dot,
+}
+
+/// Output format of the virtualization map
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum MapFormat {
+    Table,
+    Json,
+}
+
+/// How an input file holds the image
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ImageLayout {
+    /// A PE or ELF64 file as it is on disk, or a user mode minidump holding the module
+    File,
+    /// A PE image dumped from memory, sections at their virtual addresses
+    Mapped,
+    /// Memory dumped at the load address, headers optional
+    Raw,
+}
+
+fn parse_register_value(input_str: &str) -> Result<(Registers, u64), String> {
+    let (name, value) = input_str.split_once('=')
+                                 .ok_or_else(|| format!("expected reg=value, got {}", input_str))?;
+    let reg = name.parse::<Registers>()?;
+    let value = parse_hex_vm_call(value).map_err(|err| err.to_string())?;
+    Ok((reg, value))
+}
+
+fn print_match_evaluations(evaluations: &[(u64, MatchEvaluation)]) {
+    println!("Ambiguous or unmatched handlers: {}", evaluations.len());
+
+    for (handler_address, evaluation) in evaluations {
+        println!("{:#x} {:?} decoded as {}",
+                 handler_address,
+                 evaluation.handler_class,
+                 evaluation.chosen);
 
-    let map = FileMap::open(input_file)?;
-    let pe_file = PeFile::from_bytes(&map)?;
-    let pe_bytes = std::fs::read(input_file)?;
+        if evaluation.is_unmatched() {
+            println!("    no matcher matched");
+        }
+
+        for result in evaluation.results.iter() {
+            println!("    {:<22} {:<20} explains {:.0}%",
+                     result.name,
+                     result.instruction.to_string(),
+                     result.coverage * 100.0);
+        }
+    }
+}
 
-    let (_, vm_entry_address) =
-        handle_vm_call(&pe_file, &pe_bytes, command_line_args.vm_call_address);
+fn print_native_state(state: &NativeState) {
+    let mut registers = state.registers.iter().collect::<Vec<_>>();
+    registers.sort_by_key(|&(&reg, _)| reg as u8);
+    for (reg, value) in registers {
+        println!("    {:<6} {:#x}", format!("{:?}", reg).to_lowercase(), value);
+    }
+}
+
+impl ImageArgs {
+    /// The image in the input file, relocated when it is analysed at a load address other than
+    /// its image base. Minidumps hold many modules, the image is the one named by module
+    fn open(&self,
+            input_file: &str)
+            -> Result<Box<dyn Image>, Box<dyn Error>> {
+        let bytes = std::fs::read(input_file)?;
+        let load_address = self.load_address;
+        Ok(match self.layout {
+            ImageLayout::File if ElfFile::is_elf(&bytes) => {
+                Box::new(ElfImage::new(bytes, load_address)?)
+            },
+            ImageLayout::File if Minidump::is_kernel_dump(&bytes) => {
+                return Err("kernel memory dumps (PAGEDUMP, PAGEDU64) are not supported, only \
+                            user mode minidumps (MDMP) are"
+                               .into())
+            },
+            ImageLayout::File if Minidump::is_minidump(&bytes) => {
+                let module = self.module.as_deref().ok_or("a minidump needs --module")?;
+                Box::new(MinidumpImage::new(&bytes, module, load_address)?)
+            },
+            ImageLayout::File => Box::new(FileImage::new(bytes, load_address)?),
+            ImageLayout::Mapped => Box::new(MappedImage::new(bytes, load_address)?),
+            ImageLayout::Raw => {
+                let base_address = load_address.ok_or("a raw dump needs --load-address")?;
+                Box::new(RawImage::new(bytes, base_address))
+            },
+        })
+    }
+
+    /// The image, for commands that only model 64 bit code
+    fn open_64(&self,
+               input_file: &str)
+               -> Result<Box<dyn Image>, Box<dyn Error>> {
+        let image = self.open(input_file)?;
+        match image.bitness() {
+            64 => Ok(image),
+            bitness => {
+                Err(format!("{} bit images are not supported by this command", bitness).into())
+            },
+        }
+    }
+
+    /// Symbols of the image, with the procedures and public symbols of its PDB when one is
+    /// given
+    fn symbols(&self,
+               image: &dyn Image)
+               -> Result<ImageSymbols, Box<dyn Error>> {
+        let mut symbols = ImageSymbols::new(image);
+        if let Some(pdb_file) = &self.pdb {
+            let pdb = Pdb::parse(&std::fs::read(pdb_file)?)?;
+            pdb.check_image(image)?;
+            symbols.add_pdb(&pdb, image.image_base());
+        }
+        Ok(symbols)
+    }
+}
+
+fn report(args: &ReportArgs) -> Result<(), Box<dyn Error>> {
+    let image = args.image.open(&args.input_file)?;
+    let symbols = args.image.symbols(&*image)?;
+
+    let mut inventory = HandlerInventory::default();
+    for &vm_call_address in args.vm_call_address.iter() {
+        println!("Vm call {}", symbols.describe(&*image, vm_call_address));
+        let (vm_context, steps, failure) = disassemble_partial_trace(&*image, vm_call_address)?;
+        let reg_allocation = &vm_context.register_allocation;
+        inventory.add_trace(vm_call_address, reg_allocation, &steps);
+        if let Some(reason) = failure {
+            println!("[Stopping] decoding {:#x} failed: {}", vm_context.handler_address, reason);
+            inventory.add_decode_failure(vm_call_address,
+                                         &*image,
+                                         reg_allocation,
+                                         vm_context.handler_address,
+                                         &reason);
+        }
+    }
+
+    let function_table = FunctionTable::new(&*image);
+    print_virtualized_functions(&virtualized_functions(&*image,
+                                                       &symbols,
+                                                       &function_table,
+                                                       &args.vm_call_address));
+    inventory.print_table();
+    inventory.print_unknown_handlers();
+
+    Ok(())
+}
+
+fn run(args: &RunArgs) -> Result<(), Box<dyn Error>> {
+    let image = args.image.open_64(&args.input_file)?;
+    let symbols = args.image.symbols(&*image)?;
+
+    let initial = NativeState { registers: args.register.iter().copied().collect() };
+    let (vm_context, steps, interpreter, end) =
+        execute_vm_call(&*image, args.vm_call_address, &initial, args.max_steps)?;
+
+    let mut register_map = RegisterMap::new(&vm_context, &*image);
+    let references = symbolize_trace(&*image, &symbols, &vm_context, &steps);
+    for (index, step) in steps.iter().enumerate() {
+        let reference = references.iter().find(|reference| reference.index == index);
+        println!("{:#x} -> {}{}{}",
+                 step.handler_address,
+                 step.instruction,
+                 format_slot_comment(register_map.step(step)),
+                 format_symbol_comment(reference));
+        if step.instruction == HandlerVmInstruction::VmExit {
+            let pop_order = step.handler.get_pop_order_vm_exit();
+            print_vm_exit_assignment(&register_map.vm_exit_assignment(&pop_order));
+        }
+    }
+
+    match end {
+        ExecutionEnd::Exit(state, return_address) => {
+            println!("[Exit] returning to {}",
+                     describe_continuation(&*image, &symbols, return_address));
+            print_native_state(&state);
+        },
+        ExecutionEnd::Unsupported(handler_address, instruction) => {
+            println!("[Stopping] {:#x} {} has no known semantics", handler_address, instruction);
+        },
+        ExecutionEnd::DecodeFailed(handler_address, reason) => {
+            println!("[Stopping] decoding {:#x} failed: {}", handler_address, reason);
+        },
+        ExecutionEnd::StepLimit => println!("[Stopping] step limit reached"),
+    }
+
+    println!("Memory writes: {}", interpreter.writes.len());
+    for write in interpreter.writes.iter() {
+        println!("    [{:#x}] = {:#x} ({} bytes)", write.address, write.value, write.size);
+    }
+
+    Ok(())
+}
+
+fn emulate(args: &EmulateArgs) -> Result<(), Box<dyn Error>> {
+    let image = args.image.open_64(&args.input_file)?;
+    let symbols = args.image.symbols(&*image)?;
+
+    let initial = NativeState { registers: args.register.iter().copied().collect() };
+    let (snapshots, emulator, end) =
+        emulate_vm_call(&*image, args.vm_call_address, &initial, args.max_steps);
+
+    for snapshot in snapshots.iter() {
+        println!("{:#x} vip {:#x} vsp {:#x} key {:#x}",
+                 snapshot.handler_address,
+                 snapshot.vip,
+                 snapshot.vsp,
+                 snapshot.key);
+    }
+
+    match end {
+        EmulationEnd::VmExit(return_address) => {
+            println!("[Exit] returning to {}",
+                     describe_continuation(&*image, &symbols, return_address));
+        },
+        EmulationEnd::StepLimit => println!("[Stopping] step limit reached"),
+        EmulationEnd::Fault(address, reason) => {
+            println!("[Stopping] {:#x} {}", address, reason);
+        },
+    }
+    print_native_state(&emulator.native_state());
+
+    Ok(())
+}
+
+fn validate(args: &ValidateArgs) -> Result<(), Box<dyn Error>> {
+    let image = args.image.open_64(&args.input_file)?;
+    let symbols = args.image.symbols(&*image)?;
+
+    println!("Vm call {}", symbols.describe(&*image, args.vm_call_address));
+    let validations =
+        validate_trace(&*image, args.vm_call_address, args.trials, args.seed)?;
+    print_validations(&validations);
+
+    Ok(())
+}
+
+fn align(args: &AlignArgs) -> Result<(), Box<dyn Error>> {
+    let image = args.image.open_64(&args.input_file)?;
+    let symbols = args.image.symbols(&*image)?;
+
+    let recording = parse_recording(&std::fs::read_to_string(&args.recording)?)?;
+    let (snapshots, return_address) =
+        recorded_dispatches(&*image, args.vm_call_address, &recording)?;
+    let alignment =
+        align_recording(&*image, args.vm_call_address, &snapshots, return_address)?;
+    print_alignment(&alignment, &*image, &symbols);
+
+    Ok(())
+}
+
+fn call_graph(args: &CallGraphArgs) -> Result<(), Box<dyn Error>> {
+    let image = args.image.open(&args.input_file)?;
+    let symbols = args.image.symbols(&*image)?;
+
+    let graph = build_call_graph(&*image, &symbols, &args.vm_call_address);
+    match args.format {
+        GraphFormat::Json => println!("{}", graph.to_json()),
+        GraphFormat::Dot => print!("{}", graph.to_dot()),
+    }
+
+    Ok(())
+}
+
+fn map(args: &MapArgs) -> Result<(), Box<dyn Error>> {
+    let image = args.image.open(&args.input_file)?;
+    let symbols = args.image.symbols(&*image)?;
+
+    let vm_call_addresses = match args.vm_call_address.is_empty() {
+        true => scan_vm_calls(&*image, &symbols),
+        false => args.vm_call_address.clone(),
+    };
+    let map = build_virtualization_map(&*image, &symbols, &vm_call_addresses);
+    match args.format {
+        MapFormat::Table => map.print_table(),
+        MapFormat::Json => println!("{}", map.to_json()),
+    }
+
+    Ok(())
+}
+
+fn disassemble(args: &DisassembleArgs) -> Result<(), Box<dyn Error>> {
+    let input_file = args.input_file.as_ref().unwrap();
+    let vm_call_address = args.vm_call_address.unwrap();
+
+    let image = args.image.open(input_file)?;
+
+    let (_, vm_entry_address) = handle_vm_call(&*image, vm_call_address);
     let mut handler_addresses = vec![vm_entry_address];
 
-    let mut vm_context = VmContext::new(&pe_file, &pe_bytes, command_line_args.vm_call_address);
+    let image_symbols = args.image.symbols(&*image)?;
+    let mut vm_context = VmContext::new(&*image, vm_call_address);
     println!("{:#?}", vm_context);
+    println!("Vm call {}", image_symbols.describe(&*image, vm_call_address));
+    let functions = virtualized_functions(&*image,
+                                          &image_symbols,
+                                          &FunctionTable::new(&*image),
+                                          &[vm_call_address]);
+    println!("In function {}", format_function(&functions[0]));
+    let mut register_map = RegisterMap::new(&vm_context, &*image);
+    let mut constant_tracker = ConstantTracker::new(&vm_context, &*image);
 
-    loop {
-        let mut halt = false;
+    let mut evaluations = Vec::new();
 
+    for index in 0 .. {
         handler_addresses.push(vm_context.handler_address);
 
-        let vm_handler = VmHandler::new(vm_context.handler_address, &pe_file, &pe_bytes);
-        let handler_class = vm_handler.match_handler_class(&vm_context.register_allocation);
-        let handler_address = vm_context.handler_address;
-        let mut handler_instruction = vm_matchers::HandlerVmInstruction::Unknown;
+        let step = vm_context.step(&*image);
+        let reference = constant_tracker.step(&step).and_then(|constant| {
+                                                        image_symbols.reference(&*image,
+                                                                                index,
+                                                                                constant)
+                                                    });
+        let comment = format!("{}{}",
+                              format_slot_comment(register_map.step(&step)),
+                              format_symbol_comment(reference.as_ref()));
 
-        match handler_class {
+        match step.handler_class {
             HandlerClass::UnconditionalBranch => {
                 println!("Disassembled unconditional branch");
                 println!("[Stopping]");
-                halt = true;
             },
             HandlerClass::NoVipChange => {
                 println!("Disassembled no vip change");
                 println!("[Stopping]");
-                handler_instruction =
-                    vm_handler.match_no_vip_change_instructions(&vm_context.register_allocation);
-                halt = true;
-            },
-            HandlerClass::ByteOperand => {
-                //println!("Disassembled single byte operand");
-                let byte_operand =
-                    vm_context.disassemble_single_byte_operand(&vm_handler, &pe_file, &pe_bytes);
-                handler_instruction =
-                    vm_handler.match_byte_operand_instructions(&vm_context.register_allocation,
-                                                               byte_operand);
-            },
-            HandlerClass::WordOperand => {
-                //println!("Disassembled single word operand");
-                let word_operand =
-                    vm_context.disassemble_single_word_operand(&vm_handler, &pe_file, &pe_bytes);
-                handler_instruction =
-                    vm_handler.match_word_operand_instructions(&vm_context.register_allocation,
-                                                               word_operand);
-            },
-            HandlerClass::DwordOperand => {
-                //println!("Disassembled single dword operand");
-                let dword_operand =
-                    vm_context.disassemble_single_dword_operand(&vm_handler, &pe_file, &pe_bytes);
-                handler_instruction =
-                    vm_handler.match_dword_operand_instructions(&vm_context.register_allocation,
-                                                                dword_operand);
-            },
-            HandlerClass::QwordOperand => {
-                //println!("Disassembled single qword operand");
-                let qword_operand =
-                    vm_context.disassemble_single_qword_operand(&vm_handler, &pe_file, &pe_bytes);
-                handler_instruction =
-                    vm_handler.match_qword_operand_instructions(&vm_context.register_allocation,
-                                                                qword_operand);
-            },
-            HandlerClass::NoOperand => {
-                //println!("Disassembled no operand");
-                vm_context.disassemble_no_operand(&vm_handler, &pe_file, &pe_bytes);
-                handler_instruction =
-                    vm_handler.match_no_operand_instructions(&vm_context.register_allocation);
             },
+            _ => {},
+        }
+
+        if args.evaluate_matchers && step.handler_class != HandlerClass::UnconditionalBranch {
+            let evaluation = step.handler.evaluate_matchers(&vm_context.register_allocation,
+                                                            step.handler_class,
+                                                            step.operand,
+                                                            step.instruction);
+            println!("{:#x} -> {}{} [confidence {:.2}]",
+                     step.handler_address,
+                     step.instruction,
+                     comment,
+                     evaluation.confidence());
+
+            if (evaluation.is_ambiguous() || evaluation.is_unmatched()) &&
+               !evaluations.iter().any(|(address, _)| *address == step.handler_address)
+            {
+                evaluations.push((step.handler_address, evaluation));
+            }
+        } else {
+            println!("{:#x} -> {}{}", step.handler_address, step.instruction, comment);
+        }
+
+        if args.show_handlers {
+            for instruction in step.handler.deobfuscate(&vm_context.register_allocation) {
+                println!("    {}", format_instruction(&instruction));
+            }
+            if step.handler.walk_end != WalkEnd::Dispatch {
+                println!("    ; walk stopped: {}", step.handler.walk_end);
+            }
         }
 
-        println!("{:#x} -> {}", handler_address, handler_instruction);
+        if step.instruction == HandlerVmInstruction::VmExit {
+            let pop_order = step.handler.get_pop_order_vm_exit();
+            print_vm_exit_assignment(&register_map.vm_exit_assignment(&pop_order));
+            if let Some(continuation) = constant_tracker.vm_exit_continuation(pop_order.len()) {
+                println!("[Exit] continues at {}",
+                         describe_continuation(&*image, &image_symbols, continuation));
+            }
+        }
 
-        if halt {
+        if step.is_halt() {
             break;
         }
 
         // println!("{:#x?}", vm_context);
     }
 
+    if args.evaluate_matchers {
+        print_match_evaluations(&evaluations);
+    }
+
     Ok(())
 }
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let command_line_args = CommandLineArgs::parse();
+
+    match &command_line_args.command {
+        Some(Command::Report(report_args)) => report(report_args),
+        Some(Command::Run(run_args)) => run(run_args),
+        Some(Command::Emulate(emulate_args)) => emulate(emulate_args),
+        Some(Command::Validate(validate_args)) => validate(validate_args),
+        Some(Command::Align(align_args)) => align(align_args),
+        Some(Command::CallGraph(call_graph_args)) => call_graph(call_graph_args),
+        Some(Command::Map(map_args)) => map(map_args),
+        None => disassemble(&command_line_args.disassemble),
+    }
+}
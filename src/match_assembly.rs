@@ -1,23 +1,26 @@
+// The register operand matchers spell out their guards as match arms
+#![allow(clippy::match_like_matches_macro)]
+
 use iced_x86::{Code, Instruction, OpKind, Register};
 
 use crate::vm_handler::VmRegisterAllocation;
 
 pub fn match_pushfq(instruction: &Instruction) -> bool {
-    if instruction.code() != Code::Pushfq {
+    if !matches!(instruction.code(), Code::Pushfq | Code::Pushfd) {
         return false;
     }
     true
 }
 
 pub fn match_popfq(instruction: &Instruction) -> bool {
-    if instruction.code() != Code::Popfq {
+    if !matches!(instruction.code(), Code::Popfq | Code::Popfd) {
         return false;
     }
     true
 }
 
 pub fn match_ret(instruction: &Instruction) -> bool {
-    if instruction.code() != Code::Retnq {
+    if !matches!(instruction.code(), Code::Retnq | Code::Retnd) {
         return false;
     }
     true
@@ -59,7 +62,7 @@ pub fn match_mov_reg_source(instruction: &Instruction,
         return false;
     }
 
-    if instruction.op1_register() != register {
+    if instruction.op1_register().full_register() != register.full_register() {
         return false;
     }
 
@@ -258,46 +261,30 @@ pub fn match_store_reg_any_size(instruction: &Instruction,
 pub fn match_fetch_encrypted_vip(instruction: &Instruction,
                                  vm_register_allocation: &VmRegisterAllocation)
                                  -> bool {
-    // Check the instruction opcode
-    if instruction.code() != Code::Mov_r64_rm64 {
-        return false;
-    }
+    // Check the instruction opcode and that the displacement is 90, or 28 in 32 bit where
+    // the saved registers take half the space
+    let displacement = match instruction.code() {
+        Code::Mov_r64_rm64 => 0x90,
+        Code::Mov_r32_rm32 => 0x28,
+        _ => return false,
+    };
 
     // Check that the second operand is a memory type operand
     if instruction.op1_kind() != OpKind::Memory {
         return false;
     }
 
-    // Check that the displacement is 90
-    if instruction.memory_displacement64() != 0x90 {
+    if instruction.memory_displacement64() != displacement {
         return false;
     }
 
     // Check that the index register is rsp
-    if instruction.memory_base() != Register::RSP {
+    if instruction.memory_base().full_register() != Register::RSP {
         return false;
     }
 
     // Check that the write is to vip
-    if instruction.op0_register() != vm_register_allocation.vip.into() {
-        return false;
-    }
-
-    true
-}
-
-pub fn match_fetch_vip(instruction: &Instruction,
-                       vm_register_allocation: &VmRegisterAllocation)
-                       -> bool {
-    if instruction.code() != Code::Mov_r32_rm32 {
-        return false;
-    }
-
-    if instruction.op1_kind() != OpKind::Memory {
-        return false;
-    }
-
-    if instruction.memory_base() != vm_register_allocation.vip.into() {
+    if instruction.op0_register().full_register() != vm_register_allocation.vip.into() {
         return false;
     }
 
@@ -307,7 +294,7 @@ pub fn match_fetch_vip(instruction: &Instruction,
 pub fn match_push_rolling_key(instruction: &Instruction,
                               vm_register_allocation: &VmRegisterAllocation)
                               -> bool {
-    if instruction.code() != Code::Push_r64 {
+    if !matches!(instruction.code(), Code::Push_r64 | Code::Push_r32) {
         return false;
     }
 
@@ -326,7 +313,7 @@ pub fn match_add_vsp_by_amount(instruction: &Instruction,
                                vm_register_allocation: &VmRegisterAllocation,
                                amount: u32)
                                -> bool {
-    if instruction.code() != Code::Add_rm64_imm32 {
+    if !matches!(instruction.code(), Code::Add_rm64_imm32 | Code::Add_rm32_imm32) {
         return false;
     }
 
@@ -349,7 +336,7 @@ pub fn match_sub_vsp_by_amount(instruction: &Instruction,
                                vm_register_allocation: &VmRegisterAllocation,
                                amount: u32)
                                -> bool {
-    if instruction.code() != Code::Sub_rm64_imm32 {
+    if !matches!(instruction.code(), Code::Sub_rm64_imm32 | Code::Sub_rm32_imm32) {
         return false;
     }
 
@@ -371,7 +358,7 @@ pub fn match_sub_vsp_by_amount(instruction: &Instruction,
 pub fn match_sub_vsp_get_amount(instruction: &Instruction,
                                 vm_register_allocation: &VmRegisterAllocation)
                                 -> Option<u32> {
-    if instruction.code() != Code::Sub_rm64_imm32 {
+    if !matches!(instruction.code(), Code::Sub_rm64_imm32 | Code::Sub_rm32_imm32) {
         return None;
     }
 
@@ -389,7 +376,7 @@ pub fn match_sub_vsp_get_amount(instruction: &Instruction,
 pub fn match_add_vsp_get_amount(instruction: &Instruction,
                                 vm_register_allocation: &VmRegisterAllocation)
                                 -> Option<u32> {
-    if instruction.code() != Code::Add_rm64_imm32 {
+    if !matches!(instruction.code(), Code::Add_rm64_imm32 | Code::Add_rm32_imm32) {
         return None;
     }
 
@@ -513,6 +500,29 @@ pub fn match_xor_32_rolling_key_source(instruction: &Instruction,
 
     true
 }
+
+pub fn match_xor_32_rolling_key_dest(instruction: &Instruction,
+                                     vm_register_allocation: &VmRegisterAllocation)
+                                     -> bool {
+    if instruction.code() != Code::Xor_r32_rm32 {
+        return false;
+    }
+
+    if instruction.op0_kind() != OpKind::Register {
+        return false;
+    }
+
+    if instruction.op1_kind() != OpKind::Register {
+        return false;
+    }
+
+    if instruction.op0_register().full_register() != vm_register_allocation.key.into() {
+        return false;
+    }
+
+    true
+}
+
 pub fn match_xor_8_rolling_key_source(instruction: &Instruction,
                                       vm_register_allocation: &VmRegisterAllocation)
                                       -> bool {
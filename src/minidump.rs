@@ -0,0 +1,191 @@
+/// Signature every minidump starts with
+pub const MINIDUMP_SIGNATURE: &[u8; 4] = b"MDMP";
+/// Signatures of the 32 and 64 bit kernel memory dumps, which are no minidumps
+const KERNEL_DUMP_SIGNATURES: [&[u8; 8]; 2] = [b"PAGEDUMP", b"PAGEDU64"];
+
+const MODULE_LIST_STREAM: u32 = 4;
+const MEMORY_LIST_STREAM: u32 = 5;
+const MEMORY64_LIST_STREAM: u32 = 9;
+
+const DIRECTORY_ENTRY_SIZE: usize = 12;
+const MODULE_SIZE: usize = 108;
+const MEMORY_DESCRIPTOR_SIZE: usize = 16;
+
+fn field<const N: usize>(bytes: &[u8],
+                         offset: usize)
+                         -> Result<[u8; N], String> {
+    bytes.get(offset .. offset + N)
+         .map(|field| field.try_into().unwrap())
+         .ok_or_else(|| format!("minidump field at {:#x} is past the end of the file", offset))
+}
+
+fn u32_at(bytes: &[u8],
+          offset: usize)
+          -> Result<u32, String> {
+    field(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8],
+          offset: usize)
+          -> Result<u64, String> {
+    field(bytes, offset).map(u64::from_le_bytes)
+}
+
+/// The UTF-16 MINIDUMP_STRING at offset
+fn string_at(bytes: &[u8],
+             offset: usize)
+             -> Result<String, String> {
+    let length = u32_at(bytes, offset)? as usize;
+    let buffer = bytes.get(offset + 4 .. offset + 4 + length)
+                      .ok_or_else(|| format!("minidump string at {:#x} is truncated", offset))?;
+    let units = buffer.chunks_exact(2)
+                      .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                      .collect::<Vec<_>>();
+    Ok(String::from_utf16_lossy(&units))
+}
+
+/// A module of the module list, with the path it was loaded from
+#[derive(Debug, Clone)]
+pub struct MinidumpModule {
+    pub path: String,
+    pub base: u64,
+    pub size: u64,
+}
+
+impl MinidumpModule {
+    /// The file name of the path, the module is looked up by it
+    pub fn name(&self) -> &str {
+        self.path.rsplit(['\\', '/']).next().unwrap()
+    }
+}
+
+/// size bytes of captured memory at address, stored at file_offset in the dump
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRange {
+    pub address: u64,
+    pub size: u64,
+    pub file_offset: usize,
+}
+
+/// The module list and the captured memory of a minidump, from a memory list or the memory64
+/// list full memory dumps use
+pub struct Minidump {
+    pub modules: Vec<MinidumpModule>,
+    pub ranges: Vec<MemoryRange>,
+}
+
+impl Minidump {
+    pub fn is_minidump(bytes: &[u8]) -> bool {
+        bytes.starts_with(MINIDUMP_SIGNATURE)
+    }
+
+    /// True for the kernel memory dumps of a crash, they are not read
+    pub fn is_kernel_dump(bytes: &[u8]) -> bool {
+        KERNEL_DUMP_SIGNATURES.iter().any(|signature| bytes.starts_with(*signature))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if !Self::is_minidump(bytes) {
+            return Err("not a minidump".to_string());
+        }
+
+        let stream_count = u32_at(bytes, 8)? as usize;
+        let directory = u32_at(bytes, 12)? as usize;
+
+        let mut modules = Vec::new();
+        let mut ranges = Vec::new();
+        for index in 0 .. stream_count {
+            let entry = directory + index * DIRECTORY_ENTRY_SIZE;
+            let stream = u32_at(bytes, entry + 8)? as usize;
+
+            match u32_at(bytes, entry)? {
+                MODULE_LIST_STREAM => {
+                    for index in 0 .. u32_at(bytes, stream)? as usize {
+                        let module = stream + 4 + index * MODULE_SIZE;
+                        let path = string_at(bytes, u32_at(bytes, module + 20)? as usize)?;
+                        modules.push(MinidumpModule { path,
+                                                      base: u64_at(bytes, module)?,
+                                                      size: u32_at(bytes, module + 8)? as u64 });
+                    }
+                },
+                MEMORY_LIST_STREAM => {
+                    for index in 0 .. u32_at(bytes, stream)? as usize {
+                        let descriptor = stream + 4 + index * MEMORY_DESCRIPTOR_SIZE;
+                        ranges.push(MemoryRange { address: u64_at(bytes, descriptor)?,
+                                                  size: u32_at(bytes, descriptor + 8)? as u64,
+                                                  file_offset: u32_at(bytes, descriptor + 12)?
+                                                               as usize });
+                    }
+                },
+                // The ranges of a memory64 list are stored back to back from a base rva
+                MEMORY64_LIST_STREAM => {
+                    let mut file_offset = u64_at(bytes, stream + 8)? as usize;
+                    for index in 0 .. u64_at(bytes, stream)? as usize {
+                        let descriptor = stream + 16 + index * MEMORY_DESCRIPTOR_SIZE;
+                        let size = u64_at(bytes, descriptor + 8)?;
+                        ranges.push(MemoryRange { address: u64_at(bytes, descriptor)?,
+                                                  size,
+                                                  file_offset });
+                        file_offset += size as usize;
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        Ok(Self { modules,
+                  ranges })
+    }
+
+    /// The module with the base address or file name, names compare case insensitively
+    pub fn find_module(&self,
+                       selector: &str)
+                       -> Result<&MinidumpModule, String> {
+        let base = u64::from_str_radix(selector.trim_start_matches("0x"), 16).ok();
+        self.modules
+            .iter()
+            .find(|module| Some(module.base) == base)
+            .or_else(|| {
+                self.modules
+                    .iter()
+                    .find(|module| module.name().eq_ignore_ascii_case(selector))
+            })
+            .ok_or_else(|| {
+                let names = self.modules
+                                .iter()
+                                .map(|module| format!("{} at {:#x}", module.name(), module.base))
+                                .collect::<Vec<_>>();
+                format!("no module {} in the minidump, it has {}", selector, names.join(", "))
+            })
+    }
+
+    /// The memory of the module laid out from its base, zero where the dump has none, and the
+    /// offsets the dump captured
+    pub fn module_memory(&self,
+                         bytes: &[u8],
+                         module: &MinidumpModule)
+                         -> Result<(Vec<u8>, Vec<std::ops::Range<usize>>), String> {
+        let mut memory = vec![0; module.size as usize];
+        let mut captured = Vec::new();
+
+        for range in self.ranges.iter() {
+            let start = range.address.max(module.base);
+            let end = (range.address + range.size).min(module.base + module.size);
+            if start >= end {
+                continue;
+            }
+
+            let source = range.file_offset + (start - range.address) as usize;
+            let data = bytes.get(source .. source + (end - start) as usize)
+                            .ok_or_else(|| {
+                                format!("memory at {:#x} is past the end of the minidump",
+                                        start)
+                            })?;
+            let offset = (start - module.base) as usize;
+            memory[offset .. offset + data.len()].copy_from_slice(data);
+            captured.push(offset .. offset + data.len());
+        }
+
+        Ok((memory, captured))
+    }
+}
@@ -0,0 +1,330 @@
+use pelite::{
+    image::GUID,
+    pe32,
+    pe64::{
+        self,
+        debug::{CodeView, Entry},
+    },
+    Wrap,
+};
+
+use crate::image::{Headers, Image};
+
+/// Magic of the MSF 7.0 superblock every PDB starts with
+const MSF_MAGIC: &[u8; 32] = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0";
+/// Block sizes an MSF 7.0 superblock may declare
+const MSF_BLOCK_SIZES: [usize; 4] = [512, 1024, 2048, 4096];
+
+const PDB_INFO_STREAM: usize = 1;
+const DBI_STREAM: usize = 3;
+/// Index of the section header stream in the optional debug header of the DBI stream
+const SECTION_HEADER_DEBUG_STREAM: usize = 5;
+
+const DBI_HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 40;
+
+const S_PUB32: u16 = 0x110e;
+const S_LPROC32: u16 = 0x110f;
+const S_GPROC32: u16 = 0x1110;
+
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
+
+fn field<const N: usize>(bytes: &[u8],
+                         offset: usize)
+                         -> Result<[u8; N], String> {
+    bytes.get(offset .. offset + N)
+         .map(|field| field.try_into().unwrap())
+         .ok_or_else(|| format!("PDB field at {:#x} is past the end of its stream", offset))
+}
+
+fn u16_at(bytes: &[u8],
+          offset: usize)
+          -> Result<u16, String> {
+    field(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8],
+          offset: usize)
+          -> Result<u32, String> {
+    field(bytes, offset).map(u32::from_le_bytes)
+}
+
+/// The nul terminated string at offset
+fn string_at(bytes: &[u8],
+             offset: usize)
+             -> String {
+    let string = bytes.get(offset ..).unwrap_or_default();
+    let end = string.iter().position(|&byte| byte == 0).unwrap_or(string.len());
+    String::from_utf8_lossy(&string[.. end]).into_owned()
+}
+
+/// Streams of a multi-stream file, the container format of a PDB
+struct Msf<'a> {
+    bytes: &'a [u8],
+    block_size: usize,
+    /// Size and blocks of every stream
+    streams: Vec<(usize, Vec<u32>)>,
+}
+
+impl<'a> Msf<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, String> {
+        if !bytes.starts_with(MSF_MAGIC) {
+            return Err("not an MSF 7.0 PDB".to_string());
+        }
+        let block_size = u32_at(bytes, 32)? as usize;
+        if !MSF_BLOCK_SIZES.contains(&block_size) {
+            return Err(format!("invalid MSF block size {}", block_size));
+        }
+        let directory_size = u32_at(bytes, 44)? as usize;
+        let block_map = u32_at(bytes, 52)? as usize * block_size;
+
+        let mut msf = Self { bytes,
+                             block_size,
+                             streams: Vec::new() };
+        let directory_blocks = (0 .. directory_size.div_ceil(block_size))
+            .map(|index| u32_at(bytes, block_map + index * 4))
+            .collect::<Result<Vec<_>, _>>()?;
+        let directory = msf.read_blocks(directory_size, &directory_blocks)?;
+
+        let stream_count = u32_at(&directory, 0)? as usize;
+        let mut block_offset = 4 + stream_count * 4;
+        for index in 0 .. stream_count {
+            let size = match u32_at(&directory, 4 + index * 4)? {
+                u32::MAX => 0,
+                size => size as usize,
+            };
+            let blocks = (0 .. size.div_ceil(block_size))
+                .map(|block| u32_at(&directory, block_offset + block * 4))
+                .collect::<Result<Vec<_>, _>>()?;
+            block_offset += blocks.len() * 4;
+            msf.streams.push((size, blocks));
+        }
+
+        Ok(msf)
+    }
+
+    fn read_blocks(&self,
+                   size: usize,
+                   blocks: &[u32])
+                   -> Result<Vec<u8>, String> {
+        let mut data = Vec::with_capacity(size);
+        for &block in blocks {
+            let start = block as usize * self.block_size;
+            let block = self.bytes
+                            .get(start .. start + self.block_size)
+                            .or_else(|| self.bytes.get(start ..))
+                            .ok_or_else(|| format!("PDB block at {:#x} is past the end", start))?;
+            data.extend_from_slice(block);
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    /// The stream at index, empty when it does not exist
+    fn stream(&self,
+              index: usize)
+              -> Result<Vec<u8>, String> {
+        match self.streams.get(index) {
+            Some((size, blocks)) => self.read_blocks(*size, blocks),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// A section of the image the PDB describes, from the section headers it keeps a copy of
+#[derive(Debug, Clone)]
+pub struct PdbSection {
+    pub name: String,
+    pub rva: u32,
+    pub size: u32,
+    pub readable: bool,
+    pub executable: bool,
+}
+
+/// A public or procedure symbol at an rva of the image
+#[derive(Debug, Clone)]
+pub struct PdbSymbol {
+    pub rva: u32,
+    pub name: String,
+}
+
+/// Sections and symbols of a PDB
+pub struct Pdb {
+    /// GUID of the PDB info stream and age of the DBI stream, the image's CodeView debug record
+    /// names the PDB by them
+    pub guid: [u8; 16],
+    pub age: u32,
+    pub sections: Vec<PdbSection>,
+    /// Procedures of the module streams and public symbols, sorted by rva. A procedure hides
+    /// the public symbol at the same rva, it has the undecorated name
+    pub symbols: Vec<PdbSymbol>,
+}
+
+/// The records of a CodeView symbol stream, kind and data without the length and kind
+fn symbol_records(stream: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let (Ok(length), Ok(kind)) = (u16_at(stream, offset), u16_at(stream, offset + 2)) {
+        let end = (offset + 2 + length as usize).min(stream.len());
+        records.push((kind, &stream[(offset + 4).min(end) .. end]));
+        if length < 2 {
+            break;
+        }
+        offset += 2 + length as usize;
+    }
+    records
+}
+
+impl Pdb {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let msf = Msf::parse(bytes)?;
+        let dbi = msf.stream(DBI_STREAM)?;
+        if dbi.len() < DBI_HEADER_SIZE {
+            return Err("the PDB has no DBI stream".to_string());
+        }
+
+        let guid = field::<16>(&msf.stream(PDB_INFO_STREAM)?, 12)?;
+        let age = u32_at(&dbi, 8)?;
+
+        let symbol_record_stream = u16_at(&dbi, 20)? as usize;
+        // Module info, section contributions, section map, file info, type server map and
+        // the EC substream come before the optional debug header. The MFC type server index
+        // between the type server map and the debug header sizes is no substream size
+        let substream_sizes = [24, 28, 32, 36, 40, 52].map(|offset| u32_at(&dbi, offset));
+        let substream_sizes = substream_sizes.into_iter().collect::<Result<Vec<_>, _>>()?;
+        let module_info_size = substream_sizes[0] as usize;
+        let debug_header = DBI_HEADER_SIZE +
+                           substream_sizes.iter().map(|&size| size as usize).sum::<usize>();
+        let section_header_stream = u16_at(&dbi, debug_header + SECTION_HEADER_DEBUG_STREAM * 2)
+            .ok()
+            .filter(|&stream| stream != u16::MAX);
+
+        let mut sections = Vec::new();
+        if let Some(stream) = section_header_stream {
+            let headers = msf.stream(stream as usize)?;
+            for header in headers.chunks_exact(SECTION_HEADER_SIZE) {
+                let virtual_size = u32_at(header, 8)?;
+                let raw_size = u32_at(header, 16)?;
+                let characteristics = u32_at(header, 36)?;
+                let executable = characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+                sections.push(PdbSection { name: string_at(&header[.. 8], 0),
+                                           rva: u32_at(header, 12)?,
+                                           size: virtual_size.max(raw_size),
+                                           readable: characteristics & IMAGE_SCN_MEM_READ != 0,
+                                           executable });
+            }
+        }
+        let rva = |segment: u16, offset: u32| {
+            let section = sections.get((segment as usize).checked_sub(1)?)?;
+            Some(section.rva + offset)
+        };
+
+        let mut symbols = Vec::new();
+
+        // Procedures of every module
+        let mut module = DBI_HEADER_SIZE;
+        while module < DBI_HEADER_SIZE + module_info_size {
+            let symbol_stream = u16_at(&dbi, module + 34)?;
+            let symbol_size = u32_at(&dbi, module + 36)? as usize;
+            let module_name = module + 64;
+            let object_name = module_name + string_at(&dbi, module_name).len() + 1;
+            module = (object_name + string_at(&dbi, object_name).len() + 1).next_multiple_of(4);
+
+            if symbol_stream == u16::MAX {
+                continue;
+            }
+            let stream = msf.stream(symbol_stream as usize)?;
+            // The records follow a four byte signature
+            let records = stream.get(4 .. symbol_size.min(stream.len())).unwrap_or_default();
+            for (kind, data) in symbol_records(records) {
+                if !matches!(kind, S_GPROC32 | S_LPROC32) {
+                    continue;
+                }
+                if let (Ok(offset), Ok(segment)) = (u32_at(data, 28), u16_at(data, 32)) {
+                    if let Some(rva) = rva(segment, offset) {
+                        symbols.push(PdbSymbol { rva,
+                                                 name: string_at(data, 35) });
+                    }
+                }
+            }
+        }
+
+        // Public symbols, the only names a stripped PDB has
+        let procedure_count = symbols.len();
+        for (kind, data) in symbol_records(&msf.stream(symbol_record_stream)?) {
+            if kind != S_PUB32 {
+                continue;
+            }
+            if let (Ok(offset), Ok(segment)) = (u32_at(data, 4), u16_at(data, 8)) {
+                match rva(segment, offset) {
+                    Some(rva) if !symbols[.. procedure_count].iter().any(|s| s.rva == rva) => {
+                        symbols.push(PdbSymbol { rva,
+                                                 name: string_at(data, 10) });
+                    },
+                    _ => {},
+                }
+            }
+        }
+        symbols.sort_by(|a, b| (a.rva, &a.name).cmp(&(b.rva, &b.name)));
+
+        Ok(Self { guid,
+                  age,
+                  sections,
+                  symbols })
+    }
+
+    /// Fails when the image's CodeView debug record names another PDB, whose symbols would
+    /// name the wrong addresses. Images without the record are taken to match
+    pub fn check_image(&self,
+                       image: &dyn Image)
+                       -> Result<(), String> {
+        let identity = match image.headers() {
+            Some(Headers::File(pe_file)) => codeview_identity(pe_file),
+            Some(Headers::Mapped(pe_view)) => codeview_identity(pe_view),
+            _ => None,
+        };
+        match identity {
+            Some((guid, age)) if (guid, age) != (self.guid, self.age) => {
+                Err(format!("the PDB is {} age {}, the image names {} age {}",
+                            format_guid(&self.guid),
+                            self.age,
+                            format_guid(&guid),
+                            age))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// GUID and age of the PDB the CodeView 7.0 debug record of the image names
+fn codeview_identity<'a, P32: pe32::Pe<'a>, P64: pe64::Pe<'a>>(pe: Wrap<P32, P64>)
+                                                               -> Option<([u8; 16], u32)> {
+    pe.debug().ok()?.into_iter().find_map(|dir| match dir.entry() {
+                                    Ok(Entry::CodeView(CodeView::Cv70 { image, .. })) => {
+                                        Some((guid_bytes(&image.Signature), image.Age))
+                                    },
+                                    _ => None,
+                                })
+}
+
+/// The GUID as it is stored in the PDB info stream
+fn guid_bytes(guid: &GUID) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[0 .. 4].copy_from_slice(&guid.Data1.to_le_bytes());
+    bytes[4 .. 6].copy_from_slice(&guid.Data2.to_le_bytes());
+    bytes[6 .. 8].copy_from_slice(&guid.Data3.to_le_bytes());
+    bytes[8 ..].copy_from_slice(&guid.Data4);
+    bytes
+}
+
+/// The GUID in registry form, as symbol servers show it
+fn format_guid(guid: &[u8; 16]) -> String {
+    format!("{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}}}",
+            u32::from_le_bytes(guid[0 .. 4].try_into().unwrap()),
+            u16::from_le_bytes(guid[4 .. 6].try_into().unwrap()),
+            u16::from_le_bytes(guid[6 .. 8].try_into().unwrap()),
+            guid[8],
+            guid[9],
+            guid[10 ..].iter().map(|byte| format!("{:02X}", byte)).collect::<String>())
+}
@@ -0,0 +1,75 @@
+use pelite::{pe32, pe64, PeFile, Wrap};
+
+use crate::elf::ElfFile;
+
+const IMAGE_REL_BASED_HIGHLOW: u8 = 3;
+const IMAGE_REL_BASED_DIR64: u8 = 10;
+
+/// Offset of ImageBase in the PE32 optional header, after BaseOfData
+const IMAGE_BASE_OFFSET_32: usize = 28;
+/// Offset of ImageBase in the PE32+ optional header
+const IMAGE_BASE_OFFSET_64: usize = 24;
+
+/// Applies the base relocations of the image to its bytes as a loader placing it at
+/// load_address would, and makes load_address the image base of the optional header.
+/// Constants like the one vmentry adds to vip move with the image, rip relative code already does
+pub fn rebase_image(pe_bytes: &mut [u8],
+                    load_address: u64)
+                    -> Result<(), pelite::Error> {
+    let pe_file = PeFile::from_bytes(pe_bytes)?;
+    let (image_base, image_base_offset, image_base_size) = match pe_file.optional_header() {
+        Wrap::T32(header) => (header.ImageBase as u64, IMAGE_BASE_OFFSET_32, 4),
+        Wrap::T64(header) => (header.ImageBase, IMAGE_BASE_OFFSET_64, 8),
+    };
+    let delta = load_address.wrapping_sub(image_base);
+    let rva_to_file_offset = |rva| match pe_file {
+        Wrap::T32(pe) => pe32::Pe::rva_to_file_offset(pe, rva),
+        Wrap::T64(pe) => pe64::Pe::rva_to_file_offset(pe, rva),
+    };
+
+    let mut fixups = Vec::new();
+    if let Ok(base_relocs) = pe_file.base_relocs() {
+        let mut result = Ok(());
+        base_relocs.for_each(|rva, kind| {
+                       match rva_to_file_offset(rva) {
+                           Ok(file_offset) => fixups.push((file_offset, kind)),
+                           Err(err) => result = Err(err),
+                       }
+                   });
+        result?;
+    }
+    let header_offset = pe_file.dos_header().e_lfanew as usize + 4 + 20 + image_base_offset;
+
+    for (file_offset, kind) in fixups {
+        let size = match kind {
+            IMAGE_REL_BASED_DIR64 => 8,
+            IMAGE_REL_BASED_HIGHLOW => 4,
+            _ => continue,
+        };
+        let bytes = pe_bytes.get_mut(file_offset .. file_offset + size)
+                            .ok_or(pelite::Error::Bounds)?;
+        let mut value = [0; 8];
+        value[.. size].copy_from_slice(bytes);
+        let relocated = u64::from_le_bytes(value).wrapping_add(delta);
+        bytes.copy_from_slice(&relocated.to_le_bytes()[.. size]);
+    }
+
+    pe_bytes[header_offset .. header_offset + image_base_size]
+        .copy_from_slice(&load_address.to_le_bytes()[.. image_base_size]);
+    Ok(())
+}
+
+/// Applies the R_X86_64_RELATIVE relocations of an ELF file as a loader placing it delta bytes
+/// above the addresses it was linked at would, the slots get their addend moved by delta
+pub fn rebase_elf(elf_bytes: &mut [u8],
+                  elf_file: &ElfFile,
+                  delta: u64)
+                  -> Result<(), String> {
+    for &(vaddr, addend) in elf_file.relative_relocations.iter() {
+        let out_of_file = || format!("relocation at {:#x} is outside the file", vaddr);
+        let file_offset = elf_file.file_offset(vaddr).ok_or_else(out_of_file)?;
+        let bytes = elf_bytes.get_mut(file_offset .. file_offset + 8).ok_or_else(out_of_file)?;
+        bytes.copy_from_slice(&addend.wrapping_add(delta).to_le_bytes());
+    }
+    Ok(())
+}
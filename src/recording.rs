@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use iced_x86::{Decoder, DecoderOptions, Instruction};
+
+use crate::{
+    emulator::{DispatchEvent, DispatchTracker, HandlerSnapshot},
+    image::Image,
+    imports::describe_continuation,
+    symbolize::ImageSymbols,
+    trace::TraceStep,
+    vm_handler::{Registers, VmContext, VmRegisterAllocation},
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+/// An instruction of a recorded trace with the registers before it executed
+#[derive(Debug, Clone)]
+pub struct RecordedInstruction {
+    pub rip: u64,
+    pub registers: HashMap<Registers, u64>,
+}
+
+/// A column or field of a recorded trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordedField {
+    Rip,
+    Register(Registers),
+}
+
+fn parse_field(name: &str) -> Option<RecordedField> {
+    match name.trim().to_lowercase().as_str() {
+        "rip" => Some(RecordedField::Rip),
+        "eflags" => Some(RecordedField::Register(Registers::Flags)),
+        name => name.parse::<Registers>().ok().map(RecordedField::Register),
+    }
+}
+
+/// Recorded values are hexadecimal with or without 0x
+fn parse_value(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(digits, 16).map_err(|err| format!("bad value {}: {}", text, err))
+}
+
+fn record(fields: impl Iterator<Item = (RecordedField, u64)>) -> Option<RecordedInstruction> {
+    let mut rip = None;
+    let mut registers = HashMap::new();
+    for (field, value) in fields {
+        match field {
+            RecordedField::Rip => rip = Some(value),
+            RecordedField::Register(reg) => {
+                registers.insert(reg, value);
+            },
+        }
+    }
+
+    rip.map(|rip| RecordedInstruction { rip, registers })
+}
+
+/// Fields of a text line, rip=0x1000 rax=0x5 or rip: 1000, rax: 5
+fn parse_text_line(line: &str) -> Result<Vec<(RecordedField, u64)>, String> {
+    let mut fields = Vec::new();
+    let mut pending = None;
+
+    for token in line.split(|c: char| c.is_whitespace() || c == ',').filter(|t| !t.is_empty()) {
+        if let Some(field) = pending.take() {
+            fields.push((field, parse_value(token)?));
+            continue;
+        }
+
+        if let Some((name, value)) = token.split_once(['=', ':']) {
+            match parse_field(name) {
+                Some(field) if value.is_empty() => pending = Some(field),
+                Some(field) => fields.push((field, parse_value(value)?)),
+                None => {},
+            }
+        }
+    }
+
+    Ok(fields)
+}
+
+/// Reads a trace recorded by a debugger or tracer, one executed instruction per line.
+/// Either CSV with a header naming rip and the registers, or text lines of name=value.
+/// Columns and fields that are not registers are ignored, lines without rip are skipped
+pub fn parse_recording(text: &str) -> Result<Vec<RecordedInstruction>, String> {
+    let mut lines = text.lines()
+                        .enumerate()
+                        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+                        .peekable();
+
+    let header = match lines.peek() {
+        Some((_, line)) if !line.contains(['=', ':']) => {
+            let header = line.split(',').map(parse_field).collect::<Vec<_>>();
+            header.contains(&Some(RecordedField::Rip)).then_some(header)
+        },
+        _ => None,
+    };
+    if header.is_some() {
+        lines.next();
+    }
+
+    let mut recording = Vec::new();
+    for (line_number, line) in lines {
+        let fields = match &header {
+            Some(header) => header.iter()
+                                  .zip(line.split(','))
+                                  .filter_map(|(field, value)| field.map(|field| (field, value)))
+                                  .map(|(field, value)| Ok((field, parse_value(value)?)))
+                                  .collect::<Result<Vec<_>, String>>(),
+            None => parse_text_line(line),
+        };
+        let fields = fields.map_err(|err| format!("line {}: {}", line_number + 1, err))?;
+
+        if let Some(instruction) = record(fields.into_iter()) {
+            recording.push(instruction);
+        }
+    }
+
+    if recording.is_empty() {
+        return Err("no instructions with rip in the recording".to_string());
+    }
+    Ok(recording)
+}
+
+fn decode_recorded(rip: u64,
+                   image: &dyn Image)
+                   -> Option<Instruction> {
+    let bytes = image.read(rip, 16)?;
+    Some(Decoder::with_ip(64, &bytes, rip, DecoderOptions::NONE).decode())
+}
+
+fn snapshot(handler_address: u64,
+            registers: &HashMap<Registers, u64>,
+            reg_allocation: &VmRegisterAllocation)
+            -> Result<HandlerSnapshot, String> {
+    let register = |reg: Registers| {
+        registers.get(&reg)
+                 .copied()
+                 .ok_or_else(|| format!("recording has no {:?} at {:#x}", reg, handler_address))
+    };
+
+    Ok(HandlerSnapshot { handler_address,
+                         vip: register(reg_allocation.vip)?,
+                         vsp: register(reg_allocation.vsp)?,
+                         key: register(reg_allocation.key)? })
+}
+
+/// Handler entries of the vm call in a recording with the vm registers at each, and the
+/// address returned to if the recording reaches vmexit
+pub fn recorded_dispatches(image: &dyn Image,
+                           vm_call_address: u64,
+                           recording: &[RecordedInstruction])
+                           -> Result<(Vec<HandlerSnapshot>, Option<u64>), String> {
+    let vm_context = VmContext::try_new(image, vm_call_address)?;
+    let mut tracker = DispatchTracker::new(vm_context.vm_entry_address, image);
+    let mut snapshots = Vec::new();
+
+    for pair in recording.windows(2) {
+        let (executed, next) = (&pair[0], &pair[1]);
+        let instruction = match decode_recorded(executed.rip, image) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+
+        match tracker.observe(&instruction, next.rip, image) {
+            Some(DispatchEvent::Handler(handler_address)) => {
+                snapshots.push(snapshot(handler_address,
+                                        &next.registers,
+                                        &vm_context.register_allocation)?);
+            },
+            Some(DispatchEvent::VmExit(return_address)) => {
+                return Ok((snapshots, Some(return_address)));
+            },
+            None => {},
+        }
+    }
+
+    Ok((snapshots, None))
+}
+
+/// A vm register the static decode predicted differently than the recording
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Index of the handler in the recording
+    pub index: usize,
+    pub location: &'static str,
+    pub decoded: u64,
+    pub recorded: u64,
+}
+
+/// A recorded handler entry with the static decode of the handler
+pub struct AlignedStep {
+    pub step: TraceStep,
+    pub recorded: HandlerSnapshot,
+    /// The handler is a branch the static decode continued past with the recorded target
+    pub resolved_branch: bool,
+}
+
+pub struct Alignment {
+    pub steps: Vec<AlignedStep>,
+    pub divergences: Vec<Divergence>,
+    /// Index of the recorded handler the static decode failed on and why, the alignment stops
+    /// there
+    pub decode_failure: Option<(usize, String)>,
+    /// Address the recording returned to at vmexit
+    pub return_address: Option<u64>,
+}
+
+/// Decodes the handlers the recording dispatched to, comparing the handler address, vip and
+/// rolling key the static decode predicts with the recorded ones. Decoding continues from the
+/// recorded values, which also carries it past branches. Fails when vmentry can not be decoded
+pub fn align_recording(image: &dyn Image,
+                       vm_call_address: u64,
+                       recorded: &[HandlerSnapshot],
+                       return_address: Option<u64>)
+                       -> Result<Alignment, String> {
+    let mut vm_context = VmContext::try_new(image, vm_call_address)?;
+    let mut steps: Vec<AlignedStep> = Vec::new();
+    let mut divergences = Vec::new();
+    let mut decode_failure = None;
+
+    for (index, snapshot) in recorded.iter().enumerate() {
+        let after_branch = steps.last().is_some_and(|aligned| aligned.resolved_branch);
+        let predictions = [("handler", vm_context.handler_address, snapshot.handler_address),
+                           ("vip", vm_context.vip_value, snapshot.vip),
+                           ("key", vm_context.rolling_key, snapshot.key)];
+        for (location, decoded, recorded) in predictions {
+            if !after_branch && decoded != recorded {
+                divergences.push(Divergence { index,
+                                              location,
+                                              decoded,
+                                              recorded });
+            }
+        }
+
+        vm_context.handler_address = snapshot.handler_address;
+        vm_context.vip_value = snapshot.vip;
+        vm_context.rolling_key = snapshot.key;
+
+        let step = match vm_context.try_step(image) {
+            Ok(step) => step,
+            Err(reason) => {
+                decode_failure = Some((index, reason));
+                break;
+            },
+        };
+        let is_exit = step.instruction == HandlerVmInstruction::VmExit;
+        let resolved_branch =
+            step.handler_class == HandlerClass::UnconditionalBranch && index + 1 < recorded.len();
+        steps.push(AlignedStep { step,
+                                 recorded: *snapshot,
+                                 resolved_branch });
+
+        if is_exit {
+            break;
+        }
+    }
+
+    Ok(Alignment { steps,
+                   divergences,
+                   decode_failure,
+                   return_address })
+}
+
+pub fn print_alignment(alignment: &Alignment,
+                       image: &dyn Image,
+                       symbols: &ImageSymbols) {
+    for (index, aligned) in alignment.steps.iter().enumerate() {
+        let recorded = &aligned.recorded;
+        println!("{:>5} {:#x} -> {:<24} vip {:#x} vsp {:#x} key {:#x}{}",
+                 index,
+                 recorded.handler_address,
+                 aligned.step.instruction.to_string(),
+                 recorded.vip,
+                 recorded.vsp,
+                 recorded.key,
+                 if aligned.resolved_branch { " [branch followed]" } else { "" });
+    }
+
+    if let Some((index, reason)) = &alignment.decode_failure {
+        println!("[Stopping] static decode of handler {} failed: {}", index, reason);
+    }
+
+    match alignment.return_address {
+        Some(return_address) => {
+            println!("[Exit] returning to {}",
+                     describe_continuation(image, symbols, return_address))
+        },
+        None => println!("[Stopping] recording ended inside the vm"),
+    }
+
+    println!("Divergences from the static decode: {}", alignment.divergences.len());
+    for divergence in alignment.divergences.iter() {
+        println!("    handler {} {}: decoded {:#x} recorded {:#x}",
+                 divergence.index,
+                 divergence.location,
+                 divergence.decoded,
+                 divergence.recorded);
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt::Display;
+
+use crate::{
+    image::Image,
+    trace::TraceStep,
+    virtual_stack::{TrackedValue, VirtualStack},
+    vm_handler::{Registers, VmContext},
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+/// A value vmentry left on the virtual stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotValue {
+    Native(Registers),
+    /// The encrypted vip the vm call pushed
+    PushedValue,
+    /// The constant vmentry pushes last, added to addresses to relocate them
+    RelocationDelta,
+    ReturnAddress,
+}
+
+impl Display for SlotValue {
+    fn fmt(&self,
+           f: &mut std::fmt::Formatter<'_>)
+           -> std::fmt::Result {
+        match self {
+            SlotValue::Native(Registers::Flags) => write!(f, "rflags"),
+            SlotValue::Native(reg) => write!(f, "{}", format!("{:?}", reg).to_lowercase()),
+            SlotValue::PushedValue => write!(f, "pushed value"),
+            SlotValue::RelocationDelta => write!(f, "relocation delta"),
+            SlotValue::ReturnAddress => write!(f, "return address"),
+        }
+    }
+}
+
+/// A value on the virtual stack, by where it came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackValue {
+    /// Left on the stack by vmentry
+    Entry(SlotValue),
+    /// Pushed from a word sized register file slot, with what the slot held
+    Register(u8, Option<SlotValue>),
+    /// Computed or pushed by the instruction
+    Computed(HandlerVmInstruction),
+    /// Flags an arithmetic instruction pushed above its result
+    Flags(HandlerVmInstruction),
+}
+
+impl StackValue {
+    /// The vmentry value this holds unchanged
+    pub fn origin(&self) -> Option<SlotValue> {
+        match *self {
+            StackValue::Entry(value) => Some(value),
+            StackValue::Register(_, value) => value,
+            _ => None,
+        }
+    }
+}
+
+impl Display for StackValue {
+    fn fmt(&self,
+           f: &mut std::fmt::Formatter<'_>)
+           -> std::fmt::Result {
+        match self {
+            StackValue::Entry(value) => write!(f, "{} from vmentry", value),
+            StackValue::Register(offset, Some(value)) => {
+                write!(f, "{} = {}", format_slot_name(*offset), value)
+            },
+            StackValue::Register(offset, None) => write!(f, "{}", format_slot_name(*offset)),
+            StackValue::Computed(instruction) => write!(f, "{}", instruction),
+            StackValue::Flags(instruction) => write!(f, "flags of {}", instruction),
+        }
+    }
+}
+
+/// The slot at offset named like the pops and pushes name it, 32 bit slots are halves of vrs
+fn format_slot_name(offset: u8) -> String {
+    match offset % 8 {
+        0 => format!("vr{}", offset / 8),
+        _ => format!("vr{}_high", offset / 8),
+    }
+}
+
+/// Listing comment naming what a pop or push moved
+pub fn format_slot_comment(value: Option<SlotValue>) -> String {
+    match value {
+        Some(value) => format!(" ; = {}", value),
+        None => String::new(),
+    }
+}
+
+impl TrackedValue for StackValue {
+    type Slot = SlotValue;
+
+    fn entry(value: SlotValue,
+             _: Option<u64>)
+             -> Self {
+        StackValue::Entry(value)
+    }
+
+    fn slot_value(&self) -> Option<SlotValue> {
+        self.origin()
+    }
+
+    fn from_slot(offset: u8,
+                 slot: Option<SlotValue>)
+                 -> Self {
+        StackValue::Register(offset, slot)
+    }
+
+    fn immediate(instruction: HandlerVmInstruction,
+                 _: u64)
+                 -> Self {
+        StackValue::Computed(instruction)
+    }
+
+    fn computed(instruction: HandlerVmInstruction) -> Self {
+        StackValue::Computed(instruction)
+    }
+
+    fn result(instruction: HandlerVmInstruction,
+              _: usize,
+              _: Option<Self>,
+              _: Option<Self>)
+              -> Self {
+        StackValue::Computed(instruction)
+    }
+
+    fn flags(instruction: HandlerVmInstruction) -> Self {
+        StackValue::Flags(instruction)
+    }
+}
+
+/// Follows the values vmentry pushed through the virtual stack into the register file, so
+/// every register file slot can be named by the native register it currently holds
+#[derive(Debug, Clone)]
+pub struct RegisterMap {
+    virtual_stack: VirtualStack<StackValue>,
+}
+
+impl RegisterMap {
+    pub fn new(vm_context: &VmContext,
+               image: &dyn Image)
+               -> Self {
+        Self { virtual_stack: VirtualStack::new(vm_context, image) }
+    }
+
+    /// Origin of the register file bytes at offset, if they are a whole tracked slot
+    pub fn slot(&self,
+                offset: u8,
+                size: usize)
+                -> Option<SlotValue> {
+        self.virtual_stack.slot(offset, size)
+    }
+
+    /// What every register vmexit pops receives, popping them in the given order from the
+    /// current virtual stack
+    pub fn vm_exit_assignment(&self,
+                              pop_order: &[Registers])
+                              -> Vec<(Registers, Option<StackValue>)> {
+        let mut virtual_stack = self.virtual_stack.clone();
+        let word_size = virtual_stack.word_size;
+        pop_order.iter()
+                 .map(|&reg| (reg, virtual_stack.pop(word_size)))
+                 .collect()
+    }
+
+    /// Applies a handler, returning the origin of the value a pop or push moved between the
+    /// virtual stack and the register file
+    pub fn step(&mut self,
+                step: &TraceStep)
+                -> Option<SlotValue> {
+        let moved = match (step.handler_class, step.instruction) {
+            (HandlerClass::UnconditionalBranch, _) => None,
+            (_, HandlerVmInstruction::Pop(size, _)) => {
+                self.virtual_stack.peek(size).and_then(|value| value.origin())
+            },
+            (_, HandlerVmInstruction::Push(size, offset)) => self.slot(offset, size),
+            _ => None,
+        };
+
+        self.virtual_stack.step(step);
+        moved
+    }
+}
+
+pub fn print_vm_exit_assignment(assignment: &[(Registers, Option<StackValue>)]) {
+    println!("Native registers at vmexit:");
+    for (reg, value) in assignment.iter() {
+        let name = match reg {
+            Registers::Flags => "rflags".to_string(),
+            reg => format!("{:?}", reg).to_lowercase(),
+        };
+        match value {
+            Some(value) => println!("    {:<6} <- {}", name, value),
+            None => println!("    {:<6} <- unknown", name),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use iced_x86::Instruction;
+
+use crate::{
+    image::Image,
+    trace::TraceStep,
+    util::format_instruction,
+    vm_handler::{VmHandler, VmRegisterAllocation},
+    vm_matchers::HandlerClass,
+    walker::WalkEnd,
+};
+
+/// Everything seen about one handler address across the traces
+pub struct HandlerInventoryEntry {
+    pub handler_address: u64,
+    pub count: usize,
+    /// None when no sighting of the handler could be classified
+    pub handler_class: Option<HandlerClass>,
+    /// Distinct instructions the handler decoded to, without operands
+    pub decoded: Vec<String>,
+    /// Matchers that accepted the handler in any sighting
+    pub matched: Vec<&'static str>,
+    /// Some sighting of the handler failed to decode
+    pub is_unknown: bool,
+    /// Distinct reasons sightings of the handler stopped the trace
+    pub decode_failures: Vec<String>,
+    /// Vm call sites whose trace dispatched the handler
+    pub vm_call_addresses: Vec<u64>,
+    pub instructions: Vec<Instruction>,
+    /// Why the walk over the native code of the handler stopped
+    pub walk_end: WalkEnd,
+    pub vip_update_slice: Vec<Instruction>,
+}
+
+impl HandlerInventoryEntry {
+    /// The handler decoded to different instructions between sightings, at most one of them
+    /// can be right
+    pub fn is_inconsistent(&self) -> bool {
+        self.decoded.len() > 1
+    }
+
+    /// The handler class, "-" when no sighting could be classified
+    pub fn class_name(&self) -> String {
+        match self.handler_class {
+            Some(handler_class) => format!("{:?}", handler_class),
+            None => "-".to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HandlerInventory {
+    pub entries: Vec<HandlerInventoryEntry>,
+    pub trace_count: usize,
+}
+
+impl HandlerInventory {
+    pub fn add_trace(&mut self,
+                     vm_call_address: u64,
+                     reg_allocation: &VmRegisterAllocation,
+                     steps: &[TraceStep]) {
+        self.trace_count += 1;
+
+        for step in steps {
+            let mnemonic = step.instruction.mnemonic();
+            let matched: Vec<&'static str> = match step.handler_class {
+                HandlerClass::UnconditionalBranch => Vec::new(),
+                handler_class => step.handler
+                                     .evaluate_matchers(reg_allocation,
+                                                        handler_class,
+                                                        step.operand,
+                                                        step.instruction)
+                                     .results
+                                     .iter()
+                                     .map(|result| result.name)
+                                     .collect(),
+            };
+
+            if let Some(entry) = self.entries
+                                     .iter_mut()
+                                     .find(|entry| entry.handler_address == step.handler_address)
+            {
+                entry.count += 1;
+                if !entry.decoded.contains(&mnemonic) {
+                    entry.decoded.push(mnemonic);
+                }
+                for name in matched {
+                    if !entry.matched.contains(&name) {
+                        entry.matched.push(name);
+                    }
+                }
+                entry.is_unknown |= step.instruction.is_unknown();
+                if !entry.vm_call_addresses.contains(&vm_call_address) {
+                    entry.vm_call_addresses.push(vm_call_address);
+                }
+                continue;
+            }
+
+            let vip_update_slice = step.handler
+                                       .vip_update_slice(reg_allocation)
+                                       .into_iter()
+                                       .copied()
+                                       .collect();
+
+            self.entries.push(HandlerInventoryEntry { handler_address: step.handler_address,
+                                                      count: 1,
+                                                      handler_class: Some(step.handler_class),
+                                                      decoded: vec![mnemonic],
+                                                      matched,
+                                                      is_unknown: step.instruction.is_unknown(),
+                                                      decode_failures: Vec::new(),
+                                                      vm_call_addresses: vec![vm_call_address],
+                                                      instructions: step.handler
+                                                                        .instructions
+                                                                        .clone(),
+                                                      walk_end: step.handler.walk_end,
+                                                      vip_update_slice });
+        }
+    }
+
+    /// Records the handler a trace stopped at because it could not be decoded
+    pub fn add_decode_failure(&mut self,
+                              vm_call_address: u64,
+                              image: &dyn Image,
+                              reg_allocation: &VmRegisterAllocation,
+                              handler_address: u64,
+                              reason: &str) {
+        if let Some(entry) = self.entries
+                                 .iter_mut()
+                                 .find(|entry| entry.handler_address == handler_address)
+        {
+            entry.count += 1;
+            entry.is_unknown = true;
+            if !entry.decode_failures.iter().any(|failure| failure == reason) {
+                entry.decode_failures.push(reason.to_string());
+            }
+            if !entry.vm_call_addresses.contains(&vm_call_address) {
+                entry.vm_call_addresses.push(vm_call_address);
+            }
+            return;
+        }
+
+        let mut vm_handler = VmHandler::new(handler_address, image);
+        vm_handler.canonicalize_moves(reg_allocation);
+        let vip_update_slice = vm_handler.vip_update_slice(reg_allocation)
+                                         .into_iter()
+                                         .copied()
+                                         .collect();
+
+        self.entries.push(HandlerInventoryEntry { handler_address,
+                                                  count: 1,
+                                                  handler_class: None,
+                                                  decoded: Vec::new(),
+                                                  matched: Vec::new(),
+                                                  is_unknown: true,
+                                                  decode_failures: vec![reason.to_string()],
+                                                  vm_call_addresses: vec![vm_call_address],
+                                                  instructions: vm_handler.instructions,
+                                                  walk_end: vm_handler.walk_end,
+                                                  vip_update_slice });
+    }
+
+    pub fn print_table(&self) {
+        println!("Traces: {}, distinct handlers: {}, unknown: {}, inconsistent: {}",
+                 self.trace_count,
+                 self.entries.len(),
+                 self.entries.iter().filter(|entry| entry.is_unknown).count(),
+                 self.entries.iter().filter(|entry| entry.is_inconsistent()).count());
+        println!("{:<14} {:>6} {:<20} {:<40} {:<24} traces",
+                 "address", "count", "class", "matched", "decoded");
+
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| {
+            (entry.handler_class.map_or(u8::MAX, |handler_class| handler_class as u8),
+             entry.handler_address)
+        });
+
+        for entry in entries {
+            let matched = match entry.matched.is_empty() {
+                true => "-".to_string(),
+                false => entry.matched.join(","),
+            };
+            let traces = entry.vm_call_addresses
+                              .iter()
+                              .map(|address| format!("{:#x}", address))
+                              .collect::<Vec<_>>()
+                              .join(",");
+
+            // Decodes that differ between sightings are marked
+            let decoded = match (entry.decoded.is_empty(), entry.is_inconsistent()) {
+                (true, _) => "-".to_string(),
+                (false, true) => format!("{}!", entry.decoded.join(",")),
+                (false, false) => entry.decoded.join(","),
+            };
+
+            println!("{:<#14x} {:>6} {:<20} {:<40} {:<24} {}",
+                     entry.handler_address,
+                     entry.count,
+                     entry.class_name(),
+                     matched,
+                     decoded,
+                     traces);
+        }
+    }
+
+    /// Dumps the full listing and the vip update slice of every handler that is unknown or
+    /// decoded differently between sightings
+    pub fn print_unknown_handlers(&self) {
+        for entry in self.entries
+                         .iter()
+                         .filter(|entry| entry.is_unknown || entry.is_inconsistent())
+        {
+            let kind = match entry.is_unknown {
+                true => "Unknown",
+                false => "Inconsistent",
+            };
+            println!();
+            println!("{} handler {:#x} ({}, seen {} times, decoded as {})",
+                     kind,
+                     entry.handler_address,
+                     entry.class_name(),
+                     entry.count,
+                     entry.decoded.join(","));
+            for reason in entry.decode_failures.iter() {
+                println!("  decoding failed: {}", reason);
+            }
+            println!("  walk ended: {}", entry.walk_end);
+
+            println!("  vip update slice:");
+            for instruction in entry.vip_update_slice.iter() {
+                println!("    {}", format_instruction(instruction));
+            }
+
+            println!("  native instructions:");
+            for instruction in entry.instructions.iter() {
+                println!("    {}", format_instruction(instruction));
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use iced_x86::{Code, Instruction, InstructionInfoFactory, OpAccess, OpKind, Register};
+
+use crate::{
+    match_assembly::{
+        match_fetch_reg_any_size, match_fetch_zx_reg_any_size, match_push_rolling_key,
+        match_xor_16_rolling_key_dest, match_xor_16_rolling_key_source,
+        match_xor_32_rolling_key_dest, match_xor_32_rolling_key_source,
+        match_xor_64_rolling_key_dest,
+        match_xor_64_rolling_key_source, match_xor_8_rolling_key_dest,
+        match_xor_8_rolling_key_source,
+    },
+    util::is_high_byte_register,
+    vm_handler::{VmHandler, VmRegisterAllocation},
+};
+
+/// Decryption of one value read from the vip stream
+#[derive(Debug, Clone)]
+pub struct DecryptionChain {
+    /// Index of the instruction fetching the encrypted value
+    pub fetch_index: usize,
+    /// Instructions applied to the value between the two rolling key xors, in order
+    pub program: Vec<Instruction>,
+}
+
+/// Every decryption performed by a handler
+#[derive(Debug, Clone)]
+pub struct HandlerDecryption {
+    /// Decryption of the operand, if the handler has one
+    pub operand: Option<DecryptionChain>,
+    /// Decryption of the offset to the next handler
+    pub next_offset: DecryptionChain,
+}
+
+fn is_vip_fetch(instruction: &Instruction,
+                reg_allocation: &VmRegisterAllocation)
+                -> bool {
+    match_fetch_reg_any_size(instruction, reg_allocation.vip.into()).is_some() ||
+    match_fetch_zx_reg_any_size(instruction, reg_allocation.vip.into()).is_some()
+}
+
+fn is_rolling_key_source(instruction: &Instruction,
+                         reg_allocation: &VmRegisterAllocation)
+                         -> bool {
+    match_xor_8_rolling_key_source(instruction, reg_allocation) ||
+    match_xor_16_rolling_key_source(instruction, reg_allocation) ||
+    match_xor_32_rolling_key_source(instruction, reg_allocation) ||
+    match_xor_64_rolling_key_source(instruction, reg_allocation)
+}
+
+/// Instructions folding a decrypted value back into the rolling key, with the register
+/// holding that value
+/// xor key, reg
+/// push key; xor [rsp], reg; pop key
+fn rolling_key_updates(instructions: &[Instruction],
+                       reg_allocation: &VmRegisterAllocation)
+                       -> Vec<(usize, Register)> {
+    let mut updates = Vec::new();
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        if match_xor_8_rolling_key_dest(instruction, reg_allocation) ||
+           match_xor_16_rolling_key_dest(instruction, reg_allocation) ||
+           match_xor_32_rolling_key_dest(instruction, reg_allocation) ||
+           match_xor_64_rolling_key_dest(instruction, reg_allocation)
+        {
+            updates.push((index, instruction.op1_register()));
+        }
+
+        if match_push_rolling_key(instruction, reg_allocation) {
+            let xor_stack = instructions[index + 1 ..].iter()
+                                                      .position(|insn| {
+                                                          matches!(insn.code(),
+                                                                   Code::Xor_rm32_r32 |
+                                                                   Code::Xor_rm64_r64) &&
+                                                          insn.op0_kind() == OpKind::Memory &&
+                                                          insn.memory_base().full_register() ==
+                                                          Register::RSP
+                                                      });
+            if let Some(offset) = xor_stack {
+                let xor_index = index + 1 + offset;
+                updates.push((xor_index, instructions[xor_index].op1_register()));
+            }
+        }
+    }
+
+    updates
+}
+
+/// Indices of the instructions that the value of `register` before `end` depends on,
+/// in order. The vm state registers are not followed, they are inputs of the handler
+pub fn backward_slice(instructions: &[Instruction],
+                      end: usize,
+                      register: Register,
+                      reg_allocation: &VmRegisterAllocation)
+                      -> Vec<usize> {
+    let stop_registers = [Register::RSP,
+                          reg_allocation.vip.into(),
+                          reg_allocation.vsp.into(),
+                          reg_allocation.key.into(),
+                          reg_allocation.handler_address.into()];
+
+    let mut info_factory = InstructionInfoFactory::new();
+    let mut tracked = vec![register.full_register()];
+    let mut slice = Vec::new();
+
+    for (index, instruction) in instructions[.. end].iter().enumerate().rev() {
+        if tracked.is_empty() {
+            break;
+        }
+
+        let info = info_factory.info(instruction);
+        let writes_tracked = info.used_registers().iter().any(|used_register| {
+                                 let reg = used_register.register();
+                                 used_register.access() != OpAccess::Read &&
+                                 used_register.access() != OpAccess::CondRead &&
+                                 !is_high_byte_register(reg) &&
+                                 tracked.contains(&reg.full_register())
+                             });
+        if !writes_tracked {
+            continue;
+        }
+
+        slice.push(index);
+
+        // Registers this instruction overwrites are not needed before it, unless it reads them
+        for used_register in info.used_registers() {
+            let reg = used_register.register();
+            if used_register.access() == OpAccess::Write && (reg.is_gpr32() || reg.is_gpr64()) {
+                tracked.retain(|&tracked_reg| tracked_reg != reg.full_register());
+            }
+        }
+
+        for used_register in info.used_registers() {
+            let full_register = used_register.register().full_register();
+            if used_register.register().is_gpr() &&
+               matches!(used_register.access(),
+                        OpAccess::Read |
+                        OpAccess::CondRead |
+                        OpAccess::ReadWrite |
+                        OpAccess::ReadCondWrite) &&
+               !stop_registers.contains(&full_register) &&
+               !tracked.contains(&full_register)
+            {
+                tracked.push(full_register);
+            }
+        }
+    }
+
+    slice.reverse();
+    slice
+}
+
+/// Walks back from a rolling key update to the vip fetch of the value it consumes
+fn decryption_chain(instructions: &[Instruction],
+                    update_index: usize,
+                    register: Register,
+                    reg_allocation: &VmRegisterAllocation)
+                    -> Option<DecryptionChain> {
+    let slice = backward_slice(instructions, update_index, register, reg_allocation);
+
+    let slice_position =
+        slice.iter().rposition(|&index| is_vip_fetch(&instructions[index], reg_allocation))?;
+    let fetch_index = slice[slice_position];
+
+    let mut chain = slice[slice_position + 1 ..].iter().copied();
+    chain.find(|&index| is_rolling_key_source(&instructions[index], reg_allocation))?;
+
+    Some(DecryptionChain { fetch_index,
+                           program: chain.map(|index| instructions[index]).collect() })
+}
+
+/// Register holding the next handler address when the handler dispatches
+/// jmp reg
+/// push reg; ret
+fn dispatch_register(instructions: &[Instruction]) -> Option<(usize, Register)> {
+    let last_index = instructions.len().checked_sub(1)?;
+    let last = &instructions[last_index];
+
+    match last.code() {
+        Code::Jmp_rm64 | Code::Jmp_rm32 if last.op0_kind() == OpKind::Register => {
+            Some((last_index, last.op0_register()))
+        },
+        Code::Retnq | Code::Retnd => {
+            let push_index = instructions[.. last_index].iter()
+                                                        .rposition(|insn| {
+                                                            matches!(insn.code(),
+                                                                     Code::Push_r64 |
+                                                                     Code::Push_r32)
+                                                        })?;
+            Some((push_index, instructions[push_index].op0_register()))
+        },
+        _ => None,
+    }
+}
+
+impl VmHandler {
+    /// Recovers the operand and next offset decryption programs by slicing backwards from
+    /// the dispatch and from every rolling key update
+    pub fn slice_decryption(&self,
+                            reg_allocation: &VmRegisterAllocation)
+                            -> Option<HandlerDecryption> {
+        let instructions = &self.instructions;
+
+        let (dispatch_index, dispatch_reg) = dispatch_register(instructions)?;
+        let next_offset_fetch = backward_slice(instructions,
+                                               dispatch_index,
+                                               dispatch_reg,
+                                               reg_allocation)
+            .into_iter()
+            .rfind(|&index| is_vip_fetch(&instructions[index], reg_allocation))?;
+
+        let mut chains = rolling_key_updates(instructions, reg_allocation)
+            .into_iter()
+            .filter_map(|(index, reg)| decryption_chain(instructions, index, reg, reg_allocation))
+            .collect::<Vec<_>>();
+        chains.sort_by_key(|chain| chain.fetch_index);
+        chains.dedup_by_key(|chain| chain.fetch_index);
+
+        let next_offset_position =
+            chains.iter().position(|chain| chain.fetch_index == next_offset_fetch)?;
+        let next_offset = chains.remove(next_offset_position);
+        let operand = chains.into_iter().find(|chain| chain.fetch_index < next_offset.fetch_index);
+
+        Some(HandlerDecryption { operand,
+                                 next_offset })
+    }
+}
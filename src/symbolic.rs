@@ -0,0 +1,1102 @@
+use std::{collections::HashMap, fmt::Display};
+
+use iced_x86::{Instruction, Mnemonic, OpKind, Register};
+
+use crate::{
+    util::XorShift64,
+    vm_handler::{Registers, VmHandler, VmRegisterAllocation},
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+const GPRS: [Registers; 16] = [Registers::Rax,
+                               Registers::Rbx,
+                               Registers::Rcx,
+                               Registers::Rdx,
+                               Registers::Rsi,
+                               Registers::Rdi,
+                               Registers::Rsp,
+                               Registers::Rbp,
+                               Registers::R8,
+                               Registers::R9,
+                               Registers::R10,
+                               Registers::R11,
+                               Registers::R12,
+                               Registers::R13,
+                               Registers::R14,
+                               Registers::R15];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+    /// Byte swap of the low n bytes
+    ByteSwap(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    /// Arithmetic shift right of an n byte value
+    Sar(usize),
+    /// Rotate left of an n byte value
+    Rol(usize),
+    /// Rotate right of an n byte value
+    Ror(usize),
+}
+
+/// Symbolic 64 bit value computed by a handler
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Const(u64),
+    /// Value of the native register on handler entry
+    Reg(Registers),
+    /// Memory read of size in bytes
+    Load(Box<Expr>, usize),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// Low size bytes of the value, zero extended
+    Extract(Box<Expr>, usize),
+    /// Low size bytes of the value, sign extended
+    SignExtend(Box<Expr>, usize),
+    /// Rflags produced by the operation computing the value
+    Flags(Box<Expr>),
+    Unknown,
+}
+
+fn size_mask(size: usize) -> u64 {
+    match size {
+        8 => u64::MAX,
+        _ => (1u64 << (size * 8)) - 1,
+    }
+}
+
+fn sign_extend(value: u64,
+               size: usize)
+               -> u64 {
+    let shift = 64 - size as u32 * 8;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+fn eval_unary(op: UnaryOp,
+              value: u64)
+              -> u64 {
+    match op {
+        UnaryOp::Not => !value,
+        UnaryOp::Neg => value.wrapping_neg(),
+        UnaryOp::ByteSwap(size) => value.swap_bytes() >> (64 - size * 8),
+    }
+}
+
+fn eval_binary(op: BinaryOp,
+               lhs: u64,
+               rhs: u64)
+               -> u64 {
+    match op {
+        BinaryOp::Add => lhs.wrapping_add(rhs),
+        BinaryOp::Sub => lhs.wrapping_sub(rhs),
+        BinaryOp::And => lhs & rhs,
+        BinaryOp::Or => lhs | rhs,
+        BinaryOp::Xor => lhs ^ rhs,
+        BinaryOp::Shl => lhs.checked_shl(rhs as u32).unwrap_or(0),
+        BinaryOp::Shr => lhs.checked_shr(rhs as u32).unwrap_or(0),
+        BinaryOp::Sar(size) => {
+            let shifted = (sign_extend(lhs, size) as i64) >> rhs.min(63);
+            shifted as u64 & size_mask(size)
+        },
+        BinaryOp::Rol(size) => rotate_sized(lhs, rhs as u32, size, true),
+        BinaryOp::Ror(size) => rotate_sized(lhs, rhs as u32, size, false),
+    }
+}
+
+fn rotate_sized(value: u64,
+                amount: u32,
+                size: usize,
+                left: bool)
+                -> u64 {
+    match (size, left) {
+        (1, true) => (value as u8).rotate_left(amount) as u64,
+        (1, false) => (value as u8).rotate_right(amount) as u64,
+        (2, true) => (value as u16).rotate_left(amount) as u64,
+        (2, false) => (value as u16).rotate_right(amount) as u64,
+        (4, true) => (value as u32).rotate_left(amount) as u64,
+        (4, false) => (value as u32).rotate_right(amount) as u64,
+        (_, true) => value.rotate_left(amount),
+        (_, false) => value.rotate_right(amount),
+    }
+}
+
+impl Expr {
+    pub fn constant(&self) -> Option<u64> {
+        match self {
+            Expr::Const(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn load(address: Expr,
+                size: usize)
+                -> Expr {
+        Expr::Load(Box::new(address), size)
+    }
+
+    pub fn unary(op: UnaryOp,
+                 value: Expr)
+                 -> Expr {
+        match (op, value) {
+            (_, Expr::Const(value)) => Expr::Const(eval_unary(op, value)),
+            (UnaryOp::Not, Expr::Unary(UnaryOp::Not, inner)) => *inner,
+            (_, value) => Expr::Unary(op, Box::new(value)),
+        }
+    }
+
+    pub fn binary(op: BinaryOp,
+                  lhs: Expr,
+                  rhs: Expr)
+                  -> Expr {
+        match (op, lhs, rhs) {
+            (_, Expr::Const(lhs), Expr::Const(rhs)) => Expr::Const(eval_binary(op, lhs, rhs)),
+            (BinaryOp::Sub, lhs, Expr::Const(rhs)) => {
+                Expr::binary(BinaryOp::Add, lhs, Expr::Const(rhs.wrapping_neg()))
+            },
+            (BinaryOp::Sub | BinaryOp::Xor, lhs, rhs) if lhs == rhs && lhs != Expr::Unknown => {
+                Expr::Const(0)
+            },
+            (BinaryOp::And | BinaryOp::Or, lhs, rhs) if lhs == rhs => lhs,
+            (BinaryOp::Add | BinaryOp::Or | BinaryOp::Xor, Expr::Const(lhs), rhs) => {
+                Expr::binary(op, rhs, Expr::Const(lhs))
+            },
+            (BinaryOp::Add | BinaryOp::Or | BinaryOp::Xor | BinaryOp::Shl | BinaryOp::Shr,
+             lhs,
+             Expr::Const(0)) => lhs,
+            (BinaryOp::Add, Expr::Binary(BinaryOp::Add, inner, inner_rhs), Expr::Const(rhs))
+                if inner_rhs.constant().is_some() =>
+            {
+                let sum = inner_rhs.constant().unwrap().wrapping_add(rhs);
+                Expr::binary(BinaryOp::Add, *inner, Expr::Const(sum))
+            },
+            (op, lhs, rhs) => Expr::Binary(op, Box::new(lhs), Box::new(rhs)),
+        }
+    }
+
+    pub fn extract(value: Expr,
+                   size: usize)
+                   -> Expr {
+        if size >= 8 {
+            return value;
+        }
+
+        match value {
+            Expr::Const(value) => Expr::Const(value & size_mask(size)),
+            Expr::Extract(inner, inner_size) => Expr::extract(*inner, inner_size.min(size)),
+            Expr::Load(address, load_size) if load_size <= size => Expr::Load(address, load_size),
+            value => Expr::Extract(Box::new(value), size),
+        }
+    }
+
+    pub fn sign_extend(value: Expr,
+                       size: usize)
+                       -> Expr {
+        match value {
+            _ if size >= 8 => value,
+            Expr::Const(value) => Expr::Const(sign_extend(value, size)),
+            value => Expr::SignExtend(Box::new(value), size),
+        }
+    }
+
+    /// Strip zero extensions that do not change the value
+    pub fn strip_extract(&self) -> &Expr {
+        match self {
+            Expr::Extract(inner, _) => inner.strip_extract(),
+            _ => self,
+        }
+    }
+
+    /// Splits an address into a base register and a constant offset
+    pub fn base_offset(&self) -> Option<(Registers, i64)> {
+        match self {
+            Expr::Reg(reg) => Some((*reg, 0)),
+            Expr::Binary(BinaryOp::Add, lhs, rhs) => match (lhs.as_ref(), rhs.as_ref()) {
+                (Expr::Reg(reg), Expr::Const(offset)) => Some((*reg, *offset as i64)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Like base_offset, also through the truncations to 32 bits every step of 32 bit code
+    /// applies, the offset wraps at 32 bits
+    pub fn base_offset_32(&self) -> Option<(Registers, i64)> {
+        match self {
+            Expr::Extract(inner, 4) => {
+                inner.base_offset_32().map(|(reg, offset)| (reg, offset as i32 as i64))
+            },
+            Expr::Binary(BinaryOp::Add, lhs, rhs) => match (lhs.base_offset_32(), rhs.constant()) {
+                (Some((reg, offset)), Some(rhs)) => {
+                    Some((reg, offset.wrapping_add(rhs as i64) as i32 as i64))
+                },
+                _ => None,
+            },
+            _ => self.base_offset(),
+        }
+    }
+
+    pub fn contains(&self,
+                    needle: &Expr)
+                    -> bool {
+        if self == needle {
+            return true;
+        }
+
+        match self {
+            Expr::Load(inner, _) |
+            Expr::Unary(_, inner) |
+            Expr::Extract(inner, _) |
+            Expr::SignExtend(inner, _) |
+            Expr::Flags(inner) => inner.contains(needle),
+            Expr::Binary(_, lhs, rhs) => lhs.contains(needle) || rhs.contains(needle),
+            _ => false,
+        }
+    }
+
+    /// Collects every memory read the value depends on, outermost first
+    pub fn loads(&self) -> Vec<&Expr> {
+        let mut loads = Vec::new();
+        self.collect_loads(&mut loads);
+        loads
+    }
+
+    fn collect_loads<'a>(&'a self,
+                         loads: &mut Vec<&'a Expr>) {
+        match self {
+            Expr::Load(address, _) => {
+                loads.push(self);
+                address.collect_loads(loads);
+            },
+            Expr::Unary(_, inner) |
+            Expr::Extract(inner, _) |
+            Expr::SignExtend(inner, _) |
+            Expr::Flags(inner) => inner.collect_loads(loads),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.collect_loads(loads);
+                rhs.collect_loads(loads);
+            },
+            _ => {},
+        }
+    }
+
+    /// Evaluates the expression, leaves (registers, loads, flags) are resolved by the callback
+    pub fn evaluate(&self,
+                    leaf: &mut dyn FnMut(&Expr) -> Option<u64>)
+                    -> Option<u64> {
+        match self {
+            Expr::Const(value) => Some(*value),
+            Expr::Reg(_) | Expr::Load(..) | Expr::Flags(_) | Expr::Unknown => leaf(self),
+            Expr::Unary(op, inner) => Some(eval_unary(*op, inner.evaluate(leaf)?)),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(leaf)?;
+                let rhs = rhs.evaluate(leaf)?;
+                Some(eval_binary(*op, lhs, rhs))
+            },
+            Expr::Extract(inner, size) => Some(inner.evaluate(leaf)? & size_mask(*size)),
+            Expr::SignExtend(inner, size) => Some(sign_extend(inner.evaluate(leaf)?, *size)),
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self,
+           f: &mut std::fmt::Formatter<'_>)
+           -> std::fmt::Result {
+        match self {
+            Expr::Const(value) => write!(f, "{:#x}", value),
+            Expr::Reg(reg) => write!(f, "{:?}", reg),
+            Expr::Load(address, size) => write!(f, "[{}]:{}", address, size * 8),
+            Expr::Unary(op, inner) => write!(f, "{:?}({})", op, inner),
+            Expr::Binary(op, lhs, rhs) => write!(f, "{:?}({}, {})", op, lhs, rhs),
+            Expr::Extract(inner, size) => write!(f, "zx{}({})", size * 8, inner),
+            Expr::SignExtend(inner, size) => write!(f, "sx{}({})", size * 8, inner),
+            Expr::Flags(inner) => write!(f, "flags({})", inner),
+            Expr::Unknown => write!(f, "?"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryWrite {
+    pub address: Expr,
+    pub size: usize,
+    pub value: Expr,
+}
+
+/// Symbolic state of the native machine while walking a handler
+struct SymbolicState {
+    registers: HashMap<Registers, Expr>,
+    flags: Expr,
+    writes: Vec<MemoryWrite>,
+}
+
+impl SymbolicState {
+    fn new() -> Self {
+        let registers = GPRS.iter().map(|&reg| (reg, Expr::Reg(reg))).collect();
+        Self { registers,
+               flags: Expr::Reg(Registers::Flags),
+               writes: Vec::new() }
+    }
+
+    fn full_register(&self,
+                     reg: Registers)
+                     -> Expr {
+        self.registers[&reg].clone()
+    }
+
+    fn read_register(&self,
+                     reg: Register)
+                     -> Expr {
+        if reg == Register::None {
+            return Expr::Const(0);
+        }
+
+        let full = self.full_register(reg.full_register().into());
+        if is_high_byte_register(reg) {
+            return Expr::extract(Expr::binary(BinaryOp::Shr, full, Expr::Const(8)), 1);
+        }
+
+        Expr::extract(full, reg.size())
+    }
+
+    fn write_register(&mut self,
+                      reg: Register,
+                      value: Expr) {
+        let full_reg: Registers = reg.full_register().into();
+        let old = self.full_register(full_reg);
+
+        let new = if is_high_byte_register(reg) {
+            let cleared = Expr::binary(BinaryOp::And, old, Expr::Const(!0xff00));
+            let shifted = Expr::binary(BinaryOp::Shl, Expr::extract(value, 1), Expr::Const(8));
+            Expr::binary(BinaryOp::Or, cleared, shifted)
+        } else {
+            match reg.size() {
+                8 => value,
+                4 => Expr::extract(value, 4),
+                size => {
+                    let cleared = Expr::binary(BinaryOp::And, old, Expr::Const(!size_mask(size)));
+                    Expr::binary(BinaryOp::Or, cleared, Expr::extract(value, size))
+                },
+            }
+        };
+
+        self.registers.insert(full_reg, new);
+    }
+
+    fn read_memory(&self,
+                   address: Expr,
+                   size: usize)
+                   -> Expr {
+        let (base, offset) = match address.base_offset() {
+            Some(base_offset) => base_offset,
+            None => {
+                return self.writes
+                           .iter()
+                           .rev()
+                           .find(|write| write.address == address && write.size >= size)
+                           .map(|write| Expr::extract(write.value.clone(), size))
+                           .unwrap_or_else(|| Expr::load(address, size));
+            },
+        };
+
+        for write in self.writes.iter().rev() {
+            if write.address == address && write.size >= size {
+                return Expr::extract(write.value.clone(), size);
+            }
+
+            match write.address.base_offset() {
+                Some((write_base, write_offset)) if write_base == base => {
+                    let overlaps = write_offset < offset + size as i64 &&
+                                   offset < write_offset + write.size as i64;
+                    if !overlaps {
+                        continue;
+                    }
+
+                    // Read contained in an earlier, wider store
+                    if write_offset <= offset &&
+                       offset + size as i64 <= write_offset + write.size as i64
+                    {
+                        let shift = (offset - write_offset) as u64 * 8;
+                        let shifted = Expr::binary(BinaryOp::Shr,
+                                                   write.value.clone(),
+                                                   Expr::Const(shift));
+                        return Expr::extract(shifted, size);
+                    }
+
+                    return Expr::Unknown;
+                },
+                _ => continue,
+            }
+        }
+
+        Expr::load(address, size)
+    }
+
+    fn write_memory(&mut self,
+                    address: Expr,
+                    size: usize,
+                    value: Expr) {
+        self.writes.push(MemoryWrite { address,
+                                       size,
+                                       value: Expr::extract(value, size) });
+    }
+
+    fn memory_address(&self,
+                      instruction: &Instruction)
+                      -> Expr {
+        let displacement = instruction.memory_displacement64();
+
+        if instruction.memory_base() == Register::RIP {
+            return Expr::Const(displacement);
+        }
+
+        if matches!(instruction.segment_prefix(), Register::FS | Register::GS) {
+            return Expr::Unknown;
+        }
+
+        let mut address = Expr::Const(0);
+        if instruction.memory_base() != Register::None {
+            address = self.read_register(instruction.memory_base());
+        }
+
+        if instruction.memory_index() != Register::None {
+            let scale = instruction.memory_index_scale().trailing_zeros() as u64;
+            let index = Expr::binary(BinaryOp::Shl,
+                                     self.read_register(instruction.memory_index()),
+                                     Expr::Const(scale));
+            address = Expr::binary(BinaryOp::Add, address, index);
+        }
+
+        Expr::binary(BinaryOp::Add, address, Expr::Const(displacement))
+    }
+
+    fn operand_size(instruction: &Instruction,
+                    operand: u32)
+                    -> usize {
+        match instruction.op_kind(operand) {
+            OpKind::Register => instruction.op_register(operand).size(),
+            OpKind::Memory => instruction.memory_size().size(),
+            _ => 8,
+        }
+    }
+
+    fn read_operand(&self,
+                    instruction: &Instruction,
+                    operand: u32)
+                    -> Expr {
+        match instruction.op_kind(operand) {
+            OpKind::Register => self.read_register(instruction.op_register(operand)),
+            OpKind::Memory => {
+                self.read_memory(self.memory_address(instruction),
+                                 instruction.memory_size().size())
+            },
+            OpKind::Immediate8 |
+            OpKind::Immediate16 |
+            OpKind::Immediate32 |
+            OpKind::Immediate64 |
+            OpKind::Immediate8to16 |
+            OpKind::Immediate8to32 |
+            OpKind::Immediate8to64 |
+            OpKind::Immediate32to64 => Expr::Const(instruction.immediate(operand)),
+            _ => Expr::Unknown,
+        }
+    }
+
+    fn write_operand(&mut self,
+                     instruction: &Instruction,
+                     operand: u32,
+                     value: Expr) {
+        match instruction.op_kind(operand) {
+            OpKind::Register => self.write_register(instruction.op_register(operand), value),
+            OpKind::Memory => {
+                let address = self.memory_address(instruction);
+                self.write_memory(address, instruction.memory_size().size(), value);
+            },
+            _ => {},
+        }
+    }
+
+    fn push(&mut self,
+            value: Expr,
+            size: usize) {
+        let rsp = Expr::binary(BinaryOp::Sub,
+                               self.full_register(Registers::Rsp),
+                               Expr::Const(size as u64));
+        self.registers.insert(Registers::Rsp, rsp.clone());
+        self.write_memory(rsp, size, value);
+    }
+
+    fn pop(&mut self,
+           size: usize)
+           -> Expr {
+        let rsp = self.full_register(Registers::Rsp);
+        let value = self.read_memory(rsp.clone(), size);
+        self.registers.insert(Registers::Rsp,
+                              Expr::binary(BinaryOp::Add, rsp, Expr::Const(size as u64)));
+        value
+    }
+
+    /// Marks every register written by an instruction that is not modelled as unknown
+    fn clobber(&mut self,
+               instruction: &Instruction) {
+        let mut info_factory = iced_x86::InstructionInfoFactory::new();
+        let info = info_factory.info_options(instruction,
+                                             iced_x86::InstructionInfoOptions::NO_MEMORY_USAGE);
+        for used_register in info.used_registers() {
+            let reg = used_register.register();
+            if !reg.is_gpr() {
+                continue;
+            }
+
+            match used_register.access() {
+                iced_x86::OpAccess::Write |
+                iced_x86::OpAccess::CondWrite |
+                iced_x86::OpAccess::ReadWrite |
+                iced_x86::OpAccess::ReadCondWrite => {
+                    self.registers.insert(reg.full_register().into(), Expr::Unknown);
+                },
+                _ => {},
+            }
+        }
+
+        if instruction.op0_kind() == OpKind::Memory {
+            let address = self.memory_address(instruction);
+            self.write_memory(address, instruction.memory_size().size(), Expr::Unknown);
+        }
+
+        self.flags = Expr::Unknown;
+    }
+
+    fn binary_operation(&mut self,
+                        instruction: &Instruction,
+                        op: BinaryOp) {
+        let size = Self::operand_size(instruction, 0);
+        let lhs = self.read_operand(instruction, 0);
+        let rhs = Expr::extract(self.read_operand(instruction, 1), size);
+
+        let result = Expr::extract(Expr::binary(op, lhs, rhs), size);
+        self.flags = Expr::Flags(Box::new(result.clone()));
+        self.write_operand(instruction, 0, result);
+    }
+
+    fn shift_operation(&mut self,
+                       instruction: &Instruction,
+                       op: BinaryOp) {
+        let size = Self::operand_size(instruction, 0);
+        let count_mask = if size == 8 { 0x3f } else { 0x1f };
+        let value = self.read_operand(instruction, 0);
+        let count = if instruction.op_count() > 1 {
+            self.read_operand(instruction, 1)
+        } else {
+            Expr::Const(1)
+        };
+        let count = Expr::binary(BinaryOp::And, Expr::extract(count, 1), Expr::Const(count_mask));
+
+        let result = Expr::extract(Expr::binary(op, value, count), size);
+        self.flags = Expr::Flags(Box::new(result.clone()));
+        self.write_operand(instruction, 0, result);
+    }
+
+    fn step(&mut self,
+            instruction: &Instruction) {
+        match instruction.mnemonic() {
+            Mnemonic::Mov => {
+                let value = self.read_operand(instruction, 1);
+                self.write_operand(instruction, 0, value);
+            },
+            Mnemonic::Movzx => {
+                let value = self.read_operand(instruction, 1);
+                self.write_operand(instruction, 0, value);
+            },
+            Mnemonic::Movsx | Mnemonic::Movsxd => {
+                let source_size = Self::operand_size(instruction, 1);
+                let value = Expr::sign_extend(self.read_operand(instruction, 1), source_size);
+                self.write_operand(instruction, 0, value);
+            },
+            Mnemonic::Lea => {
+                let address = self.memory_address(instruction);
+                self.write_operand(instruction, 0, address);
+            },
+            Mnemonic::Xchg => {
+                let lhs = self.read_operand(instruction, 0);
+                let rhs = self.read_operand(instruction, 1);
+                self.write_operand(instruction, 0, rhs);
+                self.write_operand(instruction, 1, lhs);
+            },
+            Mnemonic::Add => self.binary_operation(instruction, BinaryOp::Add),
+            Mnemonic::Sub => self.binary_operation(instruction, BinaryOp::Sub),
+            Mnemonic::And => self.binary_operation(instruction, BinaryOp::And),
+            Mnemonic::Or => self.binary_operation(instruction, BinaryOp::Or),
+            Mnemonic::Xor => self.binary_operation(instruction, BinaryOp::Xor),
+            Mnemonic::Shl | Mnemonic::Sal => self.shift_operation(instruction, BinaryOp::Shl),
+            Mnemonic::Shr => self.shift_operation(instruction, BinaryOp::Shr),
+            Mnemonic::Sar => {
+                let size = Self::operand_size(instruction, 0);
+                self.shift_operation(instruction, BinaryOp::Sar(size))
+            },
+            Mnemonic::Rol => {
+                let size = Self::operand_size(instruction, 0);
+                self.shift_operation(instruction, BinaryOp::Rol(size))
+            },
+            Mnemonic::Ror => {
+                let size = Self::operand_size(instruction, 0);
+                self.shift_operation(instruction, BinaryOp::Ror(size))
+            },
+            Mnemonic::Inc | Mnemonic::Dec => {
+                let size = Self::operand_size(instruction, 0);
+                let op = if instruction.mnemonic() == Mnemonic::Inc {
+                    BinaryOp::Add
+                } else {
+                    BinaryOp::Sub
+                };
+                let value = self.read_operand(instruction, 0);
+                let result = Expr::extract(Expr::binary(op, value, Expr::Const(1)), size);
+                self.flags = Expr::Flags(Box::new(result.clone()));
+                self.write_operand(instruction, 0, result);
+            },
+            Mnemonic::Not | Mnemonic::Neg => {
+                let size = Self::operand_size(instruction, 0);
+                let op = if instruction.mnemonic() == Mnemonic::Not {
+                    UnaryOp::Not
+                } else {
+                    UnaryOp::Neg
+                };
+                let value = self.read_operand(instruction, 0);
+                let result = Expr::extract(Expr::unary(op, value), size);
+                if op == UnaryOp::Neg {
+                    self.flags = Expr::Flags(Box::new(result.clone()));
+                }
+                self.write_operand(instruction, 0, result);
+            },
+            Mnemonic::Bswap => {
+                let size = Self::operand_size(instruction, 0);
+                let value = self.read_operand(instruction, 0);
+                self.write_operand(instruction, 0, Expr::unary(UnaryOp::ByteSwap(size), value));
+            },
+            Mnemonic::Cmp | Mnemonic::Test => {
+                let op = if instruction.mnemonic() == Mnemonic::Cmp {
+                    BinaryOp::Sub
+                } else {
+                    BinaryOp::And
+                };
+                let size = Self::operand_size(instruction, 0);
+                let lhs = self.read_operand(instruction, 0);
+                let rhs = self.read_operand(instruction, 1);
+                let result = Expr::extract(Expr::binary(op, lhs, rhs), size);
+                self.flags = Expr::Flags(Box::new(result));
+            },
+            Mnemonic::Cbw | Mnemonic::Cwde | Mnemonic::Cdqe => {
+                let (source, dest) = match instruction.mnemonic() {
+                    Mnemonic::Cbw => (Register::AL, Register::AX),
+                    Mnemonic::Cwde => (Register::AX, Register::EAX),
+                    _ => (Register::EAX, Register::RAX),
+                };
+                let value = Expr::sign_extend(self.read_register(source), source.size());
+                self.write_register(dest, value);
+            },
+            Mnemonic::Push => {
+                let size = Self::operand_size(instruction, 0);
+                let value = self.read_operand(instruction, 0);
+                self.push(value, size);
+            },
+            Mnemonic::Pop => {
+                let size = Self::operand_size(instruction, 0);
+                let value = self.pop(size);
+                self.write_operand(instruction, 0, value);
+            },
+            Mnemonic::Pushfq | Mnemonic::Pushfd => {
+                let flags = self.flags.clone();
+                self.push(flags, -instruction.stack_pointer_increment() as usize);
+            },
+            Mnemonic::Popfq | Mnemonic::Popfd => {
+                self.flags = self.pop(instruction.stack_pointer_increment() as usize);
+            },
+            Mnemonic::Ret => {
+                self.pop(instruction.stack_pointer_increment() as usize);
+            },
+            Mnemonic::Jmp | Mnemonic::Nop => {},
+            Mnemonic::Clc | Mnemonic::Stc | Mnemonic::Cmc | Mnemonic::Cld | Mnemonic::Std => {
+                self.flags = Expr::Unknown;
+            },
+            _ => self.clobber(instruction),
+        }
+    }
+}
+
+fn is_high_byte_register(reg: Register) -> bool {
+    matches!(reg, Register::AH | Register::BH | Register::CH | Register::DH)
+}
+
+/// Classes of the handlers that step over their bytecode, with the vip delta of each: the
+/// operand and the dword offset of the next handler
+const VIP_DELTAS: [(HandlerClass, u64); 6] = [(HandlerClass::NoVipChange, 0),
+                                              (HandlerClass::NoOperand, 4),
+                                              (HandlerClass::ByteOperand, 5),
+                                              (HandlerClass::WordOperand, 6),
+                                              (HandlerClass::DwordOperand, 8),
+                                              (HandlerClass::QwordOperand, 12)];
+
+/// Effect of a handler on the native machine, in terms of its entry state
+#[derive(Debug)]
+pub struct HandlerSemantics {
+    /// Native register values when the handler dispatches
+    pub registers: HashMap<Registers, Expr>,
+    /// Every memory store performed by the handler, in program order
+    pub writes: Vec<MemoryWrite>,
+    /// Virtual stack pointer register
+    pub vsp: Registers,
+    /// Virtual instruction pointer register
+    pub vip: Registers,
+    /// 32 or 64, the mode of the native instructions
+    pub bitness: u32,
+}
+
+/// Computes the effect of a list of native instructions on the entry state
+pub fn symbolic_execute(instructions: &[Instruction])
+                        -> (HashMap<Registers, Expr>, Vec<MemoryWrite>) {
+    let mut state = SymbolicState::new();
+    for instruction in instructions {
+        state.step(instruction);
+    }
+
+    (state.registers, state.writes)
+}
+
+impl VmHandler {
+    pub fn compute_semantics(&self,
+                             reg_allocation: &VmRegisterAllocation)
+                             -> HandlerSemantics {
+        let (registers, writes) = symbolic_execute(&self.instructions);
+
+        HandlerSemantics { registers,
+                           writes,
+                           vsp: reg_allocation.vsp,
+                           vip: reg_allocation.vip,
+                           bitness: self.bitness }
+    }
+}
+
+impl HandlerSemantics {
+    fn delta(&self,
+             reg: Registers)
+             -> Option<i64> {
+        let base_offset = match self.bitness {
+            32 => self.registers[&reg].base_offset_32(),
+            _ => self.registers[&reg].base_offset(),
+        };
+        match base_offset {
+            Some((base, offset)) if base == reg => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Amount the virtual stack pointer moved
+    pub fn vsp_delta(&self) -> Option<i64> {
+        self.delta(self.vsp)
+    }
+
+    /// Amount the virtual instruction pointer moved
+    pub fn vip_delta(&self) -> Option<i64> {
+        self.delta(self.vip)
+    }
+
+    /// Offset relative to the entry vsp if the address points into the virtual stack
+    pub fn stack_offset(&self,
+                        address: &Expr)
+                        -> Option<i64> {
+        match address.base_offset() {
+            Some((base, offset)) if base == self.vsp => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// True if the address is an operand indexed slot in the native stack register file
+    pub fn is_register_file(&self,
+                            address: &Expr)
+                            -> bool {
+        address.base_offset().is_none() && address.contains(&Expr::Reg(Registers::Rsp))
+    }
+
+    /// Virtual stack stores, offset relative to the entry vsp
+    pub fn stack_writes(&self) -> Vec<(i64, &MemoryWrite)> {
+        self.writes
+            .iter()
+            .filter_map(|write| self.stack_offset(&write.address).map(|offset| (offset, write)))
+            .collect()
+    }
+
+    /// Stores into the register file
+    pub fn register_file_writes(&self) -> Vec<&MemoryWrite> {
+        self.writes
+            .iter()
+            .filter(|write| self.is_register_file(&write.address))
+            .collect()
+    }
+
+    /// Stores that go neither to the virtual stack nor to the native stack
+    pub fn memory_writes(&self) -> Vec<&MemoryWrite> {
+        self.writes
+            .iter()
+            .filter(|write| {
+                self.stack_offset(&write.address).is_none() &&
+                !write.address.contains(&Expr::Reg(Registers::Rsp))
+            })
+            .collect()
+    }
+
+    fn is_stack_load(&self,
+                     expr: &Expr,
+                     offset: i64)
+                     -> Option<usize> {
+        match expr.strip_extract() {
+            Expr::Load(address, size) if self.stack_offset(address) == Some(offset) => Some(*size),
+            _ => None,
+        }
+    }
+
+    fn is_register_file_load(&self,
+                             expr: &Expr)
+                             -> Option<usize> {
+        match expr.strip_extract() {
+            Expr::Load(address, size) if self.is_register_file(address) => Some(*size),
+            _ => None,
+        }
+    }
+
+    /// The last store to each virtual stack slot that survives the handler
+    fn live_stack_writes(&self) -> Vec<(i64, &MemoryWrite)> {
+        let vsp_delta = self.vsp_delta().unwrap_or(i64::MIN);
+        let mut live: Vec<(i64, &MemoryWrite)> = Vec::new();
+        for (offset, write) in self.stack_writes() {
+            live.retain(|(live_offset, _)| *live_offset != offset);
+            if offset >= vsp_delta {
+                live.push((offset, write));
+            }
+        }
+
+        live
+    }
+
+    /// Class of the handler by the bytecode it steps over, or a branch when it pops the new vip
+    /// off the virtual stack. None when vip ends up in no known shape
+    pub fn handler_class(&self) -> Option<HandlerClass> {
+        // Vmexit switches the native stack to the virtual stack and pops vip off it with the
+        // other registers
+        if self.is_vm_exit() {
+            return Some(HandlerClass::NoVipChange);
+        }
+
+        if let Some(vip_delta) = self.vip_delta() {
+            return VIP_DELTAS.into_iter()
+                             .find(|&(_, delta)| delta == vip_delta.unsigned_abs())
+                             .map(|(handler_class, _)| handler_class);
+        }
+
+        let pops_vip = self.registers[&self.vip].loads()
+                                                .iter()
+                                                .any(|load| self.is_stack_load(load, 0).is_some());
+        (pops_vip && self.vsp_delta() > Some(0)).then_some(HandlerClass::UnconditionalBranch)
+    }
+
+    /// True if the native stack is switched to the virtual stack before returning
+    fn is_vm_exit(&self) -> bool {
+        self.registers[&Registers::Rsp].contains(&Expr::Reg(self.vsp))
+    }
+
+    /// Classifies the handler by its computed semantics
+    pub fn classify(&self,
+                    handler_class: HandlerClass,
+                    operand: u64)
+                    -> Option<HandlerVmInstruction> {
+        let vsp_delta = self.vsp_delta();
+
+        // The bytecode consumed has to agree with the handler class
+        if self.handler_class() != Some(handler_class) {
+            return None;
+        }
+
+        match handler_class {
+            HandlerClass::NoVipChange => self.is_vm_exit().then_some(HandlerVmInstruction::VmExit),
+            HandlerClass::ByteOperand => {
+                for write in self.register_file_writes() {
+                    if self.is_stack_load(&write.value, 0).is_some() &&
+                       vsp_delta == Some(write.size as i64)
+                    {
+                        return Some(HandlerVmInstruction::Pop(write.size, operand as u8));
+                    }
+                }
+
+                for (offset, write) in self.live_stack_writes() {
+                    if self.is_register_file_load(&write.value).is_some() &&
+                       vsp_delta == Some(offset) &&
+                       offset == -(write.size as i64)
+                    {
+                        return Some(HandlerVmInstruction::Push(write.size, operand as u8));
+                    }
+                }
+                None
+            },
+            HandlerClass::WordOperand | HandlerClass::DwordOperand | HandlerClass::QwordOperand => {
+                let (offset, write) = *self.live_stack_writes().first()?;
+                if vsp_delta != Some(offset) {
+                    return None;
+                }
+
+                // The immediate only depends on the bytecode and the rolling key
+                let vip = Expr::Reg(self.vip);
+                let from_bytecode = write.value.loads().iter().all(|load| match load {
+                                                                     Expr::Load(address, _) => {
+                                                                         address.contains(&vip)
+                                                                     },
+                                                                     _ => false,
+                                                                 });
+                if !from_bytecode {
+                    return None;
+                }
+
+                match handler_class {
+                    HandlerClass::WordOperand => {
+                        Some(HandlerVmInstruction::PushImm16(operand as u16))
+                    },
+                    HandlerClass::DwordOperand => {
+                        Some(HandlerVmInstruction::PushImm32(operand as u32))
+                    },
+                    _ => Some(HandlerVmInstruction::PushImm64(operand)),
+                }
+            },
+            HandlerClass::NoOperand => self.classify_no_operand(),
+            HandlerClass::UnconditionalBranch => None,
+        }
+    }
+
+    fn classify_no_operand(&self) -> Option<HandlerVmInstruction> {
+        let vsp_delta = self.vsp_delta();
+
+        // vsp is loaded from the virtual stack
+        if let Some(8) = self.is_stack_load(&self.registers[&self.vsp], 0) {
+            return Some(HandlerVmInstruction::PopVsp(8));
+        }
+
+        // Store: [[vsp]] = [vsp + 8]
+        for write in self.memory_writes() {
+            if self.is_stack_load(&write.address, 0) == Some(8) &&
+               write.value.loads().iter().any(|load| match load {
+                                                 Expr::Load(address, _) => {
+                                                     self.stack_offset(address).is_some()
+                                                 },
+                                                 _ => false,
+                                             })
+            {
+                return Some(HandlerVmInstruction::Store(write.size));
+            }
+        }
+
+        let live_writes = self.live_stack_writes();
+
+        for &(offset, write) in live_writes.iter() {
+            if matches!(write.value, Expr::Flags(_)) {
+                continue;
+            }
+
+            // Push of the vsp value before the push
+            if write.value.strip_extract() == &Expr::Reg(self.vsp) &&
+               vsp_delta == Some(offset) &&
+               offset == -(write.size as i64)
+            {
+                return Some(HandlerVmInstruction::PushVsp(write.size));
+            }
+
+            // Fetch: replaces the address on top of the stack with the value it points to
+            if let Expr::Load(address, size) = write.value.strip_extract() {
+                if self.is_stack_load(address, 0) == Some(8) {
+                    return Some(HandlerVmInstruction::Fetch(*size));
+                }
+            }
+
+            if let Some(instruction) = self.classify_binary(write) {
+                return Some(instruction);
+            }
+        }
+
+        None
+    }
+
+    /// Identifies a two operand arithmetic handler by evaluating the stored result
+    fn classify_binary(&self,
+                       write: &MemoryWrite)
+                       -> Option<HandlerVmInstruction> {
+        let mut operands = Vec::new();
+        for load in write.value.loads() {
+            if let Expr::Load(address, size) = load {
+                if let Some(offset) = self.stack_offset(address) {
+                    if !operands.contains(&(offset, *size)) {
+                        operands.push((offset, *size));
+                    }
+                }
+            }
+        }
+        operands.sort_unstable();
+
+        let (first, second) = match operands.as_slice() {
+            &[first, second] => (first, second),
+            _ => return None,
+        };
+
+        let size = first.1;
+        let mask = size_mask(size);
+        let count_mask = if size == 8 { 0x3f } else { 0x1f };
+
+        type Semantic = fn(u64, u64, u64) -> u64;
+        let candidates: [(HandlerVmInstruction, Semantic); 4] =
+            [(HandlerVmInstruction::Add(size), |a, b, _| a.wrapping_add(b)),
+             (HandlerVmInstruction::Nand(size), |a, b, _| !a | !b),
+             (HandlerVmInstruction::Nor(size), |a, b, _| !a & !b),
+             (HandlerVmInstruction::Shr(size), |a, b, count_mask| a >> (b & count_mask))];
+
+        let mut rng = XorShift64::new(0x9e3779b97f4a7c15);
+        let samples = (0 .. 32).map(|_| {
+                                   (rng.next_u64() & mask, rng.next_u64() & size_mask(second.1))
+                               })
+                               .collect::<Vec<_>>();
+
+        candidates.into_iter().find_map(|(instruction, semantic)| {
+                                  samples.iter()
+                                         .all(|&(a, b)| {
+                                             let result =
+                                                 self.evaluate_with_operands(&write.value,
+                                                                             (first.0, a),
+                                                                             (second.0, b));
+                                             result.map(|result| result & mask) ==
+                                             Some(semantic(a, b, count_mask) & mask)
+                                         })
+                                         .then_some(instruction)
+                              })
+    }
+
+    /// Evaluates a value with the two virtual stack operands replaced by concrete values
+    fn evaluate_with_operands(&self,
+                              value: &Expr,
+                              first: (i64, u64),
+                              second: (i64, u64))
+                              -> Option<u64> {
+        value.evaluate(&mut |leaf| match leaf {
+                 Expr::Load(address, size) => match self.stack_offset(address) {
+                     Some(offset) if offset == first.0 => Some(first.1 & size_mask(*size)),
+                     Some(offset) if offset == second.0 => Some(second.1 & size_mask(*size)),
+                     _ => None,
+                 },
+                 _ => None,
+             })
+    }
+}
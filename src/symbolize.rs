@@ -0,0 +1,487 @@
+use std::{collections::HashMap, fmt::Display};
+
+use pelite::{
+    pe32,
+    pe64::{self, exports::Export, imports::Import},
+    Wrap,
+};
+
+use crate::{
+    elf::ElfFile,
+    image::{Headers, Image},
+    imports::resolve_import_stub,
+    interpreter::{add, nand, nor, shr},
+    pdb::Pdb,
+    register_map::SlotValue,
+    trace::TraceStep,
+    virtual_stack::{TrackedValue, VirtualStack},
+    vm_handler::VmContext,
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
+
+/// Longest string shown for an address pointing at text
+const STRING_PREVIEW_LENGTH: usize = 48;
+
+/// What an address in the image is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageSymbol {
+    pub address: u64,
+    pub section: String,
+    pub rva: u32,
+    /// Closest export, ELF or PDB symbol at or below the address in the same section, with the
+    /// distance to it
+    pub export: Option<(String, u64)>,
+    /// dll!function of the import address table slot at the address
+    pub import: Option<String>,
+    /// dll!function an import protection stub at the address jumps to
+    pub stub_import: Option<String>,
+    pub string: Option<String>,
+}
+
+impl Display for ImageSymbol {
+    fn fmt(&self,
+           f: &mut std::fmt::Formatter<'_>)
+           -> std::fmt::Result {
+        write!(f, "{}:{:#x}", self.section, self.rva)?;
+        match &self.export {
+            Some((name, 0)) => write!(f, " {}", name)?,
+            Some((name, offset)) => write!(f, " {}+{:#x}", name, offset)?,
+            None => {},
+        }
+        if let Some(import) = &self.import {
+            write!(f, " [iat {}]", import)?;
+        }
+        if let Some(import) = &self.stub_import {
+            write!(f, " [stub {}]", import)?;
+        }
+        if let Some(string) = &self.string {
+            write!(f, " {:?}", string)?;
+        }
+        Ok(())
+    }
+}
+
+struct Section {
+    name: String,
+    start: u64,
+    end: u64,
+    readable: bool,
+    executable: bool,
+}
+
+/// Sections, exports and import address table slots of an image, by virtual address
+pub struct ImageSymbols {
+    sections: Vec<Section>,
+    /// Sorted by address
+    exports: Vec<(u64, String)>,
+    iat: HashMap<u64, String>,
+}
+
+impl ImageSymbols {
+    /// Images without headers have no sections, so nothing in them resolves
+    pub fn new(image: &dyn Image) -> Self {
+        match image.headers() {
+            Some(Headers::File(pe_file)) => Self::from_pe(pe_file, image.image_base()),
+            Some(Headers::Mapped(pe_view)) => Self::from_pe(pe_view, image.image_base()),
+            Some(Headers::Elf(elf_file)) => Self::from_elf(elf_file, image.image_base()),
+            None => Self { sections: Vec::new(),
+                           exports: Vec::new(),
+                           iat: HashMap::new() },
+        }
+    }
+
+    fn from_pe<'a, P32: pe32::Pe<'a>, P64: pe64::Pe<'a>>(pe: Wrap<P32, P64>,
+                                                          image_base: u64)
+                                                          -> Self {
+        let sections = pe.section_headers()
+                         .iter()
+                         .map(|section| {
+                             let name = section.name().unwrap_or("?").to_string();
+                             let start = image_base + section.VirtualAddress as u64;
+                             let size = section.VirtualSize.max(section.SizeOfRawData);
+                             let readable = section.Characteristics & IMAGE_SCN_MEM_READ != 0;
+                             let executable =
+                                 section.Characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+                             Section { name,
+                                       start,
+                                       end: start + size as u64,
+                                       readable,
+                                       executable }
+                         })
+                         .collect();
+
+        let mut exports = Vec::new();
+        if let Ok(by) = pe.exports().and_then(|exports| exports.by()) {
+            for (name, export) in by.iter_names() {
+                if let (Ok(name), Ok(Export::Symbol(&rva))) = (name, export) {
+                    exports.push((image_base + rva as u64, name.to_string()));
+                }
+            }
+        }
+        exports.sort();
+
+        // Import address table slots are pointer sized
+        let slot_size = match pe {
+            Wrap::T32(_) => 4,
+            Wrap::T64(_) => 8,
+        };
+        let mut iat = HashMap::new();
+        if let Ok(imports) = pe.imports() {
+            for desc in imports {
+                let (dll_name, names) = match (desc.dll_name(), desc.int()) {
+                    (Ok(dll_name), Ok(names)) => (dll_name, names),
+                    _ => continue,
+                };
+                let first_thunk = image_base + desc.image().FirstThunk as u64;
+                for (index, import) in names.enumerate() {
+                    let name = match import {
+                        Ok(Import::ByName { name, .. }) => name.to_string(),
+                        Ok(Import::ByOrdinal { ord }) => format!("#{}", ord),
+                        Err(_) => continue,
+                    };
+                    iat.insert(first_thunk + index as u64 * slot_size,
+                               format!("{}!{}", dll_name, name));
+                }
+            }
+        }
+
+        Self { sections,
+               exports,
+               iat }
+    }
+
+    /// Allocated sections and the function and object symbols of an ELF file, which has no
+    /// import address table
+    fn from_elf(elf_file: &ElfFile,
+                image_base: u64)
+                -> Self {
+        let bias = image_base.wrapping_sub(elf_file.preferred_base());
+        let sections = elf_file.sections
+                               .iter()
+                               .map(|section| {
+                                   let start = section.vaddr.wrapping_add(bias);
+                                   Section { name: section.name.clone(),
+                                             start,
+                                             end: start + section.size,
+                                             readable: true,
+                                             executable: section.executable }
+                               })
+                               .collect();
+        let exports = elf_file.symbols
+                              .iter()
+                              .map(|symbol| (symbol.vaddr.wrapping_add(bias), symbol.name.clone()))
+                              .collect();
+
+        Self { sections,
+               exports,
+               iat: HashMap::new() }
+    }
+
+    /// Adds the procedures and public symbols of the image's PDB. The sections of the PDB stand
+    /// in for the headers of images without them
+    pub fn add_pdb(&mut self,
+                   pdb: &Pdb,
+                   image_base: u64) {
+        if self.sections.is_empty() {
+            self.sections = pdb.sections
+                               .iter()
+                               .map(|section| {
+                                   let start = image_base + section.rva as u64;
+                                   Section { name: section.name.clone(),
+                                             start,
+                                             end: start + section.size as u64,
+                                             readable: section.readable,
+                                             executable: section.executable }
+                               })
+                               .collect();
+        }
+
+        let pdb_symbols = pdb.symbols
+                             .iter()
+                             .map(|symbol| (image_base + symbol.rva as u64, symbol.name.clone()));
+        self.exports.extend(pdb_symbols);
+        self.exports.sort();
+        self.exports.dedup();
+    }
+
+    /// dll!function of every import address table slot, by slot address
+    pub fn import_slots(&self) -> &HashMap<u64, String> {
+        &self.iat
+    }
+
+    /// Start and end address of every executable section, empty for images without headers
+    /// or a PDB
+    pub fn code_ranges(&self) -> Vec<(u64, u64)> {
+        self.sections
+            .iter()
+            .filter(|section| section.executable)
+            .map(|section| (section.start, section.end))
+            .collect()
+    }
+
+    /// The export, ELF or PDB symbol starting at the address
+    pub fn name_at(&self,
+                   address: u64)
+                   -> Option<&str> {
+        self.exports
+            .iter()
+            .find(|(export_address, _)| *export_address == address)
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// The address followed by what it is, for addresses printed outside of a listing comment
+    pub fn describe(&self,
+                    image: &dyn Image,
+                    address: u64)
+                    -> String {
+        match self.resolve(image, address) {
+            Some(symbol) => format!("{:#x} ({})", address, symbol),
+            None => format!("{:#x}", address),
+        }
+    }
+
+    /// The reference of the handler at index to a constant, if the constant is in the image
+    pub fn reference(&self,
+                     image: &dyn Image,
+                     index: usize,
+                     (usage, address): (SymbolUse, u64))
+                     -> Option<SymbolReference> {
+        let mut symbol = self.resolve(image, address)?;
+        // Virtualized code pushes the stub of an import before it exits into it
+        if usage == SymbolUse::Immediate {
+            symbol.stub_import = resolve_import_stub(image, self, address).map(|stub| stub.import);
+        }
+        Some(SymbolReference { index,
+                               usage,
+                               symbol })
+    }
+
+    /// Describes the address if it lies in a section of the image
+    pub fn resolve(&self,
+                   image: &dyn Image,
+                   address: u64)
+                   -> Option<ImageSymbol> {
+        let section = self.sections
+                          .iter()
+                          .find(|section| (section.start .. section.end).contains(&address))?;
+        let image_base = image.image_base();
+
+        let export = self.exports
+                         .iter()
+                         .rev()
+                         .find(|(export_address, _)| *export_address <= address)
+                         .filter(|(export_address, _)| *export_address >= section.start)
+                         .map(|(export_address, name)| (name.clone(), address - export_address));
+
+        let string = match section.readable {
+            true => string_preview(image, address),
+            false => None,
+        };
+
+        Some(ImageSymbol { address,
+                           section: section.name.clone(),
+                           rva: (address - image_base) as u32,
+                           export,
+                           import: self.iat.get(&address).cloned(),
+                           stub_import: None,
+                           string })
+    }
+}
+
+/// Printable ASCII or UTF-16 text of at least four characters at the address
+fn string_preview(image: &dyn Image,
+                  address: u64)
+                  -> Option<String> {
+    let bytes = image.read(address, STRING_PREVIEW_LENGTH * 2)?;
+
+    let is_text = |c: u16| c == 0x9 || c == 0xa || c == 0xd || (0x20 .. 0x7f).contains(&c);
+
+    let ascii = bytes.iter()
+                     .take_while(|&&byte| is_text(byte as u16))
+                     .take(STRING_PREVIEW_LENGTH)
+                     .map(|&byte| byte as char)
+                     .collect::<String>();
+    let utf16 = bytes.chunks_exact(2)
+                     .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                     .take_while(|&c| is_text(c))
+                     .take(STRING_PREVIEW_LENGTH)
+                     .map(|c| c as u8 as char)
+                     .collect::<String>();
+
+    [ascii, utf16].into_iter().filter(|text| text.len() >= 4).max_by_key(|text| text.len())
+}
+
+/// How a constant is used by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolUse {
+    Immediate,
+    FetchAddress,
+    StoreAddress,
+}
+
+impl Display for SymbolUse {
+    fn fmt(&self,
+           f: &mut std::fmt::Formatter<'_>)
+           -> std::fmt::Result {
+        match self {
+            SymbolUse::Immediate => write!(f, "immediate"),
+            SymbolUse::FetchAddress => write!(f, "fetch"),
+            SymbolUse::StoreAddress => write!(f, "store"),
+        }
+    }
+}
+
+/// A constant on the virtual stack, None when it is not known
+impl TrackedValue for Option<u64> {
+    type Slot = u64;
+
+    fn entry(_: SlotValue,
+             constant: Option<u64>)
+             -> Self {
+        constant
+    }
+
+    fn slot_value(&self) -> Option<u64> {
+        *self
+    }
+
+    fn from_slot(_: u8,
+                 slot: Option<u64>)
+                 -> Self {
+        slot
+    }
+
+    fn immediate(_: HandlerVmInstruction,
+                 value: u64)
+                 -> Self {
+        Some(value)
+    }
+
+    fn computed(_: HandlerVmInstruction) -> Self {
+        None
+    }
+
+    fn result(instruction: HandlerVmInstruction,
+              size: usize,
+              first: Option<Self>,
+              second: Option<Self>)
+              -> Self {
+        let operation: fn(u64, u64, usize) -> (u64, bool, bool) = match instruction {
+            HandlerVmInstruction::Add(_) => add,
+            HandlerVmInstruction::Nand(_) => nand,
+            HandlerVmInstruction::Nor(_) => nor,
+            HandlerVmInstruction::Shr(_) => shr,
+            _ => return None,
+        };
+        first.flatten()
+             .zip(second.flatten())
+             .map(|(first, second)| operation(first, second, size).0)
+    }
+
+    fn flags(_: HandlerVmInstruction) -> Self {
+        None
+    }
+}
+
+/// Follows constants through the virtual stack and register file, so the addresses fetch and
+/// store use are known when they are computed from immediates and the relocation delta
+#[derive(Debug, Clone)]
+pub struct ConstantTracker {
+    virtual_stack: VirtualStack<Option<u64>>,
+}
+
+impl ConstantTracker {
+    pub fn new(vm_context: &VmContext,
+               image: &dyn Image)
+               -> Self {
+        Self { virtual_stack: VirtualStack::new(vm_context, image) }
+    }
+
+    /// Applies a handler, returning the constant it pushes or the constant address it accesses
+    pub fn step(&mut self,
+                step: &TraceStep)
+                -> Option<(SymbolUse, u64)> {
+        let word_size = self.virtual_stack.word_size;
+        let address = || self.virtual_stack.peek(word_size).copied().flatten();
+        let used = match (step.handler_class, step.instruction) {
+            (HandlerClass::UnconditionalBranch, _) => None,
+            (_, HandlerVmInstruction::PushImm64(value)) => Some((SymbolUse::Immediate, value)),
+            (_, HandlerVmInstruction::PushImm32(value)) => {
+                Some((SymbolUse::Immediate, value as u64))
+            },
+            (_, HandlerVmInstruction::Fetch(_)) => {
+                address().map(|address| (SymbolUse::FetchAddress, address))
+            },
+            (_, HandlerVmInstruction::Store(_)) => {
+                address().map(|address| (SymbolUse::StoreAddress, address))
+            },
+            _ => None,
+        };
+
+        self.virtual_stack.step(step);
+        used
+    }
+
+    /// The address vmexit returns to, when it is a constant. vmexit pops pop_count registers
+    /// before it returns
+    pub fn vm_exit_continuation(&self,
+                                pop_count: usize)
+                                -> Option<u64> {
+        self.virtual_stack.word_below(pop_count).copied().flatten()
+    }
+
+    /// The constant below the continuation, the return address of the code the vm exits into.
+    /// Native calls out of virtualized code return into another vm call this way
+    pub fn vm_exit_return_address(&self,
+                                  pop_count: usize)
+                                  -> Option<u64> {
+        self.virtual_stack.word_below(pop_count + 1).copied().flatten()
+    }
+}
+
+/// An instruction of a trace using a constant that lies in the image
+#[derive(Debug, Clone)]
+pub struct SymbolReference {
+    /// Index of the handler in the trace
+    pub index: usize,
+    pub usage: SymbolUse,
+    pub symbol: ImageSymbol,
+}
+
+impl Display for SymbolReference {
+    fn fmt(&self,
+           f: &mut std::fmt::Formatter<'_>)
+           -> std::fmt::Result {
+        match self.usage {
+            SymbolUse::Immediate => write!(f, "{}", self.symbol),
+            usage => write!(f, "{} [{:#x}] {}", usage, self.symbol.address, self.symbol),
+        }
+    }
+}
+
+/// The symbols of every constant in the image a trace pushes, fetches from or stores to
+pub fn symbolize_trace(image: &dyn Image,
+                       symbols: &ImageSymbols,
+                       vm_context: &VmContext,
+                       steps: &[TraceStep])
+                       -> Vec<SymbolReference> {
+    let mut tracker = ConstantTracker::new(vm_context, image);
+
+    steps.iter()
+         .enumerate()
+         .filter_map(|(index, step)| {
+             symbols.reference(image, index, tracker.step(step)?)
+         })
+         .collect()
+}
+
+/// Listing comment describing what a constant of the instruction points at
+pub fn format_symbol_comment(reference: Option<&SymbolReference>) -> String {
+    match reference {
+        Some(reference) => format!(" ; {}", reference),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,1349 @@
+use iced_x86::{Code, Encoder, Instruction, Mnemonic, Register};
+use pelite::pe64::{Pe, PeFile};
+
+use crate::{
+    callgraph::{build_call_graph, EdgeKind, NodeKind},
+    emulator::{emulate_vm_call, EmulationEnd, X86Emulator},
+    fixture::{
+        build_minidump, build_pdb, generate, round_trip_program, round_trip_program_sized,
+        DumpModule, Fixture, FixtureConfig, FixtureFormat, FIXTURE_IMPORTS, FIXTURE_PDB_IDENTITY,
+        IMAGE_BASE, IMAGE_BASE_32, SAVED_REGISTERS, SECTION_RVA,
+    },
+    functions::{virtualized_functions, FunctionTable},
+    image::{ElfImage, FileImage, Image, MappedImage, MinidumpImage, RawImage},
+    imports::{describe_continuation, resolve_import_call, resolve_import_stub},
+    interpreter::{execute_vm_call, ExecutionEnd, NativeState},
+    minidump::Minidump,
+    pdb::Pdb,
+    recording::{align_recording, parse_recording, recorded_dispatches, Alignment},
+    report::HandlerInventory,
+    register_map::{RegisterMap, SlotValue, StackValue},
+    symbolize::{symbolize_trace, ConstantTracker, ImageSymbols, SymbolUse},
+    trace::{disassemble_partial_trace, disassemble_trace},
+    util::{handle_vm_call, is_vm_call, XorShift64},
+    validate::{validate_trace, ValidationOutcome},
+    vm_handler::{is_vm_entry_call, Registers, VmHandler, VmRegisterAllocation},
+    vm_map::{build_virtualization_map, scan_vm_calls, RoutineEnd},
+    vm_matchers::HandlerVmInstruction,
+};
+
+const SEEDS: [u64; 8] = [1, 2, 3, 0x1337, 0xdead, 0xbeef, 0x5eed, 0xc0ffee];
+
+/// Every instruction the generator has handlers for
+fn every_instruction(rng: &mut XorShift64) -> Vec<HandlerVmInstruction> {
+    let mut offset = || (rng.next_u64() % 0x20) as u8 * 8;
+    let mut program = vec![HandlerVmInstruction::Pop(8, offset()),
+                           HandlerVmInstruction::Pop(4, offset()),
+                           HandlerVmInstruction::Pop(2, offset()),
+                           HandlerVmInstruction::Push(8, offset()),
+                           HandlerVmInstruction::Push(4, offset()),
+                           HandlerVmInstruction::Push(2, offset())];
+    program.extend([HandlerVmInstruction::PushImm64(rng.next_u64()),
+                    HandlerVmInstruction::PushImm32(rng.next_u64() as u32),
+                    HandlerVmInstruction::PushImm16(rng.next_u64() as u16),
+                    HandlerVmInstruction::PushVsp(8),
+                    HandlerVmInstruction::PushVsp(4),
+                    HandlerVmInstruction::PushVsp(2),
+                    HandlerVmInstruction::PopVsp(8),
+                    HandlerVmInstruction::Shr(8),
+                    HandlerVmInstruction::Shr(2),
+                    HandlerVmInstruction::Shr(1),
+                    HandlerVmInstruction::Fetch(8),
+                    HandlerVmInstruction::Fetch(4),
+                    HandlerVmInstruction::Fetch(2),
+                    HandlerVmInstruction::Fetch(1),
+                    HandlerVmInstruction::Store(8)]);
+    for size in [8, 4, 2, 1] {
+        program.extend([HandlerVmInstruction::Add(size),
+                        HandlerVmInstruction::Nand(size),
+                        HandlerVmInstruction::Nor(size)]);
+    }
+    program.push(HandlerVmInstruction::VmExit);
+    program
+}
+
+/// Every instruction the generator has 32 bit handlers for
+fn every_instruction_32(rng: &mut XorShift64) -> Vec<HandlerVmInstruction> {
+    let mut offset = || (rng.next_u64() % 0x40) as u8 * 4;
+    let mut program = vec![HandlerVmInstruction::Pop(4, offset()),
+                           HandlerVmInstruction::Pop(2, offset()),
+                           HandlerVmInstruction::Push(4, offset()),
+                           HandlerVmInstruction::Push(2, offset())];
+    program.extend([HandlerVmInstruction::PushImm32(rng.next_u64() as u32),
+                    HandlerVmInstruction::PushImm16(rng.next_u64() as u16),
+                    HandlerVmInstruction::PushVsp(4),
+                    HandlerVmInstruction::PushVsp(2),
+                    HandlerVmInstruction::PopVsp(4),
+                    HandlerVmInstruction::Shr(2),
+                    HandlerVmInstruction::Shr(1),
+                    HandlerVmInstruction::Fetch(4),
+                    HandlerVmInstruction::Fetch(2),
+                    HandlerVmInstruction::Fetch(1),
+                    HandlerVmInstruction::Store(4)]);
+    for size in [4, 2, 1] {
+        program.extend([HandlerVmInstruction::Add(size),
+                        HandlerVmInstruction::Nand(size),
+                        HandlerVmInstruction::Nor(size)]);
+    }
+    program.push(HandlerVmInstruction::VmExit);
+    program
+}
+
+fn random_state(push_order: &[Registers],
+                rng: &mut XorShift64)
+                -> NativeState {
+    let mut state = NativeState::default();
+    for &reg in push_order {
+        let value = match reg {
+            Registers::Flags => 0x202 | (rng.next_u64() & 0x8d5),
+            _ => rng.next_u64(),
+        };
+        state.registers.insert(reg, value);
+    }
+    state
+}
+
+fn assert_same_allocation(found: &VmRegisterAllocation,
+                          expected: &VmRegisterAllocation) {
+    assert_eq!(found.vip, expected.vip);
+    assert_eq!(found.vsp, expected.vsp);
+    assert_eq!(found.key, expected.key);
+    assert_eq!(found.handler_address, expected.handler_address);
+}
+
+fn assert_disassembles(fixture: &Fixture,
+                       config: &FixtureConfig) {
+    assert_image_disassembles(&fixture.image(), fixture, config);
+}
+
+fn assert_image_disassembles(image: &dyn Image,
+                             fixture: &Fixture,
+                             config: &FixtureConfig) {
+    let (vm_context, steps) = disassemble_trace(image, fixture.vm_call_address);
+
+    assert_same_allocation(&vm_context.register_allocation, &config.reg_allocation);
+    assert_eq!(vm_context.push_order, config.push_order);
+    assert_eq!(vm_context.vip_direction_forwards, config.vip_direction_forwards);
+
+    let program = steps.iter().map(|step| step.instruction).collect::<Vec<_>>();
+    assert_eq!(program, fixture.program, "seed {:#x}", config.seed);
+    let handler_addresses = steps.iter().map(|step| step.handler_address).collect::<Vec<_>>();
+    assert_eq!(handler_addresses, fixture.handler_addresses);
+
+    // The symbolic state alone tells the class, the vip update matchers agree with it
+    let reg_allocation = &vm_context.register_allocation;
+    for step in steps.iter() {
+        let semantics = step.handler.compute_semantics(reg_allocation);
+        assert_eq!(semantics.handler_class(), Some(step.handler_class));
+        assert_eq!(step.handler.match_handler_class(reg_allocation), Ok(step.handler_class));
+    }
+}
+
+#[test]
+fn disassembly_reproduces_every_instruction() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let program = every_instruction(&mut XorShift64::new(seed));
+        assert_disassembles(&generate(&config, &program), &config);
+    }
+}
+
+#[test]
+fn disassembly_reproduces_both_vip_directions() {
+    for seed in SEEDS {
+        for vip_direction_forwards in [true, false] {
+            let config = FixtureConfig { vip_direction_forwards,
+                                         ..FixtureConfig::random(seed) };
+            let program = every_instruction(&mut XorShift64::new(!seed));
+            assert_disassembles(&generate(&config, &program), &config);
+        }
+    }
+}
+
+#[test]
+fn disassembly_reproduces_unmutated_handlers() {
+    for seed in SEEDS {
+        let config = FixtureConfig { mutate: false,
+                                     handler_copies: 1,
+                                     ..FixtureConfig::random(seed) };
+        let program = every_instruction(&mut XorShift64::new(seed));
+        assert_disassembles(&generate(&config, &program), &config);
+    }
+}
+
+#[test]
+fn disassembly_reproduces_32_bit_images() {
+    for seed in SEEDS {
+        for vip_direction_forwards in [true, false] {
+            let config = FixtureConfig { vip_direction_forwards,
+                                         ..FixtureConfig::random_32(seed) };
+            let program = every_instruction_32(&mut XorShift64::new(seed));
+            let fixture = generate(&config, &program);
+            assert_eq!(fixture.image().bitness(), 32);
+            assert_disassembles(&fixture, &config);
+        }
+    }
+}
+
+#[test]
+fn register_file_slots_of_32_bit_images_follow_push_order() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random_32(seed);
+        let program = round_trip_program_sized(&config.push_order, 4);
+        let fixture = generate(&config, &program);
+        let image = fixture.image();
+        let (vm_context, steps) = disassemble_trace(&image, fixture.vm_call_address);
+
+        let mut register_map = RegisterMap::new(&vm_context, &image);
+        let moved = steps.iter().map(|step| register_map.step(step)).collect::<Vec<_>>();
+
+        assert_eq!(register_map.slot(0x80, 4), Some(SlotValue::RelocationDelta));
+        for (index, &reg) in config.push_order.iter().enumerate() {
+            assert_eq!(register_map.slot((index * 4) as u8, 4), Some(SlotValue::Native(reg)));
+        }
+
+        let count = config.push_order.len();
+        let pushed = config.push_order.iter().map(|&reg| Some(SlotValue::Native(reg)));
+        assert!(moved[1 + count ..= 2 * count].iter().copied().eq(pushed), "seed {:#x}", seed);
+    }
+}
+
+#[test]
+fn elf_images_decode_like_pe_images() {
+    for seed in SEEDS {
+        let config = FixtureConfig { format: FixtureFormat::Elf,
+                                     ..FixtureConfig::random(seed) };
+        let program = every_instruction(&mut XorShift64::new(seed));
+        let fixture = generate(&config, &program);
+        let image = ElfImage::new(fixture.bytes.clone(), None).unwrap();
+        assert_eq!(image.image_base(), IMAGE_BASE);
+        assert_image_disassembles(&image, &fixture, &config);
+    }
+}
+
+#[test]
+fn elf_symbols_name_addresses() {
+    let load_address = 0x5555_5555_4000;
+    let config = FixtureConfig { format: FixtureFormat::Elf,
+                                 ..FixtureConfig::random(0xe1f) };
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let image = ElfImage::new(fixture.bytes.clone(), Some(load_address)).unwrap();
+    let symbols = ImageSymbols::new(&image);
+
+    let vm_call_address = fixture.vm_call_address - IMAGE_BASE + load_address;
+    let symbol = symbols.resolve(&image, vm_call_address + 2).unwrap();
+    assert_eq!(symbol.section, ".text");
+    assert_eq!(symbol.rva as u64, fixture.vm_call_address - IMAGE_BASE + 2);
+    assert_eq!(symbol.export, Some(("vm_call".to_string(), 2)));
+    assert!(symbols.resolve(&image, load_address - 1).is_none());
+}
+
+#[test]
+fn interpreter_restores_registers_at_vm_exit() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let image = fixture.image();
+        let initial = random_state(&config.push_order, &mut XorShift64::new(seed));
+
+        let (_, steps, _, end) =
+            execute_vm_call(&image, fixture.vm_call_address, &initial, 0x100).unwrap();
+        assert_eq!(steps.len(), fixture.program.len());
+
+        match end {
+            ExecutionEnd::Exit(state, return_address) => {
+                assert_eq!(return_address, fixture.return_address);
+                for &reg in config.push_order.iter() {
+                    assert_eq!(state.register(reg), initial.register(reg), "{:?}", reg);
+                }
+            },
+            end => panic!("Execution ended with {:?}", end),
+        }
+    }
+}
+
+/// The fixture's image with the first handler not run before the middle of the program
+/// replaced by one that moves vip past an operand but decrypts no next handler: add vip, 4;
+/// ret. Returns the index of the instruction first running it and its address
+fn undecodable_handler_image(config: &FixtureConfig,
+                             fixture: &Fixture)
+                             -> (FileImage, usize, u64) {
+    let index = (fixture.program.len() / 2 ..).find(|&index| {
+        !fixture.handler_addresses[.. index].contains(&fixture.handler_addresses[index])
+    }).unwrap();
+    let handler_address = fixture.handler_addresses[index];
+    let add = Instruction::with2(Code::Add_rm64_imm8,
+                                 Register::from(config.reg_allocation.vip),
+                                 4).unwrap();
+    let mut encoder = Encoder::new(64);
+    encoder.encode(&add, handler_address).unwrap();
+    let mut code = encoder.take_buffer();
+    code.push(0xc3);
+
+    let mut bytes = fixture.bytes.clone();
+    let rva = (handler_address - IMAGE_BASE) as u32;
+    let offset = PeFile::from_bytes(&bytes).unwrap().rva_to_file_offset(rva).unwrap();
+    bytes[offset .. offset + code.len()].copy_from_slice(&code);
+    (FileImage::new(bytes, None).unwrap(), index, handler_address)
+}
+
+#[test]
+fn interpreter_stops_at_undecodable_handlers() {
+    let config = FixtureConfig::random(SEEDS[0]);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let (image, index, handler_address) = undecodable_handler_image(&config, &fixture);
+    let initial = random_state(&config.push_order, &mut XorShift64::new(SEEDS[0]));
+
+    let (_, steps, _, end) =
+        execute_vm_call(&image, fixture.vm_call_address, &initial, 0x100).unwrap();
+    assert_eq!(steps.len(), index);
+    match end {
+        ExecutionEnd::DecodeFailed(address, _) => assert_eq!(address, handler_address),
+        end => panic!("Execution ended with {:?}", end),
+    }
+}
+
+#[test]
+fn emulation_restores_registers_at_vm_exit() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let image = fixture.image();
+        let initial = random_state(&config.push_order, &mut XorShift64::new(seed));
+
+        let (snapshots, emulator, end) =
+            emulate_vm_call(&image, fixture.vm_call_address, &initial, 0x10000);
+        assert!(matches!(end, EmulationEnd::VmExit(address) if address == fixture.return_address),
+                "Emulation ended with {:?}",
+                end);
+
+        let handler_addresses = snapshots.iter()
+                                         .map(|snapshot| snapshot.handler_address)
+                                         .collect::<Vec<_>>();
+        assert_eq!(handler_addresses, fixture.handler_addresses);
+
+        let state = emulator.native_state();
+        for reg in SAVED_REGISTERS {
+            assert_eq!(state.register(reg), initial.register(reg), "{:?}", reg);
+        }
+        assert_eq!(emulator.rflags, initial.register(Registers::Flags));
+    }
+}
+
+#[test]
+fn handler_inventory_merges_every_sighting() {
+    let config = FixtureConfig::random(0x1a7e);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let image = fixture.image();
+    let trace = || disassemble_trace(&image, fixture.vm_call_address);
+
+    // The same handler decoding as a push in one trace and failing in the other
+    let (vm_context, steps) = trace();
+    let index = steps.iter()
+                     .position(|step| matches!(step.instruction, HandlerVmInstruction::Push(..)))
+                     .unwrap();
+    let handler_address = steps[index].handler_address;
+    let (_, mut failed_steps) = trace();
+    failed_steps[index].instruction = HandlerVmInstruction::UnknownByteOperand;
+
+    for failed_first in [false, true] {
+        let mut inventory = HandlerInventory::default();
+        let reg_allocation = &vm_context.register_allocation;
+        match failed_first {
+            true => {
+                inventory.add_trace(fixture.vm_call_address, reg_allocation, &failed_steps);
+                inventory.add_trace(fixture.vm_call_address, reg_allocation, &steps);
+            },
+            false => {
+                inventory.add_trace(fixture.vm_call_address, reg_allocation, &steps);
+                inventory.add_trace(fixture.vm_call_address, reg_allocation, &failed_steps);
+            },
+        }
+
+        let entry = inventory.entries
+                             .iter()
+                             .find(|entry| entry.handler_address == handler_address)
+                             .unwrap();
+        assert!(entry.is_unknown);
+        assert!(entry.is_inconsistent());
+        assert!(entry.matched.contains(&"vm_match_vm_reg_push"), "{:?}", entry.matched);
+        assert!(inventory.entries
+                         .iter()
+                         .filter(|entry| entry.handler_address != handler_address)
+                         .all(|entry| !entry.is_unknown && !entry.is_inconsistent()));
+    }
+}
+
+#[test]
+fn handler_inventory_keeps_undecodable_handlers() {
+    let config = FixtureConfig::random(SEEDS[1]);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let (image, index, handler_address) = undecodable_handler_image(&config, &fixture);
+
+    let (vm_context, steps, failure) =
+        disassemble_partial_trace(&image, fixture.vm_call_address).unwrap();
+    assert_eq!(steps.len(), index);
+    assert_eq!(vm_context.handler_address, handler_address);
+
+    let mut inventory = HandlerInventory::default();
+    let reg_allocation = &vm_context.register_allocation;
+    inventory.add_trace(fixture.vm_call_address, reg_allocation, &steps);
+    inventory.add_decode_failure(fixture.vm_call_address,
+                                 &image,
+                                 reg_allocation,
+                                 handler_address,
+                                 &failure.unwrap());
+
+    let entry = inventory.entries
+                         .iter()
+                         .find(|entry| entry.handler_address == handler_address)
+                         .unwrap();
+    assert!(entry.is_unknown);
+    assert!(entry.handler_class.is_none());
+    assert_eq!(entry.decode_failures.len(), 1);
+    assert_eq!(entry.instructions.last().map(|instruction| instruction.mnemonic()),
+               Some(Mnemonic::Ret));
+}
+
+#[test]
+fn handlers_agree_with_their_semantics() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let program = every_instruction(&mut XorShift64::new(seed));
+        let fixture = generate(&config, &program);
+        let image = fixture.image();
+
+        let validations =
+            validate_trace(&image, fixture.vm_call_address, 16, seed).unwrap();
+        assert!(!validations.is_empty());
+        for validation in validations {
+            assert!(matches!(validation.outcome, ValidationOutcome::Agrees(_)),
+                    "{:?} at {:#x}: {:?}",
+                    validation.instruction,
+                    validation.handler_address,
+                    validation.outcome);
+        }
+    }
+}
+
+#[test]
+fn validation_fails_on_undecodable_handlers() {
+    let config = FixtureConfig::random(SEEDS[2]);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let (image, _, _) = undecodable_handler_image(&config, &fixture);
+    assert!(validate_trace(&image, fixture.vm_call_address, 1, SEEDS[2]).is_err());
+}
+
+#[test]
+fn canonical_forms_keep_live_flags() {
+    // cmp rax, rcx; lea rbp, [rbp+8]; pushfq; inc rax; pushfq; lea rbp, [rbp+8]; inc rax;
+    // jmp rdx
+    let code = [0x48, 0x39, 0xc8, 0x48, 0x8d, 0x6d, 0x08, 0x9c, 0x48, 0xff, 0xc0, 0x9c, 0x48,
+                0x8d, 0x6d, 0x08, 0x48, 0xff, 0xc0, 0xff, 0xe2];
+    let image = RawImage::new(code.to_vec(), IMAGE_BASE);
+    let vm_handler = VmHandler::new(IMAGE_BASE, &image);
+    let mnemonics = vm_handler.instructions
+                              .iter()
+                              .map(|instruction| instruction.mnemonic())
+                              .collect::<Vec<_>>();
+    // Flags are dead at the dispatch, so only the last lea and inc are rewritten
+    assert_eq!(mnemonics,
+               [Mnemonic::Cmp,
+                Mnemonic::Lea,
+                Mnemonic::Pushfq,
+                Mnemonic::Inc,
+                Mnemonic::Pushfq,
+                Mnemonic::Add,
+                Mnemonic::Add,
+                Mnemonic::Jmp]);
+
+    // The flags the cmp leaves are pushed, so it is no junk
+    let reg_allocation = VmRegisterAllocation { vip: Registers::Rsi,
+                                                vsp: Registers::Rbp,
+                                                key: Registers::Rbx,
+                                                handler_address: Registers::Rdx };
+    let deobfuscated = vm_handler.deobfuscate(&reg_allocation);
+    assert_eq!(deobfuscated[0].mnemonic(), Mnemonic::Cmp);
+}
+
+#[test]
+fn cancelling_pairs_on_32_bit_registers_keep_the_zero_extension() {
+    // not eax; not eax; add eax, 5; sub eax, 5; xchg eax, ecx; xchg eax, ecx; not rax;
+    // not rax; jmp rdx
+    let code = [0xf7, 0xd0, 0xf7, 0xd0, 0x83, 0xc0, 0x05, 0x83, 0xe8, 0x05, 0x91, 0x91, 0x48,
+                0xf7, 0xd0, 0x48, 0xf7, 0xd0, 0xff, 0xe2];
+    let image = RawImage::new(code.to_vec(), IMAGE_BASE);
+    let reg_allocation = VmRegisterAllocation { vip: Registers::Rax,
+                                                vsp: Registers::Rbp,
+                                                key: Registers::Rcx,
+                                                handler_address: Registers::Rdx };
+    let mnemonics = |vm_handler: &VmHandler| {
+        vm_handler.deobfuscate(&reg_allocation)
+                  .iter()
+                  .map(|instruction| instruction.mnemonic())
+                  .collect::<Vec<_>>()
+    };
+
+    let vm_handler = VmHandler::new(IMAGE_BASE, &image);
+    assert_eq!(mnemonics(&vm_handler),
+               [Mnemonic::Not,
+                Mnemonic::Not,
+                Mnemonic::Add,
+                Mnemonic::Sub,
+                Mnemonic::Xchg,
+                Mnemonic::Xchg,
+                Mnemonic::Jmp]);
+
+    // 32 bit code has no upper half to clear
+    let vm_handler = VmHandler { bitness: 32,
+                                 ..vm_handler };
+    assert_eq!(mnemonics(&vm_handler), [Mnemonic::Jmp]);
+}
+
+#[test]
+fn register_file_slots_follow_push_order() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let image = fixture.image();
+        let (vm_context, steps) =
+            disassemble_trace(&image, fixture.vm_call_address);
+
+        let mut register_map = RegisterMap::new(&vm_context, &image);
+        let moved = steps.iter().map(|step| register_map.step(step)).collect::<Vec<_>>();
+
+        assert_eq!(register_map.slot(0x80, 8), Some(SlotValue::RelocationDelta));
+        for (index, &reg) in config.push_order.iter().enumerate() {
+            assert_eq!(register_map.slot((index * 8) as u8, 8), Some(SlotValue::Native(reg)));
+        }
+
+        let count = config.push_order.len();
+        assert_eq!(moved[0], Some(SlotValue::RelocationDelta));
+        let pushed = config.push_order.iter().map(|&reg| Some(SlotValue::Native(reg)));
+        assert!(moved[1 + count ..= 2 * count].iter().copied().eq(pushed), "seed {:#x}", seed);
+    }
+}
+
+#[test]
+fn vm_exit_assignment_follows_final_pushes() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let image = fixture.image();
+        let (vm_context, steps) =
+            disassemble_trace(&image, fixture.vm_call_address);
+
+        let mut register_map = RegisterMap::new(&vm_context, &image);
+        for step in steps.iter() {
+            register_map.step(step);
+        }
+
+        let vm_exit = &steps.last().unwrap().handler;
+        let pop_order = vm_exit.get_pop_order_vm_exit();
+        assert!(pop_order.iter().eq(config.push_order.iter().rev()));
+
+        for (reg, value) in register_map.vm_exit_assignment(&pop_order) {
+            let index = config.push_order.iter().position(|&pushed| pushed == reg).unwrap();
+            let expected = StackValue::Register((index * 8) as u8, Some(SlotValue::Native(reg)));
+            assert_eq!(value, Some(expected), "seed {:#x}", seed);
+        }
+    }
+}
+
+/// A program fetching from one address of the code section and storing to another, returned
+/// after the two addresses
+fn constant_address_program() -> (u64, u64, [HandlerVmInstruction; 11]) {
+    let fetched = IMAGE_BASE + SECTION_RVA + 0x20;
+    let stored = IMAGE_BASE + SECTION_RVA + 0x48;
+    let program = [HandlerVmInstruction::Pop(8, 0x80),
+                   HandlerVmInstruction::PushImm64(fetched),
+                   HandlerVmInstruction::Fetch(8),
+                   HandlerVmInstruction::Pop(8, 0),
+                   HandlerVmInstruction::PushImm64(0x1234),
+                   HandlerVmInstruction::Push(8, 0x80),
+                   HandlerVmInstruction::PushImm64(stored),
+                   HandlerVmInstruction::Add(8),
+                   HandlerVmInstruction::Pop(8, 8),
+                   HandlerVmInstruction::Store(8),
+                   HandlerVmInstruction::VmExit];
+    (fetched, stored, program)
+}
+
+#[test]
+fn constant_addresses_resolve_to_the_image() {
+    let (fetched, stored, program) = constant_address_program();
+
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &program);
+        let image = fixture.image();
+        let (vm_context, steps) =
+            disassemble_trace(&image, fixture.vm_call_address);
+
+        let symbols = ImageSymbols::new(&image);
+        let references = symbolize_trace(&image, &symbols, &vm_context, &steps);
+        let found = references.iter()
+                              .map(|reference| {
+                                  assert_eq!(reference.symbol.section, ".text");
+                                  let rva = reference.symbol.address - IMAGE_BASE;
+                                  assert_eq!(reference.symbol.rva as u64, rva);
+                                  (reference.index, reference.usage, reference.symbol.address)
+                              })
+                              .collect::<Vec<_>>();
+        assert_eq!(found,
+                   [(1, SymbolUse::Immediate, fetched),
+                    (2, SymbolUse::FetchAddress, fetched),
+                    (6, SymbolUse::Immediate, stored),
+                    (9, SymbolUse::StoreAddress, stored)]);
+    }
+}
+
+/// The address vmexit continues at, from the constants the trace leaves on the stack
+fn vm_exit_continuation(image: &dyn Image,
+                        vm_call_address: u64)
+                        -> Option<u64> {
+    let (vm_context, steps) = disassemble_trace(image, vm_call_address);
+    let mut tracker = ConstantTracker::new(&vm_context, image);
+    for step in steps.iter() {
+        tracker.step(step);
+    }
+    let pop_order = steps.last().unwrap().handler.get_pop_order_vm_exit();
+    tracker.vm_exit_continuation(pop_order.len())
+}
+
+#[test]
+fn vm_exit_continues_after_the_vm_call() {
+    for seed in SEEDS {
+        for config in [FixtureConfig::random(seed), FixtureConfig::random_32(seed)] {
+            let word_size = config.bitness as usize / 8;
+            let program = round_trip_program_sized(&config.push_order, word_size);
+            let fixture = generate(&config, &program);
+            let image = fixture.image();
+            assert_eq!(vm_exit_continuation(&image, fixture.vm_call_address),
+                       Some(fixture.return_address),
+                       "seed {:#x}",
+                       seed);
+        }
+    }
+}
+
+#[test]
+fn pdb_symbols_name_vm_call_sites_and_constants() {
+    let (fetched, stored, program) = constant_address_program();
+    let config = FixtureConfig::random(0xdb);
+    let fixture = generate(&config, &program);
+    let image = fixture.image();
+
+    let rva = |address: u64| (address - IMAGE_BASE) as u32;
+    let vm_call_rva = rva(fixture.vm_call_address);
+    let pdb_bytes = build_pdb(FIXTURE_PDB_IDENTITY,
+                              &[(".text", SECTION_RVA as u32, 0x10000)],
+                              &[("protected_function", vm_call_rva)],
+                              &[("?protected_function@@YAXXZ", vm_call_rva),
+                                ("g_table", rva(fetched)),
+                                ("g_output", rva(stored))]);
+    let pdb = Pdb::parse(&pdb_bytes).unwrap();
+    assert_eq!(pdb.sections.len(), 1);
+    // The procedure hides the decorated public symbol at its address
+    assert_eq!(pdb.symbols.len(), 3);
+    assert_eq!(pdb.check_image(&image), Ok(()));
+
+    // Block sizes other than the ones MSF 7.0 allows are refused
+    for block_size in [0u32, 3000, 0x10000] {
+        let mut bytes = pdb_bytes.clone();
+        bytes[32 .. 36].copy_from_slice(&block_size.to_le_bytes());
+        assert!(Pdb::parse(&bytes).is_err());
+    }
+
+    let mut symbols = ImageSymbols::new(&image);
+    symbols.add_pdb(&pdb, image.image_base());
+
+    let call_site = symbols.resolve(&image, fixture.vm_call_address + 5).unwrap();
+    assert_eq!(call_site.export, Some(("protected_function".to_string(), 5)));
+
+    let (vm_context, steps) = disassemble_trace(&image, fixture.vm_call_address);
+    let references = symbolize_trace(&image, &symbols, &vm_context, &steps);
+    let named = references.iter()
+                          .filter(|reference| reference.usage != SymbolUse::Immediate)
+                          .map(|reference| reference.symbol.export.clone().unwrap())
+                          .collect::<Vec<_>>();
+    assert_eq!(named, [("g_table".to_string(), 0), ("g_output".to_string(), 0)]);
+
+    let continuation = vm_exit_continuation(&image, fixture.vm_call_address).unwrap();
+    let offset = continuation - fixture.vm_call_address;
+    assert!(symbols.describe(&image, continuation)
+                   .ends_with(&format!("protected_function+{:#x})", offset)));
+}
+
+#[test]
+fn pdbs_of_another_build_are_rejected() {
+    let (guid, age) = FIXTURE_PDB_IDENTITY;
+    let mut other_guid = guid;
+    other_guid[15] ^= 1;
+    let sections = [(".text", SECTION_RVA as u32, 0x10000)];
+    let parse = |identity| Pdb::parse(&build_pdb(identity, &sections, &[], &[])).unwrap();
+
+    for config in [FixtureConfig::random(0x9db), FixtureConfig::random_32(0x9db)] {
+        let fixture = generate(&config, &round_trip_program_sized(&config.push_order, 4));
+        let image = fixture.image();
+        assert_eq!(parse(FIXTURE_PDB_IDENTITY).check_image(&image), Ok(()));
+        assert!(parse((guid, age + 1)).check_image(&image).is_err());
+        assert!(parse((other_guid, age)).check_image(&image).is_err());
+
+        // Raw dumps carry no debug directory to check against
+        let raw = RawImage::new(fixture.bytes.clone(), image.image_base());
+        assert_eq!(parse((other_guid, age)).check_image(&raw), Ok(()));
+    }
+}
+
+#[test]
+fn vm_call_sites_belong_to_their_runtime_function() {
+    let config = FixtureConfig::random(0x9da7a);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let image = fixture.image();
+    let table = FunctionTable::new(&image);
+
+    let function = table.containing(&image, fixture.vm_call_address).unwrap();
+    assert_eq!(function.start, fixture.vm_call_address);
+    // The return address lies in the chained entry, which belongs to the same function
+    let chained = table.containing(&image, fixture.return_address).unwrap();
+    assert_eq!(chained.start, fixture.vm_call_address);
+    assert_eq!(chained.end, fixture.return_address + 1);
+    assert!(table.containing(&image, chained.end).is_none());
+
+    let rva = (fixture.vm_call_address - IMAGE_BASE) as u32;
+    let pdb_bytes = build_pdb(FIXTURE_PDB_IDENTITY,
+                              &[(".text", SECTION_RVA as u32, 0x10000)],
+                              &[("protected_function", rva)],
+                              &[]);
+    let mut symbols = ImageSymbols::new(&image);
+    symbols.add_pdb(&Pdb::parse(&pdb_bytes).unwrap(), image.image_base());
+
+    let vm_call_addresses = [fixture.vm_call_address, fixture.return_address, IMAGE_BASE];
+    let functions = virtualized_functions(&image, &symbols, &table, &vm_call_addresses);
+    assert_eq!(functions.len(), 2);
+    assert!(functions[0].function.is_none());
+    assert_eq!(functions[0].vm_call_addresses, [IMAGE_BASE]);
+    assert_eq!(functions[1].name.as_deref(), Some("protected_function"));
+    assert_eq!(functions[1].vm_call_addresses, vm_call_addresses[.. 2]);
+
+    // Only PE32+ images have an exception directory
+    let config = FixtureConfig::random_32(0x9da7a);
+    let fixture = generate(&config, &round_trip_program_sized(&config.push_order, 4));
+    let image = fixture.image();
+    assert!(FunctionTable::new(&image).containing(&image, fixture.vm_call_address).is_none());
+}
+
+#[test]
+fn import_protection_stubs_resolve_to_their_imports() {
+    let load_address = 0x7ff6_1234_0000;
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let imports = fixture.imports.as_ref().unwrap();
+        let delta = load_address - IMAGE_BASE;
+
+        for (image, delta) in [(fixture.image(), 0),
+                               (FileImage::new(fixture.bytes.clone(), Some(load_address)).unwrap(),
+                                delta)]
+        {
+            let symbols = ImageSymbols::new(&image);
+            for (index, name) in FIXTURE_IMPORTS.into_iter().enumerate() {
+                let stub = resolve_import_stub(&image, &symbols, imports.stubs[index] + delta)
+                    .unwrap();
+                assert_eq!(stub.import, format!("KERNEL32.dll!{}", name));
+                assert_eq!(stub.slot, imports.slots[index] + delta);
+            }
+
+            // The stub moves the return address past the junk byte after the call
+            let call_address = imports.call_address + delta;
+            let stub = resolve_import_call(&image, &symbols, call_address).unwrap();
+            assert_eq!(stub.stub_address, imports.stubs[0] + delta);
+            assert_eq!(stub.return_address, Some(call_address + 6));
+
+            assert!(resolve_import_stub(&image, &symbols, fixture.vm_call_address + delta)
+                        .is_none());
+        }
+    }
+}
+
+#[test]
+fn functions_calling_an_import_are_not_stubs() {
+    let config = FixtureConfig::random(0x1a8);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let slot = fixture.imports.as_ref().unwrap().slots[0];
+
+    // sub rsp, 0x28; call [slot]; add rsp, 0x28; ret, in the int3 padding after the bytecode
+    let function = fixture.bytecode.end + 0x10;
+    let displacement = slot.wrapping_sub(function + 10) as u32;
+    let mut code = vec![0x48, 0x83, 0xec, 0x28, 0xff, 0x15];
+    code.extend_from_slice(&displacement.to_le_bytes());
+    code.extend_from_slice(&[0x48, 0x83, 0xc4, 0x28, 0xc3]);
+
+    let mut bytes = fixture.bytes.clone();
+    let rva = (function - IMAGE_BASE) as u32;
+    let offset = PeFile::from_bytes(&bytes).unwrap().rva_to_file_offset(rva).unwrap();
+    bytes[offset .. offset + code.len()].copy_from_slice(&code);
+    let image = FileImage::new(bytes, None).unwrap();
+    let symbols = ImageSymbols::new(&image);
+
+    assert!(resolve_import_stub(&image, &symbols, function).is_none());
+    assert_eq!(describe_continuation(&image, &symbols, function),
+               symbols.describe(&image, function));
+}
+
+#[test]
+fn vm_exit_into_import_stub_names_the_import() {
+    let config = FixtureConfig::random(0x1a7);
+    // Pushes the stub below the saved registers, vmexit returns into it. The layout of the
+    // fixture does not depend on the pushed value
+    let program_with = |stub: u64| {
+        let mut program = round_trip_program(&config.push_order);
+        program.insert(config.push_order.len() + 1, HandlerVmInstruction::PushImm64(stub));
+        program
+    };
+    let stub = generate(&config, &program_with(0)).imports.unwrap().stubs[1];
+    let fixture = generate(&config, &program_with(stub));
+    assert_eq!(fixture.imports.as_ref().unwrap().stubs[1], stub);
+    let image = fixture.image();
+    let symbols = ImageSymbols::new(&image);
+
+    let continuation = vm_exit_continuation(&image, fixture.vm_call_address).unwrap();
+    assert_eq!(continuation, stub);
+    assert!(describe_continuation(&image, &symbols, continuation)
+                .ends_with(&format!("-> KERNEL32.dll!GetTickCount via stub {:#x}", stub)));
+
+    let (vm_context, steps) = disassemble_trace(&image, fixture.vm_call_address);
+    let references = symbolize_trace(&image, &symbols, &vm_context, &steps);
+    let pushed = references.iter()
+                           .find(|reference| reference.symbol.address == stub)
+                           .unwrap();
+    assert_eq!(pushed.index, config.push_order.len() + 1);
+    assert_eq!(pushed.symbol.stub_import.as_deref(), Some("KERNEL32.dll!GetTickCount"));
+}
+
+#[test]
+fn call_graph_follows_exits_into_imports_and_returns_into_vm_calls() {
+    let config = FixtureConfig::random(0xca11);
+    // Exits into the stub of the second import, which returns into the vm call again
+    let program_with = |stub: u64, vm_call_address: u64| {
+        let mut program = round_trip_program(&config.push_order);
+        let pushes = [HandlerVmInstruction::PushImm64(vm_call_address),
+                      HandlerVmInstruction::PushImm64(stub)];
+        program.splice(config.push_order.len() + 1 .. config.push_order.len() + 1, pushes);
+        program
+    };
+    let placeholder = generate(&config, &program_with(0, 0));
+    let stub = placeholder.imports.unwrap().stubs[1];
+    let fixture = generate(&config, &program_with(stub, placeholder.vm_call_address));
+    let image = fixture.image();
+    let symbols = ImageSymbols::new(&image);
+
+    let graph = build_call_graph(&image, &symbols, &[fixture.vm_call_address]);
+    let nodes = graph.nodes
+                     .iter()
+                     .map(|node| (node.address, node.kind, node.label.as_str()))
+                     .collect::<Vec<_>>();
+    assert_eq!(nodes[1], (stub, NodeKind::Import, "KERNEL32.dll!GetTickCount"));
+    assert_eq!(nodes.len(), 2);
+    assert_eq!(graph.nodes[0].kind, NodeKind::Vm);
+    assert_eq!(graph.nodes[0].handler_count, fixture.program.len());
+    let edges = graph.edges
+                     .iter()
+                     .map(|edge| (edge.from, edge.to, edge.kind))
+                     .collect::<Vec<_>>();
+    assert_eq!(edges,
+               [(fixture.vm_call_address, stub, EdgeKind::Exit),
+                (stub, fixture.vm_call_address, EdgeKind::Return),
+                (fixture.vm_call_address, fixture.vm_call_address, EdgeKind::Reference)]);
+
+    let json = graph.to_json();
+    assert!(json.contains(&format!("\"from\": \"{:#x}\", \"to\": \"{:#x}\", \"kind\": \"exit\"",
+                                   fixture.vm_call_address,
+                                   stub)));
+    assert!(graph.to_dot()
+                 .contains(&format!("\"{:#x}\" -> \"{:#x}\" [label=\"return\"];",
+                                    stub,
+                                    fixture.vm_call_address)));
+
+    // A plain round trip exits to native code after the vm call
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let image = fixture.image();
+    let symbols = ImageSymbols::new(&image);
+    let graph = build_call_graph(&image, &symbols, &[fixture.vm_call_address]);
+    assert_eq!(graph.nodes[1].address, fixture.return_address);
+    assert_eq!(graph.nodes[1].kind, NodeKind::Native);
+    assert_eq!(graph.edges.len(), 1);
+}
+
+#[test]
+fn call_graph_keeps_push_call_pairs_of_native_code_native() {
+    let config = FixtureConfig::random(0xca12);
+    let program_with = |pushed: u64| {
+        let mut program = round_trip_program(&config.push_order);
+        program.insert(config.push_order.len() + 1, HandlerVmInstruction::PushImm64(pushed));
+        program
+    };
+    // push 0; call the ret after it, in the int3 padding after the bytecode
+    let native_call = generate(&config, &program_with(0)).bytecode.end + 0x10;
+    let fixture = generate(&config, &program_with(native_call));
+
+    let mut bytes = fixture.bytes.clone();
+    let offset = PeFile::from_bytes(&bytes).unwrap()
+                                           .rva_to_file_offset((native_call - IMAGE_BASE) as u32)
+                                           .unwrap();
+    bytes[offset .. offset + 11].copy_from_slice(&[0x68, 0, 0, 0, 0, 0xe8, 0, 0, 0, 0, 0xc3]);
+    let image = FileImage::new(bytes, None).unwrap();
+    let symbols = ImageSymbols::new(&image);
+    assert!(is_vm_call(&image, native_call));
+    assert!(!is_vm_entry_call(&image, native_call));
+    assert!(is_vm_entry_call(&image, fixture.vm_call_address));
+
+    let graph = build_call_graph(&image, &symbols, &[fixture.vm_call_address]);
+    assert_eq!(graph.nodes[0].kind, NodeKind::Vm);
+    assert_eq!(graph.nodes.iter().filter(|node| node.kind == NodeKind::Vm).count(), 1);
+    assert!(graph.nodes
+                 .iter()
+                 .all(|node| node.address != native_call || node.kind == NodeKind::Native));
+
+    // Asked to start there, the graph holds it as native code
+    let graph = build_call_graph(&image, &symbols, &[native_call]);
+    assert_eq!(graph.nodes.len(), 1);
+    assert_eq!(graph.nodes[0].kind, NodeKind::Native);
+    assert!(graph.edges.is_empty());
+}
+
+#[test]
+fn virtualization_map_finds_the_vm_call_and_its_routine() {
+    for seed in SEEDS {
+        let configs = [FixtureConfig::random(seed),
+                       FixtureConfig::random_32(seed),
+                       FixtureConfig { format: FixtureFormat::Elf,
+                                       ..FixtureConfig::random(seed) }];
+        for config in configs {
+            let mut rng = XorShift64::new(seed);
+            let program = match config.bitness {
+                32 => every_instruction_32(&mut rng),
+                _ => every_instruction(&mut rng),
+            };
+            let fixture = generate(&config, &program);
+            let image: Box<dyn Image> = match config.format {
+                FixtureFormat::Pe => Box::new(fixture.image()),
+                FixtureFormat::Elf => Box::new(ElfImage::new(fixture.bytes.clone(), None).unwrap()),
+            };
+            let symbols = ImageSymbols::new(&*image);
+            let vm_call_addresses = scan_vm_calls(&*image, &symbols);
+            assert_eq!(vm_call_addresses, [fixture.vm_call_address], "seed {:#x}", seed);
+
+            let map = build_virtualization_map(&*image, &symbols, &vm_call_addresses);
+            assert_eq!(map.vm_entry_count(), 1);
+            let entry = &map.entries[0];
+            let vm_entry = entry.vm_entry.as_ref().unwrap();
+            let (_, vm_entry_address) = handle_vm_call(&*image, fixture.vm_call_address);
+            assert_eq!(vm_entry.address, vm_entry_address);
+            assert_same_allocation(&vm_entry.register_allocation, &config.reg_allocation);
+            assert_eq!(entry.bytecode, fixture.bytecode);
+            assert_eq!(entry.instruction_count, fixture.program.len());
+            assert_eq!(entry.unknown_handlers, 0);
+            assert_eq!(entry.end, RoutineEnd::VmExit);
+
+            // Only PE32+ images have an exception directory
+            let has_functions = config.bitness == 64 && config.format == FixtureFormat::Pe;
+            assert_eq!(entry.function.map(|function| function.start),
+                       has_functions.then_some(fixture.vm_call_address));
+            assert_eq!(map.functions.len(), 1);
+            assert_eq!(map.functions[0].function, entry.function);
+            assert_eq!(map.functions[0].vm_call_addresses, [fixture.vm_call_address]);
+
+            let json = map.to_json();
+            let function = match has_functions {
+                true => format!("\"function\": {{\"start\": \"{:#x}\"", fixture.vm_call_address),
+                false => "\"function\": null".to_string(),
+            };
+            assert_eq!(json.matches(&function).count(), 2, "{}", json);
+            assert!(json.contains(&format!("\"handler_base\": \"{:#x}\"", vm_entry.handler_base)));
+            assert!(json.contains("\"end\": \"vmexit\", \"error\": null"));
+        }
+    }
+}
+
+#[test]
+fn virtualization_map_reports_vm_calls_that_fail_to_decode() {
+    let config = FixtureConfig::random(0xb40c);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let (_, vm_entry_address) = handle_vm_call(&fixture.image(), fixture.vm_call_address);
+
+    // vmentry returns at once, it saves no registers and has no handler to dispatch to
+    let mut bytes = fixture.bytes.clone();
+    let rva = (vm_entry_address - IMAGE_BASE) as u32;
+    let offset = PeFile::from_bytes(&bytes).unwrap().rva_to_file_offset(rva).unwrap();
+    bytes[offset] = 0xc3;
+    let image = FileImage::new(bytes, None).unwrap();
+    let symbols = ImageSymbols::new(&image);
+    assert!(scan_vm_calls(&image, &symbols).is_empty());
+
+    // vmentry itself is no vm call
+    let map = build_virtualization_map(&image,
+                                       &symbols,
+                                       &[fixture.vm_call_address, vm_entry_address]);
+    assert_eq!(map.entries.len(), 2);
+    assert_eq!(map.vm_entry_count(), 0);
+    for entry in map.entries.iter() {
+        assert!(entry.vm_entry.is_none());
+        assert_eq!(entry.instruction_count, 0);
+        assert!(matches!(entry.end, RoutineEnd::DecodeFailed(_)), "{:?}", entry.end);
+    }
+    assert!(map.to_json().contains("\"vm_entry\": null, \"registers\": null"));
+
+    // The intact vm call still decodes next to a failing one
+    let image = fixture.image();
+    let map = build_virtualization_map(&image,
+                                       &symbols,
+                                       &[fixture.vm_call_address, vm_entry_address]);
+    assert_eq!(map.entries[0].end, RoutineEnd::VmExit);
+    assert!(matches!(map.entries[1].end, RoutineEnd::DecodeFailed(_)));
+}
+
+#[test]
+fn rebased_images_decode_at_load_address() {
+    let cases = [(FixtureFormat::Pe, 64, 0x7ff6_1234_0000),
+                 (FixtureFormat::Pe, 32, 0x7740_0000),
+                 (FixtureFormat::Elf, 64, 0x7f12_3456_0000)];
+
+    for (format, bitness, load_address) in cases {
+        for seed in SEEDS {
+            let (config, program, preferred_base) = match bitness {
+                32 => (FixtureConfig::random_32(seed),
+                       every_instruction_32(&mut XorShift64::new(seed)),
+                       IMAGE_BASE_32),
+                _ => (FixtureConfig::random(seed),
+                      every_instruction(&mut XorShift64::new(seed)),
+                      IMAGE_BASE),
+            };
+            let config = FixtureConfig { format,
+                                         ..config };
+            let fixture = generate(&config, &program);
+            let image: Box<dyn Image> = match format {
+                FixtureFormat::Pe => {
+                    Box::new(FileImage::new(fixture.bytes.clone(), Some(load_address)).unwrap())
+                },
+                FixtureFormat::Elf => {
+                    Box::new(ElfImage::new(fixture.bytes.clone(), Some(load_address)).unwrap())
+                },
+            };
+            let delta = load_address - preferred_base;
+
+            let (vm_context, steps) = disassemble_trace(&*image, fixture.vm_call_address + delta);
+            assert_eq!(vm_context.relocation_delta, delta);
+            let decoded = steps.iter().map(|step| step.instruction).collect::<Vec<_>>();
+            assert_eq!(decoded, program, "{:?} {} bit, seed {:#x}", format, bitness, seed);
+            assert!(steps.iter()
+                         .map(|step| step.handler_address)
+                         .eq(fixture.handler_addresses.iter().map(|address| address + delta)));
+        }
+    }
+}
+
+#[test]
+fn rebased_emulation_matches_static_decode() {
+    let load_address: u64 = 0xfffff806_4a210000;
+    let delta = load_address.wrapping_sub(IMAGE_BASE);
+
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let image = FileImage::new(fixture.bytes.clone(), Some(load_address)).unwrap();
+        let vm_call_address = fixture.vm_call_address.wrapping_add(delta);
+        let initial = random_state(&config.push_order, &mut XorShift64::new(seed));
+
+        let (snapshots, _, end) =
+            emulate_vm_call(&image, vm_call_address, &initial, 0x10000);
+        let return_address = fixture.return_address.wrapping_add(delta);
+        assert!(matches!(end, EmulationEnd::VmExit(address) if address == return_address),
+                "Emulation ended with {:?}",
+                end);
+
+        let (_, steps) = disassemble_trace(&image, vm_call_address);
+        assert!(snapshots.iter()
+                         .map(|snapshot| snapshot.handler_address)
+                         .eq(steps.iter().map(|step| step.handler_address)));
+    }
+}
+
+#[test]
+fn written_fixture_loads_from_disk() {
+    let config = FixtureConfig::random(0x600d);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let path = std::env::temp_dir().join(format!("vmp3_fixture_{}.exe", std::process::id()));
+    fixture.write(&path).unwrap();
+
+    let image = FileImage::new(std::fs::read(&path).unwrap(), None).unwrap();
+    let (_, steps) = disassemble_trace(&image, fixture.vm_call_address);
+    std::fs::remove_file(&path).unwrap();
+
+    let program = steps.iter().map(|step| step.instruction).collect::<Vec<_>>();
+    assert_eq!(program, fixture.program);
+}
+
+/// The file as the loader maps it, sections at their virtual addresses
+fn map_image(bytes: &[u8]) -> Vec<u8> {
+    let pe_file = PeFile::from_bytes(bytes).unwrap();
+    let headers_size = pe_file.optional_header().SizeOfHeaders as usize;
+    let mut mapped = vec![0; pe_file.optional_header().SizeOfImage as usize];
+    mapped[.. headers_size].copy_from_slice(&bytes[.. headers_size]);
+    for section in pe_file.section_headers() {
+        let size = section.VirtualSize.min(section.SizeOfRawData) as usize;
+        let rva = section.VirtualAddress as usize;
+        let file_offset = section.PointerToRawData as usize;
+        mapped[rva .. rva + size].copy_from_slice(&bytes[file_offset .. file_offset + size]);
+    }
+    mapped
+}
+
+#[test]
+fn memory_dumps_decode_like_the_file() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let mapped_bytes = map_image(&fixture.bytes);
+
+        // A dump of a driver usually has its headers erased
+        let mut erased_bytes = mapped_bytes.clone();
+        erased_bytes[.. SECTION_RVA as usize].fill(0);
+
+        let images: [Box<dyn Image>; 3] =
+            [Box::new(MappedImage::new(mapped_bytes.clone(), None).unwrap()),
+             Box::new(RawImage::new(mapped_bytes, IMAGE_BASE)),
+             Box::new(RawImage::new(erased_bytes, IMAGE_BASE))];
+        for image in images.iter() {
+            let (_, steps) = disassemble_trace(&**image, fixture.vm_call_address);
+            let program = steps.iter().map(|step| step.instruction).collect::<Vec<_>>();
+            assert_eq!(program, fixture.program, "seed {:#x}", seed);
+            assert!(steps.iter()
+                         .map(|step| step.handler_address)
+                         .eq(fixture.handler_addresses.iter().copied()));
+        }
+    }
+}
+
+#[test]
+fn minidump_modules_decode_like_the_file() {
+    let decoy_base = 0x7ff8_1000_0000;
+    let decoy = vec![0x90; 0x3000];
+
+    for (seed, memory64) in SEEDS.into_iter().zip([true, false].into_iter().cycle()) {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let mapped_bytes = map_image(&fixture.bytes);
+
+        let modules = [DumpModule { path: "C:\\Windows\\System32\\ntdll.dll",
+                                    base: decoy_base,
+                                    memory: &decoy,
+                                    captured: vec![0 .. 0x1000, 0x2000 .. 0x3000] },
+                       DumpModule { path: "\\SystemRoot\\system32\\drivers\\vgk.sys",
+                                    base: IMAGE_BASE,
+                                    memory: &mapped_bytes,
+                                    captured: vec![0 .. 0x1000, 0x1000 .. mapped_bytes.len()] }];
+        let dump = build_minidump(&modules, memory64);
+
+        for module in ["VGK.SYS".to_string(), format!("{:#x}", IMAGE_BASE)] {
+            let image = MinidumpImage::new(&dump, &module, None).unwrap();
+            assert_eq!(image.image_base(), IMAGE_BASE);
+            assert_image_disassembles(&image, &fixture, &config);
+        }
+
+        let decoy_image = MinidumpImage::new(&dump, "ntdll.dll", None).unwrap();
+        assert!(decoy_image.read(decoy_base + 0x1800, 1).is_none());
+        assert_eq!(decoy_image.read(decoy_base + 0x2000, 2).unwrap().as_ref(), &[0x90, 0x90]);
+        assert!(MinidumpImage::new(&dump, "kernel32.dll", None).is_err());
+        assert!(!Minidump::is_kernel_dump(&dump));
+    }
+
+    // Kernel memory dumps are told apart instead of being taken for a PE file
+    for signature in [b"PAGEDUMP", b"PAGEDU64"] {
+        let mut kernel_dump = signature.to_vec();
+        kernel_dump.resize(0x2000, 0);
+        assert!(Minidump::is_kernel_dump(&kernel_dump));
+        assert!(Minidump::parse(&kernel_dump).is_err());
+    }
+}
+
+#[test]
+fn file_image_reads_are_bounded_and_zero_filled() {
+    let config = FixtureConfig::random(0x2e70);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let image = fixture.image();
+    let pe_file = PeFile::from_bytes(&fixture.bytes).unwrap();
+    let section = &pe_file.section_headers().as_slice()[0];
+
+    // The last bytes of the section's raw data and the zeroes the loader maps after them
+    let raw_end = IMAGE_BASE + (section.VirtualAddress + section.SizeOfRawData) as u64;
+    let file_end = (section.PointerToRawData + section.SizeOfRawData) as usize;
+    let bytes = image.read(raw_end - 4, 16).unwrap();
+    assert_eq!(bytes[.. 4], fixture.bytes[file_end - 4 .. file_end]);
+    assert!(bytes[4 ..].iter().all(|&byte| byte == 0));
+
+    assert!(image.read(IMAGE_BASE - 1, 1).is_none());
+    assert!(image.read(IMAGE_BASE + pe_file.optional_header().SizeOfImage as u64, 1).is_none());
+
+    // SizeOfHeaders past the end of the file is refused up front, header reads never slice
+    // past the file
+    let mut bytes = fixture.bytes.clone();
+    let size_of_headers = 0x40 + 4 + 20 + 60;
+    bytes[size_of_headers .. size_of_headers + 4].copy_from_slice(&0x100000u32.to_le_bytes());
+    assert!(FileImage::new(bytes, None).is_err());
+}
+
+#[test]
+fn operand_decryption_holds_across_seeds() {
+    for seed in 1 .. 0x100 {
+        let mut rng = XorShift64::new(seed);
+        let config = FixtureConfig::random(seed);
+        let program = vec![HandlerVmInstruction::PushImm64(rng.next_u64()),
+                           HandlerVmInstruction::PushImm32(rng.next_u64() as u32),
+                           HandlerVmInstruction::PushImm16(rng.next_u64() as u16),
+                           HandlerVmInstruction::Pop(8, (rng.next_u64() % 0x20) as u8 * 8),
+                           HandlerVmInstruction::VmExit];
+        assert_disassembles(&generate(&config, &program), &config);
+    }
+}
+
+/// Native execution of the fixture as rip and the registers before every instruction
+fn record_execution(fixture: &Fixture,
+                    initial: &NativeState)
+                    -> Vec<(u64, Vec<(Registers, u64)>)> {
+    let image = fixture.image();
+    let mut emulator = X86Emulator::new(&image, fixture.vm_call_address, initial);
+    let mut recording = Vec::new();
+
+    loop {
+        let mut registers = emulator.native_state().registers.into_iter().collect::<Vec<_>>();
+        registers.sort_by_key(|&(reg, _)| reg as u8);
+        recording.push((emulator.rip, registers));
+
+        if emulator.rip == fixture.return_address {
+            break;
+        }
+        emulator.step().unwrap();
+    }
+
+    recording
+}
+
+/// The recording in the text format, one line of rip and registers per instruction
+fn recording_text(recording: &[(u64, Vec<(Registers, u64)>)]) -> String {
+    let mut text = String::new();
+    for (rip, registers) in recording {
+        text += &format!("rip={:#x}", rip);
+        for (reg, value) in registers {
+            text += &format!(" {:?}={:#x}", reg, value);
+        }
+        text += "\n";
+    }
+    text
+}
+
+fn align_text(image: &dyn Image,
+              vm_call_address: u64,
+              text: &str)
+              -> Alignment {
+    let recording = parse_recording(text).unwrap();
+    let (snapshots, return_address) =
+        recorded_dispatches(image, vm_call_address, &recording).unwrap();
+    align_recording(image, vm_call_address, &snapshots, return_address).unwrap()
+}
+
+#[test]
+fn recorded_trace_aligns_with_static_decode() {
+    for seed in SEEDS {
+        let config = FixtureConfig::random(seed);
+        let fixture = generate(&config, &round_trip_program(&config.push_order));
+        let initial = random_state(&config.push_order, &mut XorShift64::new(seed));
+
+        let text = recording_text(&record_execution(&fixture, &initial));
+        let alignment = align_text(&fixture.image(), fixture.vm_call_address, &text);
+
+        assert!(alignment.divergences.is_empty(), "{:?}", alignment.divergences);
+        assert!(alignment.decode_failure.is_none());
+        assert_eq!(alignment.return_address, Some(fixture.return_address));
+        let program = alignment.steps
+                               .iter()
+                               .map(|aligned| aligned.step.instruction)
+                               .collect::<Vec<_>>();
+        assert_eq!(program, fixture.program);
+    }
+}
+
+#[test]
+fn csv_recording_reports_divergent_key() {
+    let config = FixtureConfig::random(0xc5f);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let mut recording = record_execution(&fixture, &NativeState::default());
+
+    // Corrupt the rolling key on entry to the vmexit handler
+    let vm_exit = *fixture.handler_addresses.last().unwrap();
+    let entry = recording.iter().position(|(rip, _)| *rip == vm_exit).unwrap();
+    for (reg, value) in recording[entry].1.iter_mut() {
+        if *reg == config.reg_allocation.key {
+            *value ^= 0x10;
+        }
+    }
+
+    let mut text = "step,rip".to_string();
+    for (reg, _) in recording[0].1.iter() {
+        text += &format!(",{:?}", reg);
+    }
+    text += ",disassembly\n";
+    for (index, (rip, registers)) in recording.iter().enumerate() {
+        text += &format!("{},{:x}", index, rip);
+        for (_, value) in registers {
+            text += &format!(",{:016x}", value);
+        }
+        text += ",nop\n";
+    }
+
+    let alignment = align_text(&fixture.image(), fixture.vm_call_address, &text);
+    assert_eq!(alignment.steps.len(), fixture.program.len());
+    assert_eq!(alignment.divergences.len(), 1);
+    assert_eq!(alignment.divergences[0].index, fixture.program.len() - 1);
+    assert_eq!(alignment.divergences[0].location, "key");
+}
+
+#[test]
+fn recorded_rsp_moves_inside_the_vm_are_not_vmexit() {
+    let config = FixtureConfig::random(0x5b);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let mut recording = record_execution(&fixture, &NativeState::default());
+
+    // Handlers moving the register file away from a growing virtual stack also move rsp
+    let middle = fixture.handler_addresses[fixture.program.len() / 2];
+    let vm_exit = *fixture.handler_addresses.last().unwrap();
+    let moved = recording.iter().position(|(rip, _)| *rip == middle).unwrap();
+    let exit = recording.iter().position(|(rip, _)| *rip == vm_exit).unwrap();
+    for (_, registers) in recording[moved .. exit].iter_mut() {
+        for (reg, value) in registers.iter_mut() {
+            if *reg == Registers::Rsp {
+                *value -= 0x100;
+            }
+        }
+    }
+
+    let text = recording_text(&recording);
+    let alignment = align_text(&fixture.image(), fixture.vm_call_address, &text);
+    assert_eq!(alignment.steps.len(), fixture.program.len());
+    assert_eq!(alignment.return_address, Some(fixture.return_address));
+}
+
+#[test]
+fn recording_alignment_stops_at_undecodable_handlers() {
+    let config = FixtureConfig::random(0xa11);
+    let fixture = generate(&config, &round_trip_program(&config.push_order));
+    let text = recording_text(&record_execution(&fixture, &NativeState::default()));
+
+    // The recording of the intact fixture aligned against a copy with a handler replaced
+    let (image, index, _) = undecodable_handler_image(&config, &fixture);
+    let alignment = align_text(&image, fixture.vm_call_address, &text);
+    assert_eq!(alignment.steps.len(), index);
+    assert_eq!(alignment.decode_failure.map(|(index, _)| index), Some(index));
+    assert!(alignment.divergences.is_empty(), "{:?}", alignment.divergences);
+}
@@ -0,0 +1,145 @@
+use crate::{
+    image::Image,
+    vm_handler::{VmContext, VmHandler},
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+/// One dispatched handler of a trace
+pub struct TraceStep {
+    pub handler_address: u64,
+    pub handler: VmHandler,
+    pub handler_class: HandlerClass,
+    pub instruction: HandlerVmInstruction,
+    /// Decrypted operand, zero for handlers without one
+    pub operand: u64,
+}
+
+impl TraceStep {
+    /// The trace can not be followed past this handler
+    pub fn is_halt(&self) -> bool {
+        matches!(self.handler_class,
+                 HandlerClass::UnconditionalBranch | HandlerClass::NoVipChange)
+    }
+}
+
+impl VmContext {
+    /// Decodes the handler at the current handler address and advances the context past it
+    pub fn step(&mut self,
+                image: &dyn Image)
+                -> TraceStep {
+        self.try_step(image).unwrap()
+    }
+
+    /// Like step, fails when the handler's class or decryption is not recognised or its
+    /// bytecode is outside the image
+    pub fn try_step(&mut self,
+                    image: &dyn Image)
+                    -> Result<TraceStep, String> {
+        let mut vm_handler = VmHandler::new(self.handler_address, image);
+        vm_handler.canonicalize_moves(&self.register_allocation);
+
+        // The symbolic vip delta and stack effect decide, the vip update matchers cover the
+        // handlers the symbolic execution can not follow
+        let semantics = vm_handler.compute_semantics(&self.register_allocation);
+        let handler_class = match semantics.handler_class() {
+            Some(handler_class) => handler_class,
+            None => vm_handler.match_handler_class(&self.register_allocation)?,
+        };
+        let handler_address = self.handler_address;
+        let mut handler_instruction = HandlerVmInstruction::Unknown;
+        let mut operand = 0;
+
+        match handler_class {
+            HandlerClass::UnconditionalBranch => {},
+            HandlerClass::NoVipChange => {
+                handler_instruction =
+                    vm_handler.match_no_vip_change_instructions(&self.register_allocation);
+            },
+            HandlerClass::ByteOperand => {
+                let byte_operand =
+                    self.disassemble_single_byte_operand(&vm_handler, image)?;
+                operand = byte_operand as u64;
+                handler_instruction =
+                    vm_handler.match_byte_operand_instructions(&self.register_allocation,
+                                                               byte_operand);
+            },
+            HandlerClass::WordOperand => {
+                let word_operand =
+                    self.disassemble_single_word_operand(&vm_handler, image)?;
+                operand = word_operand as u64;
+                handler_instruction =
+                    vm_handler.match_word_operand_instructions(&self.register_allocation,
+                                                               word_operand);
+            },
+            HandlerClass::DwordOperand => {
+                let dword_operand =
+                    self.disassemble_single_dword_operand(&vm_handler, image)?;
+                operand = dword_operand as u64;
+                handler_instruction =
+                    vm_handler.match_dword_operand_instructions(&self.register_allocation,
+                                                                dword_operand);
+            },
+            HandlerClass::QwordOperand => {
+                let qword_operand =
+                    self.disassemble_single_qword_operand(&vm_handler, image)?;
+                operand = qword_operand;
+                handler_instruction =
+                    vm_handler.match_qword_operand_instructions(&self.register_allocation,
+                                                                qword_operand);
+            },
+            HandlerClass::NoOperand => {
+                self.disassemble_no_operand(&vm_handler, image)?;
+                handler_instruction =
+                    vm_handler.match_no_operand_instructions(&self.register_allocation);
+            },
+        }
+
+        Ok(TraceStep { handler_address,
+                       handler: vm_handler,
+                       handler_class,
+                       instruction: handler_instruction,
+                       operand })
+    }
+}
+
+/// Disassembles the routine entered at a vm call until it halts
+#[cfg(test)]
+pub fn disassemble_trace(image: &dyn Image,
+                         vm_call_address: u64)
+                         -> (VmContext, Vec<TraceStep>) {
+    try_disassemble_trace(image, vm_call_address).unwrap()
+}
+
+/// Like disassemble_trace, fails when vmentry or a handler can not be decoded
+pub fn try_disassemble_trace(image: &dyn Image,
+                             vm_call_address: u64)
+                             -> Result<(VmContext, Vec<TraceStep>), String> {
+    match disassemble_partial_trace(image, vm_call_address)? {
+        (vm_context, steps, None) => Ok((vm_context, steps)),
+        (_, _, Some(reason)) => Err(reason),
+    }
+}
+
+/// Like disassemble_trace, stops early at a handler that can not be decoded with the reason
+/// why. The context is left at the failing handler. Fails when vmentry can not be decoded
+pub fn disassemble_partial_trace(image: &dyn Image,
+                                 vm_call_address: u64)
+                                 -> Result<(VmContext, Vec<TraceStep>, Option<String>), String> {
+    let mut vm_context = VmContext::try_new(image, vm_call_address)?;
+    let mut steps = Vec::new();
+
+    loop {
+        let step = match vm_context.try_step(image) {
+            Ok(step) => step,
+            Err(reason) => return Ok((vm_context, steps, Some(reason))),
+        };
+        let halt = step.is_halt();
+        steps.push(step);
+
+        if halt {
+            break;
+        }
+    }
+
+    Ok((vm_context, steps, None))
+}
@@ -1,6 +1,4 @@
-use iced_x86::{Code, Instruction, Register};
-
-use crate::util::check_full_reg_written;
+use iced_x86::{Code, Instruction};
 
 pub fn get_transform_for_instruction(instruction: &Instruction) -> Option<Transform> {
     // Add the transform that represents this instruction to the transforms vec
@@ -15,8 +13,12 @@ pub fn get_transform_for_instruction(instruction: &Instruction) -> Option<Transf
         Code::Sub_rm16_imm16 => Some(Transform::SubtractConstant16(instruction.immediate16())),
         Code::Sub_EAX_imm32 => Some(Transform::SubtractConstant32(instruction.immediate32())),
         Code::Sub_rm32_imm32 => Some(Transform::SubtractConstant32(instruction.immediate32())),
-        Code::Sub_RAX_imm32 => Some(Transform::SubtractConstant64(instruction.immediate64())),
-        Code::Sub_rm64_imm32 => Some(Transform::SubtractConstant64(instruction.immediate64())),
+        Code::Sub_RAX_imm32 => {
+            Some(Transform::SubtractConstant64(instruction.immediate32to64() as u64))
+        },
+        Code::Sub_rm64_imm32 => {
+            Some(Transform::SubtractConstant64(instruction.immediate32to64() as u64))
+        },
 
         Code::Add_AL_imm8 => Some(Transform::AddConstant8(instruction.immediate8())),
         Code::Add_rm8_imm8 => Some(Transform::AddConstant8(instruction.immediate8())),
@@ -24,8 +26,12 @@ pub fn get_transform_for_instruction(instruction: &Instruction) -> Option<Transf
         Code::Add_rm16_imm16 => Some(Transform::AddConstant16(instruction.immediate16())),
         Code::Add_EAX_imm32 => Some(Transform::AddConstant32(instruction.immediate32())),
         Code::Add_rm32_imm32 => Some(Transform::AddConstant32(instruction.immediate32())),
-        Code::Add_RAX_imm32 => Some(Transform::AddConstant64(instruction.immediate64())),
-        Code::Add_rm64_imm32 => Some(Transform::AddConstant64(instruction.immediate64())),
+        Code::Add_RAX_imm32 => {
+            Some(Transform::AddConstant64(instruction.immediate32to64() as u64))
+        },
+        Code::Add_rm64_imm32 => {
+            Some(Transform::AddConstant64(instruction.immediate32to64() as u64))
+        },
 
         Code::Neg_rm8 => Some(Transform::Negate8),
         Code::Neg_rm16 => Some(Transform::Negate16),
@@ -73,8 +79,12 @@ pub fn get_transform_for_instruction(instruction: &Instruction) -> Option<Transf
         Code::Xor_rm16_imm16 => Some(Transform::XorConstant16(instruction.immediate16())),
         Code::Xor_EAX_imm32 => Some(Transform::XorConstant32(instruction.immediate32())),
         Code::Xor_rm32_imm32 => Some(Transform::XorConstant32(instruction.immediate32())),
-        Code::Xor_RAX_imm32 => Some(Transform::XorConstant64(instruction.immediate64())),
-        Code::Xor_rm64_imm32 => Some(Transform::XorConstant64(instruction.immediate64())),
+        Code::Xor_RAX_imm32 => {
+            Some(Transform::XorConstant64(instruction.immediate32to64() as u64))
+        },
+        Code::Xor_rm64_imm32 => {
+            Some(Transform::XorConstant64(instruction.immediate32to64() as u64))
+        },
         _ => None,
     }
 }
@@ -171,8 +181,7 @@ impl EmulateTransform for u64 {
 pub trait EmulateEncryption {
     fn emulate_encryption<'a, I>(self,
                                  instruction_iter: I,
-                                 rolling_key: &mut u64,
-                                 encrypted_reg: Register)
+                                 rolling_key: &mut u64)
                                  -> Self
         where I: Iterator<Item = &'a Instruction>;
 }
@@ -180,16 +189,13 @@ pub trait EmulateEncryption {
 impl EmulateEncryption for u64 {
     fn emulate_encryption<'a, I>(mut self,
                                  instruction_iter: I,
-                                 rolling_key: &mut u64,
-                                 encrypted_reg: Register)
+                                 rolling_key: &mut u64)
                                  -> Self
         where I: Iterator<Item = &'a Instruction>
     {
-        self ^= *rolling_key as u64;
+        self ^= *rolling_key;
 
-        for instruction in
-            instruction_iter.filter(|&insn| check_full_reg_written(insn, encrypted_reg))
-        {
+        for instruction in instruction_iter {
             let transform = get_transform_for_instruction(instruction);
 
             if let Some(transform) = transform {
@@ -197,7 +203,7 @@ impl EmulateEncryption for u64 {
             }
         }
 
-        *rolling_key ^= self as u64;
+        *rolling_key ^= self;
 
         self
     }
@@ -206,16 +212,13 @@ impl EmulateEncryption for u64 {
 impl EmulateEncryption for u32 {
     fn emulate_encryption<'a, I>(mut self,
                                  instruction_iter: I,
-                                 rolling_key: &mut u64,
-                                 encrypted_reg: Register)
+                                 rolling_key: &mut u64)
                                  -> Self
         where I: Iterator<Item = &'a Instruction>
     {
         self ^= *rolling_key as u32;
 
-        for instruction in
-            instruction_iter.filter(|&insn| check_full_reg_written(insn, encrypted_reg))
-        {
+        for instruction in instruction_iter {
             let transform = get_transform_for_instruction(instruction);
 
             if let Some(transform) = transform {
@@ -232,16 +235,13 @@ impl EmulateEncryption for u32 {
 impl EmulateEncryption for u16 {
     fn emulate_encryption<'a, I>(mut self,
                                  instruction_iter: I,
-                                 rolling_key: &mut u64,
-                                 encrypted_reg: Register)
+                                 rolling_key: &mut u64)
                                  -> Self
         where I: Iterator<Item = &'a Instruction>
     {
         self ^= *rolling_key as u16;
 
-        for instruction in
-            instruction_iter.filter(|&insn| check_full_reg_written(insn, encrypted_reg))
-        {
+        for instruction in instruction_iter {
             let transform = get_transform_for_instruction(instruction);
 
             if let Some(transform) = transform {
@@ -258,16 +258,13 @@ impl EmulateEncryption for u16 {
 impl EmulateEncryption for u8 {
     fn emulate_encryption<'a, I>(mut self,
                                  instruction_iter: I,
-                                 rolling_key: &mut u64,
-                                 encrypted_reg: Register)
+                                 rolling_key: &mut u64)
                                  -> Self
         where I: Iterator<Item = &'a Instruction>
     {
         self ^= *rolling_key as u8;
 
-        for instruction in
-            instruction_iter.filter(|&insn| check_full_reg_written(insn, encrypted_reg))
-        {
+        for instruction in instruction_iter {
             let transform = get_transform_for_instruction(instruction);
             if let Some(transform) = transform {
                 self = self.emulate_transform(transform);
@@ -1,58 +1,68 @@
 use iced_x86::{
-    Code, Decoder, DecoderOptions, Instruction, InstructionInfoFactory, InstructionInfoOptions,
-    OpAccess, Register,
+    Code, Decoder, DecoderOptions, Formatter, Instruction, InstructionInfoFactory,
+    InstructionInfoOptions, IntelFormatter, OpAccess, Register,
 };
-use pelite::pe64::{Pe, PeFile};
-
-pub fn read_bytes_at_va<'a>(pe_file: &'_ PeFile,
-                            pe_bytes: &'a [u8],
-                            va: u64,
-                            size: usize)
-                            -> Result<&'a [u8], pelite::Error> {
-    let rva = pe_file.va_to_rva(va)?;
-    let file_offset = pe_file.rva_to_file_offset(rva)?;
-
-    let bytes = &pe_bytes[file_offset .. file_offset + size];
-    Ok(bytes)
-}
 
-pub fn disassemble_instruction_at_va(pe_file: &PeFile,
-                                     pe_bytes: &[u8],
+use crate::image::Image;
+
+pub fn disassemble_instruction_at_va(image: &dyn Image,
                                      instruction_address: u64)
                                      -> Instruction {
-    let instruction_bytes = read_bytes_at_va(pe_file, pe_bytes, instruction_address, 16).unwrap();
+    let instruction_bytes = image.read(instruction_address, 16).unwrap();
 
-    let mut decoder = Decoder::with_ip(64,
-                                       instruction_bytes,
+    let mut decoder = Decoder::with_ip(image.bitness(),
+                                       &instruction_bytes,
                                        instruction_address,
                                        DecoderOptions::NONE);
 
     decoder.decode()
 }
 
-pub fn handle_vm_call(pe_file: &PeFile,
-                      pe_bytes: &[u8],
+pub fn format_instruction(instruction: &Instruction) -> String {
+    let mut formatter = IntelFormatter::new();
+    let mut output = String::new();
+    formatter.format(instruction, &mut output);
+
+    format!("{:#x} {}", instruction.ip(), output)
+}
+
+pub fn handle_vm_call(image: &dyn Image,
                       push_call_addr: u64)
                       -> (u64, u64) {
-    let push_instruction = disassemble_instruction_at_va(pe_file, pe_bytes, push_call_addr);
-    let call_instruction = disassemble_instruction_at_va(pe_file,
-                                                         pe_bytes,
+    let push_instruction = disassemble_instruction_at_va(image, push_call_addr);
+    let call_instruction = disassemble_instruction_at_va(image,
                                                          push_call_addr +
                                                          push_instruction.len() as u64);
-    if push_instruction.code() != Code::Pushq_imm32 {
+    if !matches!(push_instruction.code(), Code::Pushq_imm32 | Code::Pushd_imm32) {
         panic!("Vm Entry address is not correctly chosen");
     }
 
-    if call_instruction.code() != Code::Call_rel32_64 {
+    if !matches!(call_instruction.code(), Code::Call_rel32_64 | Code::Call_rel32_32) {
         panic!("Vm Entry address is not correctly chosen");
     }
 
-    let pushed_val = push_instruction.immediate32to64() as u64;
+    let pushed_val = push_instruction.immediate(0);
     let vm_entry_address = call_instruction.near_branch64();
 
     (pushed_val, vm_entry_address)
 }
 
+/// Whether the instructions at the address are a push, call pair like the ones entering the vm
+pub fn is_vm_call(image: &dyn Image,
+                  address: u64)
+                  -> bool {
+    if image.read(address, 16).is_none() {
+        return false;
+    }
+    let push_instruction = disassemble_instruction_at_va(image, address);
+    if !matches!(push_instruction.code(), Code::Pushq_imm32 | Code::Pushd_imm32) {
+        return false;
+    }
+    let call_instruction = disassemble_instruction_at_va(image, push_instruction.next_ip());
+    matches!(call_instruction.code(), Code::Call_rel32_64 | Code::Call_rel32_32) &&
+    image.read(call_instruction.near_branch64(), 16).is_some()
+}
+
 pub fn check_full_reg_written(instruction: &Instruction,
                               reg: Register)
                               -> bool {
@@ -81,3 +91,42 @@ pub fn check_full_reg_written(instruction: &Instruction,
 
     false
 }
+
+/// ah, ch, dh and bh, the registers that are not the low bits of their full register
+pub fn is_high_byte_register(reg: Register) -> bool {
+    matches!(reg, Register::AH | Register::CH | Register::DH | Register::BH)
+}
+
+/// The string quoted and escaped for JSON, DOT accepts the same escapes
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            c if (c as u32) < 0x20 => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Small deterministic pseudo random generator
+pub struct XorShift64 {
+    state: u64,
+}
+
+impl XorShift64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
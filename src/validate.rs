@@ -0,0 +1,362 @@
+use crate::{
+    emulator::{dispatch_address, X86Emulator},
+    image::Image,
+    interpreter::{
+        InterpreterEvent, NativeState, VmInterpreter, VmMemory, NATIVE_STACK_BASE,
+        REGISTER_FILE_SIZE,
+    },
+    trace::TraceStep,
+    util::XorShift64,
+    vm_handler::{Registers, VmContext, VmRegisterAllocation},
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+/// Native rsp during a trial, far enough below vsp that no stack overflow check triggers
+const TRIAL_RSP: u64 = NATIVE_STACK_BASE - 0x4000;
+/// vsp during a trial
+const TRIAL_VSP: u64 = NATIVE_STACK_BASE - 0x100;
+/// Random bytes on each side of vsp
+const STACK_WINDOW: u64 = 0x40;
+/// Memory fetch and store handlers access this region
+const SCRATCH_ADDRESS: u64 = NATIVE_STACK_BASE + 0x1000;
+const SCRATCH_SIZE: u64 = 0x40;
+/// Native instructions a single handler may execute before it counts as not dispatching
+const MAX_HANDLER_STEPS: usize = 0x10000;
+
+const DEFINED_FLAGS: u64 = (1 << 0) | (1 << 2) | (1 << 6) | (1 << 7) | (1 << 11);
+
+/// A value the native handler computed differently than its decoded instruction
+#[derive(Debug, Clone)]
+pub struct Disagreement {
+    pub location: String,
+    pub native: u64,
+    pub expected: u64,
+}
+
+/// Inputs of a failing trial and everything that differed
+#[derive(Debug, Clone)]
+pub struct Counterexample {
+    /// Qwords at vsp before the handler executed
+    pub stack: Vec<u64>,
+    pub disagreements: Vec<Disagreement>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ValidationOutcome {
+    /// Every trial agreed
+    Agrees(usize),
+    Disagrees(Counterexample),
+    /// The native handler could not be run to its dispatch
+    NotEmulated(String),
+}
+
+/// Validation of one distinct handler of a trace
+#[derive(Debug, Clone)]
+pub struct HandlerValidation {
+    pub handler_address: u64,
+    pub instruction: HandlerVmInstruction,
+    pub outcome: ValidationOutcome,
+}
+
+/// Instructions the interpreter gives semantics to and that keep the vm running
+fn has_semantics(instruction: HandlerVmInstruction) -> bool {
+    matches!(instruction,
+             HandlerVmInstruction::Pop(..) |
+             HandlerVmInstruction::Push(..) |
+             HandlerVmInstruction::PushImm64(_) |
+             HandlerVmInstruction::PushImm32(_) |
+             HandlerVmInstruction::PushImm16(_) |
+             HandlerVmInstruction::PushVsp(_) |
+             HandlerVmInstruction::PopVsp(_) |
+             HandlerVmInstruction::Add(_) |
+             HandlerVmInstruction::Shr(_) |
+             HandlerVmInstruction::Nand(_) |
+             HandlerVmInstruction::Nor(_) |
+             HandlerVmInstruction::Fetch(_) |
+             HandlerVmInstruction::Store(_))
+}
+
+/// Bits of the pushed rflags the interpreter defines for an arithmetic handler, given the
+/// stack it started from
+fn compared_flags(instruction: HandlerVmInstruction,
+                  memory: &VmMemory)
+                  -> Option<u64> {
+    match instruction {
+        HandlerVmInstruction::Add(_) |
+        HandlerVmInstruction::Nand(_) |
+        HandlerVmInstruction::Nor(_) => Some(DEFINED_FLAGS),
+        HandlerVmInstruction::Shr(size) => {
+            // A zero count leaves the flags alone and of is undefined for counts above one
+            let count_mask = if size == 8 { 0x3f } else { 0x1f };
+            let count = memory.read(TRIAL_VSP + size.max(2) as u64, 1) & count_mask;
+            match count {
+                0 => Some(0),
+                1 => Some(DEFINED_FLAGS),
+                _ => Some(DEFINED_FLAGS & !(1 << 11)),
+            }
+        },
+        _ => None,
+    }
+}
+
+/// vip and rolling key before and after a handler, as decoded by the trace
+struct TraceState {
+    vip: u64,
+    key: u64,
+    next_vip: u64,
+    next_key: u64,
+}
+
+/// Disassembles the routine like `disassemble_trace`, keeping the vip and rolling key around
+/// every handler. Fails when vmentry or a handler can not be decoded
+fn trace_with_states(image: &dyn Image,
+                     vm_call_address: u64)
+                     -> Result<(VmContext, Vec<(TraceStep, TraceState)>), String> {
+    let mut vm_context = VmContext::try_new(image, vm_call_address)?;
+    let mut steps = Vec::new();
+
+    loop {
+        let (vip, key) = (vm_context.vip_value, vm_context.rolling_key);
+        let step = vm_context.try_step(image)?;
+        let halt = step.is_halt();
+        let state = TraceState { vip,
+                                 key,
+                                 next_vip: vm_context.vip_value,
+                                 next_key: vm_context.rolling_key };
+        steps.push((step, state));
+
+        if halt {
+            break;
+        }
+    }
+
+    Ok((vm_context, steps))
+}
+
+/// Runs one trial of a handler on random inputs, returns the counterexample if the native
+/// code and the interpreter disagree
+fn run_trial(image: &dyn Image,
+             reg_allocation: &VmRegisterAllocation,
+             step: &TraceStep,
+             state: &TraceState,
+             dispatch: u64,
+             rng: &mut XorShift64)
+             -> Result<Option<Counterexample>, String> {
+    let mut initial = NativeState::default();
+    for reg in [Registers::Rax,
+                Registers::Rbx,
+                Registers::Rcx,
+                Registers::Rdx,
+                Registers::Rsi,
+                Registers::Rdi,
+                Registers::Rbp,
+                Registers::R8,
+                Registers::R9,
+                Registers::R10,
+                Registers::R11,
+                Registers::R12,
+                Registers::R13,
+                Registers::R14,
+                Registers::R15]
+    {
+        initial.registers.insert(reg, rng.next_u64());
+    }
+    initial.registers.insert(Registers::Rsp, TRIAL_RSP);
+    initial.registers.insert(Registers::Flags, 0x2);
+    initial.registers.insert(reg_allocation.vsp, TRIAL_VSP);
+    initial.registers.insert(reg_allocation.vip, state.vip);
+    initial.registers.insert(reg_allocation.key, state.key);
+
+    let mut emulator = X86Emulator::new(image, step.handler_address, &initial);
+    let mut interpreter = VmInterpreter { memory: VmMemory::new(image),
+                                          register_file: [0; REGISTER_FILE_SIZE],
+                                          vsp: TRIAL_VSP,
+                                          writes: Vec::new() };
+
+    for offset in 0 .. REGISTER_FILE_SIZE {
+        let byte = rng.next_u64() as u8;
+        interpreter.register_file[offset] = byte;
+        emulator.memory.write(TRIAL_RSP + offset as u64, 1, byte as u64);
+    }
+    for address in (TRIAL_VSP - STACK_WINDOW .. TRIAL_VSP + STACK_WINDOW)
+                   .chain(SCRATCH_ADDRESS .. SCRATCH_ADDRESS + SCRATCH_SIZE)
+    {
+        let byte = rng.next_u64() & 0xff;
+        interpreter.memory.write(address, 1, byte);
+        emulator.memory.write(address, 1, byte);
+    }
+    if matches!(step.instruction,
+                HandlerVmInstruction::Fetch(_) | HandlerVmInstruction::Store(_))
+    {
+        let address = SCRATCH_ADDRESS + (rng.next_u64() & 0x18);
+        interpreter.memory.write(TRIAL_VSP, 8, address);
+        emulator.memory.write(TRIAL_VSP, 8, address);
+    }
+
+    let stack = (0 .. 4).map(|index| interpreter.memory.read(TRIAL_VSP + index * 8, 8))
+                        .collect::<Vec<_>>();
+    let flags_mask = compared_flags(step.instruction, &interpreter.memory);
+
+    let mut steps = 0;
+    while emulator.rip != dispatch {
+        if steps == MAX_HANDLER_STEPS {
+            return Err("no dispatch within the step limit".to_string());
+        }
+        let address = emulator.rip;
+        emulator.step()
+                .map_err(|reason| format!("{:#x} {}", address, reason))?;
+        steps += 1;
+    }
+
+    match interpreter.execute(step) {
+        InterpreterEvent::Continue => {},
+        event => return Err(format!("interpreter stopped with {:?}", event)),
+    }
+
+    let mut disagreements = Vec::new();
+    let mut compare = |location: String, native: u64, expected: u64| {
+        if native != expected {
+            disagreements.push(Disagreement { location,
+                                              native,
+                                              expected });
+        }
+    };
+
+    let native_vsp = emulator.read_register(reg_allocation.vsp.into());
+    compare("vsp".to_string(), native_vsp, interpreter.vsp);
+    compare("vip".to_string(),
+            emulator.read_register(reg_allocation.vip.into()),
+            state.next_vip);
+    compare("key".to_string(),
+            emulator.read_register(reg_allocation.key.into()),
+            state.next_key);
+
+    let flags_address = flags_mask.map(|_| interpreter.vsp);
+    if let (Some(address), Some(mask)) = (flags_address, flags_mask) {
+        compare(format!("flags at [{:#x}]", address),
+                emulator.memory.read(address, 8) & mask,
+                interpreter.memory.read(address, 8) & mask);
+    }
+    let is_flags_byte =
+        |address: u64| flags_address.is_some_and(|flags| (flags .. flags + 8).contains(&address));
+
+    for address in (TRIAL_VSP - STACK_WINDOW .. TRIAL_VSP + STACK_WINDOW)
+                   .chain(SCRATCH_ADDRESS .. SCRATCH_ADDRESS + SCRATCH_SIZE)
+    {
+        if !is_flags_byte(address) {
+            compare(format!("byte at [{:#x}]", address),
+                    emulator.memory.read(address, 1),
+                    interpreter.memory.read(address, 1));
+        }
+    }
+    for offset in 0 .. REGISTER_FILE_SIZE {
+        compare(format!("register file byte {:#x}", offset),
+                emulator.memory.read(TRIAL_RSP + offset as u64, 1),
+                interpreter.register_file[offset] as u64);
+    }
+
+    match disagreements.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(Counterexample { stack,
+                                          disagreements })),
+    }
+}
+
+/// Emulates every distinct handler of the trace on random virtual stacks and register files
+/// and compares the outcome with the semantics of its decoded instruction. Fails when the trace
+/// can not be decoded
+pub fn validate_trace(image: &dyn Image,
+                      vm_call_address: u64,
+                      trials: usize,
+                      seed: u64)
+                      -> Result<Vec<HandlerValidation>, String> {
+    let (vm_context, steps) = trace_with_states(image, vm_call_address)?;
+    let reg_allocation = &vm_context.register_allocation;
+    let mut rng = XorShift64::new(seed);
+    let mut validations: Vec<HandlerValidation> = Vec::new();
+
+    for (step, state) in steps.iter() {
+        if step.handler_class == HandlerClass::UnconditionalBranch ||
+           !has_semantics(step.instruction) ||
+           validations.iter()
+                      .any(|validation| validation.handler_address == step.handler_address)
+        {
+            continue;
+        }
+
+        let outcome = match dispatch_address(step.handler_address, image) {
+            None => ValidationOutcome::NotEmulated("handler does not dispatch".to_string()),
+            Some(dispatch) => {
+                let mut outcome = ValidationOutcome::Agrees(trials);
+                for _ in 0 .. trials {
+                    match run_trial(image,
+                                    reg_allocation,
+                                    step,
+                                    state,
+                                    dispatch,
+                                    &mut rng)
+                    {
+                        Ok(None) => {},
+                        Ok(Some(counterexample)) => {
+                            outcome = ValidationOutcome::Disagrees(counterexample);
+                            break;
+                        },
+                        Err(reason) => {
+                            outcome = ValidationOutcome::NotEmulated(reason);
+                            break;
+                        },
+                    }
+                }
+                outcome
+            },
+        };
+
+        validations.push(HandlerValidation { handler_address: step.handler_address,
+                                             instruction: step.instruction,
+                                             outcome });
+    }
+
+    Ok(validations)
+}
+
+pub fn print_validations(validations: &[HandlerValidation]) {
+    let failures = validations.iter()
+                              .filter(|validation| {
+                                  !matches!(validation.outcome, ValidationOutcome::Agrees(_))
+                              })
+                              .count();
+    println!("Validated handlers: {}, disagreeing or not emulated: {}",
+             validations.len(),
+             failures);
+
+    for validation in validations.iter() {
+        match &validation.outcome {
+            ValidationOutcome::Agrees(trials) => {
+                println!("{:#x} {:<20} agrees in {} trials",
+                         validation.handler_address,
+                         validation.instruction.to_string(),
+                         trials);
+            },
+            ValidationOutcome::NotEmulated(reason) => {
+                println!("{:#x} {:<20} not emulated: {}",
+                         validation.handler_address,
+                         validation.instruction.to_string(),
+                         reason);
+            },
+            ValidationOutcome::Disagrees(counterexample) => {
+                println!("{:#x} {:<20} DISAGREES",
+                         validation.handler_address,
+                         validation.instruction.to_string());
+                for (index, value) in counterexample.stack.iter().enumerate() {
+                    println!("    [vsp+{:#x}] = {:#x}", index * 8, value);
+                }
+                for disagreement in counterexample.disagreements.iter() {
+                    println!("    {}: native {:#x}, expected {:#x}",
+                             disagreement.location,
+                             disagreement.native,
+                             disagreement.expected);
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::fmt::Debug;
+
+use crate::{
+    image::Image,
+    interpreter::REGISTER_FILE_SIZE,
+    register_map::SlotValue,
+    trace::TraceStep,
+    vm_handler::{VmContext, VmHandler},
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+/// What a tracker knows about a value on the virtual stack
+pub trait TrackedValue: Clone + Debug {
+    /// What a word sized register file slot keeps of a value popped into it
+    type Slot: Copy + Debug;
+
+    /// A value vmentry left on the stack, with its constant when the vm call decides it
+    fn entry(value: SlotValue,
+             constant: Option<u64>)
+             -> Self;
+
+    fn slot_value(&self) -> Option<Self::Slot>;
+
+    /// Pushed from a whole register file slot at offset
+    fn from_slot(offset: u8,
+                 slot: Option<Self::Slot>)
+                 -> Self;
+
+    /// Pushed by an instruction with an immediate
+    fn immediate(instruction: HandlerVmInstruction,
+                 value: u64)
+                 -> Self;
+
+    /// Computed or pushed by the instruction from something not tracked
+    fn computed(instruction: HandlerVmInstruction) -> Self;
+
+    /// Result of a binary instruction on its operands, None where an operand was lost
+    fn result(instruction: HandlerVmInstruction,
+              size: usize,
+              first: Option<Self>,
+              second: Option<Self>)
+              -> Self;
+
+    /// Flags an arithmetic instruction pushes above its result
+    fn flags(instruction: HandlerVmInstruction) -> Self;
+}
+
+/// Bytes an operand of the given size occupies on the virtual stack
+fn stack_slot(size: usize) -> usize {
+    size.max(2)
+}
+
+/// The virtual stack and the word sized register file slots, followed through a trace
+#[derive(Debug, Clone)]
+pub struct VirtualStack<V: TrackedValue> {
+    /// Size and value of the values on the virtual stack, top last. The stack below a
+    /// misaligned pop or popvsp is unknown
+    stack: Vec<(usize, V)>,
+    slots: Vec<Option<V::Slot>>,
+    /// Bytes of the native words vmentry pushes and register file slots hold
+    pub word_size: usize,
+}
+
+impl<V: TrackedValue> VirtualStack<V> {
+    /// The virtual stack at the first handler: the pushed value, the return address, the
+    /// native registers in push order and the relocation delta
+    pub fn new(vm_context: &VmContext,
+               image: &dyn Image)
+               -> Self {
+        let word_size = vm_context.bitness as usize / 8;
+        let mut virtual_stack = Self { stack: Vec::new(),
+                                       slots: vec![None; REGISTER_FILE_SIZE / word_size],
+                                       word_size };
+
+        virtual_stack.push(word_size,
+                           V::entry(SlotValue::PushedValue, Some(vm_context.pushed_val)));
+        virtual_stack.push(word_size,
+                           V::entry(SlotValue::ReturnAddress,
+                                    Some(vm_context.call_return_address)));
+        for &reg in vm_context.push_order.iter() {
+            virtual_stack.push(word_size, V::entry(SlotValue::Native(reg), None));
+        }
+
+        let vm_entry_handler = VmHandler::new(vm_context.vm_entry_address, image);
+        if let Some(constant) = vm_entry_handler.get_pushed_constant_vm_entry() {
+            virtual_stack.push(word_size, V::entry(SlotValue::RelocationDelta, Some(constant)));
+        }
+
+        virtual_stack
+    }
+
+    fn push(&mut self,
+            size: usize,
+            value: V) {
+        self.stack.push((stack_slot(size), value));
+    }
+
+    /// Pops a value of the size, a value of another size makes the whole stack unknown
+    pub fn pop(&mut self,
+               size: usize)
+               -> Option<V> {
+        match self.stack.pop() {
+            Some((slot, value)) if slot == stack_slot(size) => Some(value),
+            Some(_) => {
+                self.stack.clear();
+                None
+            },
+            None => None,
+        }
+    }
+
+    /// The value a pop of the size would return
+    pub fn peek(&self,
+                size: usize)
+                -> Option<&V> {
+        match self.stack.last() {
+            Some((slot, value)) if *slot == stack_slot(size) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The word sized value under depth values of the stack
+    pub fn word_below(&self,
+                      depth: usize)
+                      -> Option<&V> {
+        let index = self.stack.len().checked_sub(depth + 1)?;
+        match &self.stack[index] {
+            (slot, value) if *slot == self.word_size => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Pops two operands, pushes the result and the flags
+    fn binary(&mut self,
+              instruction: HandlerVmInstruction,
+              size: usize,
+              second_size: usize) {
+        let first = self.pop(size);
+        let second = self.pop(second_size);
+        self.push(size, V::result(instruction, size, first, second));
+        self.push(self.word_size, V::flags(instruction));
+    }
+
+    /// True if the bytes at offset are exactly one register file slot
+    fn is_whole_slot(&self,
+                     offset: u8,
+                     size: usize)
+                     -> bool {
+        size == self.word_size && (offset as usize).is_multiple_of(self.word_size)
+    }
+
+    /// The register file bytes at offset, if they are a whole tracked slot
+    pub fn slot(&self,
+                offset: u8,
+                size: usize)
+                -> Option<V::Slot> {
+        match self.is_whole_slot(offset, size) {
+            true => self.slots.get(offset as usize / self.word_size).copied().flatten(),
+            false => None,
+        }
+    }
+
+    fn write_slot(&mut self,
+                  offset: u8,
+                  size: usize,
+                  value: Option<V::Slot>) {
+        let first = offset as usize / self.word_size;
+        let last = ((offset as usize + size - 1) / self.word_size).min(self.slots.len() - 1);
+        for slot in self.slots[first ..= last].iter_mut() {
+            *slot = None;
+        }
+
+        if self.is_whole_slot(offset, size) {
+            self.slots[first] = value;
+        }
+    }
+
+    /// Applies a handler to the stack and the register file
+    pub fn step(&mut self,
+                step: &TraceStep) {
+        if step.handler_class == HandlerClass::UnconditionalBranch {
+            self.pop(self.word_size);
+            return;
+        }
+
+        match step.instruction {
+            HandlerVmInstruction::Pop(size, offset) => {
+                let value = self.pop(size).and_then(|value| value.slot_value());
+                self.write_slot(offset, size, value);
+            },
+            HandlerVmInstruction::Push(size, offset) => {
+                let value = match self.is_whole_slot(offset, size) {
+                    true => V::from_slot(offset, self.slot(offset, size)),
+                    false => V::computed(step.instruction),
+                };
+                self.push(size, value);
+            },
+            HandlerVmInstruction::PushImm64(value) => {
+                self.push(8, V::immediate(step.instruction, value));
+            },
+            HandlerVmInstruction::PushImm32(value) => {
+                self.push(4, V::immediate(step.instruction, value as u64));
+            },
+            HandlerVmInstruction::PushImm16(value) => {
+                self.push(2, V::immediate(step.instruction, value as u64));
+            },
+            HandlerVmInstruction::PushVsp(size) => self.push(size, V::computed(step.instruction)),
+            HandlerVmInstruction::PopVsp(_) => self.stack.clear(),
+            HandlerVmInstruction::Add(size) |
+            HandlerVmInstruction::Nand(size) |
+            HandlerVmInstruction::Nor(size) => self.binary(step.instruction, size, size),
+            // The shift count is a byte
+            HandlerVmInstruction::Shr(size) => self.binary(step.instruction, size, 1),
+            HandlerVmInstruction::Fetch(size) => {
+                self.pop(self.word_size);
+                self.push(size, V::computed(step.instruction));
+            },
+            HandlerVmInstruction::Store(size) => {
+                self.pop(self.word_size);
+                self.pop(size);
+            },
+            HandlerVmInstruction::VmExit => {},
+            _ => self.stack.clear(),
+        }
+    }
+}
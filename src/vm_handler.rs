@@ -1,16 +1,13 @@
 use crate::{
-    match_assembly::{
-        match_fetch_encrypted_vip, match_fetch_vip, match_push_rolling_key,
-        match_xor_16_rolling_key_dest, match_xor_16_rolling_key_source,
-        match_xor_32_rolling_key_source, match_xor_64_rolling_key_dest,
-        match_xor_64_rolling_key_source, match_xor_8_rolling_key_dest,
-        match_xor_8_rolling_key_source,
-    },
+    canonicalize::canonicalize_instructions,
+    image::Image,
+    match_assembly::match_fetch_encrypted_vip,
+    slicer::HandlerDecryption,
     transforms::{get_transform_for_instruction, EmulateEncryption, EmulateTransform},
     util::*,
+    walker::{walk_handler, WalkEnd},
 };
 use iced_x86::{Code, Instruction, OpKind};
-use pelite::pe64::PeFile;
 
 #[derive(Debug)]
 pub struct VmRegisterAllocation {
@@ -28,6 +25,8 @@ pub struct VmContext {
     pub vm_entry_address: u64,
     /// Pushed value
     pub pushed_val: u64,
+    /// Address after the call into vmentry, the return address the call pushes
+    pub call_return_address: u64,
     /// Vip direction
     pub vip_direction_forwards: bool,
     /// Register push order
@@ -38,54 +37,80 @@ pub struct VmContext {
     pub vip_value: u64,
     /// Next handler address
     pub handler_address: u64,
+    /// Address vmentry adds the decrypted offset of the first handler to
+    pub handler_base: u64,
+    /// Difference of the load address and the preferred image base, added to vip
+    pub relocation_delta: u64,
+    /// 32 or 64, the native word size the vm works with
+    pub bitness: u32,
 }
 
 impl VmContext {
-    pub fn new(pe_file: &PeFile,
-               pe_bytes: &[u8],
+    pub fn new(image: &dyn Image,
                vm_call_address: u64)
                -> Self {
-        let (pushed_val, vm_entry_address) = handle_vm_call(pe_file, pe_bytes, vm_call_address);
+        Self::try_new(image, vm_call_address).unwrap()
+    }
 
-        let vm_entry_handler = VmHandler::new(vm_entry_address, pe_file, pe_bytes);
+    /// Decodes vmentry up to the first handler, fails when the address is not a push, call pair
+    /// into a vmentry or the first handler offset is outside the image
+    pub fn try_new(image: &dyn Image,
+                   vm_call_address: u64)
+                   -> Result<Self, String> {
+        if !is_vm_call(image, vm_call_address) {
+            return Err(format!("no push, call pair at {:#x}", vm_call_address));
+        }
+        let (pushed_val, vm_entry_address) = handle_vm_call(image, vm_call_address);
+        let push_length = disassemble_instruction_at_va(image, vm_call_address).len() as u64;
+        let call_return_address =
+            disassemble_instruction_at_va(image, vm_call_address + push_length).next_ip();
+
+        let vm_entry_handler = VmHandler::new(vm_entry_address, image);
+        if !vm_entry_handler.is_vm_entry() {
+            return Err(format!("the call at {:#x} does not enter a vmentry", vm_call_address));
+        }
 
         let push_order = vm_entry_handler.get_push_order_vm_entry();
 
         let register_allocation = vm_entry_handler.get_register_allocation_vm_entry();
 
-        let direction_is_forwards = vm_entry_handler.determine_is_forwards(&register_allocation);
-
-        // Get the initial_vip
-        let initial_vip =
-            vm_entry_handler.get_initial_vip(&register_allocation, pushed_val) + 0x100000000;
-        let mut vip = initial_vip;
-
-        // Rolling key is initialized to the initial vip
+        let direction_is_forwards = vm_entry_handler.determine_is_forwards(&register_allocation)
+                                                    .ok_or("vmentry does not move vip")?;
+
+        // Get the initial_vip, relative to the vip base vmentry adds or the image base rounded
+        // down to 4gb
+        let bitness = image.bitness();
+        let address_mask = u64::MAX >> (64 - bitness);
+        let image_base = image.image_base();
+        let vip_base = vm_entry_handler.get_vip_base_vm_entry(&register_allocation)
+                                       .unwrap_or(image_base & !0xffff_ffff);
+        let initial_vip = vm_entry_handler.get_initial_vip(&register_allocation, pushed_val)
+                                          .wrapping_add(vip_base);
+
+        // Rolling key is initialized to the initial vip at the preferred image base, the vip
+        // then moves by the relocation delta vmentry pushes
         let mut rolling_key = initial_vip;
+        let relocation_delta = vm_entry_handler.get_pushed_constant_vm_entry().unwrap_or(0);
+        let mut vip = initial_vip.wrapping_add(relocation_delta) & address_mask;
 
         // Get the handler base address value
-        let instruction_iter = vm_entry_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !(insn.code() == Code::Lea_r64_m &&
-                                                         insn.memory_displacement64() != 0)
-                                                   });
-        let handler_base_address = instruction_iter.next().unwrap().memory_displacement64();
-        let mut instruction_iter =
-            instruction_iter.skip_while(|insn| !match_fetch_vip(insn, &register_allocation));
-
-        // Get the reg where the encrypted offset has been loaded into
-        let encrypted_offset_reg = instruction_iter.next().unwrap().op0_register();
-
-        let encrypted_offset = fetch_dword_vip(pe_file, pe_bytes, &mut vip, direction_is_forwards);
-
-        let encryption_iter = instruction_iter.take_while(|&insn| {
-                                                  !(match_push_rolling_key(insn,
-                                                                           &register_allocation))
-                                              });
+        let lea_code = vm_entry_handler.word_codes().lea;
+        let handler_base_address = vm_entry_handler.instructions
+                                                   .iter()
+                                                   .find(|insn| {
+                                                       insn.code() == lea_code &&
+                                                       insn.memory_displacement64() != 0
+                                                   })
+                                                   .unwrap()
+                                                   .memory_displacement64();
+
+        let decryption = handler_decryption(&vm_entry_handler, &register_allocation)?;
 
-        let unencrypted_offset = encrypted_offset.emulate_encryption(encryption_iter,
-                                                                     &mut rolling_key,
-                                                                     encrypted_offset_reg);
+        let encrypted_offset = fetch_dword_vip(image, &mut vip, direction_is_forwards)?;
+
+        let unencrypted_offset =
+            encrypted_offset.emulate_encryption(decryption.next_offset.program.iter(),
+                                                &mut rolling_key);
 
         // hmmm yes
         // movsxd offset_reg, offset_reg_32
@@ -94,69 +119,57 @@ impl VmContext {
             handler_base_address.wrapping_add(unencrypted_offset as i32 as i64 as u64);
 
         let vip_value = vip;
-        Self { register_allocation,
-               vm_entry_address,
-               pushed_val,
-               vip_direction_forwards: direction_is_forwards,
-               push_order,
-               rolling_key,
-               vip_value,
-               handler_address: next_handler_address }
+        Ok(Self { register_allocation,
+                  vm_entry_address,
+                  pushed_val,
+                  call_return_address,
+                  vip_direction_forwards: direction_is_forwards,
+                  push_order,
+                  rolling_key,
+                  vip_value,
+                  handler_address: next_handler_address,
+                  handler_base: handler_base_address,
+                  relocation_delta,
+                  bitness })
     }
 
-    pub fn disassemble_single_dword_operand(&mut self,
-                                            vm_handler: &VmHandler,
-                                            pe_file: &PeFile,
-                                            pe_bytes: &[u8])
-                                            -> u32 {
-        let instruction_iter = vm_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !match_xor_32_rolling_key_source(insn,
-                                                                       &self.register_allocation)
-                                                   });
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                                  !match_push_rolling_key(insn,
-                                                                          &self.register_allocation)
-                                              });
-
-        let encrypted_dword = fetch_dword_vip(pe_file,
-                                              pe_bytes,
-                                              &mut self.vip_value,
-                                              self.vip_direction_forwards);
+    /// Continues decoding at a concrete branch target. Like vmentry, the branch handler
+    /// seeds the rolling key with the new vip and decrypts the first handler offset. Fails when
+    /// the handler has no offset decryption or the target is outside the image
+    pub fn follow_branch(&mut self,
+                         vm_handler: &VmHandler,
+                         target: u64,
+                         image: &dyn Image)
+                         -> Result<(), String> {
+        let address_mask = u64::MAX >> (64 - self.bitness);
+        self.vip_value = target;
+        self.rolling_key = target.wrapping_sub(self.relocation_delta) & address_mask;
+
+        let lea_code = vm_handler.word_codes().lea;
+        if let Some(lea) = vm_handler.instructions.iter().find(|insn| {
+                                                          insn.code() == lea_code &&
+                                                          insn.memory_displacement64() != 0
+                                                      })
+        {
+            self.handler_address = lea.memory_displacement64();
+        }
 
-        let return_dword = encrypted_dword.emulate_encryption(encryption_iter,
-                                                              &mut self.rolling_key,
-                                                              encrypted_reg);
+        let decryption = handler_decryption(vm_handler, &self.register_allocation)?;
+        self.advance_handler_address(&decryption, image)
+    }
 
-        let encrypted_offset = fetch_dword_vip(pe_file,
-                                               pe_bytes,
+    /// Decrypts the offset to the next handler and moves the handler address to it
+    fn advance_handler_address(&mut self,
+                               decryption: &HandlerDecryption,
+                               image: &dyn Image)
+                               -> Result<(), String> {
+        let encrypted_offset = fetch_dword_vip(image,
                                                &mut self.vip_value,
-                                               self.vip_direction_forwards);
-
-        let instruction_iter = vm_handler.instructions.iter();
-        // Skip it twice because dword arg
-        let mut match_count = 0;
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       if match_xor_32_rolling_key_source(insn,
-                                                                          &self.register_allocation)
-                                       {
-                                           match_count += 1;
-                                       }
-
-                                                       match_count != 2
-                                                   });
-
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                                  !match_push_rolling_key(insn,
-                                                                          &self.register_allocation)
-                                              });
+                                               self.vip_direction_forwards)?;
 
-        let unencrypted_offset = encrypted_offset.emulate_encryption(encryption_iter,
-                                                                     &mut self.rolling_key,
-                                                                     encrypted_reg);
+        let unencrypted_offset =
+            encrypted_offset.emulate_encryption(decryption.next_offset.program.iter(),
+                                                &mut self.rolling_key);
 
         // hmmm yes
         // movsxd offset_reg, offset_reg_32
@@ -165,257 +178,211 @@ impl VmContext {
                                        .wrapping_add(unencrypted_offset as i32 as i64 as u64);
 
         self.handler_address = next_handler_address;
-
-        return_dword
+        Ok(())
     }
 
-    pub fn disassemble_single_qword_operand(&mut self,
+    pub fn disassemble_single_dword_operand(&mut self,
                                             vm_handler: &VmHandler,
-                                            pe_file: &PeFile,
-                                            pe_bytes: &[u8])
-                                            -> u64 {
-        let instruction_iter = vm_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !match_xor_64_rolling_key_source(insn,
-                                                                       &self.register_allocation)
-                                                   });
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                  !match_xor_64_rolling_key_dest(insn, &self.register_allocation)
-                              });
-        let encrypted_qword = fetch_qword_vip(pe_file,
-                                              pe_bytes,
+                                            image: &dyn Image)
+                                            -> Result<u32, String> {
+        let decryption = handler_decryption(vm_handler, &self.register_allocation)?;
+        let operand = decryption.operand
+                                .as_ref()
+                                .ok_or("no operand decryption found in the handler")?;
+
+        let encrypted_dword = fetch_dword_vip(image,
                                               &mut self.vip_value,
-                                              self.vip_direction_forwards);
+                                              self.vip_direction_forwards)?;
 
-        let return_qword = encrypted_qword.emulate_encryption(encryption_iter,
-                                                              &mut self.rolling_key,
-                                                              encrypted_reg);
+        let return_dword =
+            encrypted_dword.emulate_encryption(operand.program.iter(), &mut self.rolling_key);
 
-        let encrypted_offset = fetch_dword_vip(pe_file,
-                                               pe_bytes,
-                                               &mut self.vip_value,
-                                               self.vip_direction_forwards);
-
-        let instruction_iter = vm_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !match_xor_32_rolling_key_source(insn,
-                                                                       &self.register_allocation)
-                                                   });
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                                  !match_push_rolling_key(insn,
-                                                                          &self.register_allocation)
-                                              });
+        self.advance_handler_address(&decryption, image)?;
 
-        let unencrypted_offset = encrypted_offset.emulate_encryption(encryption_iter,
-                                                                     &mut self.rolling_key,
-                                                                     encrypted_reg);
+        Ok(return_dword)
+    }
 
-        // hmmm yes
-        // movsxd offset_reg, offset_reg_32
-        // add handler_base, offset_reg
-        let next_handler_address = self.handler_address
-                                       .wrapping_add(unencrypted_offset as i32 as i64 as u64);
+    pub fn disassemble_single_qword_operand(&mut self,
+                                            vm_handler: &VmHandler,
+                                            image: &dyn Image)
+                                            -> Result<u64, String> {
+        let decryption = handler_decryption(vm_handler, &self.register_allocation)?;
+        let operand = decryption.operand
+                                .as_ref()
+                                .ok_or("no operand decryption found in the handler")?;
+
+        let encrypted_qword = fetch_qword_vip(image,
+                                              &mut self.vip_value,
+                                              self.vip_direction_forwards)?;
 
-        self.handler_address = next_handler_address;
+        let return_qword =
+            encrypted_qword.emulate_encryption(operand.program.iter(), &mut self.rolling_key);
+
+        self.advance_handler_address(&decryption, image)?;
 
-        return_qword
+        Ok(return_qword)
     }
 
     pub fn disassemble_single_word_operand(&mut self,
                                            vm_handler: &VmHandler,
-                                           pe_file: &PeFile,
-                                           pe_bytes: &[u8])
-                                           -> u16 {
-        let instruction_iter = vm_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !match_xor_16_rolling_key_source(insn,
-                                                                       &self.register_allocation)
-                                                   });
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                  !match_xor_16_rolling_key_dest(insn, &self.register_allocation)
-                              });
-        let encrypted_word = fetch_word_vip(pe_file,
-                                            pe_bytes,
+                                           image: &dyn Image)
+                                           -> Result<u16, String> {
+        let decryption = handler_decryption(vm_handler, &self.register_allocation)?;
+        let operand = decryption.operand
+                                .as_ref()
+                                .ok_or("no operand decryption found in the handler")?;
+
+        let encrypted_word = fetch_word_vip(image,
                                             &mut self.vip_value,
-                                            self.vip_direction_forwards);
+                                            self.vip_direction_forwards)?;
 
-        let return_word = encrypted_word.emulate_encryption(encryption_iter,
-                                                            &mut self.rolling_key,
-                                                            encrypted_reg);
+        let return_word =
+            encrypted_word.emulate_encryption(operand.program.iter(), &mut self.rolling_key);
 
-        let encrypted_offset = fetch_dword_vip(pe_file,
-                                               pe_bytes,
-                                               &mut self.vip_value,
-                                               self.vip_direction_forwards);
-
-        let instruction_iter = vm_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !match_xor_32_rolling_key_source(insn,
-                                                                       &self.register_allocation)
-                                                   });
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                                  !match_push_rolling_key(insn,
-                                                                          &self.register_allocation)
-                                              });
+        self.advance_handler_address(&decryption, image)?;
 
-        let unencrypted_offset = encrypted_offset.emulate_encryption(encryption_iter,
-                                                                     &mut self.rolling_key,
-                                                                     encrypted_reg);
-
-        // hmmm yes
-        // movsxd offset_reg, offset_reg_32
-        // add handler_base, offset_reg
-        let next_handler_address = self.handler_address
-                                       .wrapping_add(unencrypted_offset as i32 as i64 as u64);
-
-        self.handler_address = next_handler_address;
-
-        return_word
+        Ok(return_word)
     }
 
     pub fn disassemble_single_byte_operand(&mut self,
                                            vm_handler: &VmHandler,
-                                           pe_file: &PeFile,
-                                           pe_bytes: &[u8])
-                                           -> u8 {
-        let instruction_iter = vm_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !match_xor_8_rolling_key_source(insn,
-                                                                       &self.register_allocation)
-                                                   });
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                  !match_xor_8_rolling_key_dest(insn, &self.register_allocation)
-                              });
-        let encrypted_byte = fetch_byte_vip(pe_file,
-                                            pe_bytes,
+                                           image: &dyn Image)
+                                           -> Result<u8, String> {
+        let decryption = handler_decryption(vm_handler, &self.register_allocation)?;
+        let operand = decryption.operand
+                                .as_ref()
+                                .ok_or("no operand decryption found in the handler")?;
+
+        let encrypted_byte = fetch_byte_vip(image,
                                             &mut self.vip_value,
-                                            self.vip_direction_forwards);
-
-        let return_byte = encrypted_byte.emulate_encryption(encryption_iter,
-                                                            &mut self.rolling_key,
-                                                            encrypted_reg);
-
-        let encrypted_offset = fetch_dword_vip(pe_file,
-                                               pe_bytes,
-                                               &mut self.vip_value,
-                                               self.vip_direction_forwards);
-
-        let instruction_iter = vm_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !match_xor_32_rolling_key_source(insn,
-                                                                       &self.register_allocation)
-                                                   });
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                                  !match_push_rolling_key(insn,
-                                                                          &self.register_allocation)
-                                              });
+                                            self.vip_direction_forwards)?;
 
-        let unencrypted_offset = encrypted_offset.emulate_encryption(encryption_iter,
-                                                                     &mut self.rolling_key,
-                                                                     encrypted_reg);
+        let return_byte =
+            encrypted_byte.emulate_encryption(operand.program.iter(), &mut self.rolling_key);
 
-        // hmmm yes
-        // movsxd offset_reg, offset_reg_32
-        // add handler_base, offset_reg
-        let next_handler_address = self.handler_address
-                                       .wrapping_add(unencrypted_offset as i32 as i64 as u64);
+        self.advance_handler_address(&decryption, image)?;
 
-        self.handler_address = next_handler_address;
-
-        return_byte
+        Ok(return_byte)
     }
 
     pub fn disassemble_no_operand(&mut self,
                                   vm_handler: &VmHandler,
-                                  pe_file: &PeFile,
-                                  pe_bytes: &[u8]) {
-        let encrypted_offset = fetch_dword_vip(pe_file,
-                                               pe_bytes,
-                                               &mut self.vip_value,
-                                               self.vip_direction_forwards);
-
-        let instruction_iter = vm_handler.instructions.iter();
-        let mut instruction_iter = instruction_iter.skip_while(|insn| {
-                                                       !match_xor_32_rolling_key_source(insn,
-                                                                       &self.register_allocation)
-                                                   });
-        let encrypted_reg = instruction_iter.next().unwrap().op0_register();
-        let encryption_iter = instruction_iter.take_while(|insn| {
-                                                  !match_push_rolling_key(insn,
-                                                                          &self.register_allocation)
-                                              });
+                                  image: &dyn Image)
+                                  -> Result<(), String> {
+        let decryption = handler_decryption(vm_handler, &self.register_allocation)?;
 
-        let unencrypted_offset = encrypted_offset.emulate_encryption(encryption_iter,
-                                                                     &mut self.rolling_key,
-                                                                     encrypted_reg);
+        self.advance_handler_address(&decryption, image)
+    }
+}
 
-        // hmmm yes
-        // movsxd offset_reg, offset_reg_32
-        // add handler_base, offset_reg
-        let next_handler_address = self.handler_address
-                                       .wrapping_add(unencrypted_offset as i32 as i64 as u64);
+/// Whether the address is a push, call pair whose call enters a vmentry, a plain push, call
+/// pair of native code is not
+pub fn is_vm_entry_call(image: &dyn Image,
+                        address: u64)
+                        -> bool {
+    is_vm_call(image, address) &&
+    VmHandler::new(handle_vm_call(image, address).1, image).is_vm_entry()
+}
 
-        self.handler_address = next_handler_address;
-    }
+/// The decryption of the handler's operand and next handler offset
+fn handler_decryption(vm_handler: &VmHandler,
+                      reg_allocation: &VmRegisterAllocation)
+                      -> Result<HandlerDecryption, String> {
+    vm_handler.slice_decryption(reg_allocation)
+              .ok_or_else(|| "no handler offset decryption found in the handler".to_string())
 }
 
+/// Native word sized forms of the instructions vmentry, vmexit, the dispatch and the vip and
+/// vsp updates are recognised by
+pub struct WordCodes {
+    pub mov_r_rm: Code,
+    pub mov_r_imm: Code,
+    pub add_r_rm: Code,
+    pub add_rm_imm: Code,
+    pub sub_rm_imm: Code,
+    pub lea: Code,
+    pub push: Code,
+    pub pop: Code,
+    pub pushf: Code,
+    pub popf: Code,
+    pub jmp_rm: Code,
+    /// Offset of the value the vm call pushed from rsp, once vmentry saved the registers, the
+    /// flags and the relocation delta
+    pub pushed_value_offset: u64,
+}
+
+const WORD_CODES_64: WordCodes = WordCodes { mov_r_rm: Code::Mov_r64_rm64,
+                                             mov_r_imm: Code::Mov_r64_imm64,
+                                             add_r_rm: Code::Add_r64_rm64,
+                                             add_rm_imm: Code::Add_rm64_imm32,
+                                             sub_rm_imm: Code::Sub_rm64_imm32,
+                                             lea: Code::Lea_r64_m,
+                                             push: Code::Push_r64,
+                                             pop: Code::Pop_r64,
+                                             pushf: Code::Pushfq,
+                                             popf: Code::Popfq,
+                                             jmp_rm: Code::Jmp_rm64,
+                                             pushed_value_offset: 0x90 };
+
+const WORD_CODES_32: WordCodes = WordCodes { mov_r_rm: Code::Mov_r32_rm32,
+                                             mov_r_imm: Code::Mov_r32_imm32,
+                                             add_r_rm: Code::Add_r32_rm32,
+                                             add_rm_imm: Code::Add_rm32_imm32,
+                                             sub_rm_imm: Code::Sub_rm32_imm32,
+                                             lea: Code::Lea_r32_m,
+                                             push: Code::Push_r32,
+                                             pop: Code::Pop_r32,
+                                             pushf: Code::Pushfd,
+                                             popf: Code::Popfd,
+                                             jmp_rm: Code::Jmp_rm32,
+                                             pushed_value_offset: 0x28 };
+
 pub struct VmHandler {
     pub instructions: Vec<Instruction>,
+    /// Why the walk over the native code stopped
+    pub walk_end: WalkEnd,
+    /// 32 or 64, the mode the handler was decoded in
+    pub bitness: u32,
 }
 
 impl VmHandler {
     pub fn new(address: u64,
-               pe_file: &PeFile,
-               pe_bytes: &[u8])
+               image: &dyn Image)
                -> Self {
-        let mut instruction_address = address;
-        let mut instructions = Vec::new();
-
-        loop {
-            let instruction = disassemble_instruction_at_va(pe_file, pe_bytes, instruction_address);
-
-            match instruction.code() {
-                Code::Retnq | Code::Jmp_rm64 => {
-                    instructions.push(instruction);
-                    break;
-                },
-                Code::Jmp_rel32_64 => {
-                    let jmp_target = instruction.near_branch64();
-                    instruction_address = jmp_target;
-                },
-
-                _ => {
-                    instruction_address += instruction.len() as u64;
-                    instructions.push(instruction);
-                },
-            }
+        let (instructions, walk_end) = walk_handler(address, image);
+
+        Self { instructions: canonicalize_instructions(&instructions),
+               walk_end,
+               bitness: image.bitness() }
+    }
+
+    pub fn word_codes(&self) -> &'static WordCodes {
+        match self.bitness {
+            32 => &WORD_CODES_32,
+            _ => &WORD_CODES_64,
         }
+    }
 
-        Self { instructions }
+    /// Bytes of a native word, the size of a virtual stack slot holding an address
+    pub fn word_size(&self) -> usize {
+        self.bitness as usize / 8
     }
 
     pub fn get_register_allocation_vm_entry(&self) -> VmRegisterAllocation {
+        let codes = self.word_codes();
+
         // Find the handler_address register
         let handler_address_reg = {
             let instruction_last = self.instructions.last().unwrap();
-            if instruction_last.code() == Code::Jmp_rm64 {
-                instruction_last.op0_register().into()
+            if instruction_last.code() == codes.jmp_rm {
+                instruction_last.op0_register().full_register().into()
             } else {
                 let instruction = self.instructions
                                       .iter()
                                       .rev()
-                                      .find(|&&insn| insn.code() == Code::Push_r64)
+                                      .find(|&&insn| insn.code() == codes.push)
                                       .unwrap();
-                instruction.op0_register().into()
+                instruction.op0_register().full_register().into()
             }
         };
 
@@ -423,31 +390,34 @@ impl VmHandler {
         let pop_instruction = self.instructions
                                   .iter()
                                   .rev()
-                                  .find(|&&insn| insn.code() == Code::Pop_r64)
+                                  .find(|&&insn| insn.code() == codes.pop)
                                   .unwrap();
 
-        let key = pop_instruction.op0_register().into();
+        let key = pop_instruction.op0_register().full_register().into();
 
         // Find vsp register
         let mov_vsp_instruction = self.instructions
                                       .iter()
                                       .find(|&&insn| {
-                                          insn.code() == Code::Mov_r64_rm64 &&
-                                          insn.op1_register() == iced_x86::Register::RSP
+                                          insn.code() == codes.mov_r_rm &&
+                                          insn.op1_kind() == OpKind::Register &&
+                                          insn.op1_register().full_register() ==
+                                          iced_x86::Register::RSP
                                       })
                                       .unwrap();
-        let vsp = mov_vsp_instruction.op0_register().into();
+        let vsp = mov_vsp_instruction.op0_register().full_register().into();
 
         // Find vip register
         let mov_vip_instruction = self.instructions
                                       .iter()
                                       .find(|&&insn| {
-                                          insn.code() == Code::Mov_r64_rm64 &&
+                                          insn.code() == codes.mov_r_rm &&
                                           insn.op1_kind() == OpKind::Memory &&
-                                          insn.memory_displacement64() == 0x90
+                                          insn.memory_displacement64() ==
+                                          codes.pushed_value_offset
                                       })
                                       .unwrap();
-        let vip = mov_vip_instruction.op0_register().into();
+        let vip = mov_vip_instruction.op0_register().full_register().into();
 
         VmRegisterAllocation { vip,
                                vsp,
@@ -455,51 +425,138 @@ impl VmHandler {
                                handler_address: handler_address_reg }
     }
 
+    /// Whether the handler has everything vmentry is decoded from: the saved registers, the key
+    /// it pops, the vsp and vip setup, the handler base and a dispatch to the first handler
+    pub fn is_vm_entry(&self) -> bool {
+        let codes = self.word_codes();
+        // Every general purpose register but the stack pointer is saved
+        let saved_register_count = self.bitness as usize / 4 - 1;
+
+        let has_pop = self.instructions.iter().any(|insn| insn.code() == codes.pop);
+        let has_mov_vsp = self.instructions
+                              .iter()
+                              .any(|insn| {
+                                  insn.code() == codes.mov_r_rm &&
+                                  insn.op1_kind() == OpKind::Register &&
+                                  insn.op1_register().full_register() == iced_x86::Register::RSP
+                              });
+        let has_mov_vip = self.instructions
+                              .iter()
+                              .any(|insn| {
+                                  insn.code() == codes.mov_r_rm &&
+                                  insn.op1_kind() == OpKind::Memory &&
+                                  insn.memory_displacement64() == codes.pushed_value_offset
+                              });
+        let has_handler_base = self.instructions
+                                   .iter()
+                                   .any(|insn| {
+                                       insn.code() == codes.lea &&
+                                       insn.memory_displacement64() != 0
+                                   });
+
+        if self.walk_end != WalkEnd::Dispatch ||
+           self.get_push_order_vm_entry().len() < saved_register_count ||
+           !(has_pop && has_mov_vsp && has_mov_vip && has_handler_base)
+        {
+            return false;
+        }
+
+        let register_allocation = self.get_register_allocation_vm_entry();
+        self.slice_decryption(&register_allocation).is_some()
+    }
+
     pub fn get_push_order_vm_entry(&self) -> Vec<Registers> {
+        let codes = self.word_codes();
         let mut registers = Vec::new();
 
         for instruction in self.instructions
                                .iter()
-                               .take_while(|&&insn| insn.code() != Code::Mov_r64_imm64)
+                               .take_while(|&&insn| insn.code() != codes.mov_r_imm)
         {
-            match instruction.code() {
-                Code::Push_r64 => {
-                    let reg = instruction.op0_register();
-                    registers.push(reg.into());
-                },
-                Code::Pushfq => {
-                    registers.push(Registers::Flags);
-                },
-                _ => {},
+            if instruction.code() == codes.push {
+                let reg = instruction.op0_register().full_register();
+                registers.push(reg.into());
+            } else if instruction.code() == codes.pushf {
+                registers.push(Registers::Flags);
             }
         }
 
         registers
     }
 
+    /// Registers vmexit pops from the virtual stack, in pop order
+    pub fn get_pop_order_vm_exit(&self) -> Vec<Registers> {
+        let codes = self.word_codes();
+        let mut registers = Vec::new();
+
+        for instruction in self.instructions.iter() {
+            if instruction.code() == codes.pop {
+                let reg = instruction.op0_register().full_register();
+                registers.push(reg.into());
+            } else if instruction.code() == codes.popf {
+                registers.push(Registers::Flags);
+            }
+        }
+
+        registers
+    }
+
+    /// The relocation delta vmentry pushes after the registers with mov reg, imm; push reg.
+    /// The loader relocates the immediate, it is zero at the preferred image base
+    pub fn get_pushed_constant_vm_entry(&self) -> Option<u64> {
+        let codes = self.word_codes();
+        let mut instruction_iter = self.instructions
+                                       .iter()
+                                       .skip_while(|insn| insn.code() != codes.mov_r_imm);
+        let mov = instruction_iter.next()?;
+        let pushes_constant = instruction_iter.any(|insn| {
+                                                  insn.code() == codes.push &&
+                                                  insn.op0_register() == mov.op0_register()
+                                              });
+
+        pushes_constant.then(|| mov.immediate(1))
+    }
+
+    /// add vip, rm or lea vip, m of the native word size, moving the decrypted vip by its base
+    /// or the relocation delta
+    fn is_vip_base_add(&self,
+                       instruction: &Instruction,
+                       vip: iced_x86::Register)
+                       -> bool {
+        let codes = self.word_codes();
+        (instruction.code() == codes.lea || instruction.code() == codes.add_r_rm) &&
+        check_full_reg_written(instruction, vip)
+    }
+
     pub fn determine_is_forwards(&self,
                                  reg_allocation: &VmRegisterAllocation)
-                                 -> bool {
-        for instruction in self.instructions.iter() {
-            match instruction.code() {
-                Code::Add_rm64_imm32 => {
-                    if reg_allocation.vip == instruction.op0_register().into() &&
-                       instruction.immediate32() == 0x4
-                    {
-                        return true;
-                    }
-                },
-                Code::Sub_rm64_imm32 => {
-                    if reg_allocation.vip == instruction.op0_register().into() &&
-                       instruction.immediate32() == 0x4
-                    {
-                        return false;
-                    }
-                },
-                _ => continue,
+                                 -> Option<bool> {
+        let codes = self.word_codes();
+        let vip = reg_allocation.vip.into();
+
+        // The 32 bit vip decryption works on the whole vip, its add vip, 4 is only told apart
+        // from the vip update by coming before the vip is moved to its base
+        let start = match self.bitness {
+            32 => self.instructions
+                      .iter()
+                      .position(|insn| self.is_vip_base_add(insn, vip))
+                      .unwrap_or(0),
+            _ => 0,
+        };
+
+        for instruction in self.instructions[start ..].iter() {
+            let updates_vip = instruction.op0_kind() == OpKind::Register &&
+                              instruction.op0_register().full_register() == vip &&
+                              instruction.immediate32() == 0x4;
+
+            if instruction.code() == codes.add_rm_imm && updates_vip {
+                return Some(true);
+            }
+            if instruction.code() == codes.sub_rm_imm && updates_vip {
+                return Some(false);
             }
         }
-        panic!("Direction of vm_entry not found")
+        None
     }
 
     pub fn get_initial_vip(&self,
@@ -511,10 +568,7 @@ impl VmHandler {
             self.instructions
                 .iter()
                 .skip_while(|&insn| !match_fetch_encrypted_vip(insn, reg_allocation))
-                .take_while(|&insn| {
-                    !((insn.code() == Code::Lea_r64_m || insn.code() == Code::Add_r64_rm64) &&
-                      check_full_reg_written(insn, reg_allocation.vip.into()))
-                })
+                .take_while(|&insn| !self.is_vip_base_add(insn, reg_allocation.vip.into()))
                 .filter(|&insn| check_full_reg_written(insn, reg_allocation.vip.into()))
         {
             let transform = get_transform_for_instruction(instruction);
@@ -526,9 +580,38 @@ impl VmHandler {
 
         encrypted_vip as u64
     }
+
+    /// The constant vmentry adds to the decrypted vip, loaded with mov reg, imm
+    /// add vip, reg
+    /// lea vip, [vip + reg]
+    pub fn get_vip_base_vm_entry(&self,
+                                 reg_allocation: &VmRegisterAllocation)
+                                 -> Option<u64> {
+        let codes = self.word_codes();
+        let vip = reg_allocation.vip.into();
+        let add_position = self.instructions
+                               .iter()
+                               .position(|insn| self.is_vip_base_add(insn, vip))?;
+        let add = &self.instructions[add_position];
+        let base_reg = if add.code() == codes.add_r_rm && add.op1_kind() == OpKind::Register {
+            add.op1_register()
+        } else if add.code() == codes.lea && add.memory_base().full_register() == vip {
+            add.memory_index()
+        } else if add.code() == codes.lea {
+            add.memory_base()
+        } else {
+            return None;
+        };
+
+        self.instructions[.. add_position].iter()
+                                         .rev()
+                                         .find(|insn| check_full_reg_written(insn, base_reg))
+                                         .filter(|insn| insn.code() == codes.mov_r_imm)
+                                         .map(|insn| insn.immediate(1))
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Registers {
     Rax,
     Rbx,
@@ -549,6 +632,34 @@ pub enum Registers {
     Flags,
 }
 
+impl std::str::FromStr for Registers {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let reg = match name.to_lowercase().as_str() {
+            "rax" => Registers::Rax,
+            "rbx" => Registers::Rbx,
+            "rcx" => Registers::Rcx,
+            "rdx" => Registers::Rdx,
+            "rsi" => Registers::Rsi,
+            "rdi" => Registers::Rdi,
+            "rsp" => Registers::Rsp,
+            "rbp" => Registers::Rbp,
+            "r8" => Registers::R8,
+            "r9" => Registers::R9,
+            "r10" => Registers::R10,
+            "r11" => Registers::R11,
+            "r12" => Registers::R12,
+            "r13" => Registers::R13,
+            "r14" => Registers::R14,
+            "r15" => Registers::R15,
+            "flags" | "rflags" => Registers::Flags,
+            _ => return Err(format!("unknown register {}", name)),
+        };
+        Ok(reg)
+    }
+}
+
 impl From<iced_x86::Register> for Registers {
     fn from(reg: iced_x86::Register) -> Self {
         match reg {
@@ -598,86 +709,47 @@ impl From<Registers> for iced_x86::Register {
     }
 }
 
-pub fn fetch_qword_vip(pe_file: &PeFile,
-                       pe_bytes: &[u8],
-                       vip: &mut u64,
-                       direction_is_forwards: bool)
-                       -> u64 {
-    let return_value;
-
+/// N bytes of bytecode at vip, moving vip past them in the direction of the vm
+fn fetch_vip<const N: usize>(image: &dyn Image,
+                             vip: &mut u64,
+                             direction_is_forwards: bool)
+                             -> Result<[u8; N], String> {
+    if !direction_is_forwards {
+        *vip -= N as u64;
+    }
+    let bytes = image.read(*vip, N)
+                     .ok_or_else(|| format!("bytecode at {:#x} is outside the image", *vip))?;
     if direction_is_forwards {
-        return_value = u64::from_le_bytes(read_bytes_at_va(pe_file, pe_bytes, *vip, 8).unwrap()
-                                                                                      .try_into()
-                                                                                      .unwrap());
-        *vip += 8;
-    } else {
-        *vip -= 8;
-        return_value = u64::from_le_bytes(read_bytes_at_va(pe_file, pe_bytes, *vip, 8).unwrap()
-                                                                                      .try_into()
-                                                                                      .unwrap());
+        *vip += N as u64;
     }
 
-    return_value
+    Ok(bytes.as_ref().try_into().unwrap())
+}
+
+pub fn fetch_qword_vip(image: &dyn Image,
+                       vip: &mut u64,
+                       direction_is_forwards: bool)
+                       -> Result<u64, String> {
+    fetch_vip(image, vip, direction_is_forwards).map(u64::from_le_bytes)
 }
 
-pub fn fetch_word_vip(pe_file: &PeFile,
-                      pe_bytes: &[u8],
+pub fn fetch_word_vip(image: &dyn Image,
                       vip: &mut u64,
                       direction_is_forwards: bool)
-                      -> u16 {
-    let return_value;
-
-    if direction_is_forwards {
-        return_value = u16::from_le_bytes(read_bytes_at_va(pe_file, pe_bytes, *vip, 2).unwrap()
-                                                                                      .try_into()
-                                                                                      .unwrap());
-        *vip += 2;
-    } else {
-        *vip -= 2;
-        return_value = u16::from_le_bytes(read_bytes_at_va(pe_file, pe_bytes, *vip, 2).unwrap()
-                                                                                      .try_into()
-                                                                                      .unwrap());
-    }
-
-    return_value
+                      -> Result<u16, String> {
+    fetch_vip(image, vip, direction_is_forwards).map(u16::from_le_bytes)
 }
 
-pub fn fetch_dword_vip(pe_file: &PeFile,
-                       pe_bytes: &[u8],
+pub fn fetch_dword_vip(image: &dyn Image,
                        vip: &mut u64,
                        direction_is_forwards: bool)
-                       -> u32 {
-    let return_value;
-
-    if direction_is_forwards {
-        return_value = u32::from_le_bytes(read_bytes_at_va(pe_file, pe_bytes, *vip, 4).unwrap()
-                                                                                      .try_into()
-                                                                                      .unwrap());
-        *vip += 4;
-    } else {
-        *vip -= 4;
-        return_value = u32::from_le_bytes(read_bytes_at_va(pe_file, pe_bytes, *vip, 4).unwrap()
-                                                                                      .try_into()
-                                                                                      .unwrap());
-    }
-
-    return_value
+                       -> Result<u32, String> {
+    fetch_vip(image, vip, direction_is_forwards).map(u32::from_le_bytes)
 }
 
-pub fn fetch_byte_vip(pe_file: &PeFile,
-                      pe_bytes: &[u8],
+pub fn fetch_byte_vip(image: &dyn Image,
                       vip: &mut u64,
                       direction_is_forwards: bool)
-                      -> u8 {
-    let return_value;
-
-    if direction_is_forwards {
-        return_value = read_bytes_at_va(pe_file, pe_bytes, *vip, 4).unwrap()[0];
-        *vip += 1;
-    } else {
-        *vip -= 1;
-        return_value = read_bytes_at_va(pe_file, pe_bytes, *vip, 4).unwrap()[0];
-    }
-
-    return_value
+                      -> Result<u8, String> {
+    fetch_vip(image, vip, direction_is_forwards).map(|[byte]: [u8; 1]| byte)
 }
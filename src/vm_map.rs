@@ -0,0 +1,382 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    functions::{
+        describe_function, print_virtualized_functions, virtualized_functions, FunctionTable,
+        RuntimeFunction, VirtualizedFunction,
+    },
+    image::Image,
+    symbolize::ImageSymbols,
+    util::{handle_vm_call, is_vm_call, json_string},
+    vm_handler::{Registers, VmContext, VmHandler, VmRegisterAllocation},
+    vm_matchers::{HandlerClass, HandlerVmInstruction},
+};
+
+/// Opcodes of push imm32 and call rel32, the pair every vm call starts with
+const PUSH_IMM32: u8 = 0x68;
+const CALL_REL32: u8 = 0xe8;
+const VM_CALL_SIZE: usize = 10;
+
+/// Why the decode of a routine stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoutineEnd {
+    VmExit,
+    /// A branch handler, its target is only known at run time
+    UnsupportedBranch,
+    /// A handler other than vmexit that leaves vip alone
+    NoVipChange,
+    /// Vmentry or a handler could not be decoded, for the reason given
+    DecodeFailed(String),
+}
+
+impl RoutineEnd {
+    fn name(&self) -> &'static str {
+        match self {
+            RoutineEnd::VmExit => "vmexit",
+            RoutineEnd::UnsupportedBranch => "branch",
+            RoutineEnd::NoVipChange => "no-vip-change",
+            RoutineEnd::DecodeFailed(_) => "decode-failed",
+        }
+    }
+}
+
+/// What vmentry sets up for the routine
+#[derive(Debug)]
+pub struct MapVmEntry {
+    pub address: u64,
+    pub register_allocation: VmRegisterAllocation,
+    pub handler_base: u64,
+}
+
+/// A vm call, the vmentry it enters and how far the decode of its routine got
+#[derive(Debug)]
+pub struct MapEntry {
+    pub vm_call_address: u64,
+    /// Section and symbol of the vm call, empty outside of the sections
+    pub label: String,
+    /// Function entry the vm call lies in, None outside of every entry
+    pub function: Option<RuntimeFunction>,
+    /// Export, ELF or PDB symbol at the start of the function
+    pub function_name: Option<String>,
+    /// None when vmentry could not be decoded
+    pub vm_entry: Option<MapVmEntry>,
+    /// Bytecode the decode read, from the lowest address to past the highest, empty when
+    /// vmentry could not be decoded
+    pub bytecode: Range<u64>,
+    /// VM instructions decoded, the one the decode stopped at included unless it failed
+    pub instruction_count: usize,
+    /// Distinct handlers no matcher recognised, branch handlers are counted as the end
+    pub unknown_handlers: usize,
+    pub end: RoutineEnd,
+}
+
+/// Every vm call of a module and the routine behind it
+#[derive(Debug, Default)]
+pub struct VirtualizationMap {
+    pub entries: Vec<MapEntry>,
+    /// The vm calls grouped by the function they lie in
+    pub functions: Vec<VirtualizedFunction>,
+}
+
+fn register_name(reg: Registers) -> String {
+    format!("{:?}", reg).to_lowercase()
+}
+
+/// A function as JSON, null outside of every function entry
+fn function_json(function: Option<RuntimeFunction>,
+                 name: Option<&str>)
+                 -> String {
+    match function {
+        Some(function) => {
+            format!("{{\"start\": \"{:#x}\", \"end\": \"{:#x}\", \"name\": {}}}",
+                    function.start,
+                    function.end,
+                    name.map(json_string).unwrap_or_else(|| "null".to_string()))
+        },
+        None => "null".to_string(),
+    }
+}
+
+impl VirtualizationMap {
+    /// Distinct vmentries the vm calls enter
+    pub fn vm_entry_count(&self) -> usize {
+        let mut vm_entries = self.entries
+                                 .iter()
+                                 .filter_map(|entry| entry.vm_entry.as_ref())
+                                 .map(|vm_entry| vm_entry.address)
+                                 .collect::<Vec<_>>();
+        vm_entries.sort();
+        vm_entries.dedup();
+        vm_entries.len()
+    }
+
+    /// Vm calls whose decode failed and why
+    fn failed(&self) -> impl Iterator<Item = (&MapEntry, &String)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match &entry.end {
+                RoutineEnd::DecodeFailed(reason) => Some((entry, reason)),
+                _ => None,
+            })
+    }
+
+    pub fn print_table(&self) {
+        println!("Vm calls: {}, vmentries: {}, ended at vmexit: {}, failed: {}, \
+                  unknown handlers: {}",
+                 self.entries.len(),
+                 self.vm_entry_count(),
+                 self.entries.iter().filter(|entry| entry.end == RoutineEnd::VmExit).count(),
+                 self.failed().count(),
+                 self.entries.iter().map(|entry| entry.unknown_handlers).sum::<usize>());
+        println!("{:<14} {:<14} {:<24} {:<14} {:<30} {:>6} {:>7} {:<13} {:<48} symbol",
+                 "vm call", "vmentry", "vip,vsp,key,handler", "handler base", "bytecode",
+                 "insns", "unknown", "end", "function");
+
+        for entry in self.entries.iter() {
+            let (vm_entry, registers, handler_base) = match &entry.vm_entry {
+                Some(vm_entry) => {
+                    let allocation = &vm_entry.register_allocation;
+                    let registers = [allocation.vip,
+                                     allocation.vsp,
+                                     allocation.key,
+                                     allocation.handler_address].map(register_name)
+                                                                .join(",");
+                    (format!("{:#x}", vm_entry.address),
+                     registers,
+                     format!("{:#x}", vm_entry.handler_base))
+                },
+                None => ("-".to_string(), "-".to_string(), "-".to_string()),
+            };
+            println!("{:<#14x} {:<14} {:<24} {:<14} {:<30} {:>6} {:>7} {:<13} {:<48} {}",
+                     entry.vm_call_address,
+                     vm_entry,
+                     registers,
+                     handler_base,
+                     format!("{:#x}-{:#x}", entry.bytecode.start, entry.bytecode.end),
+                     entry.instruction_count,
+                     entry.unknown_handlers,
+                     entry.end.name(),
+                     describe_function(entry.function, entry.function_name.as_deref()),
+                     entry.label);
+        }
+
+        for (entry, reason) in self.failed() {
+            println!("Decode of {:#x} failed: {}", entry.vm_call_address, reason);
+        }
+
+        print_virtualized_functions(&self.functions);
+    }
+
+    /// The map as JSON, addresses as hex strings. The vmentry fields are null when vmentry
+    /// could not be decoded, the error is null unless the decode failed and the function is null
+    /// outside of every function entry
+    pub fn to_json(&self) -> String {
+        let entries = self.entries
+                          .iter()
+                          .map(|entry| {
+                              let (vm_entry, registers, handler_base) = match &entry.vm_entry {
+                                  Some(vm_entry) => {
+                                      let allocation = &vm_entry.register_allocation;
+                                      (format!("\"{:#x}\"", vm_entry.address),
+                                       format!("{{\"vip\": \"{}\", \"vsp\": \"{}\", \
+                                                \"key\": \"{}\", \"handler\": \"{}\"}}",
+                                               register_name(allocation.vip),
+                                               register_name(allocation.vsp),
+                                               register_name(allocation.key),
+                                               register_name(allocation.handler_address)),
+                                       format!("\"{:#x}\"", vm_entry.handler_base))
+                                  },
+                                  None => {
+                                      ("null".to_string(), "null".to_string(), "null".to_string())
+                                  },
+                              };
+                              let error = match &entry.end {
+                                  RoutineEnd::DecodeFailed(reason) => json_string(reason),
+                                  _ => "null".to_string(),
+                              };
+                              format!("    {{\"vm_call\": \"{:#x}\", \"label\": {}, \
+                                       \"function\": {}, \
+                                       \"vm_entry\": {}, \"registers\": {}, \
+                                       \"handler_base\": {}, \"bytecode\": \
+                                       {{\"start\": \"{:#x}\", \"end\": \"{:#x}\"}}, \
+                                       \"instructions\": {}, \"unknown_handlers\": {}, \
+                                       \"end\": \"{}\", \"error\": {}}}",
+                                      entry.vm_call_address,
+                                      json_string(&entry.label),
+                                      function_json(entry.function,
+                                                    entry.function_name.as_deref()),
+                                      vm_entry,
+                                      registers,
+                                      handler_base,
+                                      entry.bytecode.start,
+                                      entry.bytecode.end,
+                                      entry.instruction_count,
+                                      entry.unknown_handlers,
+                                      entry.end.name(),
+                                      error)
+                          })
+                          .collect::<Vec<_>>();
+        let functions = self.functions
+                            .iter()
+                            .map(|virtualized| {
+                                let vm_calls = virtualized.vm_call_addresses
+                                                          .iter()
+                                                          .map(|address| {
+                                                              format!("\"{:#x}\"", address)
+                                                          })
+                                                          .collect::<Vec<_>>()
+                                                          .join(", ");
+                                format!("    {{\"function\": {}, \"vm_calls\": [{}]}}",
+                                        function_json(virtualized.function,
+                                                      virtualized.name.as_deref()),
+                                        vm_calls)
+                            })
+                            .collect::<Vec<_>>();
+        format!("{{\n  \"vm_calls\": {},\n  \"vm_entries\": {},\n  \"routines\": [\n{}\n  ],\n  \
+                 \"functions\": [\n{}\n  ]\n}}",
+                self.entries.len(),
+                self.vm_entry_count(),
+                entries.join(",\n"),
+                functions.join(",\n"))
+    }
+}
+
+/// Every push, call pair of the executable sections whose call enters a vmentry. Images without
+/// sections have nothing to scan
+pub fn scan_vm_calls(image: &dyn Image,
+                     symbols: &ImageSymbols)
+                     -> Vec<u64> {
+    let mut vm_entries = HashMap::new();
+    let mut vm_call_addresses = Vec::new();
+
+    for (start, end) in symbols.code_ranges() {
+        let code = match image.read(start, (end - start) as usize) {
+            Some(code) => code,
+            None => continue,
+        };
+        for (offset, window) in code.windows(VM_CALL_SIZE).enumerate() {
+            if window[0] != PUSH_IMM32 || window[5] != CALL_REL32 {
+                continue;
+            }
+            let address = start + offset as u64;
+            if !is_vm_call(image, address) {
+                continue;
+            }
+            let (_, vm_entry_address) = handle_vm_call(image, address);
+            let is_vm_entry = *vm_entries.entry(vm_entry_address).or_insert_with(|| {
+                                              VmHandler::new(vm_entry_address, image).is_vm_entry()
+                                          });
+            if is_vm_entry {
+                vm_call_addresses.push(address);
+            }
+        }
+    }
+
+    vm_call_addresses
+}
+
+/// Decodes the routine of the vm call up to the handler it can not follow, or up to the
+/// vmentry or handler that fails to decode
+pub fn map_vm_call(image: &dyn Image,
+                   symbols: &ImageSymbols,
+                   table: &FunctionTable,
+                   vm_call_address: u64)
+                   -> MapEntry {
+    let label = symbols.resolve(image, vm_call_address)
+                       .map(|symbol| symbol.to_string())
+                       .unwrap_or_default();
+    let function = table.containing(image, vm_call_address);
+    let function_name = function.and_then(|function| symbols.name_at(function.start))
+                                .map(str::to_string);
+    let mut vm_context = match VmContext::try_new(image, vm_call_address) {
+        Ok(vm_context) => vm_context,
+        Err(reason) => {
+            return MapEntry { vm_call_address,
+                              label,
+                              function,
+                              function_name,
+                              vm_entry: None,
+                              bytecode: 0 .. 0,
+                              instruction_count: 0,
+                              unknown_handlers: 0,
+                              end: RoutineEnd::DecodeFailed(reason) }
+        },
+    };
+
+    // Vmentry already read the offset of the first handler
+    let first_vip = match vm_context.vip_direction_forwards {
+        true => vm_context.vip_value.wrapping_sub(4),
+        false => vm_context.vip_value.wrapping_add(4),
+    };
+    let mut bytecode = first_vip .. first_vip;
+    let mut steps = Vec::new();
+    let mut failure = None;
+    loop {
+        let step = match vm_context.try_step(image) {
+            Ok(step) => step,
+            Err(reason) => {
+                failure = Some(reason);
+                break;
+            },
+        };
+        bytecode.start = bytecode.start.min(vm_context.vip_value);
+        bytecode.end = bytecode.end.max(vm_context.vip_value);
+        let halt = step.is_halt();
+        steps.push(step);
+
+        if halt {
+            break;
+        }
+    }
+
+    let mut unknown_handlers = steps.iter()
+                                    .filter(|step| {
+                                        step.handler_class != HandlerClass::UnconditionalBranch &&
+                                        step.instruction.is_unknown()
+                                    })
+                                    .map(|step| step.handler_address)
+                                    .collect::<Vec<_>>();
+    unknown_handlers.sort();
+    unknown_handlers.dedup();
+
+    let end = match (failure, steps.last()) {
+        (Some(reason), _) => RoutineEnd::DecodeFailed(reason),
+        (None, Some(last)) if last.instruction == HandlerVmInstruction::VmExit => {
+            RoutineEnd::VmExit
+        },
+        (None, Some(last)) if last.handler_class == HandlerClass::UnconditionalBranch => {
+            RoutineEnd::UnsupportedBranch
+        },
+        (None, _) => RoutineEnd::NoVipChange,
+    };
+
+    let vm_entry = MapVmEntry { address: vm_context.vm_entry_address,
+                                register_allocation: vm_context.register_allocation,
+                                handler_base: vm_context.handler_base };
+    MapEntry { vm_call_address,
+               label,
+               function,
+               function_name,
+               vm_entry: Some(vm_entry),
+               bytecode,
+               instruction_count: steps.len(),
+               unknown_handlers: unknown_handlers.len(),
+               end }
+}
+
+/// Maps every vm call and groups the vm calls by the function of the exception directory they
+/// lie in
+pub fn build_virtualization_map(image: &dyn Image,
+                                symbols: &ImageSymbols,
+                                vm_call_addresses: &[u64])
+                                -> VirtualizationMap {
+    let table = FunctionTable::new(image);
+    let entries = vm_call_addresses.iter()
+                                   .map(|&vm_call_address| {
+                                       map_vm_call(image, symbols, &table, vm_call_address)
+                                   })
+                                   .collect();
+    let functions = virtualized_functions(image, symbols, &table, vm_call_addresses);
+    VirtualizationMap { entries,
+                        functions }
+}
@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-use iced_x86::{Code, Register};
+use iced_x86::{Instruction, OpKind, Register};
 
 use crate::{
     match_assembly::{
@@ -10,6 +10,7 @@ use crate::{
         match_store_reg2_in_reg1, match_store_reg_any_size, match_sub_vsp_by_amount,
         match_sub_vsp_get_amount,
     },
+    match_assembly::match_push_rolling_key,
     util::check_full_reg_written,
     vm_handler::{Registers, VmHandler, VmRegisterAllocation},
 };
@@ -51,6 +52,31 @@ pub enum HandlerVmInstruction {
     Unknown,
 }
 
+impl HandlerVmInstruction {
+    pub fn is_unknown(&self) -> bool {
+        matches!(self,
+                 HandlerVmInstruction::UnknownByteOperand |
+                 HandlerVmInstruction::UnknownWordOperand |
+                 HandlerVmInstruction::UnknownDwordOperand |
+                 HandlerVmInstruction::UnknownQwordOperand |
+                 HandlerVmInstruction::UnknownNoOperand |
+                 HandlerVmInstruction::UnknownNoVipChange |
+                 HandlerVmInstruction::Unknown)
+    }
+
+    /// The instruction without its operands
+    pub fn mnemonic(&self) -> String {
+        match self {
+            HandlerVmInstruction::Pop(size, _) => format!("pop{}", size * 8),
+            HandlerVmInstruction::Push(size, _) => format!("push{}", size * 8),
+            HandlerVmInstruction::PushImm64(_) => "push_imm64".to_string(),
+            HandlerVmInstruction::PushImm32(_) => "push_imm32".to_string(),
+            HandlerVmInstruction::PushImm16(_) => "push_imm16".to_string(),
+            instruction => instruction.to_string(),
+        }
+    }
+}
+
 impl Display for HandlerVmInstruction {
     fn fmt(&self,
            f: &mut std::fmt::Formatter<'_>)
@@ -63,11 +89,11 @@ impl Display for HandlerVmInstruction {
 
             match size {
                 8 => {
-                    format!("r{}", register_number)
+                    format!("vr{}", register_number)
                 },
                 4 => match inner_reg_offset {
-                    0 => format!("r{}_low", register_number),
-                    4 => format!("r{}_high", register_number),
+                    0 => format!("vr{}_low", register_number),
+                    4 => format!("vr{}_high", register_number),
                     _ => unimplemented!(),
                 },
                 _ => unimplemented!(),
@@ -118,158 +144,366 @@ impl Display for HandlerVmInstruction {
     }
 }
 
+/// A matcher in the cascade of a handler class
+pub struct Matcher {
+    pub name: &'static str,
+    /// Number of native instructions the pattern consists of
+    pub pattern_length: usize,
+    pub matcher: fn(&VmHandler, &VmRegisterAllocation, u64) -> Option<HandlerVmInstruction>,
+}
+
+const NO_VIP_CHANGE_MATCHERS: &[Matcher] =
+    &[Matcher { name: "vm_match_vm_exit",
+                pattern_length: 18,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_vm_exit(handler, reg_allocation)
+                        .then_some(HandlerVmInstruction::VmExit)
+                } }];
+
+const BYTE_OPERAND_MATCHERS: &[Matcher] =
+    &[Matcher { name: "vm_match_vm_reg_pop",
+                pattern_length: 2,
+                matcher: |handler, reg_allocation, operand| {
+                    vm_match_vm_reg_pop(handler, reg_allocation).map(|size| {
+                        HandlerVmInstruction::Pop(size, operand as u8)
+                    })
+                } },
+      Matcher { name: "vm_match_vm_reg_push",
+                pattern_length: 2,
+                matcher: |handler, reg_allocation, operand| {
+                    vm_match_vm_reg_push(handler, reg_allocation).map(|size| {
+                        HandlerVmInstruction::Push(size, operand as u8)
+                    })
+                } }];
+
+const WORD_OPERAND_MATCHERS: &[Matcher] =
+    &[Matcher { name: "vm_match_push_imm16",
+                pattern_length: 2,
+                matcher: |handler, reg_allocation, operand| {
+                    vm_match_push_imm16(handler, reg_allocation)
+                        .then_some(HandlerVmInstruction::PushImm16(operand as u16))
+                } }];
+
+const DWORD_OPERAND_MATCHERS: &[Matcher] =
+    &[Matcher { name: "vm_match_push_imm32",
+                pattern_length: 2,
+                matcher: |handler, reg_allocation, operand| {
+                    vm_match_push_imm32(handler, reg_allocation)
+                        .then_some(HandlerVmInstruction::PushImm32(operand as u32))
+                } }];
+
+const QWORD_OPERAND_MATCHERS: &[Matcher] =
+    &[Matcher { name: "vm_match_push_imm64",
+                pattern_length: 2,
+                matcher: |handler, reg_allocation, operand| {
+                    vm_match_push_imm64(handler, reg_allocation)
+                        .then_some(HandlerVmInstruction::PushImm64(operand))
+                } }];
+
+const NO_OPERAND_MATCHERS: &[Matcher] =
+    &[Matcher { name: "vm_match_add",
+                pattern_length: 4,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_add(handler, reg_allocation).map(HandlerVmInstruction::Add)
+                } },
+      Matcher { name: "vm_match_add_byte",
+                pattern_length: 4,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_add_byte(handler, reg_allocation).map(HandlerVmInstruction::Add)
+                } },
+      Matcher { name: "vm_match_shr",
+                pattern_length: 4,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_shr(handler, reg_allocation).map(HandlerVmInstruction::Shr)
+                } },
+      Matcher { name: "vm_match_shr_byte",
+                pattern_length: 4,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_shr_byte(handler, reg_allocation).map(HandlerVmInstruction::Shr)
+                } },
+      Matcher { name: "vm_match_nand",
+                pattern_length: 6,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_nand(handler, reg_allocation).map(HandlerVmInstruction::Nand)
+                } },
+      Matcher { name: "vm_match_nand_byte",
+                pattern_length: 6,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_nand_byte(handler, reg_allocation).map(HandlerVmInstruction::Nand)
+                } },
+      Matcher { name: "vm_match_nor",
+                pattern_length: 6,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_nor(handler, reg_allocation).map(HandlerVmInstruction::Nor)
+                } },
+      Matcher { name: "vm_match_nor_byte",
+                pattern_length: 6,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_nor_byte(handler, reg_allocation).map(HandlerVmInstruction::Nor)
+                } },
+      Matcher { name: "vm_match_push_vsp",
+                pattern_length: 3,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_push_vsp(handler, reg_allocation).map(HandlerVmInstruction::PushVsp)
+                } },
+      Matcher { name: "vm_match_pop_vsp",
+                pattern_length: 1,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_pop_vsp(handler, reg_allocation).map(HandlerVmInstruction::PopVsp)
+                } },
+      Matcher { name: "vm_match_fetch",
+                pattern_length: 2,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_fetch(handler, reg_allocation).map(HandlerVmInstruction::Fetch)
+                } },
+      Matcher { name: "vm_match_fetch_byte",
+                pattern_length: 2,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_fetch_byte(handler, reg_allocation).map(HandlerVmInstruction::Fetch)
+                } },
+      Matcher { name: "vm_match_store",
+                pattern_length: 4,
+                matcher: |handler, reg_allocation, _| {
+                    vm_match_store(handler, reg_allocation).map(HandlerVmInstruction::Store)
+                } }];
+
+pub fn matchers_for_class(handler_class: HandlerClass) -> &'static [Matcher] {
+    match handler_class {
+        HandlerClass::ByteOperand => BYTE_OPERAND_MATCHERS,
+        HandlerClass::WordOperand => WORD_OPERAND_MATCHERS,
+        HandlerClass::DwordOperand => DWORD_OPERAND_MATCHERS,
+        HandlerClass::QwordOperand => QWORD_OPERAND_MATCHERS,
+        HandlerClass::NoOperand => NO_OPERAND_MATCHERS,
+        HandlerClass::NoVipChange => NO_VIP_CHANGE_MATCHERS,
+        HandlerClass::UnconditionalBranch => &[],
+    }
+}
+
+fn unknown_instruction_for_class(handler_class: HandlerClass) -> HandlerVmInstruction {
+    match handler_class {
+        HandlerClass::ByteOperand => HandlerVmInstruction::UnknownByteOperand,
+        HandlerClass::WordOperand => HandlerVmInstruction::UnknownWordOperand,
+        HandlerClass::DwordOperand => HandlerVmInstruction::UnknownDwordOperand,
+        HandlerClass::QwordOperand => HandlerVmInstruction::UnknownQwordOperand,
+        HandlerClass::NoOperand => HandlerVmInstruction::UnknownNoOperand,
+        HandlerClass::NoVipChange => HandlerVmInstruction::UnknownNoVipChange,
+        HandlerClass::UnconditionalBranch => HandlerVmInstruction::Unknown,
+    }
+}
+
+/// Result of a single matcher run during evaluation
+#[derive(Clone, Copy, Debug)]
+pub struct MatcherResult {
+    pub name: &'static str,
+    pub instruction: HandlerVmInstruction,
+    /// Fraction of the handler's operation instructions explained by the match
+    pub coverage: f32,
+}
+
+/// Every matcher of a handler class run against one handler
+#[derive(Debug)]
+pub struct MatchEvaluation {
+    pub handler_class: HandlerClass,
+    /// Instruction the cascade decoded
+    pub chosen: HandlerVmInstruction,
+    pub results: Vec<MatcherResult>,
+}
+
+impl MatchEvaluation {
+    /// True if the matchers disagree on what the handler is
+    pub fn is_ambiguous(&self) -> bool {
+        self.results
+            .iter()
+            .any(|result| result.instruction != self.results[0].instruction)
+    }
+
+    pub fn is_unmatched(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Coverage of the best match for the chosen instruction, scaled down by the share of
+    /// coverage claimed by disagreeing matches
+    pub fn confidence(&self) -> f32 {
+        let agreeing = self.results
+                           .iter()
+                           .filter(|result| result.instruction == self.chosen)
+                           .map(|result| result.coverage);
+        let best = agreeing.clone().fold(0.0, f32::max);
+        let total = self.results
+                        .iter()
+                        .filter(|result| result.instruction != self.chosen)
+                        .map(|result| result.coverage)
+                        .sum::<f32>() +
+                    best;
+
+        if total == 0.0 {
+            return 0.0;
+        }
+
+        best * (best / total)
+    }
+}
+
 impl VmHandler {
+    /// Instructions writing vip, these decide the handler class
+    pub fn vip_update_slice(&self,
+                            reg_allocation: &VmRegisterAllocation)
+                            -> Vec<&Instruction> {
+        self.instructions
+            .iter()
+            .filter(|insn| check_full_reg_written(insn, reg_allocation.vip.into()))
+            .collect()
+    }
+
+    /// Class of the handler by how its vip update instructions move vip, for handlers whose
+    /// symbolic state leaves vip in no known shape. Fails on vip updates of no known shape
     pub fn match_handler_class(&self,
                                reg_allocation: &VmRegisterAllocation)
-                               -> HandlerClass {
-        let instruction_iter = self.instructions.iter();
+                               -> Result<HandlerClass, String> {
+        let codes = self.word_codes();
+        let vip_update_slice = self.vip_update_slice(reg_allocation);
 
-        let vip_modification_vec =
-            instruction_iter.clone()
-                            .filter(|insn| check_full_reg_written(insn, reg_allocation.vip.into()))
-                            .filter(|insn| insn.code() == Code::Mov_r64_rm64)
-                            .collect::<Vec<_>>();
+        let vip_modification_vec = vip_update_slice.iter()
+                                                   .filter(|insn| insn.code() == codes.mov_r_rm)
+                                                   .collect::<Vec<_>>();
 
         if (reg_allocation.vip != Registers::Rsi &&
             reg_allocation.vip != Registers::Rdi &&
             !vip_modification_vec.is_empty()) ||
            (vip_modification_vec.len() >= 2)
         {
-            return HandlerClass::UnconditionalBranch;
+            return Ok(HandlerClass::UnconditionalBranch);
         }
 
-        let vip_update_vec =
-            instruction_iter.filter(|insn| check_full_reg_written(insn, reg_allocation.vip.into()))
-                            .filter(|insn| {
-                                insn.code() == Code::Add_rm64_imm32 ||
-                                insn.code() == Code::Sub_rm64_imm32
-                            })
-                            .map(|insn| insn.immediate32())
-                            .collect::<Vec<_>>();
+        let vip_update_vec = vip_update_slice.iter()
+                                             .filter(|insn| {
+                                                 insn.code() == codes.add_rm_imm ||
+                                                 insn.code() == codes.sub_rm_imm
+                                             })
+                                             .map(|insn| insn.immediate32())
+                                             .collect::<Vec<_>>();
 
         match vip_update_vec.as_slice() {
-            &[] => HandlerClass::NoVipChange,
-            &[4] => HandlerClass::NoOperand,
-            &[8, 4] => HandlerClass::QwordOperand,
-            &[4, 4] => HandlerClass::DwordOperand,
-            &[2, 4] => HandlerClass::WordOperand,
-            &[1, 4] => HandlerClass::ByteOperand,
-            slice => {
-                panic!("Unimplemented handler class with slice {:?}", slice)
-            },
+            &[] => Ok(HandlerClass::NoVipChange),
+            &[4] => Ok(HandlerClass::NoOperand),
+            &[8, 4] => Ok(HandlerClass::QwordOperand),
+            &[4, 4] => Ok(HandlerClass::DwordOperand),
+            &[2, 4] => Ok(HandlerClass::WordOperand),
+            &[1, 4] => Ok(HandlerClass::ByteOperand),
+            slice => Err(format!("unimplemented handler class with vip updates {:?}", slice)),
         }
     }
 
+    fn match_cascade(&self,
+                     reg_allocation: &VmRegisterAllocation,
+                     handler_class: HandlerClass,
+                     operand: u64)
+                     -> HandlerVmInstruction {
+        let matched = matchers_for_class(handler_class).iter()
+                                                       .find_map(|matcher| {
+                                                           (matcher.matcher)(self,
+                                                                             reg_allocation,
+                                                                             operand)
+                                                       });
+        if let Some(instruction) = matched {
+            return instruction;
+        }
+
+        self.compute_semantics(reg_allocation)
+            .classify(handler_class, operand)
+            .unwrap_or_else(|| unknown_instruction_for_class(handler_class))
+    }
+
     pub fn match_no_vip_change_instructions(&self,
                                             reg_allocation: &VmRegisterAllocation)
                                             -> HandlerVmInstruction {
-        if vm_match_vm_exit(self, reg_allocation) {
-            return HandlerVmInstruction::VmExit;
-        }
-
-        HandlerVmInstruction::UnknownNoVipChange
+        self.match_cascade(reg_allocation, HandlerClass::NoVipChange, 0)
     }
 
     pub fn match_byte_operand_instructions(&self,
                                            reg_allocation: &VmRegisterAllocation,
                                            byte_operand: u8)
                                            -> HandlerVmInstruction {
-        if let Some(size) = vm_match_vm_reg_pop(self, reg_allocation) {
-            return HandlerVmInstruction::Pop(size, byte_operand);
-        }
-
-        if let Some(size) = vm_match_vm_reg_push(self, reg_allocation) {
-            return HandlerVmInstruction::Push(size, byte_operand);
-        }
-
-        HandlerVmInstruction::UnknownByteOperand
+        self.match_cascade(reg_allocation, HandlerClass::ByteOperand, byte_operand as u64)
     }
 
     pub fn match_word_operand_instructions(&self,
                                            reg_allocation: &VmRegisterAllocation,
                                            word_operand: u16)
                                            -> HandlerVmInstruction {
-        if vm_match_push_imm16(self, reg_allocation) {
-            return HandlerVmInstruction::PushImm16(word_operand);
-        }
-        HandlerVmInstruction::UnknownWordOperand
+        self.match_cascade(reg_allocation, HandlerClass::WordOperand, word_operand as u64)
     }
 
     pub fn match_dword_operand_instructions(&self,
                                             reg_allocation: &VmRegisterAllocation,
                                             dword_operand: u32)
                                             -> HandlerVmInstruction {
-        if vm_match_push_imm32(self, reg_allocation) {
-            return HandlerVmInstruction::PushImm32(dword_operand);
-        }
-        HandlerVmInstruction::UnknownDwordOperand
+        self.match_cascade(reg_allocation, HandlerClass::DwordOperand, dword_operand as u64)
     }
 
     pub fn match_qword_operand_instructions(&self,
                                             reg_allocation: &VmRegisterAllocation,
                                             qword_operand: u64)
                                             -> HandlerVmInstruction {
-        if vm_match_push_imm64(self, reg_allocation) {
-            return HandlerVmInstruction::PushImm64(qword_operand);
-        }
-        HandlerVmInstruction::UnknownQwordOperand
+        self.match_cascade(reg_allocation, HandlerClass::QwordOperand, qword_operand)
     }
 
     pub fn match_no_operand_instructions(&self,
                                          reg_allocation: &VmRegisterAllocation)
                                          -> HandlerVmInstruction {
-        if let Some(size) = vm_match_add(self, reg_allocation) {
-            return HandlerVmInstruction::Add(size);
-        }
-
-        if let Some(size) = vm_match_add_byte(self, reg_allocation) {
-            return HandlerVmInstruction::Add(size);
-        }
-
-        if let Some(size) = vm_match_shr(self, reg_allocation) {
-            return HandlerVmInstruction::Shr(size);
-        }
-
-        if let Some(size) = vm_match_shr_byte(self, reg_allocation) {
-            return HandlerVmInstruction::Shr(size);
-        }
-
-        if let Some(size) = vm_match_nand(self, reg_allocation) {
-            return HandlerVmInstruction::Nand(size);
-        }
-
-        if let Some(size) = vm_match_nand_byte(self, reg_allocation) {
-            return HandlerVmInstruction::Nand(size);
-        }
-
-        if let Some(size) = vm_match_nor(self, reg_allocation) {
-            return HandlerVmInstruction::Nor(size);
-        }
-
-        if let Some(size) = vm_match_nor_byte(self, reg_allocation) {
-            return HandlerVmInstruction::Nor(size);
-        }
-
-        if let Some(size) = vm_match_push_vsp(self, reg_allocation) {
-            return HandlerVmInstruction::PushVsp(size);
-        }
+        self.match_cascade(reg_allocation, HandlerClass::NoOperand, 0)
+    }
 
-        if vm_match_pop_vsp_64(self, reg_allocation) {
-            return HandlerVmInstruction::PopVsp(8);
-        }
+    /// Instructions doing the handler's work, everything but the bytecode decryption and
+    /// the dispatch to the next handler
+    fn operation_instruction_count(&self,
+                                   reg_allocation: &VmRegisterAllocation)
+                                   -> usize {
+        let dispatch_registers = [reg_allocation.vip,
+                                  reg_allocation.key,
+                                  reg_allocation.handler_address];
+
+        self.deobfuscate(reg_allocation)
+            .iter()
+            .filter(|insn| insn.flow_control() == iced_x86::FlowControl::Next)
+            .filter(|insn| !match_push_rolling_key(insn, reg_allocation))
+            .filter(|insn| {
+                !dispatch_registers.iter()
+                                   .any(|&reg| check_full_reg_written(insn, reg.into()))
+            })
+            .count()
+    }
 
-        if let Some(size) = vm_match_fetch(self, reg_allocation) {
-            return HandlerVmInstruction::Fetch(size);
+    /// Runs every matcher of the handler class and the semantic classifier
+    pub fn evaluate_matchers(&self,
+                             reg_allocation: &VmRegisterAllocation,
+                             handler_class: HandlerClass,
+                             operand: u64,
+                             chosen: HandlerVmInstruction)
+                             -> MatchEvaluation {
+        let operation_count = self.operation_instruction_count(reg_allocation).max(1);
+
+        let mut results = Vec::new();
+        for matcher in matchers_for_class(handler_class) {
+            if let Some(instruction) = (matcher.matcher)(self, reg_allocation, operand) {
+                let coverage = matcher.pattern_length as f32 / operation_count as f32;
+                results.push(MatcherResult { name: matcher.name,
+                                             instruction,
+                                             coverage: coverage.min(1.0) });
+            }
         }
 
-        if let Some(size) = vm_match_fetch_byte(self, reg_allocation) {
-            return HandlerVmInstruction::Fetch(size);
+        if let Some(instruction) =
+            self.compute_semantics(reg_allocation).classify(handler_class, operand)
+        {
+            results.push(MatcherResult { name: "semantics",
+                                         instruction,
+                                         coverage: 1.0 });
         }
 
-        if let Some(size) = vm_match_store(self, reg_allocation) {
-            return HandlerVmInstruction::Store(size);
-        }
-        HandlerVmInstruction::UnknownNoOperand
+        MatchEvaluation { handler_class,
+                          chosen,
+                          results }
     }
 }
 
@@ -327,20 +561,21 @@ fn vm_match_push_imm16(vm_handler: &VmHandler,
     instruction_iter.any(|insn| match_store_reg_any_size(insn, reg_allocation.vsp.into()).is_some())
 }
 
-fn vm_match_pop_vsp_64(vm_handler: &VmHandler,
-                       reg_allocation: &VmRegisterAllocation)
-                       -> bool {
+fn vm_match_pop_vsp(vm_handler: &VmHandler,
+                    reg_allocation: &VmRegisterAllocation)
+                    -> Option<usize> {
     let mut instruction_iter = vm_handler.instructions.iter();
 
     let fetch_vsp_instruction_1 =
         instruction_iter.find(|insn| {
                             match_fetch_reg_any_size(insn, reg_allocation.vsp.into()).is_some()
-                        });
-    if fetch_vsp_instruction_1.is_none() {
-        return false;
+                        })?;
+
+    if fetch_vsp_instruction_1.op0_register().full_register() != reg_allocation.vsp.into() {
+        return None;
     }
 
-    fetch_vsp_instruction_1.unwrap().op0_register() == reg_allocation.vsp.into()
+    Some(fetch_vsp_instruction_1.memory_size().size())
 }
 
 fn vm_match_add(vm_handler: &VmHandler,
@@ -404,7 +639,7 @@ fn vm_match_shr(vm_handler: &VmHandler,
         instruction_iter.find(|insn| {
                             match_fetch_reg_any_size(insn, reg_allocation.vsp.into()).is_some()
                         })?;
-    let reg = fetch_vsp_instruction_1.op0_register();
+    let reg = fetch_vsp_instruction_1.op0_register().full_register();
 
     let _fetch_vsp_instruction_2 =
         instruction_iter.find(|insn| {
@@ -576,7 +811,9 @@ fn vm_match_store(vm_handler: &VmHandler,
                         })?;
     let reg2 = fetch_vsp_instruction_2.op0_register();
 
-    instruction_iter.find(|insn| match_add_vsp_by_amount(insn, reg_allocation, 0x10))?;
+    // The address and the value each take a word
+    let word_size = vm_handler.word_size() as u32;
+    instruction_iter.find(|insn| match_add_vsp_by_amount(insn, reg_allocation, 2 * word_size))?;
 
     let mut instruction_size = 0;
     instruction_iter.find(|insn| match match_store_reg2_in_reg1(insn, reg1, reg2) {
@@ -598,7 +835,7 @@ fn vm_match_fetch(vm_handler: &VmHandler,
                             match_fetch_reg_any_size(insn, reg_allocation.vsp.into()).is_some()
                         })?;
 
-    let fetch_register = fetch_vsp_instruction.op0_register();
+    let fetch_register = fetch_vsp_instruction.op0_register().full_register();
 
     let mut fetch_size = 0;
     instruction_iter.find(|insn| {
@@ -665,18 +902,26 @@ fn vm_match_vm_exit(vm_handler: &VmHandler,
         return false;
     }
 
+    let codes = vm_handler.word_codes();
     if !instruction_iter.clone().any(|insn| {
-                                    insn.code() == Code::Mov_r64_rm64 &&
-                                    insn.op0_register() == Register::RSP &&
-                                    insn.op1_register() == reg_allocation.vsp.into()
+                                    insn.code() == codes.mov_r_rm &&
+                                    insn.op1_kind() == OpKind::Register &&
+                                    insn.op0_register().full_register() == Register::RSP &&
+                                    insn.op1_register().full_register() ==
+                                    reg_allocation.vsp.into()
                                 })
     {
         return false;
     }
 
-    if instruction_iter.filter(|insn| insn.code() == Code::Pop_r64)
+    // Every general purpose register but the stack pointer
+    let saved_registers = match vm_handler.bitness {
+        32 => 7,
+        _ => 15,
+    };
+    if instruction_iter.filter(|insn| insn.code() == codes.pop)
                        .count() !=
-       15
+       saved_registers
     {
         return false;
     }
@@ -0,0 +1,426 @@
+use std::{collections::HashSet, fmt};
+
+use iced_x86::{
+    Code, ConditionCode, Decoder, DecoderOptions, FlowControl, Instruction, InstructionInfoFactory,
+    InstructionInfoOptions, Mnemonic, OpAccess, OpKind, Register, RflagsBits,
+};
+
+use crate::{
+    image::Image,
+    util::is_high_byte_register,
+};
+
+/// Upper bound on the native instructions decoded for one handler
+pub const MAX_HANDLER_INSTRUCTIONS: usize = 4096;
+
+/// Why the walker stopped following a handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkEnd {
+    /// Jump or return to a computed address, the normal end of a handler
+    Dispatch,
+    /// Control flow reached an address that was already walked
+    Revisited(u64),
+    /// More than MAX_HANDLER_INSTRUCTIONS instructions were decoded
+    InstructionLimit,
+    /// Branch whose condition or target is not constant
+    UnresolvedBranch(u64),
+    /// Bytes that are outside the image or do not decode
+    InvalidInstruction(u64),
+}
+
+impl fmt::Display for WalkEnd {
+    fn fmt(&self,
+           f: &mut fmt::Formatter<'_>)
+           -> fmt::Result {
+        match self {
+            WalkEnd::Dispatch => write!(f, "dispatch"),
+            WalkEnd::Revisited(address) => write!(f, "revisited {:#x}", address),
+            WalkEnd::InstructionLimit => {
+                write!(f, "more than {} instructions", MAX_HANDLER_INSTRUCTIONS)
+            },
+            WalkEnd::UnresolvedBranch(address) => write!(f, "unresolved branch at {:#x}", address),
+            WalkEnd::InvalidInstruction(address) => {
+                write!(f, "invalid instruction at {:#x}", address)
+            },
+        }
+    }
+}
+
+const ARITHMETIC_FLAGS: u32 =
+    RflagsBits::OF | RflagsBits::SF | RflagsBits::ZF | RflagsBits::CF | RflagsBits::PF;
+
+/// Result and arithmetic flags of an alu operation on constants
+fn alu_constant(mnemonic: Mnemonic,
+                lhs: u64,
+                rhs: u64,
+                size: usize)
+                -> Option<(u64, u32)> {
+    let bits = size as u32 * 8;
+    let mask = u64::MAX >> (64 - bits);
+    let sign = 1u64 << (bits - 1);
+    let (lhs, rhs) = (lhs & mask, rhs & mask);
+
+    let result = match mnemonic {
+        Mnemonic::Add => lhs.wrapping_add(rhs),
+        Mnemonic::Sub | Mnemonic::Cmp => lhs.wrapping_sub(rhs),
+        Mnemonic::And | Mnemonic::Test => lhs & rhs,
+        Mnemonic::Or => lhs | rhs,
+        Mnemonic::Xor => lhs ^ rhs,
+        _ => return None,
+    } & mask;
+
+    let (carry, overflow) = match mnemonic {
+        Mnemonic::Add => (result < lhs, (lhs ^ result) & (rhs ^ result) & sign != 0),
+        Mnemonic::Sub | Mnemonic::Cmp => (lhs < rhs, (lhs ^ rhs) & (lhs ^ result) & sign != 0),
+        _ => (false, false),
+    };
+
+    let mut flags = 0;
+    if carry {
+        flags |= RflagsBits::CF;
+    }
+    if overflow {
+        flags |= RflagsBits::OF;
+    }
+    if result == 0 {
+        flags |= RflagsBits::ZF;
+    }
+    if result & sign != 0 {
+        flags |= RflagsBits::SF;
+    }
+    if (result as u8).count_ones() & 1 == 0 {
+        flags |= RflagsBits::PF;
+    }
+
+    Some((result, flags))
+}
+
+/// Registers and flags with a value known while walking a handler
+struct ConstantState {
+    registers: [Option<u64>; 16],
+    known_flags: u32,
+    flags: u32,
+}
+
+impl ConstantState {
+    fn new() -> Self {
+        Self { registers: [None; 16],
+               known_flags: 0,
+               flags: 0 }
+    }
+
+    fn register(&self,
+                reg: Register)
+                -> Option<u64> {
+        if !reg.is_gpr() || is_high_byte_register(reg) {
+            return None;
+        }
+
+        let value = self.registers[reg.full_register().number()]?;
+        match reg.size() {
+            8 => Some(value),
+            size => Some(value & (u64::MAX >> (64 - size * 8))),
+        }
+    }
+
+    fn set_register(&mut self,
+                    reg: Register,
+                    value: Option<u64>) {
+        if !reg.is_gpr() {
+            return;
+        }
+
+        let index = reg.full_register().number();
+        self.registers[index] = match (reg.size(), value) {
+            (8, value) => value,
+            (4, value) => value.map(|value| value & 0xffff_ffff),
+            _ if is_high_byte_register(reg) => None,
+            (size, Some(value)) => {
+                let mask = u64::MAX >> (64 - size * 8);
+                self.registers[index].map(|old| (old & !mask) | (value & mask))
+            },
+            (_, None) => None,
+        };
+    }
+
+    fn operand_value(&self,
+                     instruction: &Instruction,
+                     operand: u32)
+                     -> Option<u64> {
+        match instruction.op_kind(operand) {
+            OpKind::Register => self.register(instruction.op_register(operand)),
+            OpKind::Memory => None,
+            _ => instruction.try_immediate(operand).ok(),
+        }
+    }
+
+    fn set_flags(&mut self,
+                 bits: u32,
+                 values: u32) {
+        self.known_flags |= bits;
+        self.flags = (self.flags & !bits) | (values & bits);
+    }
+
+    fn flag(&self,
+            bit: u32)
+            -> Option<bool> {
+        match self.known_flags & bit {
+            0 => None,
+            _ => Some(self.flags & bit != 0),
+        }
+    }
+
+    /// Whether a conditional branch is taken, if the flags it reads are known
+    fn condition(&self,
+                 condition_code: ConditionCode)
+                 -> Option<bool> {
+        let of = || self.flag(RflagsBits::OF);
+        let sf = || self.flag(RflagsBits::SF);
+        let zf = || self.flag(RflagsBits::ZF);
+        let cf = || self.flag(RflagsBits::CF);
+        let pf = || self.flag(RflagsBits::PF);
+
+        let taken = match condition_code {
+            ConditionCode::o => of()?,
+            ConditionCode::no => !of()?,
+            ConditionCode::b => cf()?,
+            ConditionCode::ae => !cf()?,
+            ConditionCode::e => zf()?,
+            ConditionCode::ne => !zf()?,
+            ConditionCode::be => cf()? || zf()?,
+            ConditionCode::a => !cf()? && !zf()?,
+            ConditionCode::s => sf()?,
+            ConditionCode::ns => !sf()?,
+            ConditionCode::p => pf()?,
+            ConditionCode::np => !pf()?,
+            ConditionCode::l => sf()? != of()?,
+            ConditionCode::ge => sf()? == of()?,
+            ConditionCode::le => zf()? || sf()? != of()?,
+            ConditionCode::g => !zf()? && sf()? == of()?,
+            ConditionCode::None => return None,
+        };
+
+        Some(taken)
+    }
+
+    /// Value written to the destination register and the arithmetic flags, when the
+    /// instruction only depends on known state
+    fn evaluate(&self,
+                instruction: &Instruction)
+                -> (Option<u64>, Option<u32>) {
+        let mnemonic = instruction.mnemonic();
+        if instruction.op0_kind() != OpKind::Register {
+            return (None, None);
+        }
+
+        match mnemonic {
+            Mnemonic::Mov => (self.operand_value(instruction, 1), None),
+            Mnemonic::Lea if instruction.is_ip_rel_memory_operand() => {
+                (Some(instruction.ip_rel_memory_address()), None)
+            },
+            Mnemonic::Add |
+            Mnemonic::Sub |
+            Mnemonic::Cmp |
+            Mnemonic::And |
+            Mnemonic::Test |
+            Mnemonic::Or |
+            Mnemonic::Xor => {
+                let reg = instruction.op0_register();
+
+                // xor reg, reg and sub reg, reg do not depend on the value of reg
+                let same_register = instruction.op1_kind() == OpKind::Register &&
+                                    instruction.op1_register() == reg &&
+                                    matches!(mnemonic,
+                                             Mnemonic::Xor | Mnemonic::Sub | Mnemonic::Cmp);
+                let operands = match same_register {
+                    true => Some((0, 0)),
+                    false => self.operand_value(instruction, 0)
+                                 .zip(self.operand_value(instruction, 1)),
+                };
+
+                match operands.and_then(|(lhs, rhs)| alu_constant(mnemonic, lhs, rhs, reg.size()))
+                {
+                    Some((_, flags)) if matches!(mnemonic, Mnemonic::Cmp | Mnemonic::Test) => {
+                        (None, Some(flags))
+                    },
+                    Some((result, flags)) => (Some(result), Some(flags)),
+                    None => (None, None),
+                }
+            },
+            _ => (None, None),
+        }
+    }
+
+    fn step(&mut self,
+            instruction: &Instruction,
+            info_factory: &mut InstructionInfoFactory) {
+        let carry = self.flag(RflagsBits::CF);
+        let (value, flags) = self.evaluate(instruction);
+
+        // Anything else written becomes unknown
+        let computed_register = match value {
+            Some(value) => {
+                self.set_register(instruction.op0_register(), Some(value));
+                instruction.op0_register().full_register()
+            },
+            None => Register::None,
+        };
+        let info = info_factory.info_options(instruction, InstructionInfoOptions::NO_MEMORY_USAGE);
+        for used_register in info.used_registers() {
+            if used_register.access() != OpAccess::Read &&
+               used_register.access() != OpAccess::CondRead &&
+               used_register.register().full_register() != computed_register
+            {
+                self.set_register(used_register.register(), None);
+            }
+        }
+
+        self.known_flags &= !instruction.rflags_modified();
+        self.set_flags(instruction.rflags_set() | instruction.rflags_cleared(),
+                       instruction.rflags_set());
+
+        if let Some(flags) = flags {
+            self.set_flags(ARITHMETIC_FLAGS, flags);
+        }
+
+        if let (Mnemonic::Cmc, Some(carry)) = (instruction.mnemonic(), carry) {
+            self.set_flags(RflagsBits::CF, if carry { 0 } else { RflagsBits::CF });
+        }
+    }
+}
+
+fn decode_at(image: &dyn Image,
+             address: u64)
+             -> Option<Instruction> {
+    let instruction_bytes = image.read(address, 16)?;
+    let instruction = Decoder::with_ip(image.bitness(),
+                                       &instruction_bytes,
+                                       address,
+                                       DecoderOptions::NONE).decode();
+
+    match instruction.code() {
+        Code::INVALID => None,
+        _ => Some(instruction),
+    }
+}
+
+/// Follows the native code of a handler from its address to its dispatch, removing the
+/// unconditional jumps, opaque branches and call/pop and push/ret gadgets between them.
+/// Every kept instruction keeps the address it was decoded at as its ip
+pub fn walk_handler(address: u64,
+                    image: &dyn Image)
+                    -> (Vec<Instruction>, WalkEnd) {
+    let mut info_factory = InstructionInfoFactory::new();
+    let mut state = ConstantState::new();
+    let mut visited = HashSet::new();
+    let mut instructions: Vec<Instruction> = Vec::new();
+    let mut pushed_constant = None;
+    let mut instruction_address = address;
+
+    for _ in 0 .. MAX_HANDLER_INSTRUCTIONS {
+        if !visited.insert(instruction_address) {
+            return (instructions, WalkEnd::Revisited(instruction_address));
+        }
+
+        let instruction = match decode_at(image, instruction_address) {
+            Some(instruction) => instruction,
+            None => return (instructions, WalkEnd::InvalidInstruction(instruction_address)),
+        };
+        let next_address = instruction.next_ip();
+        let push_value = pushed_constant.take();
+
+        match instruction.code() {
+            Code::Jmp_rel32_64 | Code::Jmp_rel8_64 | Code::Jmp_rel32_32 | Code::Jmp_rel8_32 => {
+                pushed_constant = push_value;
+                instruction_address = instruction.near_branch64();
+            },
+
+            // Opaque predicates only ever go one way
+            _ if instruction.is_jcc_short_or_near() => {
+                pushed_constant = push_value;
+                match state.condition(instruction.condition_code()) {
+                    Some(true) => instruction_address = instruction.near_branch64(),
+                    Some(false) => instruction_address = next_address,
+                    None => {
+                        instructions.push(instruction);
+                        return (instructions, WalkEnd::UnresolvedBranch(instruction.ip()));
+                    },
+                }
+            },
+
+            // call $+n; pop reg loads the return address
+            Code::Call_rel32_64 | Code::Call_rel32_32 => {
+                let target = instruction.near_branch64();
+                match decode_at(image, target) {
+                    Some(pop) if matches!(pop.code(), Code::Pop_r64 | Code::Pop_r32) &&
+                                 visited.insert(target) =>
+                    {
+                        let mov = match pop.code() {
+                            Code::Pop_r64 => Instruction::with2(Code::Mov_r64_imm64,
+                                                                pop.op0_register(),
+                                                                next_address),
+                            _ => Instruction::with2(Code::Mov_r32_imm32,
+                                                    pop.op0_register(),
+                                                    next_address as u32),
+                        };
+                        let mut mov = mov.unwrap();
+                        mov.set_len(instruction.len());
+                        mov.set_ip(instruction.ip());
+
+                        state.step(&mov, &mut info_factory);
+                        instructions.push(mov);
+                        instruction_address = pop.next_ip();
+                    },
+                    _ => {
+                        instructions.push(instruction);
+                        return (instructions, WalkEnd::UnresolvedBranch(instruction.ip()));
+                    },
+                }
+            },
+
+            Code::Jmp_rm64 | Code::Jmp_rm32 if instruction.op0_kind() == OpKind::Register => {
+                match state.register(instruction.op0_register()) {
+                    Some(target) => instruction_address = target,
+                    None => {
+                        instructions.push(instruction);
+                        return (instructions, WalkEnd::Dispatch);
+                    },
+                }
+            },
+
+            // push constant; ret is a jump to the constant
+            Code::Retnq | Code::Retnd => match push_value {
+                Some(target) => {
+                    instructions.pop();
+                    instruction_address = target;
+                },
+                None => {
+                    instructions.push(instruction);
+                    return (instructions, WalkEnd::Dispatch);
+                },
+            },
+
+            _ if instruction.flow_control() != FlowControl::Next => {
+                instructions.push(instruction);
+                return (instructions, WalkEnd::UnresolvedBranch(instruction.ip()));
+            },
+
+            _ => {
+                pushed_constant = match instruction.code() {
+                    Code::Push_r64 | Code::Push_r32 => state.register(instruction.op0_register()),
+                    Code::Pushq_imm32 | Code::Pushq_imm8 => Some(instruction.immediate(0)),
+                    Code::Pushd_imm32 | Code::Pushd_imm8 => {
+                        Some(instruction.immediate(0) & 0xffff_ffff)
+                    },
+                    _ => None,
+                };
+
+                state.step(&instruction, &mut info_factory);
+                instructions.push(instruction);
+                instruction_address = next_address;
+            },
+        }
+    }
+
+    (instructions, WalkEnd::InstructionLimit)
+}